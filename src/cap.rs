@@ -7,6 +7,18 @@
 
 use kvm_bindings::*;
 
+// The following definitions come from `include/uapi/linux/kvm.h` and are missing from the
+// `kvm-bindings` used by this crate.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const KVM_CAP_PMU_EVENT_MASKED_EVENTS: u32 = 226;
+#[cfg(target_arch = "aarch64")]
+const KVM_CAP_COUNTER_OFFSET: u32 = 227;
+const KVM_CAP_MEMORY_FAULT_INFO: u32 = 232;
+const KVM_CAP_MEMORY_ATTRIBUTES: u32 = 233;
+const KVM_CAP_GUEST_MEMFD: u32 = 234;
+#[cfg(target_arch = "aarch64")]
+const KVM_CAP_ARM_EL2: u32 = 240;
+
 /// Capabilities exposed by KVM.
 ///
 /// The capabilities list can be used in conjunction with
@@ -158,6 +170,8 @@ pub enum Cap {
     DebugHwBps = KVM_CAP_GUEST_DEBUG_HW_BPS,
     DebugHwWps = KVM_CAP_GUEST_DEBUG_HW_WPS,
     GetMsrFeatures = KVM_CAP_GET_MSR_FEATURES,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    HypervEventfd = KVM_CAP_HYPERV_EVENTFD,
     CoalescedPio = KVM_CAP_COALESCED_PIO,
     #[cfg(target_arch = "aarch64")]
     ArmSve = KVM_CAP_ARM_SVE,
@@ -169,4 +183,70 @@ pub enum Cap {
     X86UserSpaceMsr = KVM_CAP_X86_USER_SPACE_MSR,
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     ExitHypercall = KVM_CAP_EXIT_HYPERCALL,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    X86MsrFilter = KVM_CAP_X86_MSR_FILTER,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    X86BusLockExit = KVM_CAP_X86_BUS_LOCK_EXIT,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    X86NotifyVmexit = KVM_CAP_X86_NOTIFY_VMEXIT,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    X2ApicApi = KVM_CAP_X2APIC_API,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    PmuEventFilter = KVM_CAP_PMU_EVENT_FILTER,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    PmuEventMaskedEvents = KVM_CAP_PMU_EVENT_MASKED_EVENTS,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    SgxAttribute = KVM_CAP_SGX_ATTRIBUTE,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    HypervEnlightenedVmcs = KVM_CAP_HYPERV_ENLIGHTENED_VMCS,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    HypervCpuid = KVM_CAP_HYPERV_CPUID,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    HypervEnforceCpuid = KVM_CAP_HYPERV_ENFORCE_CPUID,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    VmCopyEncContextFrom = KVM_CAP_VM_COPY_ENC_CONTEXT_FROM,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    VmMoveEncContextFrom = KVM_CAP_VM_MOVE_ENC_CONTEXT_FROM,
+    #[cfg(target_arch = "aarch64")]
+    CounterOffset = KVM_CAP_COUNTER_OFFSET,
+    #[cfg(target_arch = "aarch64")]
+    ArmMte = KVM_CAP_ARM_MTE,
+    #[cfg(target_arch = "aarch64")]
+    ArmEl2 = KVM_CAP_ARM_EL2,
+    #[cfg(target_arch = "aarch64")]
+    ArmSystemSuspend = KVM_CAP_ARM_SYSTEM_SUSPEND,
+    DirtyLogRing = KVM_CAP_DIRTY_LOG_RING,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Sregs2 = KVM_CAP_SREGS2,
+    BinaryStatsFd = KVM_CAP_BINARY_STATS_FD,
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    Xsave2 = KVM_CAP_XSAVE2,
+    MemoryFaultInfo = KVM_CAP_MEMORY_FAULT_INFO,
+    MemoryAttributes = KVM_CAP_MEMORY_ATTRIBUTES,
+    GuestMemfd = KVM_CAP_GUEST_MEMFD,
+    StealTime = KVM_CAP_STEAL_TIME,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cap_values() {
+        assert_eq!(Cap::DirtyLogRing as u32, KVM_CAP_DIRTY_LOG_RING);
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            assert_eq!(Cap::Sregs2 as u32, KVM_CAP_SREGS2);
+            assert_eq!(Cap::Xsave2 as u32, KVM_CAP_XSAVE2);
+        }
+        assert_eq!(Cap::BinaryStatsFd as u32, KVM_CAP_BINARY_STATS_FD);
+        assert_eq!(Cap::MemoryFaultInfo as u32, 232);
+        assert_eq!(Cap::MemoryAttributes as u32, 233);
+        assert_eq!(Cap::GuestMemfd as u32, 234);
+        #[cfg(target_arch = "aarch64")]
+        {
+            assert_eq!(Cap::ArmSystemSuspend as u32, KVM_CAP_ARM_SYSTEM_SUSPEND);
+            assert_eq!(Cap::ArmEl2 as u32, KVM_CAP_ARM_EL2);
+        }
+    }
 }
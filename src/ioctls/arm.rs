@@ -0,0 +1,750 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use kvm_bindings::{
+    kvm_create_device, kvm_device_attr, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+    kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+    KVM_ARM_VCPU_PVTIME_CTRL, KVM_ARM_VCPU_PVTIME_IPA, KVM_DEV_ARM_ITS_RESTORE_TABLES,
+    KVM_DEV_ARM_ITS_SAVE_TABLES, KVM_DEV_ARM_VGIC_CTRL_INIT, KVM_DEV_ARM_VGIC_GRP_ADDR,
+    KVM_DEV_ARM_VGIC_GRP_CTRL, KVM_DEV_ARM_VGIC_GRP_NR_IRQS, KVM_VGIC_ITS_ADDR_TYPE,
+    KVM_VGIC_V2_ADDR_TYPE_CPU, KVM_VGIC_V2_ADDR_TYPE_DIST, KVM_VGIC_V3_ADDR_TYPE_DIST,
+    KVM_VGIC_V3_ADDR_TYPE_REDIST,
+};
+use vmm_sys_util::errno;
+
+use crate::cap::Cap;
+use crate::ioctls::device::DeviceFd;
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::Result;
+
+/// Version of the in-kernel vGIC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicVersion {
+    /// GICv2 (`KVM_DEV_TYPE_ARM_VGIC_V2`).
+    V2,
+    /// GICv3 (`KVM_DEV_TYPE_ARM_VGIC_V3`).
+    V3,
+    /// GICv3 if the host can emulate it, GICv2 otherwise.
+    Auto,
+}
+
+impl GicVersion {
+    /// Returns the `KVM_DEV_TYPE_ARM_VGIC_*` device type, GICv3 for [GicVersion::Auto].
+    pub(crate) fn device_type(self) -> u32 {
+        match self {
+            GicVersion::V2 => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+            GicVersion::V3 | GicVersion::Auto => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+        }
+    }
+}
+
+/// Errors returned by [GicBuilder::build], telling which step of the vGIC setup failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GicError {
+    /// Creating the vGIC device failed.
+    CreateDevice(errno::Error),
+    /// Setting the base address of the distributor failed.
+    SetDistAddr(errno::Error),
+    /// Setting the base address of the redistributors (GICv3) or of the CPU interface (GICv2)
+    /// failed.
+    SetRedistAddr(errno::Error),
+    /// Creating the ITS device failed. A GICv2 has no ITS (`ENODEV`).
+    CreateIts(errno::Error),
+    /// Setting the base address of the ITS failed.
+    SetItsAddr(errno::Error),
+    /// Initializing the ITS failed.
+    InitIts(errno::Error),
+    /// Setting the number of interrupts failed.
+    SetNrIrqs(errno::Error),
+    /// Initializing the vGIC failed.
+    Init(errno::Error),
+    /// Reading back the number of interrupts failed.
+    GetNrIrqs(errno::Error),
+}
+
+impl GicError {
+    /// Returns the errno code corresponding to the error.
+    pub fn errno(&self) -> i32 {
+        errno::Error::from(*self).errno()
+    }
+}
+
+impl From<GicError> for errno::Error {
+    fn from(err: GicError) -> errno::Error {
+        match err {
+            GicError::CreateDevice(err)
+            | GicError::SetDistAddr(err)
+            | GicError::SetRedistAddr(err)
+            | GicError::CreateIts(err)
+            | GicError::SetItsAddr(err)
+            | GicError::InitIts(err)
+            | GicError::SetNrIrqs(err)
+            | GicError::Init(err)
+            | GicError::GetNrIrqs(err) => err,
+        }
+    }
+}
+
+impl std::fmt::Display for GicError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let step = match self {
+            GicError::CreateDevice(_) => "create the vGIC device",
+            GicError::SetDistAddr(_) => "set the distributor address",
+            GicError::SetRedistAddr(_) => "set the redistributor or CPU interface address",
+            GicError::CreateIts(_) => "create the ITS device",
+            GicError::SetItsAddr(_) => "set the ITS address",
+            GicError::InitIts(_) => "initialize the ITS",
+            GicError::SetNrIrqs(_) => "set the number of interrupts",
+            GicError::Init(_) => "initialize the vGIC",
+            GicError::GetNrIrqs(_) => "get the number of interrupts",
+        };
+        write!(f, "failed to {}: {}", step, errno::Error::from(*self))
+    }
+}
+
+impl std::error::Error for GicError {}
+
+/// Creates a device of type `type_`.
+pub(crate) fn create_device(vm: &VmFd, type_: u32) -> Result<DeviceFd> {
+    let mut device = kvm_create_device {
+        type_,
+        fd: 0,
+        flags: 0,
+    };
+    vm.create_device(&mut device)
+}
+
+/// Sets the base address of type `type_` of a vGIC or ITS device.
+fn set_addr(device: &DeviceFd, type_: u32, addr: u64) -> Result<()> {
+    device.set_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_ADDR, u64::from(type_), &addr)
+}
+
+/// Sets the number of interrupts of a vGIC device.
+pub(crate) fn set_nr_irqs(device: &DeviceFd, nr_irqs: u32) -> Result<()> {
+    device.set_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0, &nr_irqs)
+}
+
+/// Sets the attribute `attr` of the `KVM_DEV_ARM_VGIC_GRP_CTRL` group of a vGIC or ITS device.
+pub(crate) fn ctrl(device: &DeviceFd, attr: u32) -> Result<()> {
+    let attr = kvm_device_attr {
+        group: KVM_DEV_ARM_VGIC_GRP_CTRL,
+        attr: u64::from(attr),
+        addr: 0,
+        flags: 0,
+    };
+    device.set_device_attr(&attr)
+}
+
+/// Creates and initializes an ITS for the vGIC of `vm`.
+fn create_its(
+    vm: &VmFd,
+    version: GicVersion,
+    addr: Option<u64>,
+) -> std::result::Result<ItsDevice, GicError> {
+    if version == GicVersion::V2 {
+        return Err(GicError::CreateIts(errno::Error::new(libc::ENODEV)));
+    }
+    let fd = create_device(vm, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS)
+        .map_err(GicError::CreateIts)?;
+    if let Some(addr) = addr {
+        set_addr(&fd, KVM_VGIC_ITS_ADDR_TYPE, addr).map_err(GicError::SetItsAddr)?;
+    }
+    ctrl(&fd, KVM_DEV_ARM_VGIC_CTRL_INIT).map_err(GicError::InitIts)?;
+    Ok(ItsDevice { fd, addr })
+}
+
+/// Builder of the in-kernel vGIC of a VM.
+///
+/// [build](GicBuilder::build) creates the vGIC and, optionally, an ITS, then configures and
+/// initializes them in the order expected by KVM. As KVM sizes the vGIC after the vCPUs when
+/// initializing it, all the vCPUs must have been created beforehand. The base addresses are
+/// not needed to initialize the vGIC, but KVM requires them before running the vCPUs.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::{GicBuilder, GicVersion, Kvm};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let _vcpu = vm.create_vcpu(0).unwrap();
+///
+/// let gic = GicBuilder::new(&vm)
+///     .version(GicVersion::Auto)
+///     .nr_irqs(128)
+///     .dist_addr(0x0800_0000)
+///     .redist_addr(0x080a_0000)
+///     .build()
+///     .unwrap();
+/// assert_eq!(gic.nr_irqs(), 128);
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct GicBuilder<'a> {
+    vm: &'a VmFd,
+    version: GicVersion,
+    nr_irqs: Option<u32>,
+    dist_addr: Option<u64>,
+    redist_addr: Option<u64>,
+    msi: bool,
+    its_addr: Option<u64>,
+}
+
+impl<'a> GicBuilder<'a> {
+    /// Starts the configuration of the vGIC of `vm`, with the version set to
+    /// [GicVersion::Auto] and the defaults of KVM for everything else.
+    pub fn new(vm: &'a VmFd) -> Self {
+        GicBuilder {
+            vm,
+            version: GicVersion::Auto,
+            nr_irqs: None,
+            dist_addr: None,
+            redist_addr: None,
+            msi: false,
+            its_addr: None,
+        }
+    }
+
+    /// Sets the version of the vGIC. [GicVersion::Auto] falls back to a GICv2 when the host
+    /// cannot emulate a GICv3.
+    pub fn version(mut self, version: GicVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Sets the number of interrupts of the vGIC, including the 32 private ones. It must be a
+    /// multiple of 32 between 64 and 1024.
+    pub fn nr_irqs(mut self, nr_irqs: u32) -> Self {
+        self.nr_irqs = Some(nr_irqs);
+        self
+    }
+
+    /// Sets the guest physical base address of the distributor.
+    pub fn dist_addr(mut self, addr: u64) -> Self {
+        self.dist_addr = Some(addr);
+        self
+    }
+
+    /// Sets the guest physical base address of the redistributors of a GICv3, or of the CPU
+    /// interface of a GICv2.
+    pub fn redist_addr(mut self, addr: u64) -> Self {
+        self.redist_addr = Some(addr);
+        self
+    }
+
+    /// Sets whether to create an ITS translating the MSIs of the guest. Only a GICv3 can
+    /// have one.
+    pub fn msi(mut self, its: bool) -> Self {
+        self.msi = its;
+        self
+    }
+
+    /// Sets the guest physical base address of the ITS created when [msi](GicBuilder::msi)
+    /// is set.
+    pub fn its_addr(mut self, addr: u64) -> Self {
+        self.its_addr = Some(addr);
+        self
+    }
+
+    /// Creates, configures and initializes the vGIC.
+    pub fn build(self) -> std::result::Result<GicDevice<'a>, GicError> {
+        let (fd, version) = match self.version {
+            version @ (GicVersion::V2 | GicVersion::V3) => {
+                create_device(self.vm, version.device_type()).map(|fd| (fd, version))
+            }
+            GicVersion::Auto => self.vm.create_vgic(GicVersion::V3),
+        }
+        .map_err(GicError::CreateDevice)?;
+
+        let (dist_type, redist_type) = match version {
+            GicVersion::V2 => (KVM_VGIC_V2_ADDR_TYPE_DIST, KVM_VGIC_V2_ADDR_TYPE_CPU),
+            _ => (KVM_VGIC_V3_ADDR_TYPE_DIST, KVM_VGIC_V3_ADDR_TYPE_REDIST),
+        };
+        if let Some(addr) = self.dist_addr {
+            set_addr(&fd, dist_type, addr).map_err(GicError::SetDistAddr)?;
+        }
+        if let Some(addr) = self.redist_addr {
+            set_addr(&fd, redist_type, addr).map_err(GicError::SetRedistAddr)?;
+        }
+
+        let its = if self.msi {
+            Some(create_its(self.vm, version, self.its_addr)?)
+        } else {
+            None
+        };
+
+        if let Some(nr_irqs) = self.nr_irqs {
+            set_nr_irqs(&fd, nr_irqs).map_err(GicError::SetNrIrqs)?;
+        }
+        ctrl(&fd, KVM_DEV_ARM_VGIC_CTRL_INIT).map_err(GicError::Init)?;
+        // Without an explicit number of interrupts, KVM picks one when initializing the vGIC.
+        let nr_irqs = fd
+            .get_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0)
+            .map_err(GicError::GetNrIrqs)?;
+
+        Ok(GicDevice {
+            vm: self.vm,
+            fd,
+            version,
+            nr_irqs,
+            dist_addr: self.dist_addr,
+            redist_addr: self.redist_addr,
+            its,
+        })
+    }
+}
+
+/// Initialized in-kernel vGIC, built by [GicBuilder]. It borrows the VM in order to add an ITS
+/// later on.
+#[derive(Debug)]
+pub struct GicDevice<'a> {
+    vm: &'a VmFd,
+    fd: DeviceFd,
+    version: GicVersion,
+    nr_irqs: u32,
+    dist_addr: Option<u64>,
+    redist_addr: Option<u64>,
+    its: Option<ItsDevice>,
+}
+
+impl<'a> GicDevice<'a> {
+    /// Returns the version of the vGIC, either [GicVersion::V2] or [GicVersion::V3].
+    pub fn version(&self) -> GicVersion {
+        self.version
+    }
+
+    /// Returns the file descriptor of the vGIC device, for instance to save its state.
+    pub fn device_fd(&self) -> &DeviceFd {
+        &self.fd
+    }
+
+    /// Returns the number of interrupts of the vGIC, including the 32 private ones.
+    pub fn nr_irqs(&self) -> u32 {
+        self.nr_irqs
+    }
+
+    /// Returns the base address of the distributor, if one was set.
+    pub fn dist_addr(&self) -> Option<u64> {
+        self.dist_addr
+    }
+
+    /// Returns the base address of the redistributors (GICv3) or of the CPU interface
+    /// (GICv2), if one was set.
+    pub fn redist_addr(&self) -> Option<u64> {
+        self.redist_addr
+    }
+
+    /// Returns the ITS, created either by [build](GicBuilder::build) when
+    /// [msi](GicBuilder::msi) was set or by [add_its](GicDevice::add_its).
+    pub fn its(&self) -> Option<&ItsDevice> {
+        self.its.as_ref()
+    }
+
+    /// Creates an ITS for this vGIC at the guest physical address `its_addr` and initializes it.
+    ///
+    /// Once the VM has an ITS, the MSIs routed with [GsiRouting](crate::GsiRouting) must carry
+    /// the ID of the device issuing them, see
+    /// [msi_with_devid](crate::IrqRoutingEntry::msi_with_devid). A GICv2 has no ITS, in which
+    /// case this fails with [GicError::CreateIts] and `ENODEV`. The same error carries
+    /// `EEXIST` if the vGIC already has an ITS.
+    ///
+    /// The ITS is kept by the vGIC and is returned by [its](GicDevice::its) afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `its_addr` - Guest physical base address of the ITS, aligned on 64 KiB.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{GicBuilder, GicVersion, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let _vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut gic = GicBuilder::new(&vm).build().unwrap();
+    /// if gic.version() == GicVersion::V3 {
+    ///     gic.add_its(0x0808_0000).unwrap();
+    ///     assert!(vm.supports_msi_devid());
+    /// }
+    /// ```
+    pub fn add_its(&mut self, its_addr: u64) -> std::result::Result<&ItsDevice, GicError> {
+        if self.its.is_some() {
+            return Err(GicError::CreateIts(errno::Error::new(libc::EEXIST)));
+        }
+        let its = create_its(self.vm, self.version, Some(its_addr))?;
+        Ok(self.its.insert(its))
+    }
+}
+
+/// In-kernel GICv3 ITS, created by [GicDevice::add_its] or by [GicBuilder::build].
+#[derive(Debug)]
+pub struct ItsDevice {
+    fd: DeviceFd,
+    addr: Option<u64>,
+}
+
+impl ItsDevice {
+    /// Returns the file descriptor of the ITS device, for instance to save its registers.
+    pub fn device_fd(&self) -> &DeviceFd {
+        &self.fd
+    }
+
+    /// Returns the base address of the ITS, if one was set.
+    pub fn addr(&self) -> Option<u64> {
+        self.addr
+    }
+
+    /// Flushes the ITS tables (devices, collections and interrupt translations) into the
+    /// guest memory designated by the `GITS_BASER<n>` registers, through
+    /// `KVM_DEV_ARM_ITS_SAVE_TABLES`.
+    ///
+    /// On migration, this is done after saving the ITS registers, with all the vCPUs stopped;
+    /// it fails with `EBUSY` if a vCPU is running.
+    pub fn save_tables(&self) -> Result<()> {
+        ctrl(&self.fd, KVM_DEV_ARM_ITS_SAVE_TABLES)
+    }
+
+    /// Reloads the ITS tables from the guest memory designated by the `GITS_BASER<n>`
+    /// registers, through `KVM_DEV_ARM_ITS_RESTORE_TABLES`.
+    ///
+    /// On migration, this is done after restoring the guest memory, the redistributors and
+    /// the `GITS_BASER<n>` registers, and before restoring `GITS_CTLR`.
+    pub fn restore_tables(&self) -> Result<()> {
+        ctrl(&self.fd, KVM_DEV_ARM_ITS_RESTORE_TABLES)
+    }
+}
+
+/// Size of the stolen time structure of a vCPU, as per the Arm Paravirtualized Time
+/// specification (DEN0057A).
+pub const PVTIME_STRUCT_SIZE: u64 = 64;
+
+/// Errors returned by [configure_pvtime].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PvTimeError {
+    /// The host does not report `KVM_CAP_STEAL_TIME`.
+    Unsupported,
+    /// The base address is not aligned on [PVTIME_STRUCT_SIZE], or the structures of the
+    /// vCPUs do not fit in the address space.
+    InvalidAddress(u64),
+    /// Setting the address of the stolen time structure of the vCPU at the given index
+    /// failed.
+    Vcpu(usize, errno::Error),
+}
+
+impl PvTimeError {
+    /// Returns the errno code corresponding to the error.
+    pub fn errno(&self) -> i32 {
+        errno::Error::from(*self).errno()
+    }
+}
+
+impl From<PvTimeError> for errno::Error {
+    fn from(err: PvTimeError) -> errno::Error {
+        match err {
+            // Same error as the unknown `KVM_ARM_VCPU_PVTIME_CTRL` attribute group.
+            PvTimeError::Unsupported => errno::Error::new(libc::ENXIO),
+            PvTimeError::InvalidAddress(_) => errno::Error::new(libc::EINVAL),
+            PvTimeError::Vcpu(_, err) => err,
+        }
+    }
+}
+
+impl std::fmt::Display for PvTimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PvTimeError::Unsupported => write!(f, "stolen time is not supported"),
+            PvTimeError::InvalidAddress(addr) => {
+                write!(f, "invalid stolen time base address {:#x}", addr)
+            }
+            PvTimeError::Vcpu(index, err) => {
+                write!(
+                    f,
+                    "failed to set the stolen time of vCPU {}: {}",
+                    index, err
+                )
+            }
+        }
+    }
+}
+
+impl std::error::Error for PvTimeError {}
+
+/// Enables the paravirtualized stolen time of `vcpus`, reported to the guest through
+/// structures of [PVTIME_STRUCT_SIZE] bytes laid out from `base_ipa`.
+///
+/// The structure of `vcpus[i]` is at `base_ipa + i * PVTIME_STRUCT_SIZE`, set with the
+/// `KVM_ARM_VCPU_PVTIME_IPA` attribute of the vCPU. The range must be backed by a memory
+/// slot, which the VMM usually describes to the guest as reserved memory. The vCPUs must be
+/// initialized, and the address of each of them can only be set once.
+///
+/// # Arguments
+///
+/// * `vm` - VM of the vCPUs.
+/// * `vcpus` - vCPUs whose stolen time is enabled.
+/// * `base_ipa` - Guest physical address of the structure of the first vCPU, aligned on
+///                [PVTIME_STRUCT_SIZE].
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # extern crate kvm_bindings;
+/// # use kvm_ioctls::{configure_pvtime, Cap, Kvm};
+/// use kvm_bindings::kvm_userspace_memory_region;
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let mut kvi = kvm_bindings::kvm_vcpu_init::default();
+/// vm.get_preferred_target(&mut kvi).unwrap();
+/// let vcpus: Vec<_> = (0..2).map(|id| vm.create_vcpu(id).unwrap()).collect();
+/// for vcpu in &vcpus {
+///     vcpu.vcpu_init(&kvi).unwrap();
+/// }
+///
+/// let mem_size = 0x1000;
+/// let load_addr = unsafe {
+///     libc::mmap(
+///         std::ptr::null_mut(),
+///         mem_size,
+///         libc::PROT_READ | libc::PROT_WRITE,
+///         libc::MAP_ANONYMOUS | libc::MAP_PRIVATE,
+///         -1,
+///         0,
+///     )
+/// };
+/// let mem_region = kvm_userspace_memory_region {
+///     slot: 0,
+///     guest_phys_addr: 0x9000_0000,
+///     memory_size: mem_size as u64,
+///     userspace_addr: load_addr as u64,
+///     flags: 0,
+/// };
+/// unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+///
+/// if vm.check_extension(Cap::StealTime) {
+///     let vcpus: Vec<_> = vcpus.iter().collect();
+///     configure_pvtime(&vm, &vcpus, 0x9000_0000).unwrap();
+/// }
+/// ```
+pub fn configure_pvtime(
+    vm: &VmFd,
+    vcpus: &[&VcpuFd],
+    base_ipa: u64,
+) -> std::result::Result<(), PvTimeError> {
+    if !vm.check_extension(Cap::StealTime) {
+        return Err(PvTimeError::Unsupported);
+    }
+    let size = (vcpus.len() as u64).checked_mul(PVTIME_STRUCT_SIZE);
+    if base_ipa & (PVTIME_STRUCT_SIZE - 1) != 0
+        || size.and_then(|s| base_ipa.checked_add(s)).is_none()
+    {
+        return Err(PvTimeError::InvalidAddress(base_ipa));
+    }
+    vcpus.iter().enumerate().try_for_each(|(index, vcpu)| {
+        let ipa = base_ipa + index as u64 * PVTIME_STRUCT_SIZE;
+        let attr = kvm_device_attr {
+            group: KVM_ARM_VCPU_PVTIME_CTRL,
+            attr: u64::from(KVM_ARM_VCPU_PVTIME_IPA),
+            addr: &ipa as *const u64 as u64,
+            flags: 0,
+        };
+        vcpu.has_device_attr(&attr)
+            .and_then(|_| vcpu.set_device_attr(&attr))
+            .map_err(|err| PvTimeError::Vcpu(index, err))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::mmap_anonymous;
+    use crate::Kvm;
+
+    #[test]
+    fn test_gic_builder() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_vcpu(0).unwrap();
+        let builder = GicBuilder::new(&vm)
+            .nr_irqs(256)
+            .dist_addr(0x0800_0000)
+            .redist_addr(0x080a_0000);
+
+        // Hosts without a GICv3 can only emulate a GICv2, which has no ITS.
+        let fallback_vm = kvm.create_vm().unwrap();
+        fallback_vm.create_vcpu(0).unwrap();
+        let gic = match builder
+            .version(GicVersion::V3)
+            .msi(true)
+            .its_addr(0x0808_0000)
+            .build()
+        {
+            Ok(gic) => {
+                assert_eq!(gic.version(), GicVersion::V3);
+                assert_eq!(gic.its().unwrap().addr(), Some(0x0808_0000));
+                gic
+            }
+            Err(GicError::CreateDevice(_)) => {
+                let gic = GicBuilder::new(&fallback_vm)
+                    .nr_irqs(256)
+                    .dist_addr(0x0800_0000)
+                    .redist_addr(0x0801_0000)
+                    .build()
+                    .unwrap();
+                assert_eq!(gic.version(), GicVersion::V2);
+                assert!(gic.its().is_none());
+                gic
+            }
+            Err(e) => panic!("unexpected error: {}", e),
+        };
+        assert_eq!(gic.nr_irqs(), 256);
+        assert_eq!(gic.dist_addr(), Some(0x0800_0000));
+        assert_eq!(
+            gic.device_fd()
+                .get_device_attr_typed::<u32>(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0)
+                .unwrap(),
+            256
+        );
+    }
+
+    #[test]
+    fn test_gic_builder_errors() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_vcpu(0).unwrap();
+
+        // The distributor must be aligned on 4 KiB (GICv2) or 64 KiB (GICv3).
+        let err = GicBuilder::new(&vm)
+            .dist_addr(0x0800_0800)
+            .build()
+            .unwrap_err();
+        assert!(matches!(err, GicError::SetDistAddr(_)));
+        assert_eq!(err.errno(), libc::EINVAL);
+        assert!(err
+            .to_string()
+            .starts_with("failed to set the distributor address"));
+
+        let vm = kvm.create_vm().unwrap();
+        vm.create_vcpu(0).unwrap();
+        let err = GicBuilder::new(&vm)
+            .version(GicVersion::V2)
+            .msi(true)
+            .build()
+            .unwrap_err();
+        // Either the host cannot emulate a GICv2, or a GICv2 has no ITS.
+        match err {
+            GicError::CreateDevice(_) => (),
+            GicError::CreateIts(e) => assert_eq!(e.errno(), libc::ENODEV),
+            e => panic!("unexpected error: {}", e),
+        }
+    }
+
+    #[test]
+    fn test_add_its() {
+        use crate::{GsiRouting, IrqRoutingEntry};
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut kvi = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi).unwrap();
+        vcpu.vcpu_init(&kvi).unwrap();
+        let mut gic = match GicBuilder::new(&vm)
+            .version(GicVersion::V3)
+            .dist_addr(0x0800_0000)
+            .redist_addr(0x080a_0000)
+            .build()
+        {
+            Ok(gic) => gic,
+            // No ITS without a GICv3.
+            Err(GicError::CreateDevice(_)) => return,
+            Err(e) => panic!("unexpected error: {}", e),
+        };
+        assert!(!vm.supports_msi_devid());
+        assert!(gic.its().is_none());
+        assert_eq!(gic.add_its(0x0808_0000).unwrap().addr(), Some(0x0808_0000));
+        assert!(vm.supports_msi_devid());
+        match gic.add_its(0x0809_0000).unwrap_err() {
+            GicError::CreateIts(e) => assert_eq!(e.errno(), libc::EEXIST),
+            e => panic!("unexpected error: {}", e),
+        }
+        let its = gic.its().unwrap();
+        assert_eq!(its.addr(), Some(0x0808_0000));
+
+        // MSIs target GITS_TRANSLATER.
+        let doorbell: u64 = 0x0808_0000 + 0x1_0040;
+        let mut routing = GsiRouting::new();
+        routing.add_entry(IrqRoutingEntry::msi_with_devid(
+            32,
+            (doorbell >> 32) as u32,
+            doorbell as u32,
+            0,
+            0x10,
+        ));
+        routing.commit(&vm).unwrap();
+
+        // The vCPU is not running. As the guest did not set up the tables, there is nothing
+        // to save or restore.
+        its.save_tables().unwrap();
+        its.restore_tables().unwrap();
+    }
+
+    #[test]
+    fn test_configure_pvtime() {
+        use kvm_bindings::kvm_userspace_memory_region;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let mut kvi = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi).unwrap();
+        let vcpus: Vec<_> = (0..2).map(|id| vm.create_vcpu(id).unwrap()).collect();
+        for vcpu in &vcpus {
+            vcpu.vcpu_init(&kvi).unwrap();
+        }
+        let vcpus: Vec<_> = vcpus.iter().collect();
+        if !vm.check_extension(Cap::StealTime) {
+            assert_eq!(
+                configure_pvtime(&vm, &vcpus, 0x9000_0000),
+                Err(PvTimeError::Unsupported)
+            );
+            return;
+        }
+
+        let mem_size = 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0x9000_0000,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+
+        let err = configure_pvtime(&vm, &vcpus, 0x9000_0020).unwrap_err();
+        assert_eq!(err, PvTimeError::InvalidAddress(0x9000_0020));
+        assert_eq!(err.errno(), libc::EINVAL);
+        assert_eq!(
+            configure_pvtime(&vm, &vcpus, u64::MAX - 0x3f),
+            Err(PvTimeError::InvalidAddress(u64::MAX - 0x3f))
+        );
+
+        configure_pvtime(&vm, &vcpus, 0x9000_0000).unwrap();
+        for (index, vcpu) in vcpus.iter().enumerate() {
+            let mut ipa = 0u64;
+            let mut attr = kvm_device_attr {
+                group: KVM_ARM_VCPU_PVTIME_CTRL,
+                attr: u64::from(KVM_ARM_VCPU_PVTIME_IPA),
+                addr: &mut ipa as *mut u64 as u64,
+                flags: 0,
+            };
+            unsafe { vcpu.get_device_attr(&mut attr) }.unwrap();
+            assert_eq!(ipa, 0x9000_0000 + index as u64 * PVTIME_STRUCT_SIZE);
+        }
+
+        // The address of a vCPU can only be set once.
+        let err = configure_pvtime(&vm, &vcpus, 0x9000_0000).unwrap_err();
+        assert!(matches!(err, PvTimeError::Vcpu(0, _)));
+        assert_eq!(err.errno(), libc::EEXIST);
+    }
+}
@@ -2,14 +2,46 @@
 // SPDX-License-Identifier: Apache-2.0 OR MIT
 
 use std::fs::File;
+use std::os::raw::c_void;
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use crate::ioctls::Result;
 use crate::kvm_ioctls::{KVM_GET_DEVICE_ATTR, KVM_HAS_DEVICE_ATTR, KVM_SET_DEVICE_ATTR};
-use kvm_bindings::kvm_device_attr;
+use kvm_bindings::{
+    kvm_device_attr, kvm_device_type_KVM_DEV_TYPE_ARM_PV_TIME,
+    kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+    kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, kvm_device_type_KVM_DEV_TYPE_VFIO,
+};
 use vmm_sys_util::errno;
 use vmm_sys_util::ioctl::{ioctl_with_mut_ref, ioctl_with_ref};
 
+mod private {
+    // Seals `DeviceAttrValue`, so that it is only implemented for the types below.
+    pub trait Sealed {}
+}
+
+/// Plain type holding the value of a device attribute, for
+/// [get_device_attr_typed](struct.DeviceFd.html#method.get_device_attr_typed) and
+/// [set_device_attr_typed](struct.DeviceFd.html#method.set_device_attr_typed).
+///
+/// The trait is sealed. It is implemented for the integer types and for arrays of them, which
+/// have no padding and for which any bit pattern written by the kernel is a valid value.
+pub trait DeviceAttrValue: Copy + Default + private::Sealed {}
+
+macro_rules! impl_device_attr_value {
+    ($($t:ty),*) => {
+        $(
+            impl private::Sealed for $t {}
+            impl DeviceAttrValue for $t {}
+        )*
+    };
+}
+
+impl_device_attr_value!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<T: DeviceAttrValue, const N: usize> private::Sealed for [T; N] where [T; N]: Default {}
+impl<T: DeviceAttrValue, const N: usize> DeviceAttrValue for [T; N] where [T; N]: Default {}
+
 /// Wrapper over the file descriptor obtained when creating an emulated device in the kernel.
 #[derive(Debug)]
 pub struct DeviceFd {
@@ -82,6 +114,128 @@ impl DeviceFd {
         Ok(())
     }
 
+    /// Sets a fixed-size device attribute, as per the `KVM_SET_DEVICE_ATTR` ioctl.
+    ///
+    /// Wrapper over [set_device_attr](struct.DeviceFd.html#method.set_device_attr) pointing
+    /// the `addr` field at `value`, so that the caller does not have to pass a raw address.
+    /// Like for [get_device_attr_typed](struct.DeviceFd.html#method.get_device_attr_typed),
+    /// `T` must have the size of the attribute value, and is bound by [`DeviceAttrValue`] so
+    /// that the kernel does not read padding bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - Attribute group.
+    /// * `attr` - Attribute within the group.
+    /// * `value` - Value of the attribute.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// #[cfg(target_arch = "aarch64")]
+    /// {
+    ///     use kvm_bindings::{
+    ///         kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+    ///         KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+    ///     };
+    ///
+    ///     let device_fd = vm
+    ///         .create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, 0)
+    ///         .or_else(|_| vm.create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, 0))
+    ///         .unwrap()
+    ///         .into_device_fd()
+    ///         .unwrap();
+    ///     device_fd
+    ///         .set_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0, &128u32)
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub fn set_device_attr_typed<T: DeviceAttrValue>(
+        &self,
+        group: u32,
+        attr: u64,
+        value: &T,
+    ) -> Result<()> {
+        let device_attr = kvm_device_attr {
+            group,
+            attr,
+            addr: value as *const T as u64,
+            flags: 0,
+        };
+        self.set_device_attr(&device_attr)
+    }
+
+    /// Sets a batch of device attributes, as per the `KVM_SET_DEVICE_ATTR` ioctl.
+    ///
+    /// The attributes are applied in order with
+    /// [set_device_attr](struct.DeviceFd.html#method.set_device_attr). The first failure stops
+    /// the batch and is returned together with the index of the rejected attribute. The
+    /// attributes preceding it remain applied.
+    ///
+    /// # Arguments
+    ///
+    /// * `attrs` - Device attributes to be set, in order.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// #[cfg(target_arch = "aarch64")]
+    /// {
+    ///     use kvm_bindings::{
+    ///         kvm_device_attr, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+    ///         kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, KVM_DEV_ARM_VGIC_CTRL_INIT,
+    ///         KVM_DEV_ARM_VGIC_GRP_CTRL, KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+    ///     };
+    ///
+    ///     let device_fd = vm
+    ///         .create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, 0)
+    ///         .or_else(|_| vm.create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, 0))
+    ///         .unwrap()
+    ///         .into_device_fd()
+    ///         .unwrap();
+    ///     vm.create_vcpu(0).unwrap();
+    ///
+    ///     let nr_irqs: u32 = 128;
+    ///     let attrs = [
+    ///         kvm_device_attr {
+    ///             group: KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+    ///             attr: 0,
+    ///             addr: &nr_irqs as *const u32 as u64,
+    ///             flags: 0,
+    ///         },
+    ///         kvm_device_attr {
+    ///             group: KVM_DEV_ARM_VGIC_GRP_CTRL,
+    ///             attr: u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+    ///             addr: 0,
+    ///             flags: 0,
+    ///         },
+    ///     ];
+    ///     if let Err((index, err)) = device_fd.set_device_attrs(&attrs) {
+    ///         panic!("attribute {} was rejected: {}", index, err);
+    ///     }
+    /// }
+    /// ```
+    pub fn set_device_attrs(
+        &self,
+        attrs: &[kvm_device_attr],
+    ) -> std::result::Result<(), (usize, errno::Error)> {
+        attrs
+            .iter()
+            .enumerate()
+            .try_for_each(|(index, attr)| self.set_device_attr(attr).map_err(|e| (index, e)))
+    }
+
     /// Gets a specified piece of device configuration and/or state.
     ///
     /// See the documentation for `KVM_GET_DEVICE_ATTR`.
@@ -151,6 +305,210 @@ impl DeviceFd {
         }
         Ok(())
     }
+
+    /// Gets a fixed-size device attribute, as per the `KVM_GET_DEVICE_ATTR` ioctl.
+    ///
+    /// Wrapper over [get_device_attr](struct.DeviceFd.html#method.get_device_attr) pointing
+    /// the `addr` field at a `T` on the stack and returning it, so that the caller does not
+    /// have to pass a raw address.
+    ///
+    /// `T` must have the size of the attribute value, e.g. `u32` for the attributes of the
+    /// vGIC distributor registers or `u64` for the ones of the VFIO device; the kernel writes
+    /// as many bytes as the attribute defines. `T` is bound by [`DeviceAttrValue`], so that
+    /// any value the kernel writes is a valid `T`.
+    ///
+    /// # Arguments
+    ///
+    /// * `group` - Attribute group.
+    /// * `attr` - Attribute within the group.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// #[cfg(target_arch = "aarch64")]
+    /// {
+    ///     use kvm_bindings::{
+    ///         kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+    ///         KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+    ///     };
+    ///
+    ///     let device_fd = vm
+    ///         .create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, 0)
+    ///         .or_else(|_| vm.create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, 0))
+    ///         .unwrap()
+    ///         .into_device_fd()
+    ///         .unwrap();
+    ///     let nr_irqs: u32 = device_fd
+    ///         .get_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0)
+    ///         .unwrap();
+    /// }
+    /// ```
+    pub fn get_device_attr_typed<T: DeviceAttrValue>(&self, group: u32, attr: u64) -> Result<T> {
+        let mut value = T::default();
+        let mut device_attr = kvm_device_attr {
+            group,
+            attr,
+            addr: &mut value as *mut T as u64,
+            flags: 0,
+        };
+        self.get_device_attr(&mut device_attr)?;
+        Ok(value)
+    }
+
+    /// Issues an arbitrary ioctl on the device fd.
+    ///
+    /// This is an escape hatch for the device ioctls which this crate does not wrap yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The ioctl request number.
+    /// * `arg` - The argument of the ioctl, either a pointer or a value cast to a pointer.
+    ///
+    /// Returns the non-negative result of the ioctl, or the `errno` it failed with.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that `req` is an ioctl of the device and that `arg` is what
+    /// the kernel expects for it, i.e. that any memory it points to is valid and large enough.
+    pub unsafe fn raw_ioctl(&self, req: u64, arg: *mut c_void) -> Result<i32> {
+        crate::ioctls::raw_ioctl(self, req, arg)
+    }
+}
+
+/// Device created with [create_typed_device](struct.VmFd.html#method.create_typed_device),
+/// tagged with its kind.
+///
+/// Each variant wraps the [`DeviceFd`] of the device, so that code setting the attributes of a
+/// given kind of device can require the matching variant. The file descriptor is `None` when
+/// the device type was only checked with `KVM_CREATE_DEVICE_TEST`, since the kernel does not
+/// create the device then.
+#[derive(Debug)]
+pub enum TypedDevice {
+    /// GICv2 emulation, `KVM_DEV_TYPE_ARM_VGIC_V2`.
+    VgicV2(Option<DeviceFd>),
+    /// GICv3 emulation, `KVM_DEV_TYPE_ARM_VGIC_V3`.
+    VgicV3(Option<DeviceFd>),
+    /// GICv3 Interrupt Translation Service, `KVM_DEV_TYPE_ARM_VGIC_ITS`.
+    Its(Option<DeviceFd>),
+    /// VFIO bridge, `KVM_DEV_TYPE_VFIO`.
+    Vfio(Option<DeviceFd>),
+    /// Stolen time of arm/aarch64 vCPUs, `KVM_DEV_TYPE_ARM_PV_TIME`.
+    PvTime(Option<DeviceFd>),
+    /// Device of any other type, e.g. `KVM_DEV_TYPE_FLIC` on s390.
+    Other(u32, Option<DeviceFd>),
+}
+
+impl TypedDevice {
+    /// Wraps `fd` in the variant for the device type `type_`.
+    #[allow(non_upper_case_globals)]
+    pub(crate) fn new(type_: u32, fd: Option<DeviceFd>) -> Self {
+        match type_ {
+            kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2 => TypedDevice::VgicV2(fd),
+            kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3 => TypedDevice::VgicV3(fd),
+            kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS => TypedDevice::Its(fd),
+            kvm_device_type_KVM_DEV_TYPE_VFIO => TypedDevice::Vfio(fd),
+            kvm_device_type_KVM_DEV_TYPE_ARM_PV_TIME => TypedDevice::PvTime(fd),
+            _ => TypedDevice::Other(type_, fd),
+        }
+    }
+
+    /// Returns the `KVM_DEV_TYPE_*` type of the device.
+    pub fn device_type(&self) -> u32 {
+        match self {
+            TypedDevice::VgicV2(_) => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+            TypedDevice::VgicV3(_) => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+            TypedDevice::Its(_) => kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+            TypedDevice::Vfio(_) => kvm_device_type_KVM_DEV_TYPE_VFIO,
+            TypedDevice::PvTime(_) => kvm_device_type_KVM_DEV_TYPE_ARM_PV_TIME,
+            TypedDevice::Other(type_, _) => *type_,
+        }
+    }
+
+    /// Returns the file descriptor of the device, whatever its kind.
+    pub fn device_fd(&self) -> Option<&DeviceFd> {
+        match self {
+            TypedDevice::VgicV2(fd)
+            | TypedDevice::VgicV3(fd)
+            | TypedDevice::Its(fd)
+            | TypedDevice::Vfio(fd)
+            | TypedDevice::PvTime(fd)
+            | TypedDevice::Other(_, fd) => fd.as_ref(),
+        }
+    }
+
+    /// Unwraps the file descriptor of the device, whatever its kind.
+    pub fn into_device_fd(self) -> Option<DeviceFd> {
+        match self {
+            TypedDevice::VgicV2(fd)
+            | TypedDevice::VgicV3(fd)
+            | TypedDevice::Its(fd)
+            | TypedDevice::Vfio(fd)
+            | TypedDevice::PvTime(fd)
+            | TypedDevice::Other(_, fd) => fd,
+        }
+    }
+
+    /// Sets the number of interrupts of a vGIC, including the 32 private ones, through the
+    /// `KVM_DEV_ARM_VGIC_GRP_NR_IRQS` attribute.
+    ///
+    /// It must be a multiple of 32 between 64 and 1024, and be set before
+    /// [init](TypedDevice::init). Fails with `EINVAL` for devices other than a created vGIC.
+    ///
+    /// # Arguments
+    ///
+    /// * `nr_irqs` - Number of interrupts.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::{
+    ///     kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+    /// };
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vgic = vm
+    ///     .create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3, 0)
+    ///     .or_else(|_| vm.create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, 0))
+    ///     .unwrap();
+    /// let _vcpu = vm.create_vcpu(0).unwrap();
+    /// vgic.set_nr_irqs(128).unwrap();
+    /// vgic.init().unwrap();
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_nr_irqs(&self, nr_irqs: u32) -> Result<()> {
+        match self {
+            TypedDevice::VgicV2(Some(fd)) | TypedDevice::VgicV3(Some(fd)) => {
+                crate::ioctls::arm::set_nr_irqs(fd, nr_irqs)
+            }
+            _ => Err(errno::Error::new(libc::EINVAL)),
+        }
+    }
+
+    /// Initializes a vGIC or an ITS through the `KVM_DEV_ARM_VGIC_CTRL_INIT` attribute.
+    ///
+    /// A vGIC is sized after the vCPUs of the VM, so they must all have been created
+    /// beforehand. Fails with `EINVAL` for devices other than a created vGIC or ITS. See
+    /// [set_nr_irqs](TypedDevice::set_nr_irqs) for an example.
+    #[cfg(target_arch = "aarch64")]
+    pub fn init(&self) -> Result<()> {
+        match self {
+            TypedDevice::VgicV2(Some(fd))
+            | TypedDevice::VgicV3(Some(fd))
+            | TypedDevice::Its(Some(fd)) => {
+                crate::ioctls::arm::ctrl(fd, kvm_bindings::KVM_DEV_ARM_VGIC_CTRL_INIT)
+            }
+            _ => Err(errno::Error::new(libc::EINVAL)),
+        }
+    }
 }
 
 /// Helper function for creating a new device.
@@ -237,6 +595,63 @@ mod tests {
         assert!(device_fd.get_device_attr(&mut dist_attr_mut).is_err());
         assert!(device_fd.set_device_attr(&dist_attr).is_err());
         assert_eq!(errno::Error::last().errno(), 25);
+
+        let ret = unsafe {
+            device_fd.raw_ioctl(
+                KVM_HAS_DEVICE_ATTR() as u64,
+                &dist_attr as *const kvm_device_attr as *mut c_void,
+            )
+        };
+        assert_eq!(
+            ret.unwrap_err().errno(),
+            device_fd.has_device_attr(&dist_attr).unwrap_err().errno()
+        );
+
+        // The VFIO device has no attribute to read.
+        assert!(device_fd
+            .get_device_attr_typed::<u64>(KVM_DEV_VFIO_GROUP, u64::from(KVM_DEV_VFIO_GROUP_ADD))
+            .is_err());
+        // There is no VFIO group with fd -1.
+        assert!(device_fd
+            .set_device_attr_typed(
+                KVM_DEV_VFIO_GROUP,
+                u64::from(KVM_DEV_VFIO_GROUP_ADD),
+                &-1i32
+            )
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_create_typed_device() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        assert!(vm
+            .create_typed_device(
+                kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+                KVM_CREATE_DEVICE_TEST
+            )
+            .is_err());
+        let device = vm
+            .create_typed_device(kvm_device_type_KVM_DEV_TYPE_VFIO, KVM_CREATE_DEVICE_TEST)
+            .unwrap();
+        assert!(matches!(device, TypedDevice::Vfio(None)));
+        assert_eq!(device.device_type(), kvm_device_type_KVM_DEV_TYPE_VFIO);
+
+        let device = vm
+            .create_typed_device(kvm_device_type_KVM_DEV_TYPE_VFIO, 0)
+            .unwrap();
+        assert!(matches!(device, TypedDevice::Vfio(Some(_))));
+        assert!(device.device_fd().is_some());
+        let device_fd = device.into_device_fd().unwrap();
+        let dist_attr = kvm_bindings::kvm_device_attr {
+            group: KVM_DEV_VFIO_GROUP,
+            attr: u64::from(KVM_DEV_VFIO_GROUP_ADD),
+            addr: 0x0,
+            flags: 0,
+        };
+        assert!(device_fd.has_device_attr(&dist_attr).is_ok());
     }
 
     #[test]
@@ -315,5 +730,169 @@ mod tests {
         // The maximum supported number of IRQs should be 128, same as the value
         // when we initialize the GIC.
         assert_eq!(data, 128);
+
+        // The same, through the safe wrapper.
+        let nr_irqs: u32 = device_fd
+            .get_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0)
+            .unwrap();
+        assert_eq!(nr_irqs, 128);
+
+        // The number of IRQs cannot change once the vGIC is initialized.
+        assert!(device_fd
+            .set_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0, &256u32)
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_create_typed_device() {
+        use kvm_bindings::{
+            kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+            KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+        };
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        // Hosts without a GICv3 can only emulate a GICv2.
+        let type_ = match vm.create_typed_device(
+            kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+            KVM_CREATE_DEVICE_TEST,
+        ) {
+            Ok(device) => {
+                assert!(matches!(device, TypedDevice::VgicV3(None)));
+                kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3
+            }
+            Err(_) => {
+                let device = vm
+                    .create_typed_device(
+                        kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+                        KVM_CREATE_DEVICE_TEST,
+                    )
+                    .unwrap();
+                assert!(matches!(device, TypedDevice::VgicV2(None)));
+                kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2
+            }
+        };
+
+        let device = vm.create_typed_device(type_, 0).unwrap();
+        assert_eq!(device.device_type(), type_);
+        vm.create_vcpu(0).unwrap();
+        match device {
+            TypedDevice::VgicV2(Some(fd)) | TypedDevice::VgicV3(Some(fd)) => {
+                fd.set_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0, &64u32)
+                    .unwrap();
+                assert_eq!(
+                    fd.get_device_attr_typed::<u32>(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0)
+                        .unwrap(),
+                    64
+                );
+                let attr = kvm_bindings::kvm_device_attr {
+                    group: KVM_DEV_ARM_VGIC_GRP_CTRL,
+                    attr: u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+                    addr: 0x0,
+                    flags: 0,
+                };
+                assert!(fd.has_device_attr(&attr).is_ok());
+            }
+            _ => panic!("unexpected device kind"),
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_set_device_attrs() {
+        use crate::ioctls::vm::create_gic_device;
+        use kvm_bindings::{kvm_device_attr, KVM_DEV_ARM_VGIC_GRP_NR_IRQS};
+
+        let kvm = Kvm::new().unwrap();
+        let nr_irqs: u32 = 128;
+        let nr_irqs_attr = kvm_device_attr {
+            group: KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+            attr: 0,
+            addr: &nr_irqs as *const u32 as u64,
+            flags: 0,
+        };
+        let init_attr = kvm_device_attr {
+            group: KVM_DEV_ARM_VGIC_GRP_CTRL,
+            attr: u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+            addr: 0,
+            flags: 0,
+        };
+        let bad_attr = kvm_device_attr {
+            group: KVM_DEV_VFIO_GROUP,
+            attr: u64::from(KVM_DEV_VFIO_GROUP_ADD),
+            addr: 0,
+            flags: 0,
+        };
+
+        let vm = kvm.create_vm().unwrap();
+        let device_fd = create_gic_device(&vm, 0);
+        vm.create_vcpu(0).unwrap();
+        assert!(device_fd.set_device_attrs(&[]).is_ok());
+        assert!(device_fd
+            .set_device_attrs(&[nr_irqs_attr, init_attr])
+            .is_ok());
+
+        // The batch stops at the attribute the vGIC does not know about.
+        let vm = kvm.create_vm().unwrap();
+        let device_fd = create_gic_device(&vm, 0);
+        vm.create_vcpu(0).unwrap();
+        let (index, _) = device_fd
+            .set_device_attrs(&[nr_irqs_attr, bad_attr, init_attr])
+            .unwrap_err();
+        assert_eq!(index, 1);
+        // The attributes before the failing one were applied.
+        assert_eq!(
+            device_fd
+                .get_device_attr_typed::<u32>(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0)
+                .unwrap(),
+            128
+        );
+        // Once set, the number of interrupts cannot change.
+        assert!(device_fd
+            .set_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0, &256u32)
+            .is_err());
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_typed_device_vgic() {
+        use crate::ioctls::arm::GicVersion;
+        use kvm_bindings::{
+            kvm_device_type_KVM_DEV_TYPE_ARM_PV_TIME, KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+        };
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let (_, version) = vm.create_vgic(GicVersion::V3).unwrap();
+        let type_ = version.device_type();
+
+        // Devices which were only checked, or which are not a vGIC, are rejected.
+        let vm = kvm.create_vm().unwrap();
+        let checked = vm
+            .create_typed_device(type_, KVM_CREATE_DEVICE_TEST)
+            .unwrap();
+        assert_eq!(checked.set_nr_irqs(128).unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(checked.init().unwrap_err().errno(), libc::EINVAL);
+        if let Ok(pv_time) = vm.create_typed_device(kvm_device_type_KVM_DEV_TYPE_ARM_PV_TIME, 0) {
+            assert_eq!(pv_time.set_nr_irqs(128).unwrap_err().errno(), libc::EINVAL);
+            assert_eq!(pv_time.init().unwrap_err().errno(), libc::EINVAL);
+        }
+
+        let vgic = vm.create_typed_device(type_, 0).unwrap();
+        vm.create_vcpu(0).unwrap();
+        // Not a multiple of 32.
+        assert_eq!(vgic.set_nr_irqs(100).unwrap_err().errno(), libc::EINVAL);
+        vgic.set_nr_irqs(128).unwrap();
+        vgic.init().unwrap();
+        let nr_irqs: u32 = vgic
+            .device_fd()
+            .unwrap()
+            .get_device_attr_typed(KVM_DEV_ARM_VGIC_GRP_NR_IRQS, 0)
+            .unwrap();
+        assert_eq!(nr_irqs, 128);
+        // The number of interrupts cannot change once the vGIC is initialized.
+        assert!(vgic.set_nr_irqs(256).is_err());
     }
 }
@@ -6,6 +6,7 @@
 // found in the THIRD-PARTY file.
 
 use std::mem::size_of;
+use std::os::raw::{c_ulong, c_void};
 use std::os::unix::io::AsRawFd;
 use std::ptr::{null_mut, NonNull};
 
@@ -13,11 +14,22 @@ use kvm_bindings::{
     kvm_coalesced_mmio, kvm_coalesced_mmio_ring, kvm_run, KVM_COALESCED_MMIO_PAGE_OFFSET,
 };
 use vmm_sys_util::errno;
+use vmm_sys_util::ioctl::ioctl_with_mut_ptr;
 
+/// Helpers setting up the in-kernel interrupt controller and the stolen time of aarch64 VMs.
+#[cfg(target_arch = "aarch64")]
+pub mod arm;
 /// Wrappers over KVM device ioctls.
 pub mod device;
+/// Wrappers over the SEV commands of `KVM_MEMORY_ENCRYPT_OP` and over the intra-host
+/// migration of SEV guests.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod sev;
 /// Wrappers over KVM system ioctls.
 pub mod system;
+/// Wrappers over the TDX commands of `KVM_MEMORY_ENCRYPT_OP`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub mod tdx;
 /// Wrappers over KVM VCPU ioctls.
 pub mod vcpu;
 /// Wrappers over KVM Virtual Machine ioctls.
@@ -29,6 +41,44 @@ pub mod vm;
 /// is otherwise a direct mapping to Result.
 pub type Result<T> = std::result::Result<T, errno::Error>;
 
+/// Issues the ioctl `req` with the argument `arg` on `fd`, on behalf of the `raw_ioctl()`
+/// methods of the file descriptor wrappers.
+///
+/// Returns the non-negative result of the ioctl, or the error it failed with.
+///
+/// # Safety
+///
+/// `req` must be an ioctl of `fd`, and `arg` must be valid for it.
+pub(crate) unsafe fn raw_ioctl<F: AsRawFd>(fd: &F, req: u64, arg: *mut c_void) -> Result<i32> {
+    let ret = ioctl_with_mut_ptr(fd, req as c_ulong, arg);
+    if ret < 0 {
+        return Err(errno::Error::last());
+    }
+    Ok(ret)
+}
+
+// Helper function for memory mapping `size` bytes of anonymous memory in the tests.
+// Panics if the mmap fails.
+#[cfg(test)]
+pub(crate) fn mmap_anonymous(size: usize) -> NonNull<u8> {
+    // SAFETY: We map a new anonymous area and check the result.
+    let addr = unsafe {
+        libc::mmap(
+            null_mut(),
+            size,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+            -1,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        panic!("mmap failed.");
+    }
+
+    NonNull::new(addr).unwrap().cast()
+}
+
 /// A wrapper around the coalesced MMIO ring page.
 #[derive(Debug)]
 pub(crate) struct KvmCoalescedIoRing {
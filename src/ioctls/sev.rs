@@ -0,0 +1,1160 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::fs::{File, OpenOptions};
+use std::ops::Range;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::Ordering;
+
+use kvm_bindings::{
+    kvm_enable_cap, kvm_sev_attestation_report, kvm_sev_cmd, kvm_sev_launch_measure,
+    kvm_sev_launch_start, kvm_sev_launch_update_data, sev_cmd_id_KVM_SEV_ES_INIT,
+    sev_cmd_id_KVM_SEV_GET_ATTESTATION_REPORT, sev_cmd_id_KVM_SEV_INIT,
+    sev_cmd_id_KVM_SEV_LAUNCH_FINISH, sev_cmd_id_KVM_SEV_LAUNCH_MEASURE,
+    sev_cmd_id_KVM_SEV_LAUNCH_START, sev_cmd_id_KVM_SEV_LAUNCH_UPDATE_DATA,
+    sev_cmd_id_KVM_SEV_LAUNCH_UPDATE_VMSA, KVM_CAP_VM_COPY_ENC_CONTEXT_FROM,
+    KVM_CAP_VM_MOVE_ENC_CONTEXT_FROM,
+};
+use vmm_sys_util::errno;
+
+use crate::ioctls::vm::VmFd;
+use crate::ioctls::Result;
+
+/// Path of the AMD Secure Processor device node.
+const SEV_DEVICE_PATH: &str = "/dev/sev";
+
+// The following definitions come from `arch/x86/include/uapi/asm/kvm.h` and are missing from
+// the `kvm-bindings` used by this crate.
+
+/// VM type of SEV guests initialized with [`VmFd::sev_init2`].
+pub const KVM_X86_SEV_VM: u64 = 2;
+/// VM type of SEV-ES guests initialized with [`VmFd::sev_init2`].
+pub const KVM_X86_SEV_ES_VM: u64 = 3;
+/// VM type of SEV-SNP guests initialized with [`VmFd::sev_init2`].
+pub const KVM_X86_SNP_VM: u64 = 4;
+
+const KVM_SEV_INIT2: u32 = 22;
+const KVM_SEV_SNP_LAUNCH_START: u32 = 100;
+const KVM_SEV_SNP_LAUNCH_UPDATE: u32 = 101;
+const KVM_SEV_SNP_LAUNCH_FINISH: u32 = 102;
+
+const KVM_SEV_SNP_FINISH_DATA_SIZE: usize = 32;
+
+// Name of the anonymous inode backing guest_memfd files.
+const GUEST_MEMFD_LINK: &str = "anon_inode:[kvm-gmem]";
+// SNP launch updates work on 4KiB pages.
+const SNP_PAGE_SHIFT: u64 = 12;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct kvm_sev_init {
+    vmsa_features: u64,
+    flags: u32,
+    ghcb_version: u16,
+    pad1: u16,
+    pad2: [u32; 8],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct kvm_sev_snp_launch_start {
+    policy: u64,
+    gosvw: [u8; 16],
+    flags: u16,
+    pad0: [u8; 6],
+    pad1: [u64; 4],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct kvm_sev_snp_launch_update {
+    gfn_start: u64,
+    uaddr: u64,
+    len: u64,
+    type_: u8,
+    pad0: u8,
+    flags: u16,
+    pad1: u32,
+    pad2: [u64; 4],
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct kvm_sev_snp_launch_finish {
+    id_block_uaddr: u64,
+    id_auth_uaddr: u64,
+    id_block_en: u8,
+    auth_key_en: u8,
+    vcek_disabled: u8,
+    host_data: [u8; KVM_SEV_SNP_FINISH_DATA_SIZE],
+    pad0: [u8; 3],
+    flags: u16,
+    pad1: [u64; 4],
+}
+
+/// Type of the pages added to an SEV-SNP guest by [`VmFd::snp_launch_update`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum SnpPageType {
+    /// Pages whose content is copied from userspace and measured.
+    Normal = 1,
+    /// Zeroed pages, which are measured.
+    Zero = 3,
+    /// Pages whose content is copied from userspace but not measured.
+    Unmeasured = 4,
+    /// The secrets page, filled by the firmware.
+    Secrets = 5,
+    /// The CPUID page, whose content is validated by the firmware.
+    Cpuid = 6,
+}
+
+/// Handle to the AMD Secure Processor device.
+///
+/// The SEV firmware only accepts commands from processes which can prove access to the
+/// secure processor, so every SEV command issued through [`VmFd`] carries this file
+/// descriptor in the `sev_fd` field of `kvm_sev_cmd`.
+#[derive(Debug)]
+pub struct SevFd {
+    sev: File,
+}
+
+impl SevFd {
+    /// Opens `/dev/sev` for reading and writing.
+    ///
+    /// # Example
+    ///
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # use kvm_ioctls::SevFd;
+    /// let sev = SevFd::open().unwrap();
+    /// ```
+    pub fn open() -> std::io::Result<Self> {
+        let sev = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(SEV_DEVICE_PATH)?;
+        Ok(SevFd { sev })
+    }
+}
+
+impl From<File> for SevFd {
+    /// Uses an already opened SEV device, e.g. one handed over by a privileged process.
+    fn from(sev: File) -> Self {
+        SevFd { sev }
+    }
+}
+
+impl AsRawFd for SevFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.sev.as_raw_fd()
+    }
+}
+
+impl FromRawFd for SevFd {
+    /// # Safety
+    ///
+    /// This function is unsafe as the primitives currently returned have the contract that
+    /// they are the sole owner of the file descriptor they are wrapping. Usage of this function
+    /// could accidentally allow violating this contract which can cause memory unsafety in code
+    /// that relies on it being true.
+    unsafe fn from_raw_fd(fd: RawFd) -> Self {
+        SevFd {
+            sev: File::from_raw_fd(fd),
+        }
+    }
+}
+
+bitflags::bitflags! {
+    /// Guest policy passed to [`VmFd::sev_launch_start`].
+    ///
+    /// The policy is enforced by the SEV firmware for the whole lifetime of the guest. Bits
+    /// not covered by the flags below, such as the minimum firmware version, are kept as is.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct SevPolicy: u32 {
+        /// Debugging of the guest is disallowed.
+        const NoDebug = 1 << 0;
+        /// Sharing keys with other guests is disallowed.
+        const NoKeySharing = 1 << 1;
+        /// The guest is an SEV-ES guest, its register state is encrypted.
+        const Es = 1 << 2;
+        /// Sending the guest to another platform is disallowed.
+        const NoSend = 1 << 3;
+        /// The guest must not be transmitted to another platform outside the domain.
+        const Domain = 1 << 4;
+        /// The guest must not be transmitted to another platform that is not SEV-capable.
+        const Sev = 1 << 5;
+        // The remaining bits hold the minimum firmware API version.
+        const _ = !0;
+    }
+}
+
+/// Error returned by the typed SEV commands of [`VmFd`].
+///
+/// Besides the error code of the `KVM_MEMORY_ENCRYPT_OP` ioctl, it carries the status code
+/// that the SEV firmware wrote into the `error` field of `kvm_sev_cmd`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SevError {
+    errno: errno::Error,
+    firmware_error: u32,
+}
+
+impl SevError {
+    /// Returns the errno code of the failed ioctl.
+    pub fn errno(&self) -> i32 {
+        self.errno.errno()
+    }
+
+    /// Returns the SEV firmware status code, or 0 if the command did not reach the firmware.
+    pub fn firmware_error(&self) -> u32 {
+        self.firmware_error
+    }
+}
+
+impl From<SevError> for errno::Error {
+    fn from(err: SevError) -> errno::Error {
+        err.errno
+    }
+}
+
+impl std::fmt::Display for SevError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (SEV firmware error {:#x})",
+            self.errno, self.firmware_error
+        )
+    }
+}
+
+impl std::error::Error for SevError {}
+
+/// Converts an optional buffer to the (address, length) pair expected by the SEV commands.
+fn sev_buffer(buf: &[u8]) -> std::result::Result<(u64, u32), SevError> {
+    if buf.is_empty() {
+        return Ok((0, 0));
+    }
+    let len = u32::try_from(buf.len()).map_err(|_| SevError {
+        errno: errno::Error::new(libc::EINVAL),
+        firmware_error: 0,
+    })?;
+    Ok((buf.as_ptr() as u64, len))
+}
+
+/// Fetches an attestation report with the size query protocol of
+/// `KVM_SEV_GET_ATTESTATION_REPORT`.
+///
+/// `issue` runs the command. It is first run with a zero length so that the firmware reports
+/// the size of the report, then with a buffer of exactly that size.
+fn sev_attestation_report<F>(
+    mnonce: [u8; 16],
+    mut issue: F,
+) -> std::result::Result<Vec<u8>, SevError>
+where
+    F: FnMut(&mut kvm_sev_attestation_report) -> std::result::Result<(), SevError>,
+{
+    let mut report = kvm_sev_attestation_report {
+        mnonce,
+        ..Default::default()
+    };
+    // The firmware fails the size query with an invalid length error, but still reports the
+    // length it needs.
+    if let Err(err) = issue(&mut report) {
+        if report.len == 0 {
+            return Err(err);
+        }
+    }
+    let mut buf = vec![0u8; report.len as usize];
+    report.uaddr = buf.as_mut_ptr() as u64;
+    issue(&mut report)?;
+    buf.truncate(report.len as usize);
+    Ok(buf)
+}
+
+/// Enables the capability `cap` taking the VM `source` as argument, i.e.
+/// `KVM_CAP_VM_COPY_ENC_CONTEXT_FROM` or `KVM_CAP_VM_MOVE_ENC_CONTEXT_FROM`.
+///
+/// `enable` enables the capability on the destination VM.
+fn enable_enc_context_cap<F>(cap: u32, source: &VmFd, enable: F) -> Result<()>
+where
+    F: FnOnce(&kvm_enable_cap) -> Result<()>,
+{
+    let cap = kvm_enable_cap {
+        cap,
+        args: [source.as_raw_fd() as u64, 0, 0, 0],
+        ..Default::default()
+    };
+    enable(&cap)
+}
+
+impl VmFd {
+    fn sev_issue_cmd<T>(
+        &self,
+        sev: &SevFd,
+        id: u32,
+        data: Option<&mut T>,
+    ) -> std::result::Result<(), SevError> {
+        let mut cmd = kvm_sev_cmd {
+            id,
+            data: data.map_or(0, |data| data as *mut T as u64),
+            sev_fd: sev.as_raw_fd() as u32,
+            ..Default::default()
+        };
+        self.encrypt_op_sev(&mut cmd).map_err(|errno| SevError {
+            errno,
+            firmware_error: cmd.error,
+        })
+    }
+
+    /// Initializes the SEV platform context of this VM (`KVM_SEV_INIT`).
+    ///
+    /// This has to be the first SEV command issued on the VM. SEV-ES guests are initialized
+    /// with [`sev_es_init`](Self::sev_es_init) instead.
+    ///
+    /// VMs created with one of the SEV VM types, such as [`KVM_X86_SEV_VM`], are
+    /// initialized with [`sev_init2`](Self::sev_init2) instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    ///
+    /// # Example
+    ///
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # use kvm_ioctls::{Kvm, SevFd};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let sev = SevFd::open().unwrap();
+    /// vm.sev_init(&sev).unwrap();
+    /// ```
+    pub fn sev_init(&self, sev: &SevFd) -> std::result::Result<(), SevError> {
+        self.sev_issue_cmd::<()>(sev, sev_cmd_id_KVM_SEV_INIT, None)
+    }
+
+    /// Initializes the SEV-ES platform context of this VM (`KVM_SEV_ES_INIT`).
+    ///
+    /// This has to be the first SEV command issued on a VM launched with
+    /// [`SevPolicy::Es`], unless it was created with [`KVM_X86_SEV_ES_VM`] and initialized
+    /// with [`sev_init2`](Self::sev_init2).
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    pub fn sev_es_init(&self, sev: &SevFd) -> std::result::Result<(), SevError> {
+        self.sev_issue_cmd::<()>(sev, sev_cmd_id_KVM_SEV_ES_INIT, None)
+    }
+
+    /// Initializes the SEV platform context of a VM created with [`KVM_X86_SEV_VM`],
+    /// [`KVM_X86_SEV_ES_VM`] or [`KVM_X86_SNP_VM`] (`KVM_SEV_INIT2`).
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    /// * `vmsa_features` - Features of the SEV-ES VMSA to enable, 0 for the default ones.
+    /// * `ghcb_version` - GHCB protocol version of the guest, 0 for the default one.
+    pub fn sev_init2(
+        &self,
+        sev: &SevFd,
+        vmsa_features: u64,
+        ghcb_version: u16,
+    ) -> std::result::Result<(), SevError> {
+        let mut init = kvm_sev_init {
+            vmsa_features,
+            ghcb_version,
+            ..Default::default()
+        };
+        self.sev_issue_cmd(sev, KVM_SEV_INIT2, Some(&mut init))
+    }
+
+    /// Makes this VM a mirror of the SEV guest `source`, by copying its encryption context
+    /// with `KVM_CAP_VM_COPY_ENC_CONTEXT_FROM`.
+    ///
+    /// The mirror shares the encryption key of `source` but has its own vCPUs and memory
+    /// slots, e.g. for a workload scheduled in the guest by the host. Both VMs keep running;
+    /// to transfer the guest to this VM instead, see
+    /// [`move_enc_context_from`](Self::move_enc_context_from).
+    ///
+    /// This must be the first SEV-related call on this VM: it fails with `EINVAL` once
+    /// the VM has an SEV context of its own or vCPUs. The VMM can then register the
+    /// encrypted memory regions and create the vCPUs of the mirror, but no SEV command is
+    /// accepted by the mirror. `source` must have been launched with
+    /// [`sev_launch_start`](Self::sev_launch_start) and must not be a mirror itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The VM whose encryption context is copied.
+    ///
+    /// # Example
+    ///
+    /// The VM can come from another process, e.g. from a VMM handing its guest over to a new
+    /// VMM. [`into_owned_fd`](Self::into_owned_fd) gives its file descriptor, which is passed
+    /// over a Unix socket:
+    ///
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{Kvm, SevFd, SevPolicy};
+    /// # use std::os::unix::io::{AsRawFd, IntoRawFd};
+    /// # use std::os::unix::net::UnixStream;
+    /// use vmm_sys_util::sock_ctrl_msg::ScmSocket;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let (old_vmm, new_vmm) = UnixStream::pair().unwrap();
+    ///
+    /// // The running VMM sends its SEV guest.
+    /// let source = kvm.create_vm().unwrap();
+    /// let sev = SevFd::open().unwrap();
+    /// source.sev_init(&sev).unwrap();
+    /// source
+    ///     .sev_launch_start(&sev, SevPolicy::NoDebug, &[], &[])
+    ///     .unwrap();
+    /// let source_fd = source.into_owned_fd();
+    /// old_vmm
+    ///     .send_with_fd(&[0u8][..], source_fd.as_raw_fd())
+    ///     .unwrap();
+    ///
+    /// // The new VMM receives it and mirrors its encryption context.
+    /// let (_, file) = new_vmm.recv_with_fd(&mut [0u8]).unwrap();
+    /// let source = unsafe {
+    ///     kvm.create_vmfd_from_rawfd(file.unwrap().into_raw_fd())
+    ///         .unwrap()
+    /// };
+    /// let vm = kvm.create_vm().unwrap();
+    /// vm.copy_enc_context_from(&source).unwrap();
+    /// ```
+    pub fn copy_enc_context_from(&self, source: &VmFd) -> Result<()> {
+        enable_enc_context_cap(KVM_CAP_VM_COPY_ENC_CONTEXT_FROM, source, |cap| {
+            self.enable_cap(cap)
+        })
+    }
+
+    /// Transfers the SEV guest `source` to this VM, by moving its encryption context with
+    /// `KVM_CAP_VM_MOVE_ENC_CONTEXT_FROM`.
+    ///
+    /// Unlike [`copy_enc_context_from`](Self::copy_enc_context_from), which lets both VMs
+    /// share the context, this is meant for replacing the VMM of a running guest: the
+    /// encryption context, the encrypted memory regions, the state of the SEV-ES vCPUs and
+    /// the mirrors of `source` all move to this VM, which must then be given the memory of
+    /// the guest.
+    ///
+    /// This VM must not have an SEV context, and must have created as many vCPUs as `source`
+    /// beforehand. The call fails with `EINVAL` otherwise.
+    ///
+    /// `source` is left dead: all its ioctls fail with `EIO`, and it can only be closed.
+    ///
+    /// # Arguments
+    ///
+    /// * `source` - The VM whose encryption context is moved, usually received from the
+    ///              previous VMM as shown for
+    ///              [`copy_enc_context_from`](Self::copy_enc_context_from).
+    ///
+    /// # Example
+    ///
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # use kvm_ioctls::{Kvm, SevFd, SevPolicy};
+    /// let kvm = Kvm::new().unwrap();
+    /// let source = kvm.create_vm().unwrap();
+    /// let sev = SevFd::open().unwrap();
+    /// source.sev_init(&sev).unwrap();
+    /// source
+    ///     .sev_launch_start(&sev, SevPolicy::NoDebug, &[], &[])
+    ///     .unwrap();
+    /// let _source_vcpu = source.create_vcpu(0).unwrap();
+    /// source.sev_launch_finish(&sev).unwrap();
+    ///
+    /// let vm = kvm.create_vm().unwrap();
+    /// let _vcpu = vm.create_vcpu(0).unwrap();
+    /// vm.move_enc_context_from(&source).unwrap();
+    /// ```
+    pub fn move_enc_context_from(&self, source: &VmFd) -> Result<()> {
+        enable_enc_context_cap(KVM_CAP_VM_MOVE_ENC_CONTEXT_FROM, source, |cap| {
+            self.enable_cap(cap)
+        })
+    }
+
+    /// Creates the memory encryption context of the guest (`KVM_SEV_LAUNCH_START`).
+    ///
+    /// Returns the handle of the new encryption context.
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    /// * `policy` - Guest policy, see the SEV API specification.
+    /// * `dh_cert` - Guest owner's Diffie-Hellman certificate, empty if not used.
+    /// * `session` - Guest owner's session parameters, empty if not used.
+    ///
+    /// # Example
+    ///
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # use kvm_ioctls::{Kvm, SevFd, SevPolicy};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let sev = SevFd::open().unwrap();
+    /// vm.sev_init(&sev).unwrap();
+    /// let handle = vm
+    ///     .sev_launch_start(&sev, SevPolicy::NoDebug, &[], &[])
+    ///     .unwrap();
+    /// ```
+    pub fn sev_launch_start(
+        &self,
+        sev: &SevFd,
+        policy: SevPolicy,
+        dh_cert: &[u8],
+        session: &[u8],
+    ) -> std::result::Result<u32, SevError> {
+        let (dh_uaddr, dh_len) = sev_buffer(dh_cert)?;
+        let (session_uaddr, session_len) = sev_buffer(session)?;
+        let mut start = kvm_sev_launch_start {
+            policy: policy.bits(),
+            dh_uaddr,
+            dh_len,
+            session_uaddr,
+            session_len,
+            ..Default::default()
+        };
+        self.sev_issue_cmd(sev, sev_cmd_id_KVM_SEV_LAUNCH_START, Some(&mut start))?;
+        self.sev_es
+            .store(policy.contains(SevPolicy::Es), Ordering::Release);
+        Ok(start.handle)
+    }
+
+    /// Encrypts a region of guest memory in place (`KVM_SEV_LAUNCH_UPDATE_DATA`).
+    ///
+    /// The memory is usually registered beforehand with
+    /// [`register_enc_memory_region_guarded`](Self::register_enc_memory_region_guarded), so
+    /// that it stays pinned for the lifetime of the guest.
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    /// * `addr` - Userspace address of the memory to encrypt.
+    /// * `len` - Length of the memory to encrypt.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the firmware overwrites the memory at `addr` with its
+    /// encrypted content, and there is no guarantee `addr` points to valid memory.
+    ///
+    /// The caller of this method must make sure that:
+    /// - `addr` points to `len` bytes of valid memory
+    /// - no Rust reference to that memory relies on its content being left unchanged.
+    pub unsafe fn sev_launch_update_data(
+        &self,
+        sev: &SevFd,
+        addr: u64,
+        len: u32,
+    ) -> std::result::Result<(), SevError> {
+        let mut update = kvm_sev_launch_update_data {
+            uaddr: addr,
+            len,
+            ..Default::default()
+        };
+        self.sev_issue_cmd(
+            sev,
+            sev_cmd_id_KVM_SEV_LAUNCH_UPDATE_DATA,
+            Some(&mut update),
+        )
+    }
+
+    /// Encrypts the register state of all the vCPUs of an SEV-ES guest
+    /// (`KVM_SEV_LAUNCH_UPDATE_VMSA`).
+    ///
+    /// This must be issued after all the vCPUs have been created and their registers set up.
+    /// Returns `EINVAL` without issuing the command if the guest was not launched with
+    /// [`SevPolicy::Es`].
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    pub fn sev_es_launch_update_vmsa(&self, sev: &SevFd) -> std::result::Result<(), SevError> {
+        if !self.sev_es.load(Ordering::Acquire) {
+            return Err(SevError {
+                errno: errno::Error::new(libc::EINVAL),
+                firmware_error: 0,
+            });
+        }
+        self.sev_issue_cmd::<()>(sev, sev_cmd_id_KVM_SEV_LAUNCH_UPDATE_VMSA, None)
+    }
+
+    /// Retrieves the measurement of the encrypted guest memory (`KVM_SEV_LAUNCH_MEASURE`).
+    ///
+    /// Returns the length of the measurement. If `buf` is empty, no measurement is retrieved
+    /// and the length of the buffer the firmware needs is returned instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    /// * `buf` - Buffer receiving the measurement.
+    pub fn sev_launch_measure(
+        &self,
+        sev: &SevFd,
+        buf: &mut [u8],
+    ) -> std::result::Result<u32, SevError> {
+        let (uaddr, len) = sev_buffer(buf)?;
+        let mut measure = kvm_sev_launch_measure {
+            uaddr,
+            len,
+            ..Default::default()
+        };
+        let result = self.sev_issue_cmd(sev, sev_cmd_id_KVM_SEV_LAUNCH_MEASURE, Some(&mut measure));
+        match result {
+            // The firmware fails the size query with an invalid length error, but still
+            // reports the length it needs.
+            Err(err) if len > 0 || measure.len == 0 => Err(err),
+            _ => Ok(measure.len),
+        }
+    }
+
+    /// Finalizes the launch flow of the guest (`KVM_SEV_LAUNCH_FINISH`).
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    pub fn sev_launch_finish(&self, sev: &SevFd) -> std::result::Result<(), SevError> {
+        self.sev_issue_cmd::<()>(sev, sev_cmd_id_KVM_SEV_LAUNCH_FINISH, None)
+    }
+
+    /// Retrieves the attestation report of the guest (`KVM_SEV_GET_ATTESTATION_REPORT`).
+    ///
+    /// The command is issued twice: once to query the size of the report and once to fetch
+    /// it. The returned buffer has the size reported by the firmware.
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    /// * `mnonce` - Nonce chosen by the guest owner, included in the report.
+    ///
+    /// # Example
+    ///
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # use kvm_ioctls::{Kvm, SevFd, SevPolicy};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let sev = SevFd::open().unwrap();
+    /// vm.sev_init(&sev).unwrap();
+    /// vm.sev_launch_start(&sev, SevPolicy::empty(), &[], &[])
+    ///     .unwrap();
+    /// let mut measurement = [0u8; 48];
+    /// vm.sev_launch_measure(&sev, &mut measurement).unwrap();
+    /// let report = vm.sev_get_attestation_report(&sev, [0x5a; 16]).unwrap();
+    /// ```
+    pub fn sev_get_attestation_report(
+        &self,
+        sev: &SevFd,
+        mnonce: [u8; 16],
+    ) -> std::result::Result<Vec<u8>, SevError> {
+        sev_attestation_report(mnonce, |report| {
+            self.sev_issue_cmd(sev, sev_cmd_id_KVM_SEV_GET_ATTESTATION_REPORT, Some(report))
+        })
+    }
+
+    /// Creates the encryption context of an SEV-SNP guest (`KVM_SEV_SNP_LAUNCH_START`).
+    ///
+    /// The VM must have been created with [`KVM_X86_SNP_VM`] and initialized with
+    /// [`sev_init2`](Self::sev_init2).
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    /// * `policy` - Guest policy, see the SEV-SNP firmware ABI specification.
+    pub fn snp_launch_start(&self, sev: &SevFd, policy: u64) -> std::result::Result<(), SevError> {
+        let mut start = kvm_sev_snp_launch_start {
+            policy,
+            ..Default::default()
+        };
+        self.sev_issue_cmd(sev, KVM_SEV_SNP_LAUNCH_START, Some(&mut start))
+    }
+
+    /// Adds a range of private pages to an SEV-SNP guest (`KVM_SEV_SNP_LAUNCH_UPDATE`).
+    ///
+    /// The guest frames in `gfns` must belong to a memory slot backed by `guest_memfd` and
+    /// must have been converted to private memory with `KVM_SET_MEMORY_ATTRIBUTES` before
+    /// this call; otherwise the kernel fails the command with `EINVAL`. The wrapper itself
+    /// returns `EINVAL` without issuing the command if `gfns` is empty, if `guest_memfd` is
+    /// not a guest_memfd file or if it is too small to back `gfns`.
+    ///
+    /// The kernel may process the range in several steps; the command is reissued until the
+    /// whole range has been added.
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    /// * `gfns` - Guest frame numbers of the pages to add.
+    /// * `uaddr` - Userspace address of the initial content of the pages. Ignored for the
+    ///             [`Zero`](SnpPageType::Zero) and [`Secrets`](SnpPageType::Secrets) types.
+    /// * `page_type` - Type of the pages.
+    /// * `guest_memfd` - The guest_memfd backing the private memory of `gfns`.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because the kernel reads the initial content of the pages from
+    /// `uaddr`, and for the [`Cpuid`](SnpPageType::Cpuid) type writes back the CPUID table
+    /// fixed up by the firmware when it is rejected. There is no guarantee `uaddr` points to
+    /// valid memory.
+    ///
+    /// Unless `page_type` is [`Zero`](SnpPageType::Zero) or [`Secrets`](SnpPageType::Secrets),
+    /// the caller of this method must make sure that `uaddr` points to as many bytes of valid
+    /// memory as the pages in `gfns`, which can be written to for the
+    /// [`Cpuid`](SnpPageType::Cpuid) type.
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use std::fs::File;
+    /// # use kvm_ioctls::{Kvm, SevFd, SnpPageType, KVM_X86_SNP_VM};
+    /// # fn launch(guest_memfd: &File, firmware: &[u8]) {
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm_with_type(KVM_X86_SNP_VM).unwrap();
+    /// let sev = SevFd::open().unwrap();
+    /// vm.sev_init2(&sev, 0, 0).unwrap();
+    /// vm.snp_launch_start(&sev, 0x30000).unwrap();
+    ///
+    /// // The first 16 pages of guest memory live in `guest_memfd`, which was attached to
+    /// // their memory slot through `KVM_SET_USER_MEMORY_REGION2`. They must be made private
+    /// // with `KVM_SET_MEMORY_ATTRIBUTES` (`KVM_MEMORY_ATTRIBUTE_PRIVATE`) at this point,
+    /// // before their content is added.
+    /// assert!(firmware.len() >= 16 * 4096);
+    /// unsafe {
+    ///     vm.snp_launch_update(
+    ///         &sev,
+    ///         0..16,
+    ///         firmware.as_ptr() as u64,
+    ///         SnpPageType::Normal,
+    ///         guest_memfd,
+    ///     )
+    /// }
+    /// .unwrap();
+    /// vm.snp_launch_finish(&sev).unwrap();
+    /// # }
+    /// ```
+    pub unsafe fn snp_launch_update(
+        &self,
+        sev: &SevFd,
+        gfns: Range<u64>,
+        uaddr: u64,
+        page_type: SnpPageType,
+        guest_memfd: &File,
+    ) -> std::result::Result<(), SevError> {
+        let einval = SevError {
+            errno: errno::Error::new(libc::EINVAL),
+            firmware_error: 0,
+        };
+        let len = gfns
+            .end
+            .checked_sub(gfns.start)
+            .filter(|&pages| pages > 0)
+            .and_then(|pages| pages.checked_mul(1 << SNP_PAGE_SHIFT))
+            .ok_or(einval)?;
+        let is_guest_memfd =
+            match std::fs::read_link(format!("/proc/self/fd/{}", guest_memfd.as_raw_fd())) {
+                Ok(link) => link.as_os_str() == GUEST_MEMFD_LINK,
+                Err(_) => false,
+            };
+        let memfd_size = guest_memfd.metadata().map_or(0, |metadata| metadata.len());
+        if !is_guest_memfd || memfd_size < len {
+            return Err(einval);
+        }
+
+        let mut update = kvm_sev_snp_launch_update {
+            gfn_start: gfns.start,
+            uaddr,
+            len,
+            type_: page_type as u8,
+            ..Default::default()
+        };
+        // The kernel advances `gfn_start`, `uaddr` and `len` past the pages it processed.
+        while update.len > 0 {
+            self.sev_issue_cmd(sev, KVM_SEV_SNP_LAUNCH_UPDATE, Some(&mut update))?;
+        }
+        Ok(())
+    }
+
+    /// Finalizes the launch flow of an SEV-SNP guest (`KVM_SEV_SNP_LAUNCH_FINISH`).
+    ///
+    /// The guest is launched without an ID block and with zeroed host data.
+    ///
+    /// # Arguments
+    ///
+    /// * `sev` - Handle to the AMD Secure Processor.
+    pub fn snp_launch_finish(&self, sev: &SevFd) -> std::result::Result<(), SevError> {
+        let mut finish = kvm_sev_snp_launch_finish::default();
+        self.sev_issue_cmd(sev, KVM_SEV_SNP_LAUNCH_FINISH, Some(&mut finish))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Kvm;
+    use kvm_bindings::kvm_enc_region;
+    use std::path::Path;
+
+    #[test]
+    fn test_sev_fd() {
+        if Path::new(SEV_DEVICE_PATH).exists() {
+            return;
+        }
+        assert_eq!(
+            SevFd::open().unwrap_err().raw_os_error(),
+            Some(libc::ENOENT)
+        );
+
+        // Commands are rejected by hosts without SEV, and the error is not attributed to
+        // the firmware.
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let sev = SevFd::from(File::open("/dev/null").unwrap());
+        let err = vm.sev_init(&sev).unwrap_err();
+        assert_eq!(err.firmware_error(), 0);
+        assert_eq!(errno::Error::from(err).errno(), err.errno());
+    }
+
+    #[test]
+    fn test_sev_policy() {
+        let policy = SevPolicy::NoDebug | SevPolicy::Es;
+        assert_eq!(policy.bits(), 0b101);
+        assert!(policy.contains(SevPolicy::Es));
+        // The firmware API version bits are preserved.
+        let policy = SevPolicy::from_bits_retain(0x0101_0005);
+        assert_eq!(policy.bits(), 0x0101_0005);
+        assert!(policy.contains(SevPolicy::NoDebug | SevPolicy::Es));
+
+        // The VMSA update is refused before launching an SEV-ES guest.
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let sev = SevFd::from(File::open("/dev/null").unwrap());
+        let err = vm.sev_es_launch_update_vmsa(&sev).unwrap_err();
+        assert_eq!(err.errno(), libc::EINVAL);
+        assert_eq!(err.firmware_error(), 0);
+    }
+
+    #[test]
+    fn test_sev_attestation_report_size_query() {
+        const SEV_RET_INVALID_LEN: u32 = 4;
+        let invalid_len = SevError {
+            errno: errno::Error::new(libc::EIO),
+            firmware_error: SEV_RET_INVALID_LEN,
+        };
+        let mnonce = [0x5a; 16];
+
+        // The firmware reports the size of the report, then fills a buffer of that size.
+        let mut calls = 0;
+        let report = sev_attestation_report(mnonce, |report| {
+            calls += 1;
+            assert_eq!(report.mnonce, mnonce);
+            if report.len == 0 {
+                assert_eq!(report.uaddr, 0);
+                report.len = 8;
+                return Err(invalid_len);
+            }
+            assert_eq!(report.len, 8);
+            // SAFETY: The buffer is `report.len` bytes long.
+            let buf = unsafe { std::slice::from_raw_parts_mut(report.uaddr as *mut u8, 8) };
+            buf.copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(calls, 2);
+        assert_eq!(report, [1, 2, 3, 4, 5, 6, 7, 8]);
+
+        // The report is trimmed to the length written back by the firmware.
+        let report = sev_attestation_report(mnonce, |report| {
+            report.len = if report.len == 0 { 16 } else { 12 };
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(report.len(), 12);
+
+        // Errors not reporting a length are returned as is.
+        let mut calls = 0;
+        let err = sev_attestation_report(mnonce, |_| {
+            calls += 1;
+            Err(invalid_len)
+        })
+        .unwrap_err();
+        assert_eq!(calls, 1);
+        assert_eq!(err, invalid_len);
+
+        // Errors of the second call carry the firmware error.
+        let err = sev_attestation_report(mnonce, |report| {
+            if report.len == 0 {
+                report.len = 8;
+                return Ok(());
+            }
+            Err(SevError {
+                errno: errno::Error::new(libc::EIO),
+                firmware_error: 0x13,
+            })
+        })
+        .unwrap_err();
+        assert_eq!(err.errno(), libc::EIO);
+        assert_eq!(err.firmware_error(), 0x13);
+    }
+
+    #[test]
+    fn test_snp_struct_layout() {
+        fn offset<T, F>(base: &T, field: &F) -> usize {
+            field as *const F as usize - base as *const T as usize
+        }
+
+        let init = kvm_sev_init::default();
+        assert_eq!(std::mem::size_of::<kvm_sev_init>(), 48);
+        assert_eq!(offset(&init, &init.flags), 8);
+        assert_eq!(offset(&init, &init.ghcb_version), 12);
+        assert_eq!(offset(&init, &init.pad2), 16);
+
+        let start = kvm_sev_snp_launch_start::default();
+        assert_eq!(std::mem::size_of::<kvm_sev_snp_launch_start>(), 64);
+        assert_eq!(offset(&start, &start.gosvw), 8);
+        assert_eq!(offset(&start, &start.flags), 24);
+        assert_eq!(offset(&start, &start.pad1), 32);
+
+        let update = kvm_sev_snp_launch_update::default();
+        assert_eq!(std::mem::size_of::<kvm_sev_snp_launch_update>(), 64);
+        assert_eq!(offset(&update, &update.uaddr), 8);
+        assert_eq!(offset(&update, &update.len), 16);
+        assert_eq!(offset(&update, &update.type_), 24);
+        assert_eq!(offset(&update, &update.flags), 26);
+        assert_eq!(offset(&update, &update.pad2), 32);
+
+        let finish = kvm_sev_snp_launch_finish::default();
+        assert_eq!(std::mem::size_of::<kvm_sev_snp_launch_finish>(), 88);
+        assert_eq!(offset(&finish, &finish.id_block_en), 16);
+        assert_eq!(offset(&finish, &finish.vcek_disabled), 18);
+        assert_eq!(offset(&finish, &finish.host_data), 19);
+        assert_eq!(offset(&finish, &finish.flags), 54);
+        assert_eq!(offset(&finish, &finish.pad1), 56);
+    }
+
+    #[test]
+    fn test_copy_enc_context_from() {
+        let kvm = Kvm::new().unwrap();
+        let source = kvm.create_vm().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        // The source VM is passed by file descriptor.
+        let mut enabled = Vec::new();
+        enable_enc_context_cap(KVM_CAP_VM_COPY_ENC_CONTEXT_FROM, &source, |cap| {
+            enabled.push(*cap);
+            Ok(())
+        })
+        .unwrap();
+        let expected = kvm_enable_cap {
+            cap: KVM_CAP_VM_COPY_ENC_CONTEXT_FROM,
+            args: [source.as_raw_fd() as u64, 0, 0, 0],
+            ..Default::default()
+        };
+        assert_eq!(enabled, [expected]);
+
+        // The source VM has no encryption context.
+        assert_eq!(
+            vm.copy_enc_context_from(&source).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    #[cfg_attr(not(has_sev), ignore)]
+    fn test_copy_enc_context_from_sev() {
+        let kvm = Kvm::new().unwrap();
+        let source = kvm.create_vm().unwrap();
+        let sev = SevFd::open().unwrap();
+        source.sev_init(&sev).unwrap();
+        source
+            .sev_launch_start(&sev, SevPolicy::NoDebug, &[], &[])
+            .unwrap();
+
+        let mirror = kvm.create_vm().unwrap();
+        mirror.copy_enc_context_from(&source).unwrap();
+        // The mirror cannot issue SEV commands.
+        assert!(mirror.sev_init(&sev).is_err());
+        // Nor can it be the source of another mirror.
+        let vm = kvm.create_vm().unwrap();
+        assert_eq!(
+            vm.copy_enc_context_from(&mirror).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        let _vcpu = mirror.create_vcpu(0).unwrap();
+    }
+
+    #[test]
+    fn test_move_enc_context_from() {
+        let kvm = Kvm::new().unwrap();
+        let source = kvm.create_vm().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        let mut enabled = Vec::new();
+        enable_enc_context_cap(KVM_CAP_VM_MOVE_ENC_CONTEXT_FROM, &source, |cap| {
+            enabled.push(*cap);
+            Ok(())
+        })
+        .unwrap();
+        let expected = kvm_enable_cap {
+            cap: KVM_CAP_VM_MOVE_ENC_CONTEXT_FROM,
+            args: [source.as_raw_fd() as u64, 0, 0, 0],
+            ..Default::default()
+        };
+        assert_eq!(enabled, [expected]);
+
+        // The source VM has no encryption context, and is left untouched.
+        assert_eq!(
+            vm.move_enc_context_from(&source).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        source.create_vcpu(0).unwrap();
+    }
+
+    #[test]
+    #[cfg_attr(not(has_sev), ignore)]
+    fn test_move_enc_context_from_sev() {
+        let kvm = Kvm::new().unwrap();
+        let source = kvm.create_vm().unwrap();
+        let sev = SevFd::open().unwrap();
+        source.sev_init(&sev).unwrap();
+        source
+            .sev_launch_start(&sev, SevPolicy::NoDebug, &[], &[])
+            .unwrap();
+        let _source_vcpu = source.create_vcpu(0).unwrap();
+        source.sev_launch_finish(&sev).unwrap();
+
+        // The destination needs as many vCPUs as the source.
+        let vm = kvm.create_vm().unwrap();
+        assert_eq!(
+            vm.move_enc_context_from(&source).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        let _vcpu = vm.create_vcpu(0).unwrap();
+        vm.move_enc_context_from(&source).unwrap();
+
+        // The source is dead.
+        assert_eq!(source.create_vcpu(1).unwrap_err().errno(), libc::EIO);
+    }
+
+    #[test]
+    fn test_snp_launch_update_validation() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let sev = SevFd::from(File::open("/dev/null").unwrap());
+        let not_memfd = File::open("/dev/null").unwrap();
+
+        // Empty ranges and files which are not guest_memfds are rejected.
+        #[allow(clippy::reversed_empty_ranges)]
+        for gfns in [0..0, 1..0, 0..u64::MAX, 0..1] {
+            // SAFETY: Zero pages have no initial content to read.
+            let err = unsafe { vm.snp_launch_update(&sev, gfns, 0, SnpPageType::Zero, &not_memfd) }
+                .unwrap_err();
+            assert_eq!(err.errno(), libc::EINVAL);
+            assert_eq!(err.firmware_error(), 0);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(not(has_sev), ignore)]
+    fn test_sev_launch() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let sev = SevFd::open().unwrap();
+
+        vm.sev_init(&sev).unwrap();
+        vm.sev_launch_start(&sev, SevPolicy::empty(), &[], &[])
+            .unwrap();
+        // This is not an SEV-ES guest.
+        assert_eq!(
+            vm.sev_es_launch_update_vmsa(&sev).unwrap_err().errno(),
+            libc::EINVAL
+        );
+
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let region = kvm_enc_region {
+            addr: addr as u64,
+            size: 4096,
+        };
+        let guard = vm.register_enc_memory_region_guarded(&region).unwrap();
+        // SAFETY: The region was just mapped and is not referenced anywhere else.
+        unsafe { vm.sev_launch_update_data(&sev, region.addr, region.size as u32) }.unwrap();
+
+        let len = vm.sev_launch_measure(&sev, &mut []).unwrap();
+        assert!(len > 0);
+        let mut measurement = vec![0u8; len as usize];
+        assert_eq!(vm.sev_launch_measure(&sev, &mut measurement).unwrap(), len);
+        assert!(!vm
+            .sev_get_attestation_report(&sev, [0x5a; 16])
+            .unwrap()
+            .is_empty());
+        vm.sev_launch_finish(&sev).unwrap();
+
+        guard.unregister().unwrap();
+        unsafe { libc::munmap(addr, 4096) };
+    }
+
+    #[test]
+    #[cfg_attr(not(has_sev), ignore)]
+    fn test_sev_es_launch() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let sev = SevFd::open().unwrap();
+
+        vm.sev_es_init(&sev).unwrap();
+        vm.sev_launch_start(&sev, SevPolicy::NoDebug | SevPolicy::Es, &[], &[])
+            .unwrap();
+
+        let addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                4096,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let region = kvm_enc_region {
+            addr: addr as u64,
+            size: 4096,
+        };
+        let _guard = vm.register_enc_memory_region_guarded(&region).unwrap();
+        // SAFETY: The region was just mapped and is not referenced anywhere else.
+        unsafe { vm.sev_launch_update_data(&sev, region.addr, region.size as u32) }.unwrap();
+
+        let _vcpu = vm.create_vcpu(0).unwrap();
+        vm.sev_es_launch_update_vmsa(&sev).unwrap();
+
+        let mut measurement = [0u8; 48];
+        assert!(vm.sev_launch_measure(&sev, &mut measurement).unwrap() > 0);
+    }
+
+    #[test]
+    #[cfg_attr(not(has_sev), ignore)]
+    fn test_snp_launch() {
+        let kvm = Kvm::new().unwrap();
+        // SEV-SNP is not necessarily supported by SEV-capable hosts.
+        let vm = match kvm.create_vm_with_type(KVM_X86_SNP_VM) {
+            Ok(vm) => vm,
+            Err(_) => return,
+        };
+        let sev = SevFd::open().unwrap();
+
+        vm.sev_init2(&sev, 0, 0).unwrap();
+        // Bit 17 of the policy is reserved and must be set.
+        vm.snp_launch_start(&sev, 1 << 17).unwrap();
+        vm.snp_launch_finish(&sev).unwrap();
+    }
+}
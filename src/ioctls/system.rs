@@ -7,26 +7,41 @@
 use libc::{open, O_CLOEXEC, O_RDWR};
 use std::ffi::CStr;
 use std::fs::File;
-use std::os::raw::{c_char, c_ulong};
+use std::os::raw::{c_char, c_ulong, c_void};
 use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use crate::cap::Cap;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::ioctls::vcpu::MsrExitReason;
 use crate::ioctls::vm::{new_vmfd, VmFd};
 use crate::ioctls::Result;
 use crate::kvm_ioctls::*;
 #[cfg(target_arch = "aarch64")]
 use kvm_bindings::KVM_VM_TYPE_ARM_IPA_SIZE_MASK;
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-use kvm_bindings::{CpuId, MsrList, Msrs, KVM_MAX_CPUID_ENTRIES, KVM_MAX_MSR_ENTRIES};
+use kvm_bindings::{
+    kvm_cpuid_entry2, CpuId, MsrList, Msrs, KVM_CPUID_FLAG_SIGNIFCANT_INDEX, KVM_MAX_CPUID_ENTRIES,
+    KVM_MAX_MSR_ENTRIES,
+};
 use vmm_sys_util::errno;
-#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-use vmm_sys_util::ioctl::ioctl_with_mut_ptr;
 use vmm_sys_util::ioctl::{ioctl, ioctl_with_val};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use vmm_sys_util::ioctl::{ioctl_with_mut_ptr, ioctl_with_mut_ref};
+
+/// IPA size of aarch64 VMs created without `KVM_CAP_ARM_VM_IPA_SIZE`, or with an IPA size of 0.
+#[cfg(target_arch = "aarch64")]
+pub(crate) const KVM_ARM_DEFAULT_IPA_BITS: u32 = 40;
+/// Largest IPA size picked by default by [VmBuilder].
+#[cfg(target_arch = "aarch64")]
+const VM_BUILDER_MAX_DEFAULT_IPA_BITS: u32 = 48;
 
 /// Wrapper over KVM system ioctls.
 #[derive(Debug)]
 pub struct Kvm {
     kvm: File,
+    // Cached result of `KVM_GET_VCPU_MMAP_SIZE`, 0 until first queried.
+    vcpu_mmap_size: AtomicUsize,
 }
 
 impl Kvm {
@@ -248,7 +263,8 @@ impl Kvm {
 
     ///  Returns the size of the memory mapping required to use the vcpu's `kvm_run` structure.
     ///
-    /// See the documentation for `KVM_GET_VCPU_MMAP_SIZE`.
+    /// See the documentation for `KVM_GET_VCPU_MMAP_SIZE`. The size is queried once and cached
+    /// for subsequent calls.
     ///
     /// # Example
     ///
@@ -258,15 +274,43 @@ impl Kvm {
     /// assert!(kvm.get_vcpu_mmap_size().unwrap() > 0);
     /// ```
     pub fn get_vcpu_mmap_size(&self) -> Result<usize> {
+        let size = self.vcpu_mmap_size.load(Ordering::Relaxed);
+        if size > 0 {
+            return Ok(size);
+        }
         // SAFETY: Safe because we know that our file is a KVM fd and we verify the return result.
         let res = unsafe { ioctl(self, KVM_GET_VCPU_MMAP_SIZE()) };
         if res > 0 {
+            self.vcpu_mmap_size.store(res as usize, Ordering::Relaxed);
             Ok(res as usize)
         } else {
             Err(errno::Error::last())
         }
     }
 
+    /// Returns the size of the memory mapping required to use the vcpu's `kvm_run` structure,
+    /// in pages.
+    ///
+    /// The `kvm_run` structure is followed by other pages in the same mapping, such as the
+    /// coalesced MMIO ring, whose offsets are expressed in pages.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// assert!(kvm.vcpu_mmap_pages().unwrap() > 0);
+    /// ```
+    pub fn vcpu_mmap_pages(&self) -> Result<usize> {
+        let size = self.get_vcpu_mmap_size()?;
+        // SAFETY: We trust the sysconf libc function and we're calling it with a correct parameter.
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            -1 => return Err(errno::Error::last()),
+            ps => ps as usize,
+        };
+        Ok(size / page_size)
+    }
+
     /// Gets the recommended number of VCPUs per VM.
     ///
     /// See the documentation for `KVM_CAP_NR_VCPUS`.
@@ -296,6 +340,9 @@ impl Kvm {
     /// and powerpc do not yet enable it.
     /// Default to 32 when `KVM_CAP_NR_MEMSLOTS` is not implemented.
     ///
+    /// This is the limit of a default VM; the limit of a given VM is returned by
+    /// [VmFd::nr_memslots](struct.VmFd.html#method.nr_memslots).
+    ///
     /// # Example
     ///
     /// ```
@@ -377,8 +424,10 @@ impl Kvm {
     ///
     /// # Arguments
     ///
-    /// * `num_entries` - Maximum number of CPUID entries. This function can return less than
-    ///                         this when the hardware does not support so many CPUID entries.
+    /// * `num_entries` - Initial number of CPUID entries. While the kernel reports that this is
+    ///                   not enough (`E2BIG`), the request is retried with twice as many
+    ///                   entries, up to `KVM_MAX_CPUID_ENTRIES`. The returned `CpuId` holds
+    ///                   exactly the entries reported by the kernel.
     ///
     /// Returns Error `errno::Error(libc::ENOMEM)` when the input `num_entries` is greater than
     /// `KVM_MAX_CPUID_ENTRIES`.
@@ -397,7 +446,16 @@ impl Kvm {
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     pub fn get_emulated_cpuid(&self, num_entries: usize) -> Result<CpuId> {
-        self.get_cpuid(KVM_GET_EMULATED_CPUID(), num_entries)
+        let mut num_entries = num_entries;
+        let cpuid = loop {
+            match self.get_cpuid(KVM_GET_EMULATED_CPUID(), num_entries) {
+                Err(e) if e.errno() == libc::E2BIG && num_entries < KVM_MAX_CPUID_ENTRIES => {
+                    num_entries = (num_entries * 2).clamp(1, KVM_MAX_CPUID_ENTRIES);
+                }
+                result => break result?,
+            }
+        };
+        CpuId::from_entries(cpuid.as_slice()).map_err(|_| errno::Error::new(libc::ENOMEM))
     }
 
     /// X86 specific call to get the system supported CPUID values.
@@ -541,6 +599,56 @@ impl Kvm {
         Ok(ret as usize)
     }
 
+    /// X86 specific call to get the machine-check capabilities supported by KVM.
+    ///
+    /// See the documentation for `KVM_X86_GET_MCE_CAP_SUPPORTED`. The returned value uses the
+    /// layout of the `IA32_MCG_CAP` MSR. The bank count (lowest byte) is left to the caller.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// if kvm.check_extension(Cap::Mce) {
+    ///     let mcg_cap = kvm.get_mce_cap_supported().unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_mce_cap_supported(&self) -> Result<u64> {
+        let mut mcg_cap = 0u64;
+        // SAFETY: Here we trust the kernel not to write past the end of the u64.
+        let ret =
+            unsafe { ioctl_with_mut_ref(self, KVM_X86_GET_MCE_CAP_SUPPORTED(), &mut mcg_cap) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(mcg_cap)
+    }
+
+    /// Returns the reasons for which KVM can forward the MSR accesses to userspace.
+    ///
+    /// See the documentation for `KVM_CAP_X86_USER_SPACE_MSR`. The result holds all the reasons
+    /// if the capability is supported, and is empty otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use kvm_ioctls::{Kvm, MsrExitReason};
+    /// let kvm = Kvm::new().unwrap();
+    /// if kvm.supported_msr_exit_reasons().contains(MsrExitReason::Filter) {
+    ///     // Filtered MSR accesses can exit to userspace.
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn supported_msr_exit_reasons(&self) -> MsrExitReason {
+        // KVM reports the capability as a boolean, and supports all the reasons when it is set.
+        if self.check_extension(Cap::X86UserSpaceMsr) {
+            MsrExitReason::all()
+        } else {
+            MsrExitReason::empty()
+        }
+    }
+
     /// Creates a VM fd using the KVM fd.
     ///
     /// See the documentation for `KVM_CREATE_VM`.
@@ -593,6 +701,7 @@ impl Kvm {
     ///
     /// Note: `Cap::ArmVmIPASize` should be checked using `check_extension` before calling
     /// this function to determine if the host machine supports the IPA size capability.
+    /// [VmBuilder](crate::VmBuilder) checks the IPA size against the host limit instead.
     ///
     /// # Arguments
     ///
@@ -626,8 +735,20 @@ impl Kvm {
     /// A call to this function will also initialize the size of the vcpu mmap area using the
     /// `KVM_GET_VCPU_MMAP_SIZE` ioctl.
     ///
+    /// # Arguments
+    ///
     /// * `vm_type` - Platform and architecture specific platform VM type. A value of 0 is the equivalent
     ///               to using the default VM type.
+    ///
+    /// The meaning of `vm_type` depends on the architecture:
+    ///
+    /// * on aarch64 it encodes the IPA size of the VM, as built by `KVM_VM_TYPE_ARM_IPA_SIZE`
+    ///   (see [create_vm_with_ipa_size](struct.Kvm.html#method.create_vm_with_ipa_size));
+    /// * on x86 it selects protected VM types, such as the SEV or TDX ones, as reported by
+    ///   `KVM_CAP_VM_TYPES`.
+    ///
+    /// Unsupported types fail with `EINVAL`.
+    ///
     /// # Example
     ///
     /// ```
@@ -645,7 +766,7 @@ impl Kvm {
             // SAFETY: Safe because we verify the value of ret and we are the owners of the fd.
             let vm_file = unsafe { File::from_raw_fd(ret) };
             let run_mmap_size = self.get_vcpu_mmap_size()?;
-            Ok(new_vmfd(vm_file, run_mmap_size))
+            Ok(new_vmfd(vm_file, run_mmap_size, Some(vm_type)))
         } else {
             Err(errno::Error::last())
         }
@@ -680,7 +801,42 @@ impl Kvm {
     /// ```
     pub unsafe fn create_vmfd_from_rawfd(&self, fd: RawFd) -> Result<VmFd> {
         let run_mmap_size = self.get_vcpu_mmap_size()?;
-        Ok(new_vmfd(File::from_raw_fd(fd), run_mmap_size))
+        Ok(new_vmfd(File::from_raw_fd(fd), run_mmap_size, None))
+    }
+
+    /// Issues an arbitrary system ioctl on the `/dev/kvm` fd.
+    ///
+    /// This is an escape hatch for the system ioctls which this crate does not wrap yet.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The ioctl request number.
+    /// * `arg` - The argument of the ioctl, either a pointer or a value cast to a pointer.
+    ///
+    /// Returns the non-negative result of the ioctl, or the `errno` it failed with.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that `req` is a system ioctl and that `arg` is what the
+    /// kernel expects for it, i.e. that any memory it points to is valid and large enough.
+    /// File descriptors returned by the ioctl, e.g. by `KVM_CREATE_VM`, are owned by the
+    /// caller.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// # use std::ptr::null_mut;
+    /// // `KVM_GET_API_VERSION`, which is `_IO(KVMIO, 0x00)`.
+    /// const KVM_GET_API_VERSION: u64 = 0xae00;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let version = unsafe { kvm.raw_ioctl(KVM_GET_API_VERSION, null_mut()) };
+    /// assert_eq!(version.unwrap(), kvm.get_api_version());
+    /// ```
+    pub unsafe fn raw_ioctl(&self, req: u64, arg: *mut c_void) -> Result<i32> {
+        crate::ioctls::raw_ioctl(self, req, arg)
     }
 }
 
@@ -721,10 +877,255 @@ impl FromRawFd for Kvm {
     unsafe fn from_raw_fd(fd: RawFd) -> Self {
         Kvm {
             kvm: File::from_raw_fd(fd),
+            vcpu_mmap_size: AtomicUsize::new(0),
+        }
+    }
+}
+
+/// Errors returned by [VmBuilder::ipa_bits] for IPA sizes the host cannot provide.
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpaSizeError {
+    /// The IPA size is below the minimum of 32 bits.
+    TooSmall(u32),
+    /// The IPA size is above the limit of the host, as reported by `KVM_CAP_ARM_VM_IPA_SIZE`.
+    TooLarge {
+        /// Requested IPA size.
+        bits: u32,
+        /// IPA limit of the host.
+        limit: u32,
+    },
+    /// The host does not support `KVM_CAP_ARM_VM_IPA_SIZE`, so the IPA size of its VMs is
+    /// always 40 bits.
+    Unsupported(u32),
+}
+
+#[cfg(target_arch = "aarch64")]
+impl IpaSizeError {
+    /// Returns the errno code corresponding to the error.
+    pub fn errno(&self) -> i32 {
+        errno::Error::from(*self).errno()
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl From<IpaSizeError> for errno::Error {
+    fn from(_: IpaSizeError) -> errno::Error {
+        errno::Error::new(libc::EINVAL)
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl std::fmt::Display for IpaSizeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IpaSizeError::TooSmall(bits) => {
+                write!(
+                    f,
+                    "the IPA size of {} bits is below the minimum of 32",
+                    bits
+                )
+            }
+            IpaSizeError::TooLarge { bits, limit } => write!(
+                f,
+                "the IPA size of {} bits is above the host limit of {}",
+                bits, limit
+            ),
+            IpaSizeError::Unsupported(bits) => write!(
+                f,
+                "the IPA size of {} bits cannot be selected, the host only supports 40",
+                bits
+            ),
         }
     }
 }
 
+#[cfg(target_arch = "aarch64")]
+impl std::error::Error for IpaSizeError {}
+
+/// Builder of aarch64 VMs, validating their IPA size against the host.
+///
+/// Unless [ipa_bits](VmBuilder::ipa_bits) is called, the VM gets the IPA limit of the host,
+/// capped at 48 bits. The IPA size of the created VM is available through
+/// [VmFd::ipa_bits](struct.VmFd.html#method.ipa_bits).
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::{Kvm, VmBuilder};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = VmBuilder::new(&kvm).build().unwrap();
+/// assert!(vm.ipa_bits().unwrap() <= 48);
+///
+/// let vm = VmBuilder::new(&kvm).ipa_bits(40).unwrap().build().unwrap();
+/// assert_eq!(vm.ipa_bits(), Some(40));
+/// ```
+#[cfg(target_arch = "aarch64")]
+#[derive(Debug, Clone, Copy)]
+pub struct VmBuilder<'a> {
+    kvm: &'a Kvm,
+    // IPA limit of the host, 0 without `KVM_CAP_ARM_VM_IPA_SIZE`.
+    limit: u32,
+    // IPA size passed to `KVM_CREATE_VM`, 0 for the default of 40 bits.
+    ipa_bits: u32,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl<'a> VmBuilder<'a> {
+    /// Starts the configuration of a VM created through `kvm`.
+    pub fn new(kvm: &'a Kvm) -> Self {
+        let limit = kvm.get_host_ipa_limit().max(0) as u32;
+        VmBuilder {
+            kvm,
+            limit,
+            ipa_bits: limit.min(VM_BUILDER_MAX_DEFAULT_IPA_BITS),
+        }
+    }
+
+    /// Sets the IPA size of the VM, in bits.
+    ///
+    /// Fails if `bits` is below 32 or above the IPA limit of the host. On hosts without
+    /// `KVM_CAP_ARM_VM_IPA_SIZE`, only the fixed IPA size of 40 bits is accepted.
+    ///
+    /// # Arguments
+    ///
+    /// * `bits` - IPA size.
+    pub fn ipa_bits(mut self, bits: u32) -> std::result::Result<Self, IpaSizeError> {
+        if bits < 32 {
+            return Err(IpaSizeError::TooSmall(bits));
+        }
+        if self.limit == 0 {
+            if bits != KVM_ARM_DEFAULT_IPA_BITS {
+                return Err(IpaSizeError::Unsupported(bits));
+            }
+            return Ok(self);
+        }
+        if bits > self.limit {
+            return Err(IpaSizeError::TooLarge {
+                bits,
+                limit: self.limit,
+            });
+        }
+        self.ipa_bits = bits;
+        Ok(self)
+    }
+
+    /// Creates the VM.
+    pub fn build(&self) -> Result<VmFd> {
+        self.kvm.create_vm_with_ipa_size(self.ipa_bits)
+    }
+}
+
+/// Helpers for patching the entries of a `CpuId`, e.g. as returned by
+/// [get_supported_cpuid](struct.Kvm.html#method.get_supported_cpuid).
+///
+/// Entries can be dropped with the `retain()` method of `CpuId` itself. All the helpers keep
+/// the number of entries of the underlying `kvm_cpuid2` in sync, and never grow it beyond
+/// `KVM_MAX_CPUID_ENTRIES`.
+///
+/// # Example
+///
+/// ```
+/// # extern crate kvm_bindings;
+/// # use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+/// # use kvm_ioctls::{CpuIdExt, Kvm};
+/// let kvm = Kvm::new().unwrap();
+/// let mut cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+/// // Advertise the hypervisor to the guest.
+/// for entry in cpuid.iter_mut().filter(|entry| entry.function == 1) {
+///     entry.ecx |= 1 << 31;
+/// }
+/// // Drop the leaves above 0x1f.
+/// cpuid.retain(|entry| entry.function <= 0x1f || entry.function >= 0x4000_0000);
+/// assert!(cpuid.leaf(1, 0).unwrap().ecx & (1 << 31) != 0);
+/// ```
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub trait CpuIdExt {
+    /// Returns the entry for the leaf `function` and subleaf `index`.
+    ///
+    /// The subleaf is ignored for the entries without `KVM_CPUID_FLAG_SIGNIFCANT_INDEX`.
+    fn leaf(&self, function: u32, index: u32) -> Option<&kvm_cpuid_entry2>;
+
+    /// Replaces the entry for the leaf `function` and subleaf `index`, or appends it if there
+    /// is none.
+    ///
+    /// The `function` and `index` fields of `entry` are overwritten. Fails with `ENOMEM` if
+    /// the entry must be appended and there are already `KVM_MAX_CPUID_ENTRIES` entries.
+    ///
+    /// # Arguments
+    ///
+    /// * `function` - CPUID leaf.
+    /// * `index` - CPUID subleaf.
+    /// * `entry` - New entry.
+    fn set_leaf(&mut self, function: u32, index: u32, entry: kvm_cpuid_entry2) -> Result<()>;
+
+    /// Returns an iterator over mutable references to the entries.
+    fn iter_mut(&mut self) -> std::slice::IterMut<'_, kvm_cpuid_entry2>;
+
+    /// Drops the duplicate entries, keeping the first one of each leaf, and sorts the
+    /// remaining entries by leaf and subleaf.
+    ///
+    /// Like in [`leaf`](Self::leaf), the subleaf only distinguishes the entries with
+    /// `KVM_CPUID_FLAG_SIGNIFCANT_INDEX`: an entry without it duplicates every other entry of
+    /// the same leaf.
+    fn dedup_and_sort(&mut self);
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn cpuid_entry_matches(entry: &kvm_cpuid_entry2, function: u32, index: u32) -> bool {
+    entry.function == function
+        && (entry.flags & KVM_CPUID_FLAG_SIGNIFCANT_INDEX == 0 || entry.index == index)
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl CpuIdExt for CpuId {
+    fn leaf(&self, function: u32, index: u32) -> Option<&kvm_cpuid_entry2> {
+        self.as_slice()
+            .iter()
+            .find(|entry| cpuid_entry_matches(entry, function, index))
+    }
+
+    fn set_leaf(&mut self, function: u32, index: u32, entry: kvm_cpuid_entry2) -> Result<()> {
+        let entry = kvm_cpuid_entry2 {
+            function,
+            index,
+            ..entry
+        };
+        match self
+            .as_mut_slice()
+            .iter_mut()
+            .find(|old| cpuid_entry_matches(old, function, index))
+        {
+            Some(old) => *old = entry,
+            None => self
+                .push(entry)
+                .map_err(|_| errno::Error::new(libc::ENOMEM))?,
+        }
+        Ok(())
+    }
+
+    fn iter_mut(&mut self) -> std::slice::IterMut<'_, kvm_cpuid_entry2> {
+        self.as_mut_slice().iter_mut()
+    }
+
+    fn dedup_and_sort(&mut self) {
+        let mut kept: Vec<kvm_cpuid_entry2> = Vec::with_capacity(self.as_slice().len());
+        self.retain(|entry| {
+            let duplicate = kept.iter().any(|old| {
+                cpuid_entry_matches(old, entry.function, entry.index)
+                    || cpuid_entry_matches(entry, old.function, old.index)
+            });
+            if !duplicate {
+                kept.push(*entry);
+            }
+            !duplicate
+        });
+        self.as_mut_slice()
+            .sort_by_key(|entry| (entry.function, entry.index));
+    }
+}
+
 #[cfg(test)]
 mod tests {
     #![allow(clippy::undocumented_unsafe_blocks)]
@@ -779,6 +1180,21 @@ mod tests {
         assert_eq!(kvm.check_extension_raw(696969), 0);
     }
 
+    #[test]
+    fn test_kvm_raw_ioctl() {
+        let kvm = Kvm::new().unwrap();
+        let max_vcpus = unsafe {
+            kvm.raw_ioctl(
+                KVM_CHECK_EXTENSION() as u64,
+                Cap::MaxVcpus as usize as *mut c_void,
+            )
+        };
+        assert_eq!(max_vcpus.unwrap(), kvm.check_extension_int(Cap::MaxVcpus));
+        // `KVM_RUN` is not a system ioctl.
+        let err = unsafe { kvm.raw_ioctl(KVM_RUN() as u64, std::ptr::null_mut()) };
+        assert_eq!(err.unwrap_err().errno(), libc::EINVAL);
+    }
+
     #[test]
     #[cfg(target_arch = "aarch64")]
     fn test_get_host_ipa_limit() {
@@ -793,6 +1209,50 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_vm_builder_ipa_bits() {
+        let kvm = Kvm::new().unwrap();
+        let limit = kvm.get_host_ipa_limit() as u32;
+
+        assert_eq!(
+            VmBuilder::new(&kvm).ipa_bits(31).unwrap_err(),
+            IpaSizeError::TooSmall(31)
+        );
+        assert_eq!(
+            VmBuilder::new(&kvm).ipa_bits(0).unwrap_err().errno(),
+            libc::EINVAL
+        );
+
+        let vm = VmBuilder::new(&kvm).build().unwrap();
+        if limit == 0 {
+            assert_eq!(vm.ipa_bits(), Some(KVM_ARM_DEFAULT_IPA_BITS));
+            assert_eq!(
+                VmBuilder::new(&kvm).ipa_bits(36).unwrap_err(),
+                IpaSizeError::Unsupported(36)
+            );
+            return;
+        }
+        // The default is the host limit, capped at 48 bits.
+        assert_eq!(vm.ipa_bits(), Some(limit.min(48)));
+
+        assert_eq!(
+            VmBuilder::new(&kvm).ipa_bits(limit + 1).unwrap_err(),
+            IpaSizeError::TooLarge {
+                bits: limit + 1,
+                limit
+            }
+        );
+        let vm = VmBuilder::new(&kvm).ipa_bits(32).unwrap().build().unwrap();
+        assert_eq!(vm.ipa_bits(), Some(32));
+        let vm = VmBuilder::new(&kvm)
+            .ipa_bits(limit)
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(vm.ipa_bits(), Some(limit));
+    }
+
     #[test]
     #[cfg(target_arch = "aarch64")]
     fn test_guest_debug_hw_capacity() {
@@ -804,6 +1264,19 @@ mod tests {
         assert!(kvm.get_guest_debug_hw_wps() >= 0);
     }
 
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_get_mce_cap_supported() {
+        let kvm = Kvm::new().unwrap();
+        if kvm.check_extension(Cap::Mce) {
+            let mcg_cap = kvm.get_mce_cap_supported().unwrap();
+            // KVM does not report a bank count.
+            assert_eq!(mcg_cap & 0xff, 0);
+        } else {
+            assert!(kvm.get_mce_cap_supported().is_err());
+        }
+    }
+
     #[test]
     fn test_kvm_getters() {
         let kvm = Kvm::new().unwrap();
@@ -819,6 +1292,24 @@ mod tests {
         assert!(kvm.get_nr_memslots() >= 32);
     }
 
+    #[test]
+    fn test_vcpu_mmap_size_cached() {
+        let kvm = Kvm::new().unwrap();
+        let raw_size = unsafe { ioctl(&kvm, KVM_GET_VCPU_MMAP_SIZE()) };
+        assert!(raw_size > 0);
+
+        let size = kvm.get_vcpu_mmap_size().unwrap();
+        assert_eq!(size, raw_size as usize);
+        assert_eq!(kvm.get_vcpu_mmap_size().unwrap(), size);
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        assert_eq!(kvm.vcpu_mmap_pages().unwrap(), size / page_size);
+        assert_eq!(
+            kvm.vcpu_mmap_pages().unwrap(),
+            kvm.vcpu_mmap_pages().unwrap()
+        );
+    }
+
     #[test]
     fn test_create_vm() {
         let kvm = Kvm::new().unwrap();
@@ -867,6 +1358,52 @@ mod tests {
         }
     }
 
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_create_vm_with_type_ipa_size() {
+        let kvm = Kvm::new().unwrap();
+        let host_ipa_limit = kvm.get_host_ipa_limit();
+        if host_ipa_limit <= 32 {
+            return;
+        }
+        // A 32-bit IPA space does not cover memory above 4GiB.
+        let vm = kvm
+            .create_vm_with_type(u64::from(32 & KVM_VM_TYPE_ARM_IPA_SIZE_MASK))
+            .unwrap();
+        let mem_size = 0x1000;
+        let load_addr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mem_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(load_addr, libc::MAP_FAILED);
+        let mut mem_region = kvm_bindings::kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 1 << 32,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        assert!(unsafe { vm.set_user_memory_region(mem_region) }.is_err());
+        mem_region.guest_phys_addr = (1 << 32) - mem_size as u64;
+        unsafe { vm.set_user_memory_region(mem_region) }.unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_create_vm_with_invalid_type() {
+        let kvm = Kvm::new().unwrap();
+        assert_eq!(
+            kvm.create_vm_with_type(0xff).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[test]
     fn test_get_supported_cpuid() {
@@ -895,6 +1432,23 @@ mod tests {
         assert!(cpuid_err.is_err());
     }
 
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_get_emulated_cpuid_retry() {
+        let kvm = Kvm::new().unwrap();
+        let cpuid = kvm.get_emulated_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        assert!(cpuid.as_slice().len() > 1);
+        assert_eq!(
+            cpuid.as_fam_struct_ref().nent as usize,
+            cpuid.as_slice().len()
+        );
+
+        // Too small capacities are grown until all the entries fit.
+        for num_entries in [0, 1, 2] {
+            assert_eq!(kvm.get_emulated_cpuid(num_entries).unwrap(), cpuid);
+        }
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[test]
     fn test_cpuid_clone() {
@@ -909,6 +1463,112 @@ mod tests {
         let _ = CpuId::new(cpuid_1.as_fam_struct_ref().len()).unwrap();
     }
 
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_cpuid_patching() {
+        const ECX_HYPERVISOR: u32 = 1 << 31;
+
+        let kvm = Kvm::new().unwrap();
+        let mut cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        let nent = cpuid.as_slice().len();
+
+        // Clear the hypervisor-present bit of leaf 1.
+        let mut leaf_1 = *cpuid.leaf(1, 0).unwrap();
+        leaf_1.ecx &= !ECX_HYPERVISOR;
+        cpuid.set_leaf(1, 0, leaf_1).unwrap();
+        assert_eq!(cpuid.as_slice().len(), nent);
+        assert_eq!(cpuid.as_fam_struct_ref().len(), nent);
+
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        vcpu.set_cpuid2(&cpuid).unwrap();
+        let cpuid = vcpu.get_cpuid2(KVM_MAX_CPUID_ENTRIES).unwrap();
+        assert_eq!(cpuid.leaf(1, 0).unwrap().ecx & ECX_HYPERVISOR, 0);
+
+        // Setting it back through `iter_mut()`.
+        let mut cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        for entry in cpuid.iter_mut().filter(|entry| entry.function == 1) {
+            entry.ecx |= ECX_HYPERVISOR;
+        }
+        assert_ne!(cpuid.leaf(1, 0).unwrap().ecx & ECX_HYPERVISOR, 0);
+
+        // Appending a leaf, then removing it.
+        assert!(cpuid.leaf(0x4000_1234, 0).is_none());
+        cpuid
+            .set_leaf(0x4000_1234, 0, kvm_cpuid_entry2::default())
+            .unwrap();
+        assert_eq!(cpuid.as_fam_struct_ref().len(), nent + 1);
+        assert_eq!(cpuid.leaf(0x4000_1234, 7).unwrap().function, 0x4000_1234);
+        cpuid.retain(|entry| entry.function != 0x4000_1234);
+        assert_eq!(cpuid.as_fam_struct_ref().len(), nent);
+
+        // The entries cannot outgrow `KVM_MAX_CPUID_ENTRIES`.
+        let mut cpuid = CpuId::new(0).unwrap();
+        for function in 0..KVM_MAX_CPUID_ENTRIES as u32 {
+            cpuid
+                .set_leaf(function, 0, kvm_cpuid_entry2::default())
+                .unwrap();
+        }
+        assert_eq!(
+            cpuid
+                .set_leaf(KVM_MAX_CPUID_ENTRIES as u32, 0, kvm_cpuid_entry2::default())
+                .unwrap_err()
+                .errno(),
+            libc::ENOMEM
+        );
+        assert_eq!(cpuid.as_slice().len(), KVM_MAX_CPUID_ENTRIES);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_cpuid_dedup_and_sort() {
+        let entry = |function, index, flags, eax| kvm_cpuid_entry2 {
+            function,
+            index,
+            flags,
+            eax,
+            ..Default::default()
+        };
+        let indexed = KVM_CPUID_FLAG_SIGNIFCANT_INDEX;
+        let mut cpuid = CpuId::from_entries(&[
+            entry(0xd, 1, indexed, 1),
+            entry(0x1, 0, 0, 2),
+            entry(0xd, 0, indexed, 3),
+            // Duplicates of the entries above.
+            entry(0x1, 0, 0, 4),
+            entry(0xd, 1, indexed, 5),
+            // The subleaf of leaf 0x1 is not significant.
+            entry(0x1, 3, 0, 6),
+            entry(0x0, 0, 0, 7),
+        ])
+        .unwrap();
+
+        cpuid.dedup_and_sort();
+        let entries: Vec<_> = cpuid
+            .as_slice()
+            .iter()
+            .map(|entry| (entry.function, entry.index, entry.eax))
+            .collect();
+        assert_eq!(
+            entries,
+            [(0x0, 0, 7), (0x1, 0, 2), (0xd, 0, 3), (0xd, 1, 1)]
+        );
+        assert_eq!(cpuid.as_fam_struct_ref().len(), 4);
+
+        // Merging some leaves of the supported CPUID into itself.
+        let kvm = Kvm::new().unwrap();
+        let supported = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        let mut cpuid = supported.clone();
+        for entry in supported.as_slice().iter().rev().take(8) {
+            cpuid.push(*entry).unwrap();
+        }
+        cpuid.dedup_and_sort();
+        assert_eq!(cpuid.as_slice().len(), supported.as_slice().len());
+        for entry in supported.as_slice() {
+            assert_eq!(cpuid.leaf(entry.function, entry.index), Some(entry));
+        }
+    }
+
     #[test]
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     fn get_msr_index_list() {
@@ -953,6 +1613,7 @@ mod tests {
 
         let faulty_kvm = Kvm {
             kvm: unsafe { File::from_raw_fd(-2) },
+            vcpu_mmap_size: AtomicUsize::new(0),
         };
 
         assert_eq!(
@@ -0,0 +1,393 @@
+// Copyright 2024 Amazon.com, Inc. or its affiliates. All Rights Reserved.
+// SPDX-License-Identifier: Apache-2.0 OR MIT
+
+use std::mem::size_of;
+use std::os::unix::io::AsRawFd;
+
+use kvm_bindings::{kvm_cpuid_entry2, CpuId, KVM_MAX_CPUID_ENTRIES};
+use vmm_sys_util::errno;
+use vmm_sys_util::ioctl::ioctl_with_mut_ref;
+
+use crate::ioctls::vcpu::VcpuFd;
+use crate::ioctls::vm::VmFd;
+use crate::kvm_ioctls::KVM_MEMORY_ENCRYPT_OP;
+
+// The following definitions come from `arch/x86/include/uapi/asm/kvm.h` and are missing from
+// the `kvm-bindings` used by this crate.
+
+/// VM type of TDX guests, to be passed to
+/// [`Kvm::create_vm_with_type`](crate::Kvm::create_vm_with_type).
+pub const KVM_X86_TDX_VM: u64 = 5;
+
+const KVM_TDX_CAPABILITIES: u32 = 0;
+const KVM_TDX_INIT_VM: u32 = 1;
+const KVM_TDX_INIT_VCPU: u32 = 2;
+const KVM_TDX_INIT_MEM_REGION: u32 = 3;
+const KVM_TDX_FINALIZE_VM: u32 = 4;
+
+const KVM_TDX_MEASURE_MEMORY_REGION: u32 = 1 << 0;
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct kvm_tdx_cmd {
+    id: u32,
+    flags: u32,
+    data: u64,
+    hw_error: u64,
+}
+
+// Followed by the `kvm_cpuid_entry2` entries of the `cpuid` member.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct kvm_tdx_capabilities {
+    supported_attrs: u64,
+    supported_xfam: u64,
+    reserved: [u64; 254],
+    // Header of the `struct kvm_cpuid2 cpuid` member.
+    cpuid_nent: u32,
+    cpuid_padding: u32,
+}
+
+// Followed by the `kvm_cpuid_entry2` entries of the `cpuid` member.
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct kvm_tdx_init_vm {
+    attributes: u64,
+    xfam: u64,
+    mrconfigid: [u64; 6],
+    mrowner: [u64; 6],
+    mrownerconfig: [u64; 6],
+    reserved: [u64; 12],
+    // Header of the `struct kvm_cpuid2 cpuid` member.
+    cpuid_nent: u32,
+    cpuid_padding: u32,
+}
+
+#[allow(non_camel_case_types)]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct kvm_tdx_init_mem_region {
+    source_addr: u64,
+    gpa: u64,
+    nr_pages: u64,
+}
+
+/// Error returned by the TDX commands of [`VmFd`] and [`VcpuFd`].
+///
+/// Besides the error code of the `KVM_MEMORY_ENCRYPT_OP` ioctl, it carries the status code of
+/// the TDX module that KVM wrote into the `hw_error` field of `kvm_tdx_cmd`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TdxError {
+    errno: errno::Error,
+    hw_error: u64,
+}
+
+impl TdxError {
+    /// Returns the errno code of the failed ioctl.
+    pub fn errno(&self) -> i32 {
+        self.errno.errno()
+    }
+
+    /// Returns the TDX module status code, or 0 if the command did not reach the module.
+    pub fn hw_error(&self) -> u64 {
+        self.hw_error
+    }
+}
+
+impl From<TdxError> for errno::Error {
+    fn from(err: TdxError) -> errno::Error {
+        err.errno
+    }
+}
+
+impl std::fmt::Display for TdxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} (TDX module error {:#x})", self.errno, self.hw_error)
+    }
+}
+
+impl std::error::Error for TdxError {}
+
+/// TDX features supported by KVM and the TDX module, returned by
+/// [`VmFd::tdx_capabilities`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TdxCapabilities {
+    /// TD attributes which can be set in [`VmFd::tdx_init_vm`].
+    pub supported_attrs: u64,
+    /// XSAVE features (XFAM) which can be enabled in [`VmFd::tdx_init_vm`].
+    pub supported_xfam: u64,
+    /// CPUID bits which are configurable by userspace.
+    pub cpuid: CpuId,
+}
+
+fn einval() -> TdxError {
+    TdxError {
+        errno: errno::Error::new(libc::EINVAL),
+        hw_error: 0,
+    }
+}
+
+/// Issues a TDX command on a VM or vCPU file descriptor.
+fn tdx_cmd<F: AsRawFd>(fd: &F, id: u32, flags: u32, data: u64) -> Result<(), TdxError> {
+    let mut cmd = kvm_tdx_cmd {
+        id,
+        flags,
+        data,
+        hw_error: 0,
+    };
+    // SAFETY: Safe because we know that our file is a VM or vCPU fd, we know the kernel will
+    // only access the correct amount of memory through our pointers, and we verify the return
+    // result.
+    let ret = unsafe { ioctl_with_mut_ref(fd, KVM_MEMORY_ENCRYPT_OP(), &mut cmd) };
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(TdxError {
+            errno: errno::Error::last(),
+            hw_error: cmd.hw_error,
+        })
+    }
+}
+
+/// Allocates a zeroed buffer holding a `T` followed by `nent` CPUID entries.
+fn with_cpuid_entries<T>(nent: usize) -> Vec<u64> {
+    let size = size_of::<T>() + nent * size_of::<kvm_cpuid_entry2>();
+    vec![0u64; size.div_ceil(size_of::<u64>())]
+}
+
+impl VmFd {
+    fn check_tdx_vm(&self) -> Result<(), TdxError> {
+        match self.vm_type {
+            Some(vm_type) if vm_type != KVM_X86_TDX_VM => Err(einval()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the TDX features supported by KVM and the TDX module (`KVM_TDX_CAPABILITIES`).
+    ///
+    /// Returns `EINVAL` without issuing the command if the VM was not created with
+    /// [`KVM_X86_TDX_VM`].
+    ///
+    /// # Example
+    ///
+    /// ```rust,no_run
+    /// # use kvm_ioctls::{Kvm, KVM_X86_TDX_VM};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm_with_type(KVM_X86_TDX_VM).unwrap();
+    /// let caps = vm.tdx_capabilities().unwrap();
+    /// println!("supported XFAM: {:#x}", caps.supported_xfam);
+    /// ```
+    pub fn tdx_capabilities(&self) -> Result<TdxCapabilities, TdxError> {
+        self.check_tdx_vm()?;
+        let mut buf = with_cpuid_entries::<kvm_tdx_capabilities>(KVM_MAX_CPUID_ENTRIES);
+        let caps = buf.as_mut_ptr() as *mut kvm_tdx_capabilities;
+        // SAFETY: `buf` is large enough and suitably aligned for `kvm_tdx_capabilities`.
+        unsafe { (*caps).cpuid_nent = KVM_MAX_CPUID_ENTRIES as u32 };
+        tdx_cmd(self, KVM_TDX_CAPABILITIES, 0, caps as u64)?;
+
+        // SAFETY: `buf` holds a `kvm_tdx_capabilities` followed by `KVM_MAX_CPUID_ENTRIES`
+        // CPUID entries, of which the kernel filled `cpuid_nent`.
+        let (supported_attrs, supported_xfam, entries) = unsafe {
+            let nent = ((*caps).cpuid_nent as usize).min(KVM_MAX_CPUID_ENTRIES);
+            let entries = caps.add(1) as *const kvm_cpuid_entry2;
+            (
+                (*caps).supported_attrs,
+                (*caps).supported_xfam,
+                std::slice::from_raw_parts(entries, nent),
+            )
+        };
+        let cpuid = CpuId::from_entries(entries).map_err(|_| TdxError {
+            errno: errno::Error::new(libc::ENOMEM),
+            hw_error: 0,
+        })?;
+        Ok(TdxCapabilities {
+            supported_attrs,
+            supported_xfam,
+            cpuid,
+        })
+    }
+
+    /// Initializes the TD of this VM (`KVM_TDX_INIT_VM`).
+    ///
+    /// This must be issued before creating any vCPU. The measurement registers `MRCONFIGID`,
+    /// `MROWNER` and `MROWNERCONFIG` are left zeroed.
+    ///
+    /// Returns `EINVAL` without issuing the command if the VM was not created with
+    /// [`KVM_X86_TDX_VM`].
+    ///
+    /// # Arguments
+    ///
+    /// * `attributes` - TD attributes, a subset of
+    ///                  [`TdxCapabilities::supported_attrs`].
+    /// * `xfam` - Enabled XSAVE features, a subset of [`TdxCapabilities::supported_xfam`].
+    /// * `cpuid` - CPUID values of the TD, which supersede the ones set on the vCPUs.
+    pub fn tdx_init_vm(&self, attributes: u64, xfam: u64, cpuid: &CpuId) -> Result<(), TdxError> {
+        self.check_tdx_vm()?;
+        let entries = cpuid.as_slice();
+        let mut buf = with_cpuid_entries::<kvm_tdx_init_vm>(entries.len());
+        let init = buf.as_mut_ptr() as *mut kvm_tdx_init_vm;
+        // SAFETY: `buf` is large enough and suitably aligned for a `kvm_tdx_init_vm` followed
+        // by `entries.len()` CPUID entries.
+        unsafe {
+            init.write(kvm_tdx_init_vm {
+                attributes,
+                xfam,
+                cpuid_nent: entries.len() as u32,
+                ..Default::default()
+            });
+            std::ptr::copy_nonoverlapping(
+                entries.as_ptr(),
+                init.add(1) as *mut kvm_cpuid_entry2,
+                entries.len(),
+            );
+        }
+        tdx_cmd(self, KVM_TDX_INIT_VM, 0, init as u64)
+    }
+
+    /// Finalizes the measurement of the TD, after which it can run (`KVM_TDX_FINALIZE_VM`).
+    ///
+    /// Returns `EINVAL` without issuing the command if the VM was not created with
+    /// [`KVM_X86_TDX_VM`].
+    pub fn tdx_finalize_vm(&self) -> Result<(), TdxError> {
+        self.check_tdx_vm()?;
+        tdx_cmd(self, KVM_TDX_FINALIZE_VM, 0, 0)
+    }
+}
+
+impl VcpuFd {
+    /// Initializes this vCPU of a TD (`KVM_TDX_INIT_VCPU`).
+    ///
+    /// # Arguments
+    ///
+    /// * `rcx` - Initial value of the RCX register, usually the address of the TD HOB list.
+    pub fn tdx_init_vcpu(&self, rcx: u64) -> Result<(), TdxError> {
+        tdx_cmd(self, KVM_TDX_INIT_VCPU, 0, rcx)
+    }
+
+    /// Copies the initial content of private guest memory into the TD
+    /// (`KVM_TDX_INIT_MEM_REGION`).
+    ///
+    /// The guest memory must have been made private beforehand. The command is reissued
+    /// until all the pages have been added if it is interrupted by a signal.
+    ///
+    /// # Arguments
+    ///
+    /// * `source_addr` - Userspace address of the initial content.
+    /// * `gpa` - Guest physical address of the first page.
+    /// * `nr_pages` - Number of 4KiB pages to add.
+    /// * `measure` - Whether the pages are included in the TD measurement.
+    pub fn tdx_init_mem_region(
+        &self,
+        source_addr: u64,
+        gpa: u64,
+        nr_pages: u64,
+        measure: bool,
+    ) -> Result<(), TdxError> {
+        if nr_pages == 0 {
+            return Err(einval());
+        }
+        let flags = if measure {
+            KVM_TDX_MEASURE_MEMORY_REGION
+        } else {
+            0
+        };
+        let mut region = kvm_tdx_init_mem_region {
+            source_addr,
+            gpa,
+            nr_pages,
+        };
+        loop {
+            // The kernel advances the region past the pages it added before being interrupted.
+            match tdx_cmd(
+                self,
+                KVM_TDX_INIT_MEM_REGION,
+                flags,
+                &mut region as *mut kvm_tdx_init_mem_region as u64,
+            ) {
+                Err(err) if err.errno() == libc::EINTR && region.nr_pages > 0 => continue,
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ioctls::system::Kvm;
+
+    fn offset<T, F>(base: &T, field: &F) -> usize {
+        field as *const F as usize - base as *const T as usize
+    }
+
+    #[test]
+    fn test_tdx_struct_layout() {
+        let cmd = kvm_tdx_cmd::default();
+        assert_eq!(size_of::<kvm_tdx_cmd>(), 24);
+        assert_eq!(offset(&cmd, &cmd.flags), 4);
+        assert_eq!(offset(&cmd, &cmd.data), 8);
+        assert_eq!(offset(&cmd, &cmd.hw_error), 16);
+
+        let buf = with_cpuid_entries::<kvm_tdx_capabilities>(0);
+        // SAFETY: `buf` is large enough and suitably aligned for `kvm_tdx_capabilities`.
+        let caps = unsafe { &*(buf.as_ptr() as *const kvm_tdx_capabilities) };
+        assert_eq!(size_of::<kvm_tdx_capabilities>(), 2056);
+        assert_eq!(offset(caps, &caps.supported_xfam), 8);
+        assert_eq!(offset(caps, &caps.reserved), 16);
+        assert_eq!(offset(caps, &caps.cpuid_nent), 2048);
+
+        let init = kvm_tdx_init_vm::default();
+        assert_eq!(size_of::<kvm_tdx_init_vm>(), 264);
+        assert_eq!(offset(&init, &init.xfam), 8);
+        assert_eq!(offset(&init, &init.mrconfigid), 16);
+        assert_eq!(offset(&init, &init.mrowner), 64);
+        assert_eq!(offset(&init, &init.mrownerconfig), 112);
+        assert_eq!(offset(&init, &init.reserved), 160);
+        assert_eq!(offset(&init, &init.cpuid_nent), 256);
+
+        let region = kvm_tdx_init_mem_region::default();
+        assert_eq!(size_of::<kvm_tdx_init_mem_region>(), 24);
+        assert_eq!(offset(&region, &region.gpa), 8);
+        assert_eq!(offset(&region, &region.nr_pages), 16);
+
+        assert_eq!(size_of::<kvm_cpuid_entry2>(), 40);
+    }
+
+    #[test]
+    fn test_tdx_vm_type() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        // The VM-scoped commands are only issued on TDX VMs.
+        assert_eq!(vm.tdx_capabilities().unwrap_err(), einval());
+        let cpuid = CpuId::new(0).unwrap();
+        assert_eq!(vm.tdx_init_vm(0, 0, &cpuid).unwrap_err(), einval());
+        assert_eq!(vm.tdx_finalize_vm().unwrap_err(), einval());
+
+        // The vCPU-scoped commands are rejected by the kernel.
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert!(vcpu.tdx_init_vcpu(0).is_err());
+        assert_eq!(
+            vcpu.tdx_init_mem_region(0, 0, 0, true).unwrap_err(),
+            einval()
+        );
+        assert!(vcpu.tdx_init_mem_region(0, 0, 1, true).is_err());
+    }
+
+    #[test]
+    fn test_tdx_capabilities() {
+        let kvm = Kvm::new().unwrap();
+        // TDX is only available on hosts with the TDX module loaded.
+        let vm = match kvm.create_vm_with_type(KVM_X86_TDX_VM) {
+            Ok(vm) => vm,
+            Err(_) => return,
+        };
+        let caps = vm.tdx_capabilities().unwrap();
+        // x87 and SSE state are always enabled.
+        assert_eq!(caps.supported_xfam & 0x3, 0x3);
+        assert!(!caps.cpuid.as_slice().is_empty());
+    }
+}
@@ -8,14 +8,37 @@
 use kvm_bindings::*;
 use libc::EINVAL;
 use std::fs::File;
+use std::os::raw::c_void;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
 
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::ioctls::system::CpuIdExt;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::ioctls::vm::HypervFeatures;
+#[cfg(target_arch = "aarch64")]
+use crate::ioctls::vm::{PmuFilterAction, PMU_PPI_RANGE};
 use crate::ioctls::{KvmCoalescedIoRing, KvmRunWrapper, Result};
 use crate::kvm_ioctls::*;
 use vmm_sys_util::errno;
-use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ref};
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+use vmm_sys_util::ioctl::ioctl_with_mut_ptr;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use vmm_sys_util::ioctl::ioctl_with_val;
+use vmm_sys_util::ioctl::{ioctl, ioctl_with_mut_ref, ioctl_with_ptr, ioctl_with_ref};
+
+/// Maximum number of machine-check banks supported by KVM.
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-use vmm_sys_util::ioctl::{ioctl_with_mut_ptr, ioctl_with_ptr, ioctl_with_val};
+const KVM_MAX_MCE_BANKS: u64 = 32;
+
+/// Index of XCR0, the mask of the XSAVE features enabled in the guest.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const XCR_XFEATURE_ENABLED_MASK: u32 = 0;
 
 /// Helper method to obtain the size of the register through its id
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
@@ -23,6 +46,744 @@ pub fn reg_size(reg_id: u64) -> usize {
     2_usize.pow(((reg_id & KVM_REG_SIZE_MASK) >> KVM_REG_SIZE_SHIFT) as u32)
 }
 
+/// Core registers of an aarch64 vCPU, accessed through `KVM_REG_ARM_CORE` ids.
+///
+/// Used by [`set_core_reg`](VcpuFd::set_core_reg) and [`get_core_reg`](VcpuFd::get_core_reg).
+/// The discriminant is the index of the register in `struct kvm_regs`, counted in 64-bit words.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(target_arch = "aarch64")]
+pub enum CoreReg {
+    /// General purpose register `x0`.
+    X0,
+    /// General purpose register `x1`.
+    X1,
+    /// General purpose register `x2`.
+    X2,
+    /// General purpose register `x3`.
+    X3,
+    /// General purpose register `x4`.
+    X4,
+    /// General purpose register `x5`.
+    X5,
+    /// General purpose register `x6`.
+    X6,
+    /// General purpose register `x7`.
+    X7,
+    /// General purpose register `x8`.
+    X8,
+    /// General purpose register `x9`.
+    X9,
+    /// General purpose register `x10`.
+    X10,
+    /// General purpose register `x11`.
+    X11,
+    /// General purpose register `x12`.
+    X12,
+    /// General purpose register `x13`.
+    X13,
+    /// General purpose register `x14`.
+    X14,
+    /// General purpose register `x15`.
+    X15,
+    /// General purpose register `x16`.
+    X16,
+    /// General purpose register `x17`.
+    X17,
+    /// General purpose register `x18`.
+    X18,
+    /// General purpose register `x19`.
+    X19,
+    /// General purpose register `x20`.
+    X20,
+    /// General purpose register `x21`.
+    X21,
+    /// General purpose register `x22`.
+    X22,
+    /// General purpose register `x23`.
+    X23,
+    /// General purpose register `x24`.
+    X24,
+    /// General purpose register `x25`.
+    X25,
+    /// General purpose register `x26`.
+    X26,
+    /// General purpose register `x27`.
+    X27,
+    /// General purpose register `x28`.
+    X28,
+    /// General purpose register `x29`.
+    X29,
+    /// General purpose register `x30`.
+    X30,
+    /// Stack pointer (`sp_el0`).
+    Sp,
+    /// Program counter.
+    Pc,
+    /// Processor state.
+    Pstate,
+    /// EL1 stack pointer.
+    SpEl1,
+    /// EL1 exception link register.
+    ElrEl1,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl CoreReg {
+    /// Returns the id of the register for `KVM_GET_ONE_REG`/`KVM_SET_ONE_REG`.
+    pub fn id(self) -> u64 {
+        // The offset in `struct kvm_regs` is encoded in 32-bit words.
+        KVM_REG_ARM64 | KVM_REG_SIZE_U64 | u64::from(KVM_REG_ARM_CORE) | (self as u64 * 2)
+    }
+}
+
+/// Multiprocessing state of a vCPU, as per the `mp_state` field of `kvm_mp_state`.
+///
+/// Used by [`get_mp_state_typed`](VcpuFd::get_mp_state_typed) and
+/// [`set_mp_state_typed`](VcpuFd::set_mp_state_typed). Which states are valid depends on the
+/// architecture; see the documentation for `KVM_GET_MP_STATE` in the
+/// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "s390"
+))]
+pub enum MpState {
+    /// The vCPU is currently running (`KVM_MP_STATE_RUNNABLE`).
+    Runnable,
+    /// The vCPU is an application processor which has not yet received an INIT signal
+    /// (`KVM_MP_STATE_UNINITIALIZED`).
+    Uninitialized,
+    /// The vCPU has received an INIT signal and is waiting for a SIPI
+    /// (`KVM_MP_STATE_INIT_RECEIVED`).
+    InitReceived,
+    /// The vCPU has executed a HLT instruction and is waiting for an interrupt
+    /// (`KVM_MP_STATE_HALTED`).
+    Halted,
+    /// The vCPU has just received a SIPI (`KVM_MP_STATE_SIPI_RECEIVED`).
+    SipiReceived,
+    /// The vCPU is stopped (`KVM_MP_STATE_STOPPED`).
+    Stopped,
+    /// The vCPU is in a special error state (`KVM_MP_STATE_CHECK_STOP`).
+    CheckStop,
+    /// The vCPU is operating (`KVM_MP_STATE_OPERATING`).
+    Operating,
+    /// The vCPU is in a special load/startup state (`KVM_MP_STATE_LOAD`).
+    Load,
+    /// The vCPU is waiting for an SEV-ES AP reset hold (`KVM_MP_STATE_AP_RESET_HOLD`).
+    ApResetHold,
+    /// The vCPU is in a suspended state, waiting for a wakeup event
+    /// (`KVM_MP_STATE_SUSPENDED`).
+    Suspended,
+    /// A state not known to this crate.
+    Unknown(u32),
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "s390"
+))]
+impl From<u32> for MpState {
+    fn from(mp_state: u32) -> Self {
+        match mp_state {
+            KVM_MP_STATE_RUNNABLE => MpState::Runnable,
+            KVM_MP_STATE_UNINITIALIZED => MpState::Uninitialized,
+            KVM_MP_STATE_INIT_RECEIVED => MpState::InitReceived,
+            KVM_MP_STATE_HALTED => MpState::Halted,
+            KVM_MP_STATE_SIPI_RECEIVED => MpState::SipiReceived,
+            KVM_MP_STATE_STOPPED => MpState::Stopped,
+            KVM_MP_STATE_CHECK_STOP => MpState::CheckStop,
+            KVM_MP_STATE_OPERATING => MpState::Operating,
+            KVM_MP_STATE_LOAD => MpState::Load,
+            KVM_MP_STATE_AP_RESET_HOLD => MpState::ApResetHold,
+            KVM_MP_STATE_SUSPENDED => MpState::Suspended,
+            other => MpState::Unknown(other),
+        }
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "s390"
+))]
+impl From<MpState> for u32 {
+    fn from(mp_state: MpState) -> Self {
+        match mp_state {
+            MpState::Runnable => KVM_MP_STATE_RUNNABLE,
+            MpState::Uninitialized => KVM_MP_STATE_UNINITIALIZED,
+            MpState::InitReceived => KVM_MP_STATE_INIT_RECEIVED,
+            MpState::Halted => KVM_MP_STATE_HALTED,
+            MpState::SipiReceived => KVM_MP_STATE_SIPI_RECEIVED,
+            MpState::Stopped => KVM_MP_STATE_STOPPED,
+            MpState::CheckStop => KVM_MP_STATE_CHECK_STOP,
+            MpState::Operating => KVM_MP_STATE_OPERATING,
+            MpState::Load => KVM_MP_STATE_LOAD,
+            MpState::ApResetHold => KVM_MP_STATE_AP_RESET_HOLD,
+            MpState::Suspended => KVM_MP_STATE_SUSPENDED,
+            MpState::Unknown(other) => other,
+        }
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "s390"
+))]
+impl From<kvm_mp_state> for MpState {
+    fn from(mp_state: kvm_mp_state) -> Self {
+        MpState::from(mp_state.mp_state)
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "s390"
+))]
+impl From<MpState> for kvm_mp_state {
+    fn from(mp_state: MpState) -> Self {
+        kvm_mp_state {
+            mp_state: mp_state.into(),
+        }
+    }
+}
+
+/// FPU state of a vCPU, created from the `kvm_fpu` returned by [`get_fpu`](VcpuFd::get_fpu).
+///
+/// Dereferences to the underlying `kvm_fpu`, and provides accessors for the x87 and XMM
+/// registers stored as byte arrays in its `fpr` and `xmm` fields.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct FpuState(kvm_fpu);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl FpuState {
+    /// Returns the value of the register XMM`n`, or `None` if `n` is greater than 15.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Index of the register, between 0 and 15.
+    pub fn xmm(&self, n: usize) -> Option<u128> {
+        self.0.xmm.get(n).map(|xmm| u128::from_le_bytes(*xmm))
+    }
+
+    /// Sets the value of the register XMM`n`.
+    ///
+    /// Returns an error with `EINVAL` if `n` is greater than 15.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Index of the register, between 0 and 15.
+    /// * `value` - Value of the register.
+    pub fn set_xmm(&mut self, n: usize, value: u128) -> Result<()> {
+        let xmm = self
+            .0
+            .xmm
+            .get_mut(n)
+            .ok_or(errno::Error::new(libc::EINVAL))?;
+        *xmm = value.to_le_bytes();
+        Ok(())
+    }
+
+    /// Returns the 80-bit value of the x87 register ST(`n`), in little-endian order, or `None`
+    /// if `n` is greater than 7.
+    ///
+    /// The first 8 bytes hold the MMX register aliased to the same physical register.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Index of the register relative to the top of the stack, between 0 and 7.
+    pub fn st(&self, n: usize) -> Option<[u8; 10]> {
+        let fpr = self.0.fpr.get(n)?;
+        let mut st = [0u8; 10];
+        st.copy_from_slice(&fpr[..10]);
+        Some(st)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<kvm_fpu> for FpuState {
+    fn from(fpu: kvm_fpu) -> Self {
+        FpuState(fpu)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<FpuState> for kvm_fpu {
+    fn from(fpu: FpuState) -> Self {
+        fpu.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::Deref for FpuState {
+    type Target = kvm_fpu;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::DerefMut for FpuState {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// State of the LAPIC of a vCPU, as returned by [`get_lapic`](VcpuFd::get_lapic).
+///
+/// Dereferences to the underlying `kvm_lapic_state`, and provides accessors for the 32-bit
+/// registers laid out every 16 bytes in its `regs` field.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct LapicState(kvm_lapic_state);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl LapicState {
+    /// Returns the value of the LAPIC register at `offset`, or `None` if `offset` is not 16-byte
+    /// aligned or is beyond `KVM_APIC_REG_SIZE`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register in the APIC register space, e.g. `0x20` for the
+    ///              APIC ID register.
+    pub fn lapic_reg(&self, offset: u32) -> Option<u32> {
+        let offset = Self::reg_index(offset)?;
+        let mut bytes = [0u8; 4];
+        for (byte, reg) in bytes.iter_mut().zip(&self.0.regs[offset..offset + 4]) {
+            *byte = *reg as u8;
+        }
+        Some(u32::from_le_bytes(bytes))
+    }
+
+    /// Sets the value of the LAPIC register at `offset`.
+    ///
+    /// Returns an error with `EINVAL` if `offset` is not 16-byte aligned or is beyond
+    /// `KVM_APIC_REG_SIZE`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register in the APIC register space, e.g. `0x380` for the
+    ///              timer initial count register.
+    /// * `value` - Value of the register.
+    pub fn set_lapic_reg(&mut self, offset: u32, value: u32) -> Result<()> {
+        let offset = Self::reg_index(offset).ok_or(errno::Error::new(libc::EINVAL))?;
+        for (reg, byte) in self.0.regs[offset..offset + 4]
+            .iter_mut()
+            .zip(value.to_le_bytes())
+        {
+            *reg = byte as _;
+        }
+        Ok(())
+    }
+
+    fn reg_index(offset: u32) -> Option<usize> {
+        (offset & 0xf == 0 && offset < KVM_APIC_REG_SIZE).then_some(offset as usize)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<kvm_lapic_state> for LapicState {
+    fn from(klapic: kvm_lapic_state) -> Self {
+        LapicState(klapic)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<LapicState> for kvm_lapic_state {
+    fn from(lapic: LapicState) -> Self {
+        lapic.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::Deref for LapicState {
+    type Target = kvm_lapic_state;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::DerefMut for LapicState {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Extended control registers of a vCPU, as returned by [`get_xcrs`](VcpuFd::get_xcrs).
+///
+/// Dereferences to the underlying `kvm_xcrs`, and provides accessors for XCR0, looked up by
+/// index among the `nr_xcrs` entries of its `xcrs` field.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct XcrsState(kvm_xcrs);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl XcrsState {
+    /// Returns the value of XCR0, the mask of the XSAVE features enabled in the guest, or 0
+    /// if the state holds no XCR0.
+    pub fn xcr0(&self) -> u64 {
+        self.entries()
+            .iter()
+            .find(|entry| entry.xcr == XCR_XFEATURE_ENABLED_MASK)
+            .map_or(0, |entry| entry.value)
+    }
+
+    /// Sets the value of XCR0, adding an entry for it if the state holds none.
+    ///
+    /// KVM fails to restore a value which is not supported by the CPUID of the vCPU.
+    ///
+    /// Returns an error with `EINVAL` if `nr_xcrs` is beyond the size of `xcrs`, or with
+    /// `ENOSPC` if the state holds no XCR0 and all its entries are used.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Mask of the XSAVE features enabled in the guest.
+    pub fn set_xcr0(&mut self, value: u64) -> Result<()> {
+        let nr_xcrs = self.0.nr_xcrs as usize;
+        if nr_xcrs > self.0.xcrs.len() {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        match self.0.xcrs[..nr_xcrs]
+            .iter_mut()
+            .find(|entry| entry.xcr == XCR_XFEATURE_ENABLED_MASK)
+        {
+            Some(entry) => entry.value = value,
+            None => {
+                let entry = self
+                    .0
+                    .xcrs
+                    .get_mut(nr_xcrs)
+                    .ok_or(errno::Error::new(libc::ENOSPC))?;
+                *entry = kvm_xcr {
+                    xcr: XCR_XFEATURE_ENABLED_MASK,
+                    value,
+                    ..Default::default()
+                };
+                self.0.nr_xcrs = nr_xcrs as u32 + 1;
+            }
+        }
+        Ok(())
+    }
+
+    fn entries(&self) -> &[kvm_xcr] {
+        let nr_xcrs = (self.0.nr_xcrs as usize).min(self.0.xcrs.len());
+        &self.0.xcrs[..nr_xcrs]
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<kvm_xcrs> for XcrsState {
+    fn from(xcrs: kvm_xcrs) -> Self {
+        XcrsState(xcrs)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<XcrsState> for kvm_xcrs {
+    fn from(xcrs: XcrsState) -> Self {
+        xcrs.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::Deref for XcrsState {
+    type Target = kvm_xcrs;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::DerefMut for XcrsState {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Debug registers of a vCPU, as returned by [`get_debug_regs`](VcpuFd::get_debug_regs).
+///
+/// Dereferences to the underlying `kvm_debugregs`, and provides accessors for the breakpoint
+/// addresses in DR0-DR3 and for the DR7 control bits.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct DebugRegs(kvm_debugregs);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl DebugRegs {
+    /// Returns the address of the breakpoint `n`, i.e. the value of DR`n`, or `None` if `n` is
+    /// greater than 3.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Index of the breakpoint, between 0 and 3.
+    pub fn breakpoint_addr(&self, n: usize) -> Option<u64> {
+        self.0.db.get(n).copied()
+    }
+
+    /// Sets the address of the breakpoint `n`, i.e. the value of DR`n`.
+    ///
+    /// Returns an error with `EINVAL` if `n` is greater than 3.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Index of the breakpoint, between 0 and 3.
+    /// * `addr` - Linear address of the breakpoint.
+    pub fn set_breakpoint_addr(&mut self, n: usize, addr: u64) -> Result<()> {
+        let db = self
+            .0
+            .db
+            .get_mut(n)
+            .ok_or(errno::Error::new(libc::EINVAL))?;
+        *db = addr;
+        Ok(())
+    }
+
+    /// Returns a [`Dr7Builder`] starting from the current value of DR7.
+    pub fn dr7_builder(&self) -> Dr7Builder {
+        Dr7Builder { dr7: self.0.dr7 }
+    }
+
+    fn breakpoint_index(n: usize) -> Result<usize> {
+        if n < 4 {
+            Ok(n)
+        } else {
+            Err(errno::Error::new(libc::EINVAL))
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<kvm_debugregs> for DebugRegs {
+    fn from(debugregs: kvm_debugregs) -> Self {
+        DebugRegs(debugregs)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<DebugRegs> for kvm_debugregs {
+    fn from(debug_regs: DebugRegs) -> Self {
+        debug_regs.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::Deref for DebugRegs {
+    type Target = kvm_debugregs;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::DerefMut for DebugRegs {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Accesses triggering a hardware breakpoint, as encoded in the R/W bits of DR7.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub enum BreakpointCondition {
+    /// Instruction execution.
+    Execute = 0,
+    /// Data writes.
+    Write = 1,
+    /// I/O reads and writes, if CR4.DE is set.
+    Io = 2,
+    /// Data reads and writes.
+    ReadWrite = 3,
+}
+
+/// Builder for the DR7 value of [`DebugRegs`], created by
+/// [`dr7_builder`](DebugRegs::dr7_builder).
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::{BreakpointCondition, Kvm};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let vcpu = vm.create_vcpu(0).unwrap();
+/// // Watch the 8-byte writes to 0x1000.
+/// let mut debug_regs = vcpu.get_debug_regs().unwrap();
+/// debug_regs.set_breakpoint_addr(0, 0x1000).unwrap();
+/// debug_regs.dr7 = debug_regs
+///     .dr7_builder()
+///     .enable_breakpoint(0, BreakpointCondition::Write, 8)
+///     .unwrap()
+///     .build();
+/// vcpu.set_debug_regs(&debug_regs).unwrap();
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct Dr7Builder {
+    dr7: u64,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Dr7Builder {
+    /// Enables the breakpoint `n` locally, i.e. sets its L bit, together with its R/W and LEN
+    /// bits.
+    ///
+    /// Returns an error with `EINVAL` if `n` is greater than 3, or if `len` is not valid for
+    /// `condition`.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Index of the breakpoint, between 0 and 3.
+    /// * `condition` - Accesses triggering the breakpoint.
+    /// * `len` - Size in bytes of the watched area: 1, 2, 4 or 8. Must be 1 for
+    ///           [`BreakpointCondition::Execute`].
+    pub fn enable_breakpoint(
+        mut self,
+        n: usize,
+        condition: BreakpointCondition,
+        len: u8,
+    ) -> Result<Self> {
+        let len_bits: u64 = match len {
+            1 => 0,
+            2 => 1,
+            4 => 3,
+            8 => 2,
+            _ => return Err(errno::Error::new(libc::EINVAL)),
+        };
+        if condition == BreakpointCondition::Execute && len != 1 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+
+        self = self.disable_breakpoint(n)?;
+        self.dr7 |= 1 << (2 * n);
+        self.dr7 |= (condition as u64) << (16 + 4 * n);
+        self.dr7 |= len_bits << (18 + 4 * n);
+        Ok(self)
+    }
+
+    /// Disables the breakpoint `n`, i.e. clears its L, G, R/W and LEN bits.
+    ///
+    /// Returns an error with `EINVAL` if `n` is greater than 3.
+    ///
+    /// # Arguments
+    ///
+    /// * `n` - Index of the breakpoint, between 0 and 3.
+    pub fn disable_breakpoint(mut self, n: usize) -> Result<Self> {
+        let n = DebugRegs::breakpoint_index(n)?;
+        self.dr7 &= !(0b11 << (2 * n));
+        self.dr7 &= !(0b1111 << (16 + 4 * n));
+        Ok(self)
+    }
+
+    /// Returns the DR7 value.
+    pub fn build(self) -> u64 {
+        self.dr7
+    }
+}
+
+/// Builder for the `kvm_vcpu_events` passed to [`set_vcpu_events`](VcpuFd::set_vcpu_events).
+///
+/// Sets the `KVM_VCPUEVENT_VALID_*` flags matching the fields being filled in, so that the
+/// kernel does not ignore them.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::{Kvm, VcpuEventsBuilder};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let vcpu = vm.create_vcpu(0).unwrap();
+/// // Inject a #GP(0) on top of the current events of the vCPU.
+/// let events = VcpuEventsBuilder::from(vcpu.get_vcpu_events().unwrap())
+///     .inject_exception(13, Some(0))
+///     .build();
+/// vcpu.set_vcpu_events(&events).unwrap();
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct VcpuEventsBuilder {
+    events: kvm_vcpu_events,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl VcpuEventsBuilder {
+    /// Creates a builder starting from empty events.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Injects the exception `vector` at the next VM entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - Exception vector, e.g. 13 for `#GP`.
+    /// * `error_code` - Error code pushed by the exception, if the exception has one.
+    pub fn inject_exception(mut self, vector: u8, error_code: Option<u32>) -> Self {
+        self.events.exception.injected = 1;
+        self.events.exception.pending = 0;
+        self.events.exception.nr = vector;
+        self.events.exception.has_error_code = u8::from(error_code.is_some());
+        self.events.exception.error_code = error_code.unwrap_or(0);
+        self
+    }
+
+    /// Injects the external interrupt `vector` at the next VM entry.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - Interrupt vector.
+    pub fn inject_interrupt(mut self, vector: u8) -> Self {
+        self.events.interrupt.injected = 1;
+        self.events.interrupt.nr = vector;
+        self.events.interrupt.soft = 0;
+        self
+    }
+
+    /// Sets or clears a pending NMI.
+    ///
+    /// # Arguments
+    ///
+    /// * `pending` - Whether an NMI is pending.
+    pub fn nmi_pending(mut self, pending: bool) -> Self {
+        self.events.nmi.pending = u8::from(pending);
+        self.events.flags |= KVM_VCPUEVENT_VALID_NMI_PENDING;
+        self
+    }
+
+    /// Returns the `kvm_vcpu_events` to be passed to
+    /// [`set_vcpu_events`](VcpuFd::set_vcpu_events).
+    pub fn build(self) -> kvm_vcpu_events {
+        self.events
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<kvm_vcpu_events> for VcpuEventsBuilder {
+    fn from(events: kvm_vcpu_events) -> Self {
+        VcpuEventsBuilder { events }
+    }
+}
+
 /// Information about a [`VcpuExit`] triggered by an Hypercall (`KVM_EXIT_HYPERCALL`).
 #[derive(Debug)]
 pub struct HypercallExit<'a> {
@@ -69,8 +830,9 @@ pub struct WriteMsrExit<'a> {
 bitflags::bitflags! {
     /// The reason for a [`VcpuExit::X86Rdmsr`] or[`VcpuExit::X86Wrmsr`]. This
     /// is also used when enabling
-    /// [`Cap::X86UserSpaceMsr`](crate::Cap::X86UserSpaceMsr) to specify which
-    /// reasons should be forwarded to the user via those exits.
+    /// [`Cap::X86UserSpaceMsr`](crate::Cap::X86UserSpaceMsr), e.g. with
+    /// [`VmFd::enable_userspace_msr`](crate::VmFd::enable_userspace_msr), to
+    /// specify which reasons should be forwarded to the user via those exits.
     #[derive(Clone, Copy, Debug, PartialEq, Eq)]
     pub struct MsrExitReason: u32 {
         /// Corresponds to [`KVM_MSR_EXIT_REASON_UNKNOWN`]. The exit was
@@ -85,16 +847,73 @@ bitflags::bitflags! {
     }
 }
 
-/// Reasons for vCPU exits.
-///
-/// The exit reasons are mapped to the `KVM_EXIT_*` defines in the
-/// [Linux KVM header](https://elixir.bootlin.com/linux/latest/source/include/uapi/linux/kvm.h).
-#[derive(Debug)]
-pub enum VcpuExit<'a> {
-    /// An out port instruction was run on the given port with the given data.
-    IoOut(u16 /* port */, &'a [u8] /* data */),
-    /// An in port instruction was run on the given port.
-    ///
+bitflags::bitflags! {
+    /// The flags of a [`VcpuExit::Notify`].
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct NotifyExitFlags: u32 {
+        /// Corresponds to [`KVM_NOTIFY_CONTEXT_INVALID`]. The VM context is corrupted and
+        /// the vCPU cannot be resumed, so the VM should be stopped.
+        const ContextInvalid = KVM_NOTIFY_CONTEXT_INVALID;
+    }
+}
+
+/// Type of a [`VcpuExit::SystemEvent`], from the `KVM_SYSTEM_EVENT_*` defines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SystemEventType {
+    /// The guest requested a shutdown (`KVM_SYSTEM_EVENT_SHUTDOWN`).
+    Shutdown,
+    /// The guest requested a reset (`KVM_SYSTEM_EVENT_RESET`).
+    Reset,
+    /// The guest crashed and requested a crash dump or a reset (`KVM_SYSTEM_EVENT_CRASH`).
+    Crash,
+    /// A vCPU woke up from a suspend and needs userspace attention (`KVM_SYSTEM_EVENT_WAKEUP`).
+    Wakeup,
+    /// The guest requested a system suspend (`KVM_SYSTEM_EVENT_SUSPEND`).
+    Suspend,
+    /// An SEV-ES guest requested its termination (`KVM_SYSTEM_EVENT_SEV_TERM`).
+    SevTerm,
+    /// A type unknown to the current version of the kvm-ioctls crate.
+    Unknown(u32),
+}
+
+impl From<u32> for SystemEventType {
+    fn from(type_: u32) -> Self {
+        match type_ {
+            KVM_SYSTEM_EVENT_SHUTDOWN => SystemEventType::Shutdown,
+            KVM_SYSTEM_EVENT_RESET => SystemEventType::Reset,
+            KVM_SYSTEM_EVENT_CRASH => SystemEventType::Crash,
+            KVM_SYSTEM_EVENT_WAKEUP => SystemEventType::Wakeup,
+            KVM_SYSTEM_EVENT_SUSPEND => SystemEventType::Suspend,
+            KVM_SYSTEM_EVENT_SEV_TERM => SystemEventType::SevTerm,
+            t => SystemEventType::Unknown(t),
+        }
+    }
+}
+
+impl From<SystemEventType> for u32 {
+    fn from(type_: SystemEventType) -> Self {
+        match type_ {
+            SystemEventType::Shutdown => KVM_SYSTEM_EVENT_SHUTDOWN,
+            SystemEventType::Reset => KVM_SYSTEM_EVENT_RESET,
+            SystemEventType::Crash => KVM_SYSTEM_EVENT_CRASH,
+            SystemEventType::Wakeup => KVM_SYSTEM_EVENT_WAKEUP,
+            SystemEventType::Suspend => KVM_SYSTEM_EVENT_SUSPEND,
+            SystemEventType::SevTerm => KVM_SYSTEM_EVENT_SEV_TERM,
+            SystemEventType::Unknown(t) => t,
+        }
+    }
+}
+
+/// Reasons for vCPU exits.
+///
+/// The exit reasons are mapped to the `KVM_EXIT_*` defines in the
+/// [Linux KVM header](https://elixir.bootlin.com/linux/latest/source/include/uapi/linux/kvm.h).
+#[derive(Debug)]
+pub enum VcpuExit<'a> {
+    /// An out port instruction was run on the given port with the given data.
+    IoOut(u16 /* port */, &'a [u8] /* data */),
+    /// An in port instruction was run on the given port.
+    ///
     /// The given slice should be filled in before [run()](struct.VcpuFd.html#method.run)
     /// is called again.
     IoIn(u16 /* port */, &'a mut [u8] /* data */),
@@ -120,6 +939,10 @@ pub enum VcpuExit<'a> {
     /// Corresponds to KVM_EXIT_IRQ_WINDOW_OPEN.
     IrqWindowOpen,
     /// Corresponds to KVM_EXIT_SHUTDOWN.
+    ///
+    /// On x86 the guest entered the shutdown state, usually because of a triple fault, and
+    /// must be reset. Guest-requested power-offs are reported through the emulated platform
+    /// devices or [`VcpuExit::SystemEvent`] instead.
     Shutdown,
     /// Corresponds to KVM_EXIT_FAIL_ENTRY.
     FailEntry(
@@ -155,10 +978,19 @@ pub enum VcpuExit<'a> {
     /// Corresponds to KVM_EXIT_EPR.
     Epr,
     /// Corresponds to KVM_EXIT_SYSTEM_EVENT.
-    SystemEvent(u32 /* type */, &'a [u64] /* data */),
+    ///
+    /// Reports guest-requested power events, e.g. a PSCI `SYSTEM_RESET` on aarch64. The data
+    /// holds the `ndata` words provided by the kernel, whose meaning depends on the type; its
+    /// length is `ndata`. On aarch64, a [`SystemEventType::Suspend`] event is only delivered
+    /// after [enable_system_suspend()](struct.VmFd.html#method.enable_system_suspend).
+    SystemEvent(SystemEventType, &'a [u64] /* data */),
     /// Corresponds to KVM_EXIT_S390_STSI.
     S390Stsi,
     /// Corresponds to KVM_EXIT_IOAPIC_EOI.
+    ///
+    /// Only generated when the split irqchip is enabled (see
+    /// [enable_split_irqchip()](struct.VmFd.html#method.enable_split_irqchip)). The userspace
+    /// IOAPIC should clear the remote IRR bit of the pins routed to the given vector.
     IoapicEoi(u8 /* vector */),
     /// Corresponds to KVM_EXIT_HYPERV.
     Hyperv,
@@ -166,6 +998,18 @@ pub enum VcpuExit<'a> {
     X86Rdmsr(ReadMsrExit<'a>),
     /// Corresponds to KVM_EXIT_X86_WRMSR.
     X86Wrmsr(WriteMsrExit<'a>),
+    /// Corresponds to KVM_EXIT_NOTIFY.
+    ///
+    /// The guest was stuck in a non-architectural state, e.g. without an interrupt window,
+    /// for longer than the window set with
+    /// [enable_notify_vmexit()](struct.VmFd.html#method.enable_notify_vmexit).
+    Notify(NotifyExitFlags),
+    /// Corresponds to KVM_EXIT_X86_BUS_LOCK.
+    ///
+    /// The guest took a bus lock and bus lock exits are enabled (see
+    /// [enable_bus_lock_exit()](struct.VmFd.html#method.enable_bus_lock_exit)). The
+    /// instruction has already completed.
+    X86BusLock,
     /// Corresponds to an exit reason that is unknown from the current version
     /// of the kvm-ioctls crate. Let the consumer decide about what to do with
     /// it.
@@ -308,6 +1152,184 @@ impl VcpuFd {
         Ok(())
     }
 
+    /// Gets a specified piece of cpu configuration and/or state.
+    ///
+    /// See the documentation for `KVM_GET_DEVICE_ATTR` in
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt)
+    /// # Arguments
+    ///
+    /// * `device_attr` - The cpu attribute to be read, its `addr` field pointing to where
+    ///                   the value is written.
+    ///
+    /// # Safety
+    ///
+    /// The kernel writes the value of the attribute at `device_attr.addr`. The caller must
+    /// ensure that it points to memory which is valid for writes of the size of the value.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_bindings::{kvm_device_attr, KVM_ARM_VCPU_TIMER_CTRL, KVM_ARM_VCPU_TIMER_IRQ_VTIMER};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_bindings::kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// vcpu.vcpu_init(&kvi).unwrap();
+    ///
+    /// let mut irq = 0u32;
+    /// let mut attr = kvm_device_attr {
+    ///     group: KVM_ARM_VCPU_TIMER_CTRL,
+    ///     attr: u64::from(KVM_ARM_VCPU_TIMER_IRQ_VTIMER),
+    ///     addr: &mut irq as *mut u32 as u64,
+    ///     flags: 0,
+    /// };
+    /// if vcpu.has_device_attr(&attr).is_ok() {
+    ///     unsafe { vcpu.get_device_attr(&mut attr) }.unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub unsafe fn get_device_attr(&self, device_attr: &mut kvm_device_attr) -> Result<()> {
+        // SAFETY: Safe because we call this with a Vcpu fd, and the caller guarantees that the
+        // kernel can write the value of the attribute at `device_attr.addr`.
+        let ret = ioctl_with_mut_ref(self, KVM_GET_DEVICE_ATTR(), device_attr);
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Sets the PPI the PMU overflow interrupt of the vCPU is delivered on, through the
+    /// `KVM_ARM_VCPU_PMU_V3_IRQ` attribute.
+    ///
+    /// Requires the vCPU to be initialized with the `KVM_ARM_VCPU_PMU_V3` feature and the
+    /// in-kernel vGIC to be created. All the vCPUs must use the same PPI.
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - INTID of the PPI, between 16 and 31. Fails with `EINVAL` otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, GicBuilder, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// if vm.check_extension(Cap::ArmPmuV3) {
+    ///     let _gic = GicBuilder::new(&vm)
+    ///         .dist_addr(0x0800_0000)
+    ///         .redist_addr(0x080a_0000)
+    ///         .build()
+    ///         .unwrap();
+    ///     let builder = vm.arm_vcpu_init_builder().unwrap().with_pmu().unwrap();
+    ///     builder.init(&vcpu).unwrap();
+    ///     // The PMU interrupt is usually PPI 7, that is INTID 23.
+    ///     vcpu.pmu_set_irq(23).unwrap();
+    ///     vcpu.pmu_init().unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn pmu_set_irq(&self, irq: u32) -> Result<()> {
+        if !PMU_PPI_RANGE.contains(&irq) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let attr = kvm_device_attr {
+            group: KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(KVM_ARM_VCPU_PMU_V3_IRQ),
+            addr: &irq as *const u32 as u64,
+            flags: 0,
+        };
+        self.set_device_attr(&attr)
+    }
+
+    /// Initializes the PMU of the vCPU, through the `KVM_ARM_VCPU_PMU_V3_INIT` attribute.
+    ///
+    /// With an in-kernel vGIC, the vGIC must be initialized and the interrupt set with
+    /// [pmu_set_irq](Self::pmu_set_irq) beforehand. The vCPU cannot run until its PMU is
+    /// initialized.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// if vm.check_extension(Cap::ArmPmuV3) {
+    ///     let builder = vm.arm_vcpu_init_builder().unwrap().with_pmu().unwrap();
+    ///     builder.init(&vcpu).unwrap();
+    ///     // Without an in-kernel vGIC, the PMU has no interrupt.
+    ///     vcpu.pmu_init().unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn pmu_init(&self) -> Result<()> {
+        let attr = kvm_device_attr {
+            group: KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(KVM_ARM_VCPU_PMU_V3_INIT),
+            addr: 0,
+            flags: 0,
+        };
+        self.set_device_attr(&attr)
+    }
+
+    /// Allows or denies the guest the use of a range of PMU events, through the
+    /// `KVM_ARM_VCPU_PMU_V3_FILTER` attribute.
+    ///
+    /// The filter applies to the whole VM and must be set before the PMU of any vCPU is
+    /// initialized. Events not covered by the first filter are denied if it allows events,
+    /// and allowed otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - Whether the events are allowed or denied.
+    /// * `base_event` - First event of the range.
+    /// * `nevents` - Number of events of the range.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm, PmuFilterAction};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// if vm.check_extension(Cap::ArmPmuV3) {
+    ///     let builder = vm.arm_vcpu_init_builder().unwrap().with_pmu().unwrap();
+    ///     builder.init(&vcpu).unwrap();
+    ///     // Only let the guest count CPU cycles.
+    ///     vcpu.pmu_set_filter(PmuFilterAction::Allow, 0x11, 1).unwrap();
+    ///     vcpu.pmu_init().unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn pmu_set_filter(
+        &self,
+        action: PmuFilterAction,
+        base_event: u16,
+        nevents: u16,
+    ) -> Result<()> {
+        let filter = kvm_pmu_event_filter {
+            base_event,
+            nevents,
+            action: action as u8,
+            ..Default::default()
+        };
+        let attr = kvm_device_attr {
+            group: KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(KVM_ARM_VCPU_PMU_V3_FILTER),
+            addr: &filter as *const kvm_pmu_event_filter as u64,
+            flags: 0,
+        };
+        self.set_device_attr(&attr)
+    }
+
     /// Sets the vCPU general purpose registers using the `KVM_SET_REGS` ioctl.
     ///
     /// # Arguments
@@ -344,6 +1366,38 @@ impl VcpuFd {
         Ok(())
     }
 
+    /// Updates the vCPU general purpose registers with a single `KVM_GET_REGS` and
+    /// `KVM_SET_REGS` round trip.
+    ///
+    /// The registers are read, passed to `f`, and written back. Nothing is written if reading
+    /// them fails.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - Closure modifying the registers.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    ///
+    /// #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    /// vcpu.modify_regs(|regs| regs.rip = 0x100).unwrap();
+    /// ```
+    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+    pub fn modify_regs<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut kvm_regs),
+    {
+        let mut regs = self.get_regs()?;
+        f(&mut regs);
+        self.set_regs(&regs)
+    }
+
     /// Returns the vCPU special registers.
     ///
     /// The registers are returned in a `kvm_sregs` structure as defined in the
@@ -412,7 +1466,8 @@ impl VcpuFd {
     ///
     /// The state is returned in a `kvm_fpu` structure as defined in the
     /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
-    /// See the documentation for `KVM_GET_FPU`.
+    /// See the documentation for `KVM_GET_FPU`. It can be wrapped in a [`FpuState`] to access
+    /// the individual registers.
     ///
     /// # Example
     ///
@@ -442,6 +1497,7 @@ impl VcpuFd {
     ///
     /// * `fpu` - FPU configuration. For details check the `kvm_fpu` structure in the
     ///           [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///           A [`FpuState`] can be passed as well.
     ///
     /// # Example
     ///
@@ -477,6 +1533,9 @@ impl VcpuFd {
     ///
     /// See the documentation for `KVM_SET_CPUID2`.
     ///
+    /// A [`CpuId`] holds at most `KVM_MAX_CPUID_ENTRIES` entries, which is within the limit of
+    /// every kernel; the kernel does not report its own limit.
+    ///
     /// # Arguments
     ///
     /// * `cpuid` - CPUID registers.
@@ -558,8 +1617,54 @@ impl VcpuFd {
         Ok(cpuid)
     }
 
+    /// X86 specific call to retrieve the Hyper-V CPUID leaves supported by the vCPU.
+    ///
+    /// See the documentation for `KVM_GET_SUPPORTED_HV_CPUID`. The leaves reflect the
+    /// enlightenments enabled on the vCPU, e.g. with [init_hyperv](VcpuFd::init_hyperv).
+    ///
+    /// # Arguments
+    ///
+    /// * `num_entries` - Maximum number of CPUID entries to be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// if vm.check_extension(Cap::HypervCpuid) {
+    ///     let hv_cpuid = vcpu.get_supported_hv_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_supported_hv_cpuid(&self, num_entries: usize) -> Result<CpuId> {
+        if num_entries > KVM_MAX_CPUID_ENTRIES {
+            // Returns the same error the underlying `ioctl` would have sent.
+            return Err(errno::Error::new(libc::ENOMEM));
+        }
+
+        let mut cpuid = CpuId::new(num_entries).map_err(|_| errno::Error::new(libc::ENOMEM))?;
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_cpuid2 struct.
+        let ret = unsafe {
+            ioctl_with_mut_ptr(
+                self,
+                KVM_GET_SUPPORTED_HV_CPUID(),
+                cpuid.as_mut_fam_struct_ptr(),
+            )
+        };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(cpuid)
+    }
+
     ///
-    /// See the documentation for `KVM_ENABLE_CAP`.
+    /// See the documentation for `KVM_ENABLE_CAP`. The kernel does not write anything back
+    /// into `cap`.
     ///
     /// # Arguments
     ///
@@ -604,10 +1709,111 @@ impl VcpuFd {
         }
     }
 
+    /// Enables the Hyper-V enlightenments `features` on the vCPU.
+    ///
+    /// SynIC and the enlightened VMCS are first enabled through their vCPU capabilities. The
+    /// Hyper-V CPUID leaves supported by the vCPU, restricted to `features`, are then installed
+    /// in the CPUID of the vCPU, and `KVM_CAP_HYPERV_ENFORCE_CPUID` is enabled so that KVM only
+    /// provides the enlightenments these leaves expose. Besides `features`, the leaves expose
+    /// the base Hyper-V interfaces such as the hypercall page and the VP index.
+    ///
+    /// The base CPUID of the vCPU must have been set with [set_cpuid2](VcpuFd::set_cpuid2)
+    /// beforehand, and the VM prepared with [enable_hyperv](struct.VmFd.html#method.enable_hyperv).
+    /// The Hyper-V leaves start at `0x4000_0000`, so the KVM leaves found there, if any, are
+    /// moved to `0x4000_0100`.
+    ///
+    /// Returns the enlightenments which the CPUID of the vCPU exposes after the call.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The enlightenments of the guest.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
+    /// # use kvm_ioctls::{HypervFeatures, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// vm.create_irq_chip().unwrap();
+    /// let features = HypervFeatures::Relaxed | HypervFeatures::Synic;
+    /// if vm.enable_hyperv(features).is_ok() {
+    ///     let vcpu = vm.create_vcpu(0).unwrap();
+    ///     let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+    ///     vcpu.set_cpuid2(&cpuid).unwrap();
+    ///     assert_eq!(vcpu.init_hyperv(&features).unwrap(), features);
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn init_hyperv(&self, features: &HypervFeatures) -> Result<HypervFeatures> {
+        // "KVMKVMKVM" signature of the KVM leaves.
+        const KVM_SIGNATURE: (u32, u32, u32) = (0x4b4d_564b, 0x564b_4d56, 0x4d);
+        const HYPERV_LEAVES: std::ops::Range<u32> = 0x4000_0000..0x4000_0100;
+
+        if features.contains(HypervFeatures::Synic) {
+            let cap = kvm_enable_cap {
+                cap: KVM_CAP_HYPERV_SYNIC2,
+                ..Default::default()
+            };
+            self.enable_cap(&cap)?;
+        }
+        if features.contains(HypervFeatures::EnlightenedVmcs) {
+            // The kernel writes the supported enlightened VMCS versions to `vmcs_version`.
+            let mut vmcs_version: u16 = 0;
+            let cap = kvm_enable_cap {
+                cap: KVM_CAP_HYPERV_ENLIGHTENED_VMCS,
+                args: [&mut vmcs_version as *mut u16 as u64, 0, 0, 0],
+                ..Default::default()
+            };
+            self.enable_cap(&cap)?;
+        }
+        let mut hv_cpuid = self.get_supported_hv_cpuid(KVM_MAX_CPUID_ENTRIES)?;
+        features.restrict_hv_cpuid(&mut hv_cpuid);
+
+        let mut cpuid = self.get_cpuid2(KVM_MAX_CPUID_ENTRIES)?;
+        let has_kvm_leaves = cpuid.leaf(HYPERV_LEAVES.start, 0).map_or(false, |entry| {
+            (entry.ebx, entry.ecx, entry.edx) == KVM_SIGNATURE
+        });
+        let mut kvm_leaves = Vec::new();
+        cpuid.retain(|entry| {
+            if !HYPERV_LEAVES.contains(&entry.function) {
+                return true;
+            }
+            if has_kvm_leaves {
+                kvm_leaves.push(*entry);
+            }
+            false
+        });
+        for mut entry in kvm_leaves {
+            // The first leaf holds the highest KVM leaf.
+            if entry.function == HYPERV_LEAVES.start && HYPERV_LEAVES.contains(&entry.eax) {
+                entry.eax += HYPERV_LEAVES.end - HYPERV_LEAVES.start;
+            }
+            entry.function += HYPERV_LEAVES.end - HYPERV_LEAVES.start;
+            cpuid.set_leaf(entry.function, entry.index, entry)?;
+        }
+        for entry in hv_cpuid.as_slice() {
+            cpuid.set_leaf(entry.function, entry.index, *entry)?;
+        }
+        self.set_cpuid2(&cpuid)?;
+
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_HYPERV_ENFORCE_CPUID,
+            ..Default::default()
+        };
+        cap.args[0] = 1;
+        self.enable_cap(&cap)?;
+
+        let cpuid = self.get_cpuid2(KVM_MAX_CPUID_ENTRIES)?;
+        Ok(*features & HypervFeatures::from_hv_cpuid(&cpuid))
+    }
+
     /// Returns the state of the LAPIC (Local Advanced Programmable Interrupt Controller).
     ///
-    /// The state is returned in a `kvm_lapic_state` structure as defined in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// The state is returned in a [`LapicState`], wrapping the `kvm_lapic_state` structure as
+    /// defined in the [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     /// See the documentation for `KVM_GET_LAPIC`.
     ///
     /// # Example
@@ -621,9 +1827,11 @@ impl VcpuFd {
     /// vm.create_irq_chip().unwrap();
     /// let vcpu = vm.create_vcpu(0).unwrap();
     /// let lapic = vcpu.get_lapic().unwrap();
+    /// // The APIC ID of the vCPU is in the top byte of the APIC ID register.
+    /// assert_eq!(lapic.lapic_reg(0x20).unwrap() >> 24, 0);
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_lapic(&self) -> Result<kvm_lapic_state> {
+    pub fn get_lapic(&self) -> Result<LapicState> {
         let mut klapic = kvm_lapic_state::default();
 
         // SAFETY: The ioctl is unsafe unless you trust the kernel not to write past the end of the
@@ -632,7 +1840,7 @@ impl VcpuFd {
         if ret < 0 {
             return Err(errno::Error::last());
         }
-        Ok(klapic)
+        Ok(LapicState(klapic))
     }
 
     /// Sets the state of the LAPIC (Local Advanced Programmable Interrupt Controller).
@@ -643,6 +1851,7 @@ impl VcpuFd {
     ///
     /// * `klapic` - LAPIC state. For details check the `kvm_lapic_state` structure in the
     ///             [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///             A [`LapicState`] can be passed as well.
     /// # Example
     ///
     /// ```rust
@@ -836,75 +2045,181 @@ impl VcpuFd {
         Ok(())
     }
 
-    /// X86 specific call that returns the vcpu's current "xsave struct".
-    ///
-    /// See the documentation for `KVM_GET_XSAVE` in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
-    ///
-    /// # Arguments
+    /// Returns the vcpu's current "multiprocessing state" as an [`MpState`].
     ///
-    /// * `kvm_xsave` - xsave struct to be read.
+    /// Typed variant of [`get_mp_state`](VcpuFd::get_mp_state).
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_ioctls::{Kvm, MpState};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
     /// let vcpu = vm.create_vcpu(0).unwrap();
-    /// let xsave = vcpu.get_xsave().unwrap();
+    /// if vcpu.get_mp_state_typed().unwrap() == MpState::Runnable {
+    ///     // The vcpu can be run.
+    /// }
     /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_xsave(&self) -> Result<kvm_xsave> {
-        let mut xsave = Default::default();
-        // SAFETY: Here we trust the kernel not to read past the end of the kvm_xsave struct.
-        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_XSAVE(), &mut xsave) };
-        if ret != 0 {
-            return Err(errno::Error::last());
-        }
-        Ok(xsave)
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "s390"
+    ))]
+    pub fn get_mp_state_typed(&self) -> Result<MpState> {
+        self.get_mp_state().map(MpState::from)
     }
 
-    /// X86 specific call that sets the vcpu's current "xsave struct".
+    /// Sets the vcpu's current "multiprocessing state" from an [`MpState`].
     ///
-    /// See the documentation for `KVM_SET_XSAVE` in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// Typed variant of [`set_mp_state`](VcpuFd::set_mp_state).
     ///
     /// # Arguments
     ///
-    /// * `kvm_xsave` - xsave struct to be written.
+    /// * `mp_state` - multiprocessing state to be written.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_ioctls::{Kvm, MpState};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
     /// let vcpu = vm.create_vcpu(0).unwrap();
-    /// let xsave = Default::default();
-    /// // Your `xsave` manipulation here.
-    /// vcpu.set_xsave(&xsave).unwrap();
+    /// vcpu.set_mp_state_typed(MpState::Runnable).unwrap();
     /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn set_xsave(&self, xsave: &kvm_xsave) -> Result<()> {
-        // SAFETY: Here we trust the kernel not to read past the end of the kvm_xsave struct.
-        let ret = unsafe { ioctl_with_ref(self, KVM_SET_XSAVE(), xsave) };
-        if ret != 0 {
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64",
+        target_arch = "s390"
+    ))]
+    pub fn set_mp_state_typed(&self, mp_state: MpState) -> Result<()> {
+        self.set_mp_state(mp_state.into())
+    }
+
+    /// Delivers an INIT to the vCPU, which then waits for a SIPI in the
+    /// [`MpState::InitReceived`] state.
+    ///
+    /// This is the first step of the bring-up of an application processor, which starts in
+    /// the [`MpState::Uninitialized`] state. The registers of the vCPU are left untouched, and
+    /// are expected to be reset by the VMM before [send_sipi](VcpuFd::send_sipi).
+    ///
+    /// Requires an in-kernel local APIC.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// vm.create_irq_chip().unwrap();
+    /// let _bsp = vm.create_vcpu(0).unwrap();
+    /// let ap = vm.create_vcpu(1).unwrap();
+    /// ap.send_init().unwrap();
+    /// ap.send_sipi(0x10).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn send_init(&self) -> Result<()> {
+        self.set_mp_state_typed(MpState::InitReceived)
+    }
+
+    /// Delivers a SIPI with the start vector `vector` to a vCPU waiting for it after
+    /// [send_init](VcpuFd::send_init), and makes the vCPU [`MpState::Runnable`].
+    ///
+    /// Like the processor receiving a SIPI, the vCPU starts executing in real mode at
+    /// `vector << 12`: `CS` is loaded with the selector `vector << 8` and the base
+    /// `vector << 12`, and `RIP` is cleared. Since `KVM_MP_STATE_SIPI_RECEIVED` cannot carry
+    /// the start vector, the vCPU goes through this state in userspace only.
+    ///
+    /// # Arguments
+    ///
+    /// * `vector` - The start vector, i.e. the page of the startup code.
+    ///
+    /// Fails with `EINVAL` if the vCPU is not waiting for a SIPI.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn send_sipi(&self, vector: u8) -> Result<()> {
+        if self.get_mp_state_typed()? != MpState::InitReceived {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let mut sregs = self.get_sregs()?;
+        sregs.cs.selector = u16::from(vector) << 8;
+        sregs.cs.base = u64::from(vector) << 12;
+        self.set_sregs(&sregs)?;
+        self.modify_regs(|regs| regs.rip = 0)?;
+        self.set_mp_state_typed(MpState::Runnable)
+    }
+
+    /// X86 specific call that returns the vcpu's current "xsave struct".
+    ///
+    /// See the documentation for `KVM_GET_XSAVE` in the
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Arguments
+    ///
+    /// * `kvm_xsave` - xsave struct to be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let xsave = vcpu.get_xsave().unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_xsave(&self) -> Result<kvm_xsave> {
+        let mut xsave = Default::default();
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_xsave struct.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_XSAVE(), &mut xsave) };
+        if ret != 0 {
             return Err(errno::Error::last());
         }
-        Ok(())
+        Ok(xsave)
     }
 
-    /// X86 specific call that returns the vcpu's current "xcrs".
+    /// X86 specific call that sets the vcpu's current "xsave struct".
     ///
-    /// See the documentation for `KVM_GET_XCRS` in the
+    /// See the documentation for `KVM_SET_XSAVE` in the
     /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     ///
     /// # Arguments
     ///
-    /// * `kvm_xcrs` - xcrs to be read.
+    /// * `kvm_xsave` - xsave struct to be written.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let xsave = Default::default();
+    /// // Your `xsave` manipulation here.
+    /// vcpu.set_xsave(&xsave).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_xsave(&self, xsave: &kvm_xsave) -> Result<()> {
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_xsave struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_XSAVE(), xsave) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// X86 specific call that returns the vcpu's current "xcrs".
+    ///
+    /// The state is returned in a [`XcrsState`], wrapping the `kvm_xcrs` structure.
+    /// See the documentation for `KVM_GET_XCRS` in the
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     ///
     /// # Example
     ///
@@ -915,16 +2230,18 @@ impl VcpuFd {
     /// let vm = kvm.create_vm().unwrap();
     /// let vcpu = vm.create_vcpu(0).unwrap();
     /// let xcrs = vcpu.get_xcrs().unwrap();
+    /// // x87 state is always enabled.
+    /// assert_eq!(xcrs.xcr0() & 1, 1);
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_xcrs(&self) -> Result<kvm_xcrs> {
+    pub fn get_xcrs(&self) -> Result<XcrsState> {
         let mut xcrs = Default::default();
         // SAFETY: Here we trust the kernel not to read past the end of the kvm_xcrs struct.
         let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_XCRS(), &mut xcrs) };
         if ret != 0 {
             return Err(errno::Error::last());
         }
-        Ok(xcrs)
+        Ok(XcrsState(xcrs))
     }
 
     /// X86 specific call that sets the vcpu's current "xcrs".
@@ -934,7 +2251,7 @@ impl VcpuFd {
     ///
     /// # Arguments
     ///
-    /// * `kvm_xcrs` - xcrs to be written.
+    /// * `kvm_xcrs` - xcrs to be written. A [`XcrsState`] can be passed as well.
     ///
     /// # Example
     ///
@@ -976,16 +2293,17 @@ impl VcpuFd {
     /// let vm = kvm.create_vm().unwrap();
     /// let vcpu = vm.create_vcpu(0).unwrap();
     /// let debug_regs = vcpu.get_debug_regs().unwrap();
+    /// let dr0 = debug_regs.breakpoint_addr(0).unwrap();
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_debug_regs(&self) -> Result<kvm_debugregs> {
-        let mut debug_regs = Default::default();
+    pub fn get_debug_regs(&self) -> Result<DebugRegs> {
+        let mut debug_regs = kvm_debugregs::default();
         // SAFETY: Here we trust the kernel not to read past the end of the kvm_debugregs struct.
         let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_DEBUGREGS(), &mut debug_regs) };
         if ret != 0 {
             return Err(errno::Error::last());
         }
-        Ok(debug_regs)
+        Ok(DebugRegs(debug_regs))
     }
 
     /// X86 specific call that sets the vcpu's current "debug registers".
@@ -1155,7 +2473,9 @@ impl VcpuFd {
     ///
     /// # Arguments
     ///
-    /// * `feature` - vCPU features that needs to be finalized.
+    /// * `feature` - vCPU feature that needs to be finalized (e.g. `KVM_ARM_VCPU_SVE`). The
+    ///               corresponding flag must have been set in the `features` of the
+    ///               `kvm_vcpu_init` passed to [`vcpu_init`](Self::vcpu_init).
     ///
     /// # Example
     /// ```rust
@@ -1174,15 +2494,14 @@ impl VcpuFd {
     /// kvi.features[0] |= 1 << KVM_ARM_VCPU_SVE;
     /// if is_aarch64_feature_detected!("sve2") || is_aarch64_feature_detected!("sve") {
     ///     vcpu.vcpu_init(&kvi).unwrap();
-    ///     let feature = KVM_ARM_VCPU_SVE as i32;
-    ///     vcpu.vcpu_finalize(&feature).unwrap();
+    ///     vcpu.vcpu_finalize(KVM_ARM_VCPU_SVE as i32).unwrap();
     /// }
     /// ```
     #[cfg(target_arch = "aarch64")]
-    pub fn vcpu_finalize(&self, feature: &std::os::raw::c_int) -> Result<()> {
+    pub fn vcpu_finalize(&self, feature: i32) -> Result<()> {
         // SAFETY: This is safe because we know the kernel will only read this
         // parameter to select the correct finalization case in KVM.
-        let ret = unsafe { ioctl_with_ref(self, KVM_ARM_VCPU_FINALIZE(), feature) };
+        let ret = unsafe { ioctl_with_ref(self, KVM_ARM_VCPU_FINALIZE(), &feature) };
         if ret < 0 {
             return Err(errno::Error::last());
         }
@@ -1192,6 +2511,9 @@ impl VcpuFd {
     /// Returns the guest registers that are supported for the
     /// KVM_GET_ONE_REG/KVM_SET_ONE_REG calls.
     ///
+    /// Fails with `E2BIG` if `reg_list` is too small, in which case its `n` is set to the
+    /// number of registers. See [get_reg_ids](Self::get_reg_ids) for lists of any size.
+    ///
     /// # Arguments
     ///
     /// * `reg_list`  - list of registers (input/output). For details check the `kvm_reg_list`
@@ -1230,6 +2552,63 @@ impl VcpuFd {
         Ok(())
     }
 
+    /// Returns the ids of the guest registers that are supported for the
+    /// KVM_GET_ONE_REG/KVM_SET_ONE_REG calls.
+    ///
+    /// Unlike [get_reg_list](Self::get_reg_list), the list is sized after the number of
+    /// registers reported by the kernel instead of being bound by the capacity of a
+    /// [`RegList`], which the EL2 system registers of a vCPU with nested virtualization can
+    /// exceed. This makes it suitable for saving the whole register state of a vCPU.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::{reg_size, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_bindings::kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// vcpu.vcpu_init(&kvi).unwrap();
+    ///
+    /// for id in vcpu.get_reg_ids().unwrap() {
+    ///     let mut value = vec![0u8; reg_size(id)];
+    ///     vcpu.get_one_reg_bytes(id, &mut value).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn get_reg_ids(&self) -> Result<Vec<u64>> {
+        // `kvm_reg_list` is the number of registers followed by their ids, all of them u64.
+        let mut buf = vec![0u64];
+        loop {
+            // SAFETY: `buf` holds the number of registers followed by room for as many ids,
+            // and the kernel writes at most that many ids.
+            let ret = unsafe {
+                ioctl_with_mut_ptr(
+                    self,
+                    KVM_GET_REG_LIST(),
+                    buf.as_mut_ptr() as *mut kvm_reg_list,
+                )
+            };
+            if ret == 0 {
+                let n = buf[0] as usize;
+                buf.truncate(n + 1);
+                buf.remove(0);
+                return Ok(buf);
+            }
+            let err = errno::Error::last();
+            let n = buf[0] as usize;
+            // The kernel wrote back the number of registers; retry with enough room for
+            // them.
+            if err.errno() != libc::E2BIG || n < buf.len() {
+                return Err(err);
+            }
+            buf.resize(n + 1, 0);
+        }
+    }
+
     /// Sets processor-specific debug registers and configures the vcpu for handling
     /// certain guest debug events using the `KVM_SET_GUEST_DEBUG` ioctl.
     ///
@@ -1347,111 +2726,436 @@ impl VcpuFd {
         Ok(reg_size)
     }
 
-    /// Notify the guest about the vCPU being paused.
+    /// Sets the value of a 128-bit register, e.g. an FP/SIMD `V` register, for this vCPU.
     ///
-    /// See the documentation for `KVM_KVMCLOCK_CTRL` in the
-    /// [KVM API documentation](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn kvmclock_ctrl(&self) -> Result<()> {
-        // SAFETY: Safe because we know that our file is a KVM fd and that the request
-        // is one of the ones defined by kernel.
-        let ret = unsafe { ioctl(self, KVM_KVMCLOCK_CTRL()) };
-        if ret != 0 {
-            return Err(errno::Error::last());
+    /// Wrapper over [`set_one_reg`](Self::set_one_reg) for registers whose `KVM_REG_SIZE`
+    /// bits in `reg_id` are `KVM_REG_SIZE_U128`. Fails with `EINVAL` for other ids.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg_id` - ID of the register.
+    /// * `value` - Value of the register.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::kvm_vcpu_init;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// vcpu.vcpu_init(&kvi).unwrap();
+    ///
+    /// // Id of `fp_regs.vregs[0]` as listed in the KVM API documentation.
+    /// vcpu.set_one_reg_u128(0x6040_0000_0010_0054, 1 << 100).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn set_one_reg_u128(&self, reg_id: u64, value: u128) -> Result<()> {
+        if reg_id & KVM_REG_SIZE_MASK != KVM_REG_SIZE_U128 {
+            return Err(errno::Error::new(libc::EINVAL));
         }
+        self.set_one_reg(reg_id, &value.to_le_bytes())?;
         Ok(())
     }
 
-    /// Triggers the running of the current virtual CPU returning an exit reason.
+    /// Returns the value of a 128-bit register, e.g. an FP/SIMD `V` register, of this vCPU.
     ///
-    /// See documentation for `KVM_RUN`.
+    /// Wrapper over [`get_one_reg`](Self::get_one_reg) for registers whose `KVM_REG_SIZE`
+    /// bits in `reg_id` are `KVM_REG_SIZE_U128`. Fails with `EINVAL` for other ids.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg_id` - ID of the register.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
     /// # extern crate kvm_bindings;
-    /// # use std::io::Write;
-    /// # use std::ptr::null_mut;
-    /// # use std::slice;
-    /// # use kvm_ioctls::{Kvm, VcpuExit};
-    /// # use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
-    /// # let kvm = Kvm::new().unwrap();
-    /// # let vm = kvm.create_vm().unwrap();
-    /// // This is a dummy example for running on x86 based on https://lwn.net/Articles/658511/.
-    /// #[cfg(target_arch = "x86_64")]
-    /// {
-    ///     let mem_size = 0x4000;
-    ///     let guest_addr: u64 = 0x1000;
-    ///     let load_addr: *mut u8 = unsafe {
-    ///         libc::mmap(
-    ///             null_mut(),
-    ///             mem_size,
-    ///             libc::PROT_READ | libc::PROT_WRITE,
-    ///             libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
-    ///             -1,
-    ///             0,
-    ///         ) as *mut u8
-    ///     };
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::kvm_vcpu_init;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// vcpu.vcpu_init(&kvi).unwrap();
     ///
-    ///     let mem_region = kvm_userspace_memory_region {
-    ///         slot: 0,
-    ///         guest_phys_addr: guest_addr,
-    ///         memory_size: mem_size as u64,
-    ///         userspace_addr: load_addr as u64,
-    ///         flags: 0,
-    ///     };
-    ///     unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+    /// // Id of `fp_regs.vregs[0]` as listed in the KVM API documentation.
+    /// let v0 = vcpu.get_one_reg_u128(0x6040_0000_0010_0054).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn get_one_reg_u128(&self, reg_id: u64) -> Result<u128> {
+        if reg_id & KVM_REG_SIZE_MASK != KVM_REG_SIZE_U128 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let mut bytes = [0_u8; 16];
+        self.get_one_reg(reg_id, &mut bytes)?;
+        Ok(u128::from_le_bytes(bytes))
+    }
+
+    /// Sets the value of a register of any size, e.g. an SVE `Z` register, for this vCPU.
     ///
-    ///     // Dummy x86 code that just calls halt.
-    ///     let x86_code = [0xf4 /* hlt */];
+    /// Unlike [`set_one_reg`](Self::set_one_reg), `buf` must be exactly the size of the
+    /// register encoded in the `KVM_REG_SIZE` bits of `reg_id`, up to 256 bytes for the
+    /// 2048-bit SVE registers. Fails with `EINVAL` otherwise.
     ///
-    ///     // Write the code in the guest memory. This will generate a dirty page.
-    ///     unsafe {
-    ///         let mut slice = slice::from_raw_parts_mut(load_addr, mem_size);
-    ///         slice.write(&x86_code).unwrap();
-    ///     }
+    /// # Arguments
     ///
-    ///     let mut vcpu_fd = vm.create_vcpu(0).unwrap();
+    /// * `reg_id` - ID of the register.
+    /// * `buf` - Value of the register.
     ///
-    ///     let mut vcpu_sregs = vcpu_fd.get_sregs().unwrap();
-    ///     vcpu_sregs.cs.base = 0;
-    ///     vcpu_sregs.cs.selector = 0;
-    ///     vcpu_fd.set_sregs(&vcpu_sregs).unwrap();
+    /// # Example
     ///
-    ///     let mut vcpu_regs = vcpu_fd.get_regs().unwrap();
-    ///     // Set the Instruction Pointer to the guest address where we loaded the code.
-    ///     vcpu_regs.rip = guest_addr;
-    ///     vcpu_regs.rax = 2;
-    ///     vcpu_regs.rbx = 3;
-    ///     vcpu_regs.rflags = 2;
-    ///     vcpu_fd.set_regs(&vcpu_regs).unwrap();
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// use kvm_bindings::{kvm_vcpu_init, KVM_ARM_VCPU_SVE};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// if kvm.check_extension(Cap::ArmSve) {
+    ///     kvi.features[0] |= 1 << KVM_ARM_VCPU_SVE;
+    ///     vcpu.vcpu_init(&kvi).unwrap();
+    ///     vcpu.vcpu_finalize(KVM_ARM_VCPU_SVE as i32).unwrap();
     ///
-    ///     loop {
-    ///         match vcpu_fd.run().expect("run failed") {
-    ///             VcpuExit::Hlt => {
-    ///                 break;
-    ///             }
-    ///             exit_reason => panic!("unexpected exit reason: {:?}", exit_reason),
-    ///         }
-    ///     }
+    ///     // Id of the first slice of `z0` as listed in the KVM API documentation.
+    ///     vcpu.set_one_reg_bytes(0x6080_0000_0015_0000, &[0_u8; 256])
+    ///         .unwrap();
     /// }
     /// ```
-    pub fn run(&mut self) -> Result<VcpuExit> {
-        // SAFETY: Safe because we know that our file is a vCPU fd and we verify the return result.
-        let ret = unsafe { ioctl(self, KVM_RUN()) };
-        if ret == 0 {
-            let run = self.kvm_run_ptr.as_mut_ref();
-            match run.exit_reason {
-                // make sure you treat all possible exit reasons from include/uapi/linux/kvm.h corresponding
-                // when upgrading to a different kernel version
-                KVM_EXIT_UNKNOWN => Ok(VcpuExit::Unknown),
-                KVM_EXIT_EXCEPTION => Ok(VcpuExit::Exception),
-                KVM_EXIT_IO => {
-                    let run_start = run as *mut kvm_run as *mut u8;
-                    // SAFETY: Safe because the exit_reason (which comes from the kernel) told us
-                    // which union field to use.
-                    let io = unsafe { run.__bindgen_anon_1.io };
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn set_one_reg_bytes(&self, reg_id: u64, buf: &[u8]) -> Result<()> {
+        if buf.len() != reg_size(reg_id) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        self.set_one_reg(reg_id, buf)?;
+        Ok(())
+    }
+
+    /// Reads the value of a register of any size, e.g. an SVE `Z` register, of this vCPU
+    /// into `buf`.
+    ///
+    /// Unlike [`get_one_reg`](Self::get_one_reg), `buf` must be exactly the size of the
+    /// register encoded in the `KVM_REG_SIZE` bits of `reg_id`, up to 256 bytes for the
+    /// 2048-bit SVE registers. Fails with `EINVAL` otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg_id` - ID of the register.
+    /// * `buf` - Buffer the value of the register is written to.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// use kvm_bindings::{kvm_vcpu_init, KVM_ARM_VCPU_SVE};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// if kvm.check_extension(Cap::ArmSve) {
+    ///     kvi.features[0] |= 1 << KVM_ARM_VCPU_SVE;
+    ///     vcpu.vcpu_init(&kvi).unwrap();
+    ///     vcpu.vcpu_finalize(KVM_ARM_VCPU_SVE as i32).unwrap();
+    ///
+    ///     // Id of the first slice of `z0` as listed in the KVM API documentation.
+    ///     let mut z0 = [0_u8; 256];
+    ///     vcpu.get_one_reg_bytes(0x6080_0000_0015_0000, &mut z0)
+    ///         .unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn get_one_reg_bytes(&self, reg_id: u64, buf: &mut [u8]) -> Result<()> {
+        if buf.len() != reg_size(reg_id) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        self.get_one_reg(reg_id, buf)?;
+        Ok(())
+    }
+
+    /// Sets the value of an aarch64 core register.
+    ///
+    /// Wrapper over [`set_one_reg`](Self::set_one_reg) computing the `KVM_REG_ARM_CORE` id
+    /// of `reg`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - Core register to be written.
+    /// * `value` - Value of the register.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::{CoreReg, Kvm};
+    /// use kvm_bindings::kvm_vcpu_init;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// vcpu.vcpu_init(&kvi).unwrap();
+    ///
+    /// vcpu.set_core_reg(CoreReg::Pc, 0x8008_0000).unwrap();
+    /// // The boot protocol expects the address of the device tree in x0.
+    /// vcpu.set_core_reg(CoreReg::X0, 0x8000_0000).unwrap();
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_core_reg(&self, reg: CoreReg, value: u64) -> Result<()> {
+        self.set_one_reg(reg.id(), &value.to_le_bytes())?;
+        Ok(())
+    }
+
+    /// Returns the value of an aarch64 core register.
+    ///
+    /// Wrapper over [`get_one_reg`](Self::get_one_reg) computing the `KVM_REG_ARM_CORE` id
+    /// of `reg`.
+    ///
+    /// # Arguments
+    ///
+    /// * `reg` - Core register to be read.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::{CoreReg, Kvm};
+    /// use kvm_bindings::kvm_vcpu_init;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// vcpu.vcpu_init(&kvi).unwrap();
+    ///
+    /// let pc = vcpu.get_core_reg(CoreReg::Pc).unwrap();
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn get_core_reg(&self, reg: CoreReg) -> Result<u64> {
+        let mut bytes = [0_u8; 8];
+        self.get_one_reg(reg.id(), &mut bytes)?;
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    /// Resumes a vCPU which requested a system suspend with the PSCI `SYSTEM_SUSPEND` call.
+    ///
+    /// As per the PSCI specification, the vCPU restarts from a reset state at `entry_point`,
+    /// with `context_id` in `x0`: the vCPU is reset with `kvi`, which must be the
+    /// initialization it was first created with, then set back to
+    /// [`MpState::Runnable`]. This is expected after the [`SystemEventType::Wakeup`] exit of a
+    /// vCPU put into [`MpState::Suspended`], or right after the [`SystemEventType::Suspend`]
+    /// exit for a VMM waking the guest up by itself.
+    ///
+    /// # Arguments
+    ///
+    /// * `kvi` - Initialization of the vCPU, see [vcpu_init](Self::vcpu_init).
+    /// * `entry_point` - Entry point passed by the guest in `x1` along with the suspend request.
+    /// * `context_id` - Context ID passed by the guest in `x2` along with the suspend request.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::{CoreReg, Kvm, SystemEventType, VcpuExit};
+    /// # use kvm_bindings::{kvm_vcpu_init, KVM_ARM_VCPU_PSCI_0_2};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut kvi = kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// kvi.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
+    /// vcpu.vcpu_init(&kvi).unwrap();
+    ///
+    /// // After a `VcpuExit::SystemEvent(SystemEventType::Suspend, _)`:
+    /// let entry_point = vcpu.get_core_reg(CoreReg::X1).unwrap();
+    /// let context_id = vcpu.get_core_reg(CoreReg::X2).unwrap();
+    /// vcpu.resume_from_suspend(&kvi, entry_point, context_id)
+    ///     .unwrap();
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn resume_from_suspend(
+        &self,
+        kvi: &kvm_vcpu_init,
+        entry_point: u64,
+        context_id: u64,
+    ) -> Result<()> {
+        self.vcpu_init(kvi)?;
+        self.set_core_reg(CoreReg::Pc, entry_point)?;
+        self.set_core_reg(CoreReg::X0, context_id)?;
+        self.set_mp_state_typed(MpState::Runnable)
+    }
+
+    /// Notify the guest about the vCPU being paused.
+    ///
+    /// See the documentation for `KVM_KVMCLOCK_CTRL` in the
+    /// [KVM API documentation](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn kvmclock_ctrl(&self) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a KVM fd and that the request
+        // is one of the ones defined by kernel.
+        let ret = unsafe { ioctl(self, KVM_KVMCLOCK_CTRL()) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Triggers the running of the current virtual CPU returning an exit reason.
+    ///
+    /// See documentation for `KVM_RUN`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use std::io::Write;
+    /// # use std::ptr::null_mut;
+    /// # use std::slice;
+    /// # use kvm_ioctls::{Kvm, VcpuExit};
+    /// # use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
+    /// # let kvm = Kvm::new().unwrap();
+    /// # let vm = kvm.create_vm().unwrap();
+    /// // This is a dummy example for running on x86 based on https://lwn.net/Articles/658511/.
+    /// #[cfg(target_arch = "x86_64")]
+    /// {
+    ///     let mem_size = 0x4000;
+    ///     let guest_addr: u64 = 0x1000;
+    ///     let load_addr: *mut u8 = unsafe {
+    ///         libc::mmap(
+    ///             null_mut(),
+    ///             mem_size,
+    ///             libc::PROT_READ | libc::PROT_WRITE,
+    ///             libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+    ///             -1,
+    ///             0,
+    ///         ) as *mut u8
+    ///     };
+    ///
+    ///     let mem_region = kvm_userspace_memory_region {
+    ///         slot: 0,
+    ///         guest_phys_addr: guest_addr,
+    ///         memory_size: mem_size as u64,
+    ///         userspace_addr: load_addr as u64,
+    ///         flags: 0,
+    ///     };
+    ///     unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+    ///
+    ///     // Dummy x86 code that just calls halt.
+    ///     let x86_code = [0xf4 /* hlt */];
+    ///
+    ///     // Write the code in the guest memory. This will generate a dirty page.
+    ///     unsafe {
+    ///         let mut slice = slice::from_raw_parts_mut(load_addr, mem_size);
+    ///         slice.write(&x86_code).unwrap();
+    ///     }
+    ///
+    ///     let mut vcpu_fd = vm.create_vcpu(0).unwrap();
+    ///
+    ///     let mut vcpu_sregs = vcpu_fd.get_sregs().unwrap();
+    ///     vcpu_sregs.cs.base = 0;
+    ///     vcpu_sregs.cs.selector = 0;
+    ///     vcpu_fd.set_sregs(&vcpu_sregs).unwrap();
+    ///
+    ///     let mut vcpu_regs = vcpu_fd.get_regs().unwrap();
+    ///     // Set the Instruction Pointer to the guest address where we loaded the code.
+    ///     vcpu_regs.rip = guest_addr;
+    ///     vcpu_regs.rax = 2;
+    ///     vcpu_regs.rbx = 3;
+    ///     vcpu_regs.rflags = 2;
+    ///     vcpu_fd.set_regs(&vcpu_regs).unwrap();
+    ///
+    ///     loop {
+    ///         match vcpu_fd.run().expect("run failed") {
+    ///             VcpuExit::Hlt => {
+    ///                 break;
+    ///             }
+    ///             exit_reason => panic!("unexpected exit reason: {:?}", exit_reason),
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn run(&mut self) -> Result<VcpuExit> {
+        self.run_with_stop(None)
+    }
+
+    /// Triggers the running of the current virtual CPU like [run()](struct.VcpuFd.html#method.run),
+    /// retrying `KVM_RUN` when it is interrupted by a signal.
+    ///
+    /// A signal delivered to the vCPU thread makes `KVM_RUN` fail with `EINTR`. This method
+    /// resumes the vCPU instead, unless `stop` is set, in which case the `EINTR` error is
+    /// returned. The usual pattern is to set `stop` and then signal the vCPU thread, so that
+    /// the vCPU returns to userspace and the loop ends. Setting `stop` from the signal handler
+    /// is also supported, as [`AtomicBool`] is async-signal-safe.
+    ///
+    /// # Arguments
+    ///
+    /// * `stop` - Whether the vCPU should stop running when interrupted by a signal.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # use kvm_ioctls::{VcpuExit, VcpuFd};
+    /// # use std::sync::atomic::AtomicBool;
+    /// fn run_vcpu(vcpu: &mut VcpuFd, stop: &AtomicBool) {
+    ///     loop {
+    ///         match vcpu.run_uninterrupted(stop) {
+    ///             Ok(VcpuExit::Hlt) => break,
+    ///             Ok(exit) => println!("unhandled exit: {:?}", exit),
+    ///             // Only returned once `stop` is set.
+    ///             Err(e) if e.errno() == libc::EINTR => break,
+    ///             Err(e) => panic!("run failed: {}", e),
+    ///         }
+    ///     }
+    /// }
+    /// ```
+    pub fn run_uninterrupted(&mut self, stop: &AtomicBool) -> Result<VcpuExit> {
+        self.run_with_stop(Some(stop))
+    }
+
+    // Issues `KVM_RUN` and decodes the exit. With `stop`, `KVM_RUN` is issued again when it is
+    // interrupted by a signal, until `stop` is set.
+    fn run_with_stop(&mut self, stop: Option<&AtomicBool>) -> Result<VcpuExit> {
+        let ret = loop {
+            // SAFETY: Safe because we know that our file is a vCPU fd and we verify the return
+            // result.
+            let ret = unsafe { ioctl(self, KVM_RUN()) };
+            match stop {
+                Some(stop)
+                    if ret != 0
+                        && errno::Error::last().errno() == libc::EINTR
+                        && !stop.load(Ordering::Acquire) => {}
+                _ => break ret,
+            }
+        };
+        if ret == 0 {
+            let run = self.kvm_run_ptr.as_mut_ref();
+            match run.exit_reason {
+                // make sure you treat all possible exit reasons from include/uapi/linux/kvm.h corresponding
+                // when upgrading to a different kernel version
+                KVM_EXIT_UNKNOWN => Ok(VcpuExit::Unknown),
+                KVM_EXIT_EXCEPTION => Ok(VcpuExit::Exception),
+                KVM_EXIT_IO => {
+                    let run_start = run as *mut kvm_run as *mut u8;
+                    // SAFETY: Safe because the exit_reason (which comes from the kernel) told us
+                    // which union field to use.
+                    let io = unsafe { run.__bindgen_anon_1.io };
                     let port = io.port;
                     let data_size = io.count as usize * io.size as usize;
                     // SAFETY: The data_offset is defined by the kernel to be some number of bytes
@@ -1522,6 +3226,15 @@ impl VcpuFd {
                     };
                     Ok(VcpuExit::X86Wrmsr(exit))
                 }
+                KVM_EXIT_X86_BUS_LOCK => Ok(VcpuExit::X86BusLock),
+                KVM_EXIT_NOTIFY => {
+                    // SAFETY: Safe because the exit_reason (which comes from the kernel) told us
+                    // which union field to use.
+                    let notify = unsafe { run.__bindgen_anon_1.notify };
+                    Ok(VcpuExit::Notify(NotifyExitFlags::from_bits_truncate(
+                        notify.flags,
+                    )))
+                }
                 KVM_EXIT_IRQ_WINDOW_OPEN => Ok(VcpuExit::IrqWindowOpen),
                 KVM_EXIT_SHUTDOWN => Ok(VcpuExit::Shutdown),
                 KVM_EXIT_FAIL_ENTRY => {
@@ -1554,7 +3267,10 @@ impl VcpuFd {
                     let ndata = system_event.ndata;
                     // SAFETY: Safe because we only populate with valid data (based on ndata)
                     let data = unsafe { &system_event.__bindgen_anon_1.data[0..ndata as usize] };
-                    Ok(VcpuExit::SystemEvent(system_event.type_, data))
+                    Ok(VcpuExit::SystemEvent(
+                        SystemEventType::from(system_event.type_),
+                        data,
+                    ))
                 }
                 KVM_EXIT_S390_STSI => Ok(VcpuExit::S390Stsi),
                 KVM_EXIT_IOAPIC_EOI => {
@@ -1571,39 +3287,166 @@ impl VcpuFd {
         }
     }
 
-    /// Returns a mutable reference to the kvm_run structure
-    pub fn get_kvm_run(&mut self) -> &mut kvm_run {
-        self.kvm_run_ptr.as_mut_ref()
-    }
-
-    /// Sets the `immediate_exit` flag on the `kvm_run` struct associated with this vCPU to `val`.
-    pub fn set_kvm_immediate_exit(&mut self, val: u8) {
-        let kvm_run = self.kvm_run_ptr.as_mut_ref();
-        kvm_run.immediate_exit = val;
-    }
-
-    /// Returns the vCPU TSC frequency in KHz or an error if the host has unstable TSC.
+    /// Sets the signal mask of the vCPU thread while it runs as per the `KVM_SET_SIGNAL_MASK`
+    /// ioctl.
+    ///
+    /// The mask replaces the mask of the thread for the duration of `KVM_RUN`. Blocking a
+    /// signal in the thread and leaving it unblocked in this mask lets the signal interrupt
+    /// the vCPU only while it runs, without racing with the entry into `KVM_RUN`.
+    ///
+    /// # Arguments
+    ///
+    /// * `sigset` - The words of the kernel signal set, in which bit `n - 1` stands for
+    ///              signal `n`. The kernel signal set is a single word on 64-bit hosts.
     ///
     /// # Example
     ///
-    ///  ```rust
+    /// ```rust
     /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
     /// # use kvm_ioctls::Kvm;
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
     /// let vcpu = vm.create_vcpu(0).unwrap();
-    /// let tsc_khz = vcpu.get_tsc_khz().unwrap();
+    /// // Block all the signals but SIGUSR1 during `KVM_RUN`.
+    /// vcpu.set_signal_mask(&[!(1 << (libc::SIGUSR1 - 1))]).unwrap();
+    /// vcpu.clear_signal_mask().unwrap();
     /// ```
-    ///
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_tsc_khz(&self) -> Result<u32> {
-        // SAFETY:  Safe because we know that our file is a KVM fd and that the request is one of
-        // the ones defined by kernel.
-        let ret = unsafe { ioctl(self, KVM_GET_TSC_KHZ()) };
-        if ret >= 0 {
-            Ok(ret as u32)
-        } else {
-            Err(errno::Error::new(ret))
+    pub fn set_signal_mask(&self, sigset: &[u64]) -> Result<()> {
+        // Allocate the header followed by enough room for the signal set.
+        let header_size = std::mem::size_of::<kvm_signal_mask>();
+        let sigset_size = std::mem::size_of_val(sigset);
+        let size = header_size + sigset_size;
+        let mut buffer = Vec::with_capacity(size.div_ceil(header_size));
+        buffer.resize_with(size.div_ceil(header_size), kvm_signal_mask::default);
+        let mask = &mut buffer[0];
+        mask.len = sigset_size as u32;
+        // SAFETY: The vector holds enough memory past the header for `len` bytes.
+        let dst = unsafe { mask.sigset.as_mut_slice(sigset_size) };
+        for (dst, word) in dst.chunks_exact_mut(8).zip(sigset) {
+            dst.copy_from_slice(&word.to_ne_bytes());
+        }
+
+        // SAFETY: Safe because we know that our file is a vCPU fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_SIGNAL_MASK(), &buffer[0]) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Clears the signal mask set by [set_signal_mask](struct.VcpuFd.html#method.set_signal_mask),
+    /// so that the vCPU runs with the signal mask of its thread.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// vcpu.clear_signal_mask().unwrap();
+    /// ```
+    pub fn clear_signal_mask(&self) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a vCPU fd, the kernel does not
+        // dereference a NULL pointer, and we verify the return result.
+        let ret = unsafe {
+            ioctl_with_ptr(
+                self,
+                KVM_SET_SIGNAL_MASK(),
+                std::ptr::null::<kvm_signal_mask>(),
+            )
+        };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Returns a mutable reference to the kvm_run structure
+    pub fn get_kvm_run(&mut self) -> &mut kvm_run {
+        self.kvm_run_ptr.as_mut_ref()
+    }
+
+    /// Sets the `immediate_exit` flag on the `kvm_run` struct associated with this vCPU to `val`.
+    pub fn set_kvm_immediate_exit(&mut self, val: u8) {
+        let kvm_run = self.kvm_run_ptr.as_mut_ref();
+        kvm_run.immediate_exit = val;
+    }
+
+    /// Returns the raw `KVM_EXIT_*` reason of the last exit of the vCPU, as stored in the
+    /// `exit_reason` field of the `kvm_run` struct.
+    ///
+    /// Useful for logging exits after the [`VcpuExit`] returned by
+    /// [run()](struct.VcpuFd.html#method.run) was dropped, or for reasons reported as
+    /// [`VcpuExit::Unsupported`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::KVM_EXIT_SHUTDOWN;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut vcpu = vm.create_vcpu(0).unwrap();
+    /// // Set up the guest and run the vCPU here.
+    /// if vcpu.last_exit_reason() == KVM_EXIT_SHUTDOWN {
+    ///     println!("the guest triple faulted");
+    /// }
+    /// ```
+    pub fn last_exit_reason(&mut self) -> u32 {
+        self.kvm_run_ptr.as_mut_ref().exit_reason
+    }
+
+    /// Returns whether the last exit of the vCPU was caused by a bus lock, i.e. whether the
+    /// `KVM_RUN_X86_BUS_LOCK` flag is set in the `kvm_run` struct.
+    ///
+    /// The flag is set for [`VcpuExit::X86BusLock`], but can also accompany other exit
+    /// reasons when the bus lock happened while another exit was being handled. It is only
+    /// set when bus lock exits are enabled (see
+    /// [enable_bus_lock_exit()](struct.VmFd.html#method.enable_bus_lock_exit)).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut vcpu = vm.create_vcpu(0).unwrap();
+    /// assert!(!vcpu.bus_lock_detected());
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn bus_lock_detected(&mut self) -> bool {
+        u32::from(self.kvm_run_ptr.as_mut_ref().flags) & KVM_RUN_X86_BUS_LOCK != 0
+    }
+
+    /// Returns the vCPU TSC frequency in KHz or an error if the host has unstable TSC.
+    ///
+    /// # Example
+    ///
+    ///  ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let tsc_khz = vcpu.get_tsc_khz().unwrap();
+    /// ```
+    ///
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_tsc_khz(&self) -> Result<u32> {
+        // SAFETY:  Safe because we know that our file is a KVM fd and that the request is one of
+        // the ones defined by kernel.
+        let ret = unsafe { ioctl(self, KVM_GET_TSC_KHZ()) };
+        if ret >= 0 {
+            Ok(ret as u32)
+        } else {
+            Err(errno::Error::new(ret))
         }
     }
 
@@ -1766,7 +3609,11 @@ impl VcpuFd {
         kvm_run.kvm_dirty_regs &= !(reg as u64);
     }
 
-    /// Get the [`kvm_sync_regs`] from the VM
+    /// Get a reference to the [`kvm_sync_regs`] from the VM
+    ///
+    /// The registers selected with [set_sync_valid_reg](Self::set_sync_valid_reg) are
+    /// written here by KVM on every exit, which saves a `KVM_GET_*` ioctl per exit in run
+    /// loops. Requires `KVM_CAP_SYNC_REGS`.
     ///
     /// # Example
     ///
@@ -1783,17 +3630,21 @@ impl VcpuFd {
     /// }
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn sync_regs(&self) -> kvm_sync_regs {
+    pub fn sync_regs(&self) -> &kvm_sync_regs {
         let kvm_run = self.kvm_run_ptr.as_ref();
 
         // SAFETY: Accessing this union field could be out of bounds if the `kvm_run`
         // allocation isn't large enough. The `kvm_run` region is set using
         // `get_vcpu_map_size`, so this region is in bounds
-        unsafe { kvm_run.s.regs }
+        unsafe { &kvm_run.s.regs }
     }
 
     /// Get a mutable reference to the [`kvm_sync_regs`] from the VM
     ///
+    /// The registers modified here are loaded by KVM on the next run when marked with
+    /// [set_sync_dirty_reg](Self::set_sync_dirty_reg), which saves a `KVM_SET_*` ioctl.
+    /// Requires `KVM_CAP_SYNC_REGS`.
+    ///
     /// # Example
     ///
     ///  ```rust
@@ -1870,6 +3721,89 @@ impl VcpuFd {
         }
     }
 
+    /// X86 specific call to set up machine-check emulation on the vCPU.
+    ///
+    /// See the documentation for `KVM_X86_SETUP_MCE`. Only usable if `KVM_CAP_MCE` is
+    /// available.
+    ///
+    /// # Arguments
+    ///
+    /// * `mcg_cap` - Value of the `IA32_MCG_CAP` MSR exposed to the guest. Its lowest byte is
+    ///               the number of error-reporting banks, which must be between 1 and the
+    ///               32 banks supported by KVM. The remaining bits must be a subset of the
+    ///               ones reported by
+    ///               [`get_mce_cap_supported`](crate::Kvm::get_mce_cap_supported).
+    ///
+    /// Returns `EINVAL` if the bank count is out of range.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # use kvm_ioctls::{Kvm, Cap};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// if kvm.check_extension(Cap::Mce) {
+    ///     // Expose 10 banks along with all the supported capabilities.
+    ///     let mcg_cap = kvm.get_mce_cap_supported().unwrap() | 10;
+    ///     vcpu.setup_mce(mcg_cap).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn setup_mce(&self, mcg_cap: u64) -> Result<()> {
+        let banks = mcg_cap & 0xff;
+        if banks == 0 || banks > KVM_MAX_MCE_BANKS {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // SAFETY: Here we trust the kernel not to read past the end of the u64.
+        let ret = unsafe { ioctl_with_ref(self, KVM_X86_SETUP_MCE(), &mcg_cap) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// X86 specific call to inject a machine-check error into the vCPU.
+    ///
+    /// See the documentation for `KVM_X86_SET_MCE`. Machine-check emulation must have been
+    /// enabled beforehand with [`setup_mce`](Self::setup_mce).
+    ///
+    /// # Arguments
+    ///
+    /// * `mce` - Error to be reported. Uncorrected errors raise a #MC exception in the guest,
+    ///           corrected errors are only logged in the `bank` registers.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # use kvm_bindings::kvm_x86_mce;
+    /// # use kvm_ioctls::{Kvm, Cap};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// if kvm.check_extension(Cap::Mce) {
+    ///     vcpu.setup_mce(kvm.get_mce_cap_supported().unwrap() | 10)
+    ///         .unwrap();
+    ///     // Corrected error (MCi_STATUS.VAL set, MCi_STATUS.UC clear) reported in bank 0.
+    ///     let mce = kvm_x86_mce {
+    ///         status: 1 << 63,
+    ///         bank: 0,
+    ///         ..Default::default()
+    ///     };
+    ///     vcpu.set_mce(&mce).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_mce(&self, mce: &kvm_x86_mce) -> Result<()> {
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_x86_mce struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_X86_SET_MCE(), mce) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
     /// Maps the coalesced MMIO ring page. This allows reading entries from
     /// the ring via [`coalesced_mmio_read()`](VcpuFd::coalesced_mmio_read).
     ///
@@ -1918,6 +3852,49 @@ impl VcpuFd {
             .ok_or(errno::Error::new(libc::EIO))
             .map(|ring| ring.read_entry())
     }
+
+    /// Issues an arbitrary ioctl on the vCPU fd.
+    ///
+    /// This is an escape hatch for the vCPU ioctls which this crate does not wrap yet. Prefer
+    /// the typed methods whenever they exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The ioctl request number.
+    /// * `arg` - The argument of the ioctl, either a pointer or a value cast to a pointer.
+    ///
+    /// Returns the non-negative result of the ioctl, or the `errno` it failed with.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that `req` is a vCPU ioctl and that `arg` is what the kernel
+    /// expects for it, i.e. that any memory it points to is valid and large enough. The
+    /// `kvm_run` structure is decoded by [run](VcpuFd::run) only, so issuing `KVM_RUN` here
+    /// leaves its exit unhandled.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::kvm_mp_state;
+    /// # use kvm_ioctls::Kvm;
+    /// // `KVM_GET_MP_STATE`, which is `_IOR(KVMIO, 0x98, struct kvm_mp_state)`.
+    /// const KVM_GET_MP_STATE: u64 = 0x8004_ae98;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let mut mp_state = kvm_mp_state::default();
+    /// unsafe {
+    ///     vcpu.raw_ioctl(KVM_GET_MP_STATE, &mut mp_state as *mut _ as *mut _)
+    ///         .unwrap()
+    /// };
+    /// assert_eq!(mp_state, vcpu.get_mp_state().unwrap());
+    /// ```
+    pub unsafe fn raw_ioctl(&self, req: u64, arg: *mut c_void) -> Result<i32> {
+        crate::ioctls::raw_ioctl(self, req, arg)
+    }
 }
 
 /// Helper function to create a new `VcpuFd`.
@@ -1952,30 +3929,8 @@ mod tests {
         target_arch = "aarch64"
     ))]
     use crate::cap::Cap;
+    use crate::ioctls::mmap_anonymous;
     use crate::ioctls::system::Kvm;
-    use std::ptr::NonNull;
-
-    // Helper function for memory mapping `size` bytes of anonymous memory.
-    // Panics if the mmap fails.
-    fn mmap_anonymous(size: usize) -> NonNull<u8> {
-        use std::ptr::null_mut;
-
-        let addr = unsafe {
-            libc::mmap(
-                null_mut(),
-                size,
-                libc::PROT_READ | libc::PROT_WRITE,
-                libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
-                -1,
-                0,
-            )
-        };
-        if addr == libc::MAP_FAILED {
-            panic!("mmap failed.");
-        }
-
-        NonNull::new(addr).unwrap().cast()
-    }
 
     #[test]
     fn test_create_vcpu() {
@@ -2036,6 +3991,29 @@ mod tests {
         }
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_set_cpuid_max_num_entries() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        // Fill the `CpuId` with leaves from a range that is not otherwise used.
+        let mut function = 0x4fff_0000;
+        while cpuid.as_slice().len() < KVM_MAX_CPUID_ENTRIES {
+            let entry = kvm_cpuid_entry2 {
+                function,
+                ..Default::default()
+            };
+            cpuid.push(entry).unwrap();
+            function += 1;
+        }
+        // A `CpuId` cannot grow past the limit the kernel accepts.
+        assert!(cpuid.push(kvm_cpuid_entry2::default()).is_err());
+        assert!(CpuId::new(KVM_MAX_CPUID_ENTRIES + 1).is_err());
+        vcpu.set_cpuid2(&cpuid).unwrap();
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_set_cpuid() {
@@ -2114,6 +4092,34 @@ mod tests {
         assert_eq!(vcpu.get_fpu().unwrap().fcw, KVM_FPU_CWD as u16);
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_fpu_state() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let xmm0 = 0x0011_2233_4455_6677_8899_aabb_ccdd_eeff;
+        let mut fpu = FpuState::from(vcpu.get_fpu().unwrap());
+        fpu.set_xmm(0, xmm0).unwrap();
+        assert_eq!(fpu.xmm[0][0], 0xff);
+        assert_eq!(fpu.xmm(1), Some(0));
+        vcpu.set_fpu(&fpu).unwrap();
+
+        let fpu = FpuState::from(vcpu.get_fpu().unwrap());
+        assert_eq!(fpu.xmm(0), Some(xmm0));
+        assert_eq!(fpu.st(7), Some([0; 10]));
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_fpu_state_invalid_index() {
+        let mut fpu = FpuState::default();
+        assert_eq!(fpu.xmm(16), None);
+        assert_eq!(fpu.set_xmm(16, 0).unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(fpu.st(8), None);
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn lapic_test() {
@@ -2129,7 +4135,7 @@ mod tests {
         // The get_lapic ioctl will fail if there is no irqchip created beforehand.
         assert!(vm.create_irq_chip().is_ok());
         let vcpu = vm.create_vcpu(0).unwrap();
-        let mut klapic: kvm_lapic_state = vcpu.get_lapic().unwrap();
+        let mut klapic = vcpu.get_lapic().unwrap();
 
         let reg_offset = 0x300;
         let value = 2_u32;
@@ -2145,6 +4151,48 @@ mod tests {
         assert_eq!(reader.read_u32::<LittleEndian>().unwrap(), value);
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn lapic_reg_test() {
+        const APIC_ID: u32 = 0x20;
+        const APIC_LVTT: u32 = 0x320;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let vcpu = vm.create_vcpu(3).unwrap();
+
+        let mut lapic = vcpu.get_lapic().unwrap();
+        assert_eq!(lapic.lapic_reg(APIC_ID).unwrap() >> 24, 3);
+        assert_eq!(
+            lapic.lapic_reg(APIC_ID).unwrap(),
+            u32::from_le_bytes([0, 0, 0, 3])
+        );
+
+        lapic.set_lapic_reg(APIC_ID, 5 << 24).unwrap();
+        assert_eq!(lapic.regs[0x23], 5);
+        // Masked, periodic timer on vector 0x30.
+        lapic.set_lapic_reg(APIC_LVTT, 0x0003_0030).unwrap();
+        vcpu.set_lapic(&lapic).unwrap();
+
+        let mut lapic = vcpu.get_lapic().unwrap();
+        assert_eq!(lapic.lapic_reg(APIC_ID).unwrap() >> 24, 5);
+        assert_eq!(lapic.lapic_reg(APIC_LVTT).unwrap(), 0x0003_0030);
+
+        // Registers are 16-byte aligned, within the 1 KiB register space.
+        assert_eq!(lapic.lapic_reg(0x24), None);
+        assert_eq!(lapic.lapic_reg(0x400), None);
+        assert!(lapic.lapic_reg(0x3f0).is_some());
+        assert_eq!(
+            lapic.set_lapic_reg(0x24, 0).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            lapic.set_lapic_reg(0x400, 0).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn msrs_test() {
@@ -2213,6 +4261,74 @@ mod tests {
         assert_eq!(mp_state, other_mp_state);
     }
 
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn test_vcpu_raw_ioctl() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut mp_state = kvm_mp_state::default();
+        let ret = unsafe {
+            vcpu.raw_ioctl(
+                KVM_GET_MP_STATE() as u64,
+                &mut mp_state as *mut kvm_mp_state as *mut c_void,
+            )
+        };
+        assert_eq!(ret.unwrap(), 0);
+        assert_eq!(mp_state, vcpu.get_mp_state().unwrap());
+    }
+
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    #[test]
+    fn mpstate_typed_test() {
+        assert_eq!(MpState::from(KVM_MP_STATE_HALTED), MpState::Halted);
+        assert_eq!(u32::from(MpState::Suspended), KVM_MP_STATE_SUSPENDED);
+        assert_eq!(MpState::from(0xdead), MpState::Unknown(0xdead));
+        assert_eq!(u32::from(MpState::Unknown(0xdead)), 0xdead);
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        // Only runnable vcpus are supported without an in-kernel LAPIC.
+        #[cfg(target_arch = "x86_64")]
+        vm.create_irq_chip().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert_eq!(vcpu.get_mp_state_typed().unwrap(), MpState::Runnable);
+
+        // aarch64 only supports the runnable, stopped and suspended states.
+        #[cfg(target_arch = "x86_64")]
+        let state = MpState::Halted;
+        #[cfg(target_arch = "aarch64")]
+        let state = MpState::Stopped;
+        vcpu.set_mp_state_typed(state).unwrap();
+        assert_eq!(vcpu.get_mp_state_typed().unwrap(), state);
+        assert_eq!(vcpu.get_mp_state().unwrap().mp_state, u32::from(state));
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_init_sipi() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let bsp = vm.create_vcpu(0).unwrap();
+        let ap = vm.create_vcpu(1).unwrap();
+        assert_eq!(bsp.get_mp_state_typed().unwrap(), MpState::Runnable);
+        assert_eq!(ap.get_mp_state_typed().unwrap(), MpState::Uninitialized);
+
+        // A SIPI is only accepted after an INIT.
+        assert_eq!(ap.send_sipi(0x10).unwrap_err().errno(), libc::EINVAL);
+        ap.send_init().unwrap();
+        assert_eq!(ap.get_mp_state_typed().unwrap(), MpState::InitReceived);
+        ap.send_sipi(0x10).unwrap();
+        assert_eq!(ap.get_mp_state_typed().unwrap(), MpState::Runnable);
+
+        let sregs = ap.get_sregs().unwrap();
+        assert_eq!(sregs.cs.selector, 0x1000);
+        assert_eq!(sregs.cs.base, 0x10000);
+        assert_eq!(ap.get_regs().unwrap().rip, 0);
+        assert_eq!(ap.send_sipi(0x10).unwrap_err().errno(), libc::EINVAL);
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[test]
     fn xsave_test() {
@@ -2237,6 +4353,70 @@ mod tests {
         assert_eq!(xcrs, other_xcrs);
     }
 
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_xcr0() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        // The XSAVE features the guest can enable are those of its CPUID.
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        vcpu.set_cpuid2(&cpuid).unwrap();
+
+        let mut xcrs = vcpu.get_xcrs().unwrap();
+        // x87 state is always enabled.
+        assert_eq!(xcrs.xcr0(), 1);
+        // x87 and SSE state.
+        xcrs.set_xcr0(0x3).unwrap();
+        assert_eq!(xcrs.nr_xcrs, 1);
+        vcpu.set_xcrs(&xcrs).unwrap();
+        assert_eq!(vcpu.get_xcrs().unwrap().xcr0(), 0x3);
+
+        // The entry is added to an empty state.
+        let mut xcrs = XcrsState::default();
+        assert_eq!(xcrs.xcr0(), 0);
+        xcrs.set_xcr0(0x1).unwrap();
+        assert_eq!(xcrs.nr_xcrs, 1);
+        assert_eq!(xcrs.xcrs[0].value, 0x1);
+        vcpu.set_xcrs(&xcrs).unwrap();
+        assert_eq!(vcpu.get_xcrs().unwrap().xcr0(), 0x1);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_xcr0_invalid_state() {
+        // No room is left for XCR0.
+        let mut xcrs = XcrsState::default();
+        xcrs.nr_xcrs = xcrs.xcrs.len() as u32;
+        for (i, entry) in xcrs.xcrs.iter_mut().enumerate() {
+            entry.xcr = i as u32 + 1;
+        }
+        assert_eq!(xcrs.set_xcr0(0x1).unwrap_err().errno(), libc::ENOSPC);
+
+        // The number of entries is beyond the size of the array.
+        xcrs.nr_xcrs += 1;
+        assert_eq!(xcrs.set_xcr0(0x1).unwrap_err().errno(), libc::EINVAL);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_modify_regs() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let regs = vcpu.get_regs().unwrap();
+        vcpu.modify_regs(|regs| {
+            regs.rip += 0x10;
+            regs.rax = 0x1234;
+        })
+        .unwrap();
+        let other_regs = vcpu.get_regs().unwrap();
+        assert_eq!(other_regs.rip, regs.rip + 0x10);
+        assert_eq!(other_regs.rax, 0x1234);
+        assert_eq!(other_regs.rbx, regs.rbx);
+    }
+
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[test]
     fn debugregs_test() {
@@ -2249,8 +4429,80 @@ mod tests {
         assert_eq!(debugregs, other_debugregs);
     }
 
-    #[cfg(any(
-        target_arch = "x86",
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_debug_regs_breakpoints() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        // Watch the 4-byte writes to 0x2000 with DR1.
+        let mut debug_regs = vcpu.get_debug_regs().unwrap();
+        debug_regs.set_breakpoint_addr(1, 0x2000).unwrap();
+        let dr7 = debug_regs
+            .dr7_builder()
+            .enable_breakpoint(1, BreakpointCondition::Write, 4)
+            .unwrap()
+            .build();
+        // L1, R/W1 = 01 and LEN1 = 11.
+        assert_eq!(dr7, (debug_regs.dr7 & !0xf0_000c) | 0xd0_0004);
+        debug_regs.dr7 = dr7;
+        vcpu.set_debug_regs(&debug_regs).unwrap();
+
+        let debug_regs = vcpu.get_debug_regs().unwrap();
+        assert_eq!(debug_regs.breakpoint_addr(1), Some(0x2000));
+        assert_eq!(debug_regs.dr7, dr7);
+        assert_eq!(
+            debug_regs
+                .dr7_builder()
+                .disable_breakpoint(1)
+                .unwrap()
+                .build()
+                & 0xf0_000c,
+            0
+        );
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_debug_regs_invalid_breakpoint() {
+        let mut debug_regs = DebugRegs::default();
+        assert_eq!(debug_regs.breakpoint_addr(4), None);
+        assert_eq!(
+            debug_regs.set_breakpoint_addr(4, 0).unwrap_err().errno(),
+            libc::EINVAL
+        );
+
+        let builder = debug_regs.dr7_builder();
+        assert_eq!(
+            builder
+                .enable_breakpoint(4, BreakpointCondition::Write, 4)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            builder
+                .enable_breakpoint(0, BreakpointCondition::Write, 3)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            builder
+                .enable_breakpoint(0, BreakpointCondition::Execute, 4)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            builder.disable_breakpoint(4).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[cfg(any(
+        target_arch = "x86",
         target_arch = "x86_64",
         target_arch = "arm",
         target_arch = "aarch64"
@@ -2365,7 +4617,7 @@ mod tests {
                     assert_eq!(dirty_pages, 1);
                 }
                 VcpuExit::SystemEvent(type_, data) => {
-                    assert_eq!(type_, KVM_SYSTEM_EVENT_SHUTDOWN);
+                    assert_eq!(type_, SystemEventType::Shutdown);
                     assert_eq!(data[0], 0);
                     break;
                 }
@@ -2374,6 +4626,291 @@ mod tests {
         }
     }
 
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_arm_vcpu_init_builder() {
+        use crate::ioctls::vm::{ArmVcpuFeature, ArmVcpuInitError};
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let builder = vm.arm_vcpu_init_builder().unwrap();
+        if !vm.check_extension(Cap::ArmEl2) {
+            assert_eq!(
+                builder.with_el2().unwrap_err(),
+                ArmVcpuInitError::Unsupported(ArmVcpuFeature::El2)
+            );
+        }
+        let builder = builder.with_psci_0_2().unwrap();
+        let pmu = vm.check_extension(Cap::ArmPmuV3);
+        let builder = if pmu {
+            builder.with_pmu().unwrap()
+        } else {
+            builder
+        };
+        assert_ne!(builder.kvi().features[0] & (1 << KVM_ARM_VCPU_PSCI_0_2), 0);
+
+        #[rustfmt::skip]
+        let code = [
+            0x00, 0x80, 0xb0, 0x52, /* mov w0, #0x84000000; PSCI_VERSION */
+            0x02, 0x00, 0x00, 0xd4, /* hvc #0x0 */
+            0x20, 0x01, 0x00, 0xb9, /* str w0, [x9]; report the version with an MMIO write */
+            0x00, 0x80, 0xb0, 0x52, /* mov w0, #0x84000000 */
+            0x00, 0x00, 0x1d, 0x32, /* orr w0, w0, #0x08; PSCI_SYSTEM_OFF */
+            0x02, 0x00, 0x00, 0xd4, /* hvc #0x0 */
+            0x00, 0x00, 0x00, 0x14, /* b <this address> */
+        ];
+        let mem_size = 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x10000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        builder.init(&vcpu).unwrap();
+        if pmu {
+            // Without an in-kernel vGIC, the PMU needs no interrupt before being initialized.
+            let pmu_init = kvm_device_attr {
+                group: KVM_ARM_VCPU_PMU_V3_CTRL,
+                attr: u64::from(KVM_ARM_VCPU_PMU_V3_INIT),
+                addr: 0,
+                flags: 0,
+            };
+            vcpu.set_device_attr(&pmu_init).unwrap();
+        }
+        let mmio_addr = guest_addr + mem_size as u64;
+        vcpu.set_core_reg(CoreReg::Pc, guest_addr).unwrap();
+        vcpu.set_core_reg(CoreReg::X9, mmio_addr).unwrap();
+
+        match vcpu.run().unwrap() {
+            VcpuExit::MmioWrite(addr, data) => {
+                assert_eq!(addr, mmio_addr);
+                // PSCI v0.2 or later.
+                assert!(u32::from_le_bytes(data.try_into().unwrap()) >= 2);
+            }
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+        match vcpu.run().unwrap() {
+            VcpuExit::SystemEvent(type_, _) => assert_eq!(type_, SystemEventType::Shutdown),
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_pmu_irq() {
+        use crate::ioctls::arm::GicBuilder;
+        use crate::ioctls::vm::ArmVcpuInitError;
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let builder = vm.arm_vcpu_init_builder().unwrap();
+        assert_eq!(
+            builder.with_pmu_irq(15).unwrap_err(),
+            ArmVcpuInitError::InvalidPmuIrq(15)
+        );
+        assert_eq!(builder.with_pmu_irq(32).unwrap_err().errno(), libc::EINVAL);
+        if !vm.check_extension(Cap::ArmPmuV3) {
+            return;
+        }
+        let builder = builder.with_psci_0_2().unwrap().with_pmu_irq(23).unwrap();
+
+        #[rustfmt::skip]
+        let code = [
+            0x00, 0x80, 0xb0, 0x52, /* mov w0, #0x84000000 */
+            0x00, 0x00, 0x1d, 0x32, /* orr w0, w0, #0x08; PSCI_SYSTEM_OFF */
+            0x02, 0x00, 0x00, 0xd4, /* hvc #0x0 */
+            0x00, 0x00, 0x00, 0x14, /* b <this address> */
+        ];
+        let mem_size = 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x10000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        assert_eq!(vcpu.pmu_set_irq(8).unwrap_err().errno(), libc::EINVAL);
+        // The vGIC is initialized before the vCPU, whose PMU interrupt goes through it.
+        let _gic = GicBuilder::new(&vm)
+            .dist_addr(0x0800_0000)
+            .redist_addr(0x080a_0000)
+            .build()
+            .unwrap();
+        builder.init(&vcpu).unwrap();
+        vcpu.set_core_reg(CoreReg::Pc, guest_addr).unwrap();
+
+        match vcpu.run().unwrap() {
+            VcpuExit::SystemEvent(type_, _) => assert_eq!(type_, SystemEventType::Shutdown),
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_psci_system_reset() {
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0x00, 0x80, 0xb0, 0x52, /* mov w0, #0x84000000 */
+            0x20, 0x01, 0x80, 0x72, /* movk w0, #0x9; PSCI SYSTEM_RESET */
+            0x02, 0x00, 0x00, 0xd4, /* hvc #0x0 */
+            0x00, 0x00, 0x00, 0x14, /* b <this address>; shouldn't get here, but if so loop forever */
+        ];
+
+        let mem_size = 0x10000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x10000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+
+            // Get a mutable slice of `mem_size` from `load_addr`.
+            // This is safe because we mapped it before.
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu_fd = vm.create_vcpu(0).unwrap();
+        let mut kvi = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi).unwrap();
+        kvi.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
+        vcpu_fd.vcpu_init(&kvi).unwrap();
+
+        // Set the PC to the guest address where we loaded the code.
+        let core_reg_base: u64 = 0x6030_0000_0010_0000;
+        vcpu_fd
+            .set_one_reg(core_reg_base + 2 * 32, &(guest_addr as u128).to_le_bytes())
+            .unwrap();
+
+        match vcpu_fd.run().expect("run failed") {
+            VcpuExit::SystemEvent(type_, _) => assert_eq!(type_, SystemEventType::Reset),
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    #[test]
+    fn test_psci_system_suspend() {
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        if !vm.check_extension(Cap::ArmSystemSuspend) {
+            return;
+        }
+        vm.enable_system_suspend().unwrap();
+
+        #[rustfmt::skip]
+        let code = [
+            0x02, 0x00, 0x00, 0xd4, /* hvc #0x0; x0 holds PSCI SYSTEM_SUSPEND */
+            0x00, 0x00, 0x00, 0x14, /* b <this address> */
+            0x20, 0x01, 0x00, 0xf9, /* str x0, [x9]; entry point, reports the context ID */
+            0x00, 0x00, 0x00, 0x14, /* b <this address> */
+        ];
+        let mem_size = 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x10000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let mut kvi = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi).unwrap();
+        kvi.features[0] |= 1 << KVM_ARM_VCPU_PSCI_0_2;
+        vcpu.vcpu_init(&kvi).unwrap();
+
+        let entry_point = guest_addr + 8;
+        let context_id = 0x1234;
+        vcpu.set_core_reg(CoreReg::Pc, guest_addr).unwrap();
+        vcpu.set_core_reg(CoreReg::X0, 0xc400_000e).unwrap();
+        vcpu.set_core_reg(CoreReg::X1, entry_point).unwrap();
+        vcpu.set_core_reg(CoreReg::X2, context_id).unwrap();
+
+        match vcpu.run().unwrap() {
+            VcpuExit::SystemEvent(type_, _) => assert_eq!(type_, SystemEventType::Suspend),
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+        assert_eq!(vcpu.get_core_reg(CoreReg::X1).unwrap(), entry_point);
+        assert_eq!(vcpu.get_core_reg(CoreReg::X2).unwrap(), context_id);
+
+        // The vCPU is reset by the resume, so x9 must be set afterwards.
+        vcpu.resume_from_suspend(&kvi, entry_point, context_id)
+            .unwrap();
+        assert_eq!(vcpu.get_mp_state_typed().unwrap(), MpState::Runnable);
+        let mmio_addr = guest_addr + mem_size as u64;
+        vcpu.set_core_reg(CoreReg::X9, mmio_addr).unwrap();
+        match vcpu.run().unwrap() {
+            VcpuExit::MmioWrite(addr, data) => {
+                assert_eq!(addr, mmio_addr);
+                assert_eq!(u64::from_le_bytes(data.try_into().unwrap()), context_id);
+            }
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_system_event_type() {
+        for type_ in [
+            SystemEventType::Shutdown,
+            SystemEventType::Reset,
+            SystemEventType::Crash,
+            SystemEventType::Wakeup,
+            SystemEventType::Suspend,
+            SystemEventType::SevTerm,
+            SystemEventType::Unknown(0x100),
+        ] {
+            assert_eq!(SystemEventType::from(u32::from(type_)), type_);
+        }
+        assert_eq!(
+            SystemEventType::from(KVM_SYSTEM_EVENT_RESET),
+            SystemEventType::Reset
+        );
+        assert_eq!(
+            SystemEventType::from(0x100),
+            SystemEventType::Unknown(0x100)
+        );
+    }
+
     #[cfg(target_arch = "x86_64")]
     #[test]
     fn test_run_code() {
@@ -2502,42 +5039,236 @@ mod tests {
     }
 
     #[test]
-    #[cfg(any(
-        target_arch = "x86",
-        target_arch = "x86_64",
-        target_arch = "arm",
-        target_arch = "aarch64"
-    ))]
-    fn test_faulty_vcpu_fd() {
-        use std::os::unix::io::FromRawFd;
+    #[cfg(target_arch = "x86_64")]
+    fn test_dirty_page_indices() {
+        use std::io::Write;
 
-        let badf_errno = libc::EBADF;
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0xc6, 0x06, 0x00, 0x0f, 0x00, /* movb $0, (0x0f00); dirty page 0 */
+            0xb8, 0x00, 0x3f, /* mov $0x3f00, %ax */
+            0x8e, 0xd8, /* mov %ax, %ds */
+            0xc6, 0x06, 0x00, 0x00, 0x00, /* movb $0, (0x0000); dirty page 63 */
+            0xb8, 0x00, 0x40, /* mov $0x4000, %ax */
+            0x8e, 0xd8, /* mov %ax, %ds */
+            0xc6, 0x06, 0x00, 0x00, 0x00, /* movb $0, (0x0000); dirty page 64 */
+            0xf4, /* hlt */
+        ];
 
-        let mut faulty_vcpu_fd = VcpuFd {
-            vcpu: unsafe { File::from_raw_fd(-2) },
-            kvm_run_ptr: KvmRunWrapper {
-                kvm_run_ptr: mmap_anonymous(10).cast(),
-                mmap_size: 10,
-            },
-            coalesced_mmio_ring: None,
+        // 70 pages, so that the last word of the bitmap is only partially used.
+        let mem_size = 70 * 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let slot: u32 = 0;
+        let mem_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: 0,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: KVM_MEM_LOG_DIRTY_PAGES,
         };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+        }
 
-        assert_eq!(
-            faulty_vcpu_fd.get_mp_state().unwrap_err().errno(),
-            badf_errno
-        );
-        assert_eq!(
-            faulty_vcpu_fd
-                .set_mp_state(kvm_mp_state::default())
-                .unwrap_err()
-                .errno(),
-            badf_errno
-        );
-        assert_eq!(
-            faulty_vcpu_fd.get_vcpu_events().unwrap_err().errno(),
-            badf_errno
-        );
-        assert_eq!(
+        unsafe {
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu_fd = vm.create_vcpu(0).unwrap();
+        let mut vcpu_sregs = vcpu_fd.get_sregs().unwrap();
+        vcpu_sregs.cs.base = 0;
+        vcpu_sregs.cs.selector = 0;
+        vcpu_sregs.ds.base = 0;
+        vcpu_sregs.ds.selector = 0;
+        vcpu_fd.set_sregs(&vcpu_sregs).unwrap();
+        let mut vcpu_regs = vcpu_fd.get_regs().unwrap();
+        vcpu_regs.rip = 0;
+        vcpu_regs.rflags = 2;
+        vcpu_fd.set_regs(&vcpu_regs).unwrap();
+
+        match vcpu_fd.run().expect("run failed") {
+            VcpuExit::Hlt => {}
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+        let dirty_pages: Vec<u64> = vm.dirty_page_indices(slot, mem_size).unwrap().collect();
+        assert_eq!(dirty_pages, vec![0, 63, 64]);
+        // Fetching the dirty log clears it.
+        assert_eq!(vm.dirty_page_indices(slot, mem_size).unwrap().count(), 0);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_vcpu_events_builder() {
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        if !kvm.check_extension(Cap::VcpuEvents) {
+            return;
+        }
+        let vm = kvm.create_vm().unwrap();
+        #[rustfmt::skip]
+        let handler = [
+            0xb0, 0x0d, /* mov $0x0d, %al */
+            0xe6, 0x10, /* out %al, $0x10 */
+            0xf4, /* hlt */
+        ];
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            let slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            // The real mode IVT entry of #GP points to the handler at 0000:2000.
+            slice[13 * 4..13 * 4 + 4].copy_from_slice(&[0x00, 0x20, 0x00, 0x00]);
+            // The vCPU starts on a `hlt`, which it only skips by taking the exception.
+            slice[0x1000] = 0xf4;
+            (&mut slice[0x2000..]).write_all(&handler).unwrap();
+        }
+
+        let mut vcpu_fd = vm.create_vcpu(0).unwrap();
+        let mut vcpu_sregs = vcpu_fd.get_sregs().unwrap();
+        vcpu_sregs.cs.base = 0;
+        vcpu_sregs.cs.selector = 0;
+        vcpu_sregs.ss.base = 0;
+        vcpu_sregs.ss.selector = 0;
+        vcpu_fd.set_sregs(&vcpu_sregs).unwrap();
+        let mut vcpu_regs = vcpu_fd.get_regs().unwrap();
+        vcpu_regs.rip = 0x1000;
+        vcpu_regs.rsp = 0x3000;
+        vcpu_regs.rflags = 2;
+        vcpu_fd.set_regs(&vcpu_regs).unwrap();
+
+        let events = VcpuEventsBuilder::from(vcpu_fd.get_vcpu_events().unwrap())
+            .inject_exception(13, Some(0))
+            .nmi_pending(false)
+            .build();
+        assert_eq!(events.exception.injected, 1);
+        assert_eq!(events.exception.nr, 13);
+        assert_eq!(events.exception.has_error_code, 1);
+        assert_ne!(events.flags & KVM_VCPUEVENT_VALID_NMI_PENDING, 0);
+        vcpu_fd.set_vcpu_events(&events).unwrap();
+        let events = vcpu_fd.get_vcpu_events().unwrap();
+        assert_eq!(events.exception.injected, 1);
+        assert_eq!(events.exception.nr, 13);
+
+        match vcpu_fd.run().expect("run failed") {
+            VcpuExit::IoOut(0x10, data) => assert_eq!(data, [0x0d]),
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+        assert_eq!(vcpu_fd.get_vcpu_events().unwrap().exception.injected, 0);
+
+        let events = VcpuEventsBuilder::new().inject_interrupt(0x20).build();
+        assert_eq!(events.interrupt.injected, 1);
+        assert_eq!(events.interrupt.nr, 0x20);
+        assert_eq!(events.flags, 0);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_pit_speaker_dummy() {
+        use crate::{PitConfig, PitError};
+
+        // Reads the speaker port from the guest, returning whether it exits to userspace.
+        fn read_speaker_port(config: PitConfig) -> bool {
+            let kvm = Kvm::new().unwrap();
+            let vm = kvm.create_vm().unwrap();
+            vm.create_irq_chip().unwrap();
+            vm.create_pit(config).unwrap();
+            assert_eq!(vm.create_pit(config), Err(PitError::AlreadyExists));
+
+            #[rustfmt::skip]
+            let code = [
+                0xe4, 0x61, /* in $0x61, %al */
+                0xe6, 0x10, /* out %al, $0x10 */
+            ];
+            let mem_size = 0x1000;
+            let load_addr = mmap_anonymous(mem_size).as_ptr();
+            let mem_region = kvm_userspace_memory_region {
+                slot: 0,
+                guest_phys_addr: 0,
+                memory_size: mem_size as u64,
+                userspace_addr: load_addr as u64,
+                flags: 0,
+            };
+            unsafe {
+                vm.set_user_memory_region(mem_region).unwrap();
+                std::ptr::copy_nonoverlapping(code.as_ptr(), load_addr, code.len());
+            }
+
+            let mut vcpu_fd = vm.create_vcpu(0).unwrap();
+            let mut vcpu_sregs = vcpu_fd.get_sregs().unwrap();
+            vcpu_sregs.cs.base = 0;
+            vcpu_sregs.cs.selector = 0;
+            vcpu_fd.set_sregs(&vcpu_sregs).unwrap();
+            let mut vcpu_regs = vcpu_fd.get_regs().unwrap();
+            vcpu_regs.rip = 0;
+            vcpu_regs.rflags = 2;
+            vcpu_fd.set_regs(&vcpu_regs).unwrap();
+
+            match vcpu_fd.run().expect("run failed") {
+                VcpuExit::IoIn(0x61, _) => true,
+                // `hlt` does not exit with an in-kernel irqchip.
+                VcpuExit::IoOut(0x10, _) => false,
+                r => panic!("unexpected exit reason: {:?}", r),
+            }
+        }
+
+        let kvm = Kvm::new().unwrap();
+        if !kvm.check_extension(Cap::Pit2) {
+            let vm = kvm.create_vm().unwrap();
+            assert_eq!(vm.create_pit(PitConfig::new()), Err(PitError::Unsupported));
+            return;
+        }
+        assert!(!read_speaker_port(PitConfig::new().speaker_dummy(true)));
+        assert!(read_speaker_port(PitConfig::new()));
+    }
+
+    #[test]
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    fn test_faulty_vcpu_fd() {
+        use std::os::unix::io::FromRawFd;
+
+        let badf_errno = libc::EBADF;
+
+        let mut faulty_vcpu_fd = VcpuFd {
+            vcpu: unsafe { File::from_raw_fd(-2) },
+            kvm_run_ptr: KvmRunWrapper {
+                kvm_run_ptr: mmap_anonymous(10).cast(),
+                mmap_size: 10,
+            },
+            coalesced_mmio_ring: None,
+        };
+
+        assert_eq!(
+            faulty_vcpu_fd.get_mp_state().unwrap_err().errno(),
+            badf_errno
+        );
+        assert_eq!(
+            faulty_vcpu_fd
+                .set_mp_state(kvm_mp_state::default())
+                .unwrap_err()
+                .errno(),
+            badf_errno
+        );
+        assert_eq!(
+            faulty_vcpu_fd.get_vcpu_events().unwrap_err().errno(),
+            badf_errno
+        );
+        assert_eq!(
             faulty_vcpu_fd
                 .set_vcpu_events(&kvm_vcpu_events::default())
                 .unwrap_err()
@@ -2733,7 +5464,7 @@ mod tests {
         );
         assert_eq!(
             faulty_vcpu_fd
-                .vcpu_finalize(&(KVM_ARM_VCPU_SVE as i32))
+                .vcpu_finalize(KVM_ARM_VCPU_SVE as i32)
                 .unwrap_err()
                 .errno(),
             badf_errno
@@ -2837,6 +5568,100 @@ mod tests {
         assert!(vcpu.get_one_reg(PSTATE_REG_ID, &mut [0_u8; 7]).is_err());
     }
 
+    #[test]
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn test_one_reg_u128() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi)
+            .expect("Cannot get preferred target");
+        vcpu.vcpu_init(&kvi).expect("Cannot initialize vcpu");
+
+        // Ids of `fp_regs.vregs[0]` and `fp_regs.vregs[31]` as listed in the KVM API
+        // documentation.
+        const V0_REG_ID: u64 = 0x6040_0000_0010_0054;
+        const V31_REG_ID: u64 = 0x6040_0000_0010_00d0;
+        let value: u128 = 0x0123_4567_89ab_cdef_fedc_ba98_7654_3210;
+        vcpu.set_one_reg_u128(V0_REG_ID, value).unwrap();
+        vcpu.set_one_reg_u128(V31_REG_ID, !value).unwrap();
+        assert_eq!(vcpu.get_one_reg_u128(V0_REG_ID).unwrap(), value);
+        assert_eq!(vcpu.get_one_reg_u128(V31_REG_ID).unwrap(), !value);
+
+        // The 64-bit PSTATE register is rejected without calling into the kernel.
+        const PSTATE_REG_ID: u64 = 0x6030_0000_0010_0042;
+        assert_eq!(
+            vcpu.get_one_reg_u128(PSTATE_REG_ID).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            vcpu.set_one_reg_u128(PSTATE_REG_ID, 0).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_core_reg() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi)
+            .expect("Cannot get preferred target");
+        vcpu.vcpu_init(&kvi).expect("Cannot initialize vcpu");
+
+        // Ids as listed in the KVM API documentation.
+        assert_eq!(CoreReg::X0.id(), 0x6030_0000_0010_0000);
+        assert_eq!(CoreReg::X30.id(), 0x6030_0000_0010_003c);
+        assert_eq!(CoreReg::Sp.id(), 0x6030_0000_0010_003e);
+        assert_eq!(CoreReg::Pc.id(), 0x6030_0000_0010_0040);
+        assert_eq!(CoreReg::Pstate.id(), 0x6030_0000_0010_0042);
+        assert_eq!(CoreReg::SpEl1.id(), 0x6030_0000_0010_0044);
+        assert_eq!(CoreReg::ElrEl1.id(), 0x6030_0000_0010_0046);
+
+        vcpu.set_core_reg(CoreReg::Pc, 0x8008_0000).unwrap();
+        vcpu.set_core_reg(CoreReg::Sp, 0x8100_0000).unwrap();
+        vcpu.set_core_reg(CoreReg::X30, 0x1234).unwrap();
+        assert_eq!(vcpu.get_core_reg(CoreReg::Pc).unwrap(), 0x8008_0000);
+        assert_eq!(vcpu.get_core_reg(CoreReg::Sp).unwrap(), 0x8100_0000);
+        assert_eq!(vcpu.get_core_reg(CoreReg::X30).unwrap(), 0x1234);
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_get_reg_ids() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert_eq!(vcpu.get_reg_ids().unwrap_err().errno(), libc::ENOEXEC);
+
+        let builder = vm.arm_vcpu_init_builder().unwrap();
+        builder.init(&vcpu).unwrap();
+        let ids = vcpu.get_reg_ids().unwrap();
+        let mut reg_list = RegList::new(ids.len()).unwrap();
+        vcpu.get_reg_list(&mut reg_list).unwrap();
+        assert_eq!(reg_list.as_slice(), &ids[..]);
+
+        if !vm.check_extension(Cap::ArmEl2) {
+            return;
+        }
+        // The EL2 system registers are listed on top of the EL1 ones.
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let builder = vm.arm_vcpu_init_builder().unwrap().with_el2().unwrap();
+        builder.init(&vcpu).unwrap();
+        let el2_ids = vcpu.get_reg_ids().unwrap();
+        assert!(el2_ids.len() > ids.len());
+        for id in el2_ids {
+            let mut value = vec![0u8; reg_size(id)];
+            vcpu.get_one_reg_bytes(id, &mut value).unwrap();
+        }
+    }
+
     #[test]
     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     fn test_get_reg_list() {
@@ -3048,114 +5873,745 @@ mod tests {
             sync_regs.regs.rax = 0x8000;
             sync_regs.regs.rflags = 2;
 
-            // Initialize the sync_reg flags
-            vcpu.set_sync_valid_reg(SyncReg::Register);
-            vcpu.set_sync_valid_reg(SyncReg::SystemRegister);
-            vcpu.set_sync_valid_reg(SyncReg::VcpuEvents);
-            vcpu.set_sync_dirty_reg(SyncReg::Register);
-            vcpu.set_sync_dirty_reg(SyncReg::SystemRegister);
-            vcpu.set_sync_dirty_reg(SyncReg::VcpuEvents);
+            // Initialize the sync_reg flags
+            vcpu.set_sync_valid_reg(SyncReg::Register);
+            vcpu.set_sync_valid_reg(SyncReg::SystemRegister);
+            vcpu.set_sync_valid_reg(SyncReg::VcpuEvents);
+            vcpu.set_sync_dirty_reg(SyncReg::Register);
+            vcpu.set_sync_dirty_reg(SyncReg::SystemRegister);
+            vcpu.set_sync_dirty_reg(SyncReg::VcpuEvents);
+
+            // hlt is the only expected return from guest execution
+            assert!(matches!(vcpu.run().expect("run failed"), VcpuExit::Hlt));
+
+            let regs = vcpu.get_regs().unwrap();
+
+            // The guest saw the `rax` set through the sync regs and incremented it.
+            let sync_regs = vcpu.sync_regs();
+            assert_eq!(regs, sync_regs.regs);
+            assert_eq!(sync_regs.regs.rax, 0x8001);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_mce() {
+        let kvm = Kvm::new().unwrap();
+        if !kvm.check_extension(Cap::Mce) {
+            return;
+        }
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mcg_cap = kvm.get_mce_cap_supported().unwrap();
+
+        // The bank count must be between 1 and `KVM_MAX_MCE_BANKS`.
+        assert_eq!(vcpu.setup_mce(mcg_cap).unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(
+            vcpu.setup_mce(mcg_cap | (KVM_MAX_MCE_BANKS + 1))
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        let banks = 10;
+        vcpu.setup_mce(mcg_cap | banks).unwrap();
+
+        // Inject a corrected error in the last bank.
+        let mut mce = kvm_x86_mce {
+            status: 1 << 63,
+            bank: (banks - 1) as u8,
+            ..Default::default()
+        };
+        vcpu.set_mce(&mce).unwrap();
+
+        // Banks past the configured count are rejected.
+        mce.bank = banks as u8;
+        assert_eq!(vcpu.set_mce(&mce).unwrap_err().errno(), libc::EINVAL);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_translate_gva() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        assert!(vcpu.translate_gva(0x10000).is_ok());
+        assert_eq!(vcpu.translate_gva(0x10000).unwrap().valid, 1);
+        assert_eq!(
+            vcpu.translate_gva(0x10000).unwrap().physical_address,
+            0x10000
+        );
+        assert!(vcpu.translate_gva(u64::MAX).is_ok());
+        assert_eq!(vcpu.translate_gva(u64::MAX).unwrap().valid, 0);
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_vcpu_attr() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let dist_attr = kvm_bindings::kvm_device_attr {
+            group: KVM_ARM_VCPU_PMU_V3_CTRL,
+            attr: u64::from(KVM_ARM_VCPU_PMU_V3_INIT),
+            addr: 0x0,
+            flags: 0,
+        };
+
+        assert!(vcpu.has_device_attr(&dist_attr).is_err());
+        assert!(vcpu.set_device_attr(&dist_attr).is_err());
+        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi)
+            .expect("Cannot get preferred target");
+        kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PSCI_0_2 | 1 << KVM_ARM_VCPU_PMU_V3;
+        assert!(vcpu.vcpu_init(&kvi).is_ok());
+        assert!(vcpu.has_device_attr(&dist_attr).is_ok());
+        assert!(vcpu.set_device_attr(&dist_attr).is_ok());
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_vcpu_finalize() {
+        let kvm = Kvm::new().unwrap();
+        if !kvm.check_extension(Cap::ArmSve) {
+            return;
+        }
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi)
+            .expect("Cannot get preferred target");
+        kvi.features[0] |= 1 << KVM_ARM_VCPU_SVE;
+        vcpu.vcpu_init(&kvi).unwrap();
+
+        // Registers depending on SVE are not accessible until it is finalized.
+        let mut reg_list = RegList::new(500).unwrap();
+        assert_eq!(
+            vcpu.get_reg_list(&mut reg_list).unwrap_err().errno(),
+            libc::EPERM
+        );
+        vcpu.vcpu_finalize(KVM_ARM_VCPU_SVE as i32).unwrap();
+        vcpu.get_reg_list(&mut reg_list).unwrap();
+
+        // SVE can only be finalized once.
+        assert_eq!(
+            vcpu.vcpu_finalize(KVM_ARM_VCPU_SVE as i32)
+                .unwrap_err()
+                .errno(),
+            libc::EPERM
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_one_reg_bytes() {
+        let kvm = Kvm::new().unwrap();
+        if !kvm.check_extension(Cap::ArmSve) {
+            return;
+        }
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi)
+            .expect("Cannot get preferred target");
+        kvi.features[0] |= 1 << KVM_ARM_VCPU_SVE;
+        vcpu.vcpu_init(&kvi).unwrap();
+        vcpu.vcpu_finalize(KVM_ARM_VCPU_SVE as i32).unwrap();
+
+        // Id of the first slice of `z31` as listed in the KVM API documentation.
+        const Z31_REG_ID: u64 = 0x6080_0000_0015_03e0;
+        assert_eq!(reg_size(Z31_REG_ID), 256);
+        // Only the bytes within the vector length, at least 128 bits, are kept by KVM.
+        let mut value = [0_u8; 256];
+        for (i, byte) in value.iter_mut().take(16).enumerate() {
+            *byte = i as u8 + 1;
+        }
+        vcpu.set_one_reg_bytes(Z31_REG_ID, &value).unwrap();
+        let mut buf = [0xff_u8; 256];
+        vcpu.get_one_reg_bytes(Z31_REG_ID, &mut buf).unwrap();
+        assert_eq!(buf, value);
+
+        // Buffers of any other size are rejected.
+        assert_eq!(
+            vcpu.set_one_reg_bytes(Z31_REG_ID, &value[..128])
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        let mut buf = [0_u8; 512];
+        assert_eq!(
+            vcpu.get_one_reg_bytes(Z31_REG_ID, &mut buf)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_pointer_authentication() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi)
+            .expect("Cannot get preferred target");
+        if kvm.check_extension(Cap::ArmPtrAuthAddress) {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PTRAUTH_ADDRESS;
+        }
+        if kvm.check_extension(Cap::ArmPtrAuthGeneric) {
+            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PTRAUTH_GENERIC;
+        }
+        assert!(vcpu.vcpu_init(&kvi).is_ok());
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_userspace_rdmsr_exit() {
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0x0F, 0x32, /* rdmsr */
+            0xF4        /* hlt */
+        ];
+
+        assert_eq!(
+            vm.supported_msr_exit_reasons(),
+            kvm.supported_msr_exit_reasons()
+        );
+        if !vm.check_extension(Cap::X86UserSpaceMsr) {
+            return;
+        }
+        vm.enable_userspace_msr(MsrExitReason::Unknown).unwrap();
+
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x1000;
+        let slot: u32 = 0;
+        let mem_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+
+            // Get a mutable slice of `mem_size` from `load_addr`.
+            // This is safe because we mapped it before.
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+
+        // Set up special registers
+        let mut vcpu_sregs = vcpu.get_sregs().unwrap();
+        assert_ne!(vcpu_sregs.cs.base, 0);
+        assert_ne!(vcpu_sregs.cs.selector, 0);
+        vcpu_sregs.cs.base = 0;
+        vcpu_sregs.cs.selector = 0;
+        vcpu.set_sregs(&vcpu_sregs).unwrap();
+
+        // Set the Instruction Pointer to the guest address where we loaded
+        // the code, and RCX to the MSR to be read.
+        let mut vcpu_regs = vcpu.get_regs().unwrap();
+        vcpu_regs.rip = guest_addr;
+        vcpu_regs.rcx = 0x474f4f00;
+        vcpu.set_regs(&vcpu_regs).unwrap();
+
+        match vcpu.run().unwrap() {
+            VcpuExit::X86Rdmsr(exit) => {
+                assert_eq!(exit.reason, MsrExitReason::Unknown);
+                assert_eq!(exit.index, 0x474f4f00);
+            }
+            e => panic!("Unexpected exit: {:?}", e),
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_msr_filter_rdmsr_exit() {
+        use crate::ioctls::vm::MsrFilterBuilder;
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0x0F, 0x32, /* rdmsr */
+            0xF4        /* hlt */
+        ];
+
+        if !vm.check_extension(Cap::X86UserSpaceMsr) || !vm.check_extension(Cap::X86MsrFilter) {
+            return;
+        }
+        vm.enable_userspace_msr(MsrExitReason::Filter).unwrap();
+        // Deny the reads of the TSC.
+        MsrFilterBuilder::default_allow()
+            .deny_range(0x10, 1)
+            .apply(&vm)
+            .unwrap();
+
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x1000;
+        let slot: u32 = 0;
+        let mem_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+
+            // Get a mutable slice of `mem_size` from `load_addr`.
+            // This is safe because we mapped it before.
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+
+        let mut vcpu_sregs = vcpu.get_sregs().unwrap();
+        vcpu_sregs.cs.base = 0;
+        vcpu_sregs.cs.selector = 0;
+        vcpu.set_sregs(&vcpu_sregs).unwrap();
+
+        let mut vcpu_regs = vcpu.get_regs().unwrap();
+        vcpu_regs.rip = guest_addr;
+        vcpu_regs.rcx = 0x10;
+        vcpu.set_regs(&vcpu_regs).unwrap();
+
+        match vcpu.run().unwrap() {
+            VcpuExit::X86Rdmsr(exit) => {
+                assert_eq!(exit.reason, MsrExitReason::Filter);
+                assert_eq!(exit.index, 0x10);
+            }
+            e => panic!("Unexpected exit: {:?}", e),
+        }
+
+        MsrFilterBuilder::clear(&vm).unwrap();
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_userspace_hypercall_exit() {
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        // Use `vmcall` or `vmmcall` depending on what's supported.
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        let supports_vmcall = cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 1)
+            .map_or(false, |entry| entry.ecx & (1 << 5) != 0);
+        let supports_vmmcall = cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 0x8000_0001)
+            .map_or(false, |entry| entry.ecx & (1 << 2) != 0);
+        #[rustfmt::skip]
+        let code = if supports_vmcall {
+            [
+                0x0F, 0x01, 0xC1, /* vmcall */
+                0xF4              /* hlt */
+            ]
+        } else if supports_vmmcall {
+            [
+                0x0F, 0x01, 0xD9, /* vmmcall */
+                0xF4              /* hlt */
+            ]
+        } else {
+            return;
+        };
+
+        if !vm.check_extension(Cap::ExitHypercall) {
+            return;
+        }
+        const KVM_HC_MAP_GPA_RANGE: u64 = 12;
+        let cap = kvm_enable_cap {
+            cap: Cap::ExitHypercall as u32,
+            args: [1 << KVM_HC_MAP_GPA_RANGE, 0, 0, 0],
+            ..Default::default()
+        };
+        vm.enable_cap(&cap).unwrap();
+
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x1000;
+        let slot: u32 = 0;
+        let mem_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+
+            // Get a mutable slice of `mem_size` from `load_addr`.
+            // This is safe because we mapped it before.
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+
+        // Set up special registers
+        let mut vcpu_sregs = vcpu.get_sregs().unwrap();
+        assert_ne!(vcpu_sregs.cs.base, 0);
+        assert_ne!(vcpu_sregs.cs.selector, 0);
+        vcpu_sregs.cs.base = 0;
+        vcpu_sregs.cs.selector = 0;
+        vcpu.set_sregs(&vcpu_sregs).unwrap();
+
+        // Set the Instruction Pointer to the guest address where we loaded
+        // the code, and RCX to the MSR to be read.
+        let mut vcpu_regs = vcpu.get_regs().unwrap();
+        vcpu_regs.rip = guest_addr;
+        vcpu_regs.rax = KVM_HC_MAP_GPA_RANGE;
+        vcpu_regs.rbx = 0x1234000;
+        vcpu_regs.rcx = 1;
+        vcpu_regs.rdx = 0;
+        vcpu.set_regs(&vcpu_regs).unwrap();
+
+        match vcpu.run().unwrap() {
+            VcpuExit::Hypercall(exit) => {
+                assert_eq!(exit.nr, KVM_HC_MAP_GPA_RANGE);
+                assert_eq!(exit.args[0], 0x1234000);
+                assert_eq!(exit.args[1], 1);
+                assert_eq!(exit.args[2], 0);
+            }
+            e => panic!("Unexpected exit: {:?}", e),
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_userspace_hypercall_exit_sw_protected_vm() {
+        use crate::ioctls::vm::KVM_HC_MAP_GPA_RANGE;
+        use std::io::Write;
+
+        // `KVM_X86_SW_PROTECTED_VM`, which is missing from the `kvm-bindings` used by this
+        // crate.
+        const KVM_X86_SW_PROTECTED_VM: u64 = 1;
+
+        let kvm = Kvm::new().unwrap();
+        // Guests of software-protected VMs convert their memory with `KVM_HC_MAP_GPA_RANGE`.
+        let vm = match kvm.create_vm_with_type(KVM_X86_SW_PROTECTED_VM) {
+            Ok(vm) => vm,
+            Err(_) => return,
+        };
+        if vm.supported_exit_hypercalls() & (1 << KVM_HC_MAP_GPA_RANGE) == 0 {
+            return;
+        }
+        assert_eq!(
+            vm.enable_exit_hypercall(1 << 63).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        vm.enable_exit_hypercall(1 << KVM_HC_MAP_GPA_RANGE).unwrap();
+
+        // Use `vmcall` or `vmmcall` depending on what's supported.
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        let supports_vmcall = cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 1)
+            .map_or(false, |entry| entry.ecx & (1 << 5) != 0);
+        let supports_vmmcall = cpuid
+            .as_slice()
+            .iter()
+            .find(|entry| entry.function == 0x8000_0001)
+            .map_or(false, |entry| entry.ecx & (1 << 2) != 0);
+        #[rustfmt::skip]
+        let code = if supports_vmcall {
+            [
+                0x0F, 0x01, 0xC1, /* vmcall */
+                0xF4              /* hlt */
+            ]
+        } else if supports_vmmcall {
+            [
+                0x0F, 0x01, 0xD9, /* vmmcall */
+                0xF4              /* hlt */
+            ]
+        } else {
+            return;
+        };
+
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x1000;
+        let slot: u32 = 0;
+        let mem_region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+
+            // Get a mutable slice of `mem_size` from `load_addr`.
+            // This is safe because we mapped it before.
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+
+        // Set up special registers
+        let mut vcpu_sregs = vcpu.get_sregs().unwrap();
+        assert_ne!(vcpu_sregs.cs.base, 0);
+        assert_ne!(vcpu_sregs.cs.selector, 0);
+        vcpu_sregs.cs.base = 0;
+        vcpu_sregs.cs.selector = 0;
+        vcpu.set_sregs(&vcpu_sregs).unwrap();
+
+        // Set the Instruction Pointer to the guest address where we loaded
+        // the code, and the hypercall number and arguments.
+        let mut vcpu_regs = vcpu.get_regs().unwrap();
+        vcpu_regs.rip = guest_addr;
+        vcpu_regs.rax = KVM_HC_MAP_GPA_RANGE;
+        vcpu_regs.rbx = 0x1234000;
+        vcpu_regs.rcx = 1;
+        vcpu_regs.rdx = 0;
+        vcpu.set_regs(&vcpu_regs).unwrap();
+
+        match vcpu.run().unwrap() {
+            VcpuExit::Hypercall(exit) => {
+                assert_eq!(exit.nr, KVM_HC_MAP_GPA_RANGE);
+                assert_eq!(exit.args[0], 0x1234000);
+                assert_eq!(exit.args[1], 1);
+                assert_eq!(exit.args[2], 0);
+                *exit.ret = 0x2a;
+            }
+            e => panic!("Unexpected exit: {:?}", e),
+        }
+        // The return value is handed to the guest in RAX when resuming.
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Hlt));
+        assert_eq!(vcpu.get_regs().unwrap().rax, 0x2a);
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn test_bus_lock_exit() {
+        use crate::ioctls::vm::BusLockExitMode;
+        use std::io::Write;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0xF0, 0x01, 0x06, 0x3F, 0x10, /* lock add [0x103f], ax */
+            0xF4,                         /* hlt */
+        ];
+
+        if !vm.check_extension(Cap::X86BusLockExit) {
+            assert_eq!(
+                vm.enable_bus_lock_exit(BusLockExitMode::Exit)
+                    .unwrap_err()
+                    .errno(),
+                libc::EINVAL
+            );
+            return;
+        }
+        vm.enable_bus_lock_exit(BusLockExitMode::Exit).unwrap();
+
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x1000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
 
-            // hlt is the only expected return from guest execution
-            assert!(matches!(vcpu.run().expect("run failed"), VcpuExit::Hlt));
+            // Get a mutable slice of `mem_size` from `load_addr`.
+            // This is safe because we mapped it before.
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
 
-            let regs = vcpu.get_regs().unwrap();
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let mut vcpu_sregs = vcpu.get_sregs().unwrap();
+        vcpu_sregs.cs.base = 0;
+        vcpu_sregs.cs.selector = 0;
+        vcpu_sregs.ds.base = 0;
+        vcpu_sregs.ds.selector = 0;
+        vcpu.set_sregs(&vcpu_sregs).unwrap();
+        let mut vcpu_regs = vcpu.get_regs().unwrap();
+        vcpu_regs.rip = guest_addr;
+        vcpu.set_regs(&vcpu_regs).unwrap();
 
-            let sync_regs = vcpu.sync_regs();
-            assert_eq!(regs, sync_regs.regs);
-            assert_eq!(sync_regs.regs.rax, 0x8001);
+        // The 2-byte locked access at 0x103f crosses the cache line boundary at 0x1040, which
+        // is a split lock. The bus lock may also be reported alongside another exit reason,
+        // hence the flag.
+        let exit = vcpu.run().unwrap();
+        let is_bus_lock_exit = matches!(exit, VcpuExit::X86BusLock);
+        assert!(is_bus_lock_exit || vcpu.bus_lock_detected());
+        if is_bus_lock_exit {
+            assert!(vcpu.bus_lock_detected());
         }
     }
 
     #[test]
-    #[cfg(target_arch = "x86_64")]
-    fn test_translate_gva() {
+    fn test_set_signal_mask() {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
         let vcpu = vm.create_vcpu(0).unwrap();
-        assert!(vcpu.translate_gva(0x10000).is_ok());
-        assert_eq!(vcpu.translate_gva(0x10000).unwrap().valid, 1);
+
+        vcpu.set_signal_mask(&[!(1 << (libc::SIGUSR1 - 1))])
+            .unwrap();
+        vcpu.clear_signal_mask().unwrap();
+        // The set must have the size of the kernel signal set.
         assert_eq!(
-            vcpu.translate_gva(0x10000).unwrap().physical_address,
-            0x10000
+            vcpu.set_signal_mask(&[0, 0]).unwrap_err().errno(),
+            libc::EINVAL
         );
-        assert!(vcpu.translate_gva(u64::MAX).is_ok());
-        assert_eq!(vcpu.translate_gva(u64::MAX).unwrap().valid, 0);
     }
 
+    #[cfg(target_arch = "x86_64")]
     #[test]
-    #[cfg(target_arch = "aarch64")]
-    fn test_vcpu_attr() {
+    fn test_run_uninterrupted() {
+        use std::io::Write;
+        use std::sync::atomic::{AtomicU8, AtomicUsize};
+        use std::sync::mpsc::channel;
+        use std::thread;
+        use std::time::Duration;
+        use vmm_sys_util::signal::{register_signal_handler, SIGRTMIN};
+
+        static SIGNALS: AtomicUsize = AtomicUsize::new(0);
+        extern "C" fn handle_signal(_: libc::c_int, _: *mut libc::siginfo_t, _: *mut libc::c_void) {
+            SIGNALS.fetch_add(1, Ordering::SeqCst);
+        }
+        let signum = SIGRTMIN() + 2;
+        register_signal_handler(signum, handle_signal).unwrap();
+
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        let vcpu = vm.create_vcpu(0).unwrap();
+        #[rustfmt::skip]
+        let code = [
+            0xFE, 0x06, 0x01, 0x30,       /* inc byte [0x3001] */
+            0x80, 0x3E, 0x00, 0x30, 0x00, /* cmp byte [0x3000], 0 */
+            0x74, 0xF5,                   /* je 0x1000 */
+            0xE6, 0x10,                   /* out 0x10, al */
+            0xF4,                         /* hlt */
+        ];
 
-        let dist_attr = kvm_bindings::kvm_device_attr {
-            group: KVM_ARM_VCPU_PMU_V3_CTRL,
-            attr: u64::from(KVM_ARM_VCPU_PMU_V3_INIT),
-            addr: 0x0,
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x1000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
             flags: 0,
         };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
 
-        assert!(vcpu.has_device_attr(&dist_attr).is_err());
-        assert!(vcpu.set_device_attr(&dist_attr).is_err());
-        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
-        vm.get_preferred_target(&mut kvi)
-            .expect("Cannot get preferred target");
-        kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PSCI_0_2 | 1 << KVM_ARM_VCPU_PMU_V3;
-        assert!(vcpu.vcpu_init(&kvi).is_ok());
-        assert!(vcpu.has_device_attr(&dist_attr).is_ok());
-        assert!(vcpu.set_device_attr(&dist_attr).is_ok());
-    }
+            // Get a mutable slice of `mem_size` from `load_addr`.
+            // This is safe because we mapped it before.
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+        // The guest increments the byte at 0x3001 until the byte at 0x3000 is set.
+        let flag = unsafe { &*(load_addr.add(0x2000) as *const AtomicBool) };
+        let counter = unsafe { &*(load_addr.add(0x2001) as *const AtomicU8) };
 
-    #[test]
-    #[cfg(target_arch = "aarch64")]
-    fn test_pointer_authentication() {
-        let kvm = Kvm::new().unwrap();
-        let vm = kvm.create_vm().unwrap();
-        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let mut vcpu_sregs = vcpu.get_sregs().unwrap();
+        vcpu_sregs.cs.base = 0;
+        vcpu_sregs.cs.selector = 0;
+        vcpu_sregs.ds.base = 0;
+        vcpu_sregs.ds.selector = 0;
+        vcpu.set_sregs(&vcpu_sregs).unwrap();
 
-        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
-        vm.get_preferred_target(&mut kvi)
-            .expect("Cannot get preferred target");
-        if kvm.check_extension(Cap::ArmPtrAuthAddress) {
-            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PTRAUTH_ADDRESS;
-        }
-        if kvm.check_extension(Cap::ArmPtrAuthGeneric) {
-            kvi.features[0] |= 1 << kvm_bindings::KVM_ARM_VCPU_PTRAUTH_GENERIC;
+        let stop = AtomicBool::new(false);
+        for stopping in [false, true] {
+            vcpu.modify_regs(|regs| regs.rip = guest_addr).unwrap();
+            flag.store(false, Ordering::SeqCst);
+            counter.store(0, Ordering::SeqCst);
+            stop.store(stopping, Ordering::SeqCst);
+            let signals = SIGNALS.load(Ordering::SeqCst);
+
+            thread::scope(|s| {
+                let (tx, rx) = channel();
+                let vcpu = &mut vcpu;
+                let stop = &stop;
+                let runner = s.spawn(move || {
+                    tx.send(unsafe { libc::pthread_self() }).unwrap();
+                    match vcpu.run_uninterrupted(stop) {
+                        Ok(VcpuExit::IoOut(port, _)) => Ok(port),
+                        Ok(exit) => panic!("Unexpected exit: {:?}", exit),
+                        Err(e) => Err(e.errno()),
+                    }
+                });
+                let thread = rx.recv().unwrap();
+
+                // Wait until the guest runs. From then on, the vCPU thread only leaves
+                // `KVM_RUN` when the guest is released, so the signal makes `KVM_RUN` fail
+                // with `EINTR`.
+                while counter.load(Ordering::SeqCst) == 0 {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                unsafe { libc::pthread_kill(thread, signum) };
+                while SIGNALS.load(Ordering::SeqCst) == signals {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                if stopping {
+                    assert_eq!(runner.join().unwrap(), Err(libc::EINTR));
+                } else {
+                    // The guest runs again, so the interrupted `KVM_RUN` was issued again.
+                    let count = counter.load(Ordering::SeqCst);
+                    while counter.load(Ordering::SeqCst) == count {
+                        assert!(!runner.is_finished());
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    flag.store(true, Ordering::SeqCst);
+                    assert_eq!(runner.join().unwrap(), Ok(0x10));
+                }
+            });
         }
-        assert!(vcpu.vcpu_init(&kvi).is_ok());
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[cfg(target_arch = "x86_64")]
     #[test]
-    fn test_userspace_rdmsr_exit() {
+    fn test_triple_fault_shutdown() {
         use std::io::Write;
 
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
         #[rustfmt::skip]
         let code = [
-            0x0F, 0x32, /* rdmsr */
-            0xF4        /* hlt */
+            0x0F, 0x0B, /* ud2 */
         ];
 
-        if !vm.check_extension(Cap::X86UserSpaceMsr) {
-            return;
-        }
-        let cap = kvm_enable_cap {
-            cap: Cap::X86UserSpaceMsr as u32,
-            args: [MsrExitReason::Unknown.bits() as u64, 0, 0, 0],
-            ..Default::default()
-        };
-        vm.enable_cap(&cap).unwrap();
-
         let mem_size = 0x4000;
         let load_addr = mmap_anonymous(mem_size).as_ptr();
         let guest_addr: u64 = 0x1000;
-        let slot: u32 = 0;
         let mem_region = kvm_userspace_memory_region {
-            slot,
+            slot: 0,
             guest_phys_addr: guest_addr,
             memory_size: mem_size as u64,
             userspace_addr: load_addr as u64,
@@ -3171,83 +6627,48 @@ mod tests {
         }
 
         let mut vcpu = vm.create_vcpu(0).unwrap();
-
-        // Set up special registers
+        // Without an IDT, the #UD escalates to a #DF and then to a triple fault.
         let mut vcpu_sregs = vcpu.get_sregs().unwrap();
-        assert_ne!(vcpu_sregs.cs.base, 0);
-        assert_ne!(vcpu_sregs.cs.selector, 0);
         vcpu_sregs.cs.base = 0;
         vcpu_sregs.cs.selector = 0;
+        vcpu_sregs.idt.base = 0;
+        vcpu_sregs.idt.limit = 0;
         vcpu.set_sregs(&vcpu_sregs).unwrap();
+        vcpu.modify_regs(|regs| regs.rip = guest_addr).unwrap();
 
-        // Set the Instruction Pointer to the guest address where we loaded
-        // the code, and RCX to the MSR to be read.
-        let mut vcpu_regs = vcpu.get_regs().unwrap();
-        vcpu_regs.rip = guest_addr;
-        vcpu_regs.rcx = 0x474f4f00;
-        vcpu.set_regs(&vcpu_regs).unwrap();
-
-        match vcpu.run().unwrap() {
-            VcpuExit::X86Rdmsr(exit) => {
-                assert_eq!(exit.reason, MsrExitReason::Unknown);
-                assert_eq!(exit.index, 0x474f4f00);
-            }
-            e => panic!("Unexpected exit: {:?}", e),
-        }
+        assert!(matches!(vcpu.run().unwrap(), VcpuExit::Shutdown));
+        assert_eq!(vcpu.last_exit_reason(), KVM_EXIT_SHUTDOWN);
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[cfg(target_arch = "x86_64")]
     #[test]
-    fn test_userspace_hypercall_exit() {
+    fn test_notify_vmexit() {
+        use crate::ioctls::vm::NotifyVmexitFlags;
         use std::io::Write;
 
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-
-        // Use `vmcall` or `vmmcall` depending on what's supported.
-        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
-        let supports_vmcall = cpuid
-            .as_slice()
-            .iter()
-            .find(|entry| entry.function == 1)
-            .map_or(false, |entry| entry.ecx & (1 << 5) != 0);
-        let supports_vmmcall = cpuid
-            .as_slice()
-            .iter()
-            .find(|entry| entry.function == 0x8000_0001)
-            .map_or(false, |entry| entry.ecx & (1 << 2) != 0);
         #[rustfmt::skip]
-        let code = if supports_vmcall {
-            [
-                0x0F, 0x01, 0xC1, /* vmcall */
-                0xF4              /* hlt */
-            ]
-        } else if supports_vmmcall {
-            [
-                0x0F, 0x01, 0xD9, /* vmmcall */
-                0xF4              /* hlt */
-            ]
-        } else {
-            return;
-        };
+        let code = [
+            0xE6, 0x10, /* out 0x10, al */
+            0xF4,       /* hlt */
+        ];
 
-        if !vm.check_extension(Cap::ExitHypercall) {
+        let flags = NotifyVmexitFlags::Enabled | NotifyVmexitFlags::User;
+        if !vm.check_extension(Cap::X86NotifyVmexit) {
+            assert_eq!(
+                vm.enable_notify_vmexit(1 << 20, flags).unwrap_err().errno(),
+                libc::EINVAL
+            );
             return;
         }
-        const KVM_HC_MAP_GPA_RANGE: u64 = 12;
-        let cap = kvm_enable_cap {
-            cap: Cap::ExitHypercall as u32,
-            args: [1 << KVM_HC_MAP_GPA_RANGE, 0, 0, 0],
-            ..Default::default()
-        };
-        vm.enable_cap(&cap).unwrap();
+        vm.enable_notify_vmexit(1 << 20, flags).unwrap();
 
         let mem_size = 0x4000;
         let load_addr = mmap_anonymous(mem_size).as_ptr();
         let guest_addr: u64 = 0x1000;
-        let slot: u32 = 0;
         let mem_region = kvm_userspace_memory_region {
-            slot,
+            slot: 0,
             guest_phys_addr: guest_addr,
             memory_size: mem_size as u64,
             userspace_addr: load_addr as u64,
@@ -3263,32 +6684,15 @@ mod tests {
         }
 
         let mut vcpu = vm.create_vcpu(0).unwrap();
-
-        // Set up special registers
         let mut vcpu_sregs = vcpu.get_sregs().unwrap();
-        assert_ne!(vcpu_sregs.cs.base, 0);
-        assert_ne!(vcpu_sregs.cs.selector, 0);
         vcpu_sregs.cs.base = 0;
         vcpu_sregs.cs.selector = 0;
         vcpu.set_sregs(&vcpu_sregs).unwrap();
+        vcpu.modify_regs(|regs| regs.rip = guest_addr).unwrap();
 
-        // Set the Instruction Pointer to the guest address where we loaded
-        // the code, and RCX to the MSR to be read.
-        let mut vcpu_regs = vcpu.get_regs().unwrap();
-        vcpu_regs.rip = guest_addr;
-        vcpu_regs.rax = KVM_HC_MAP_GPA_RANGE;
-        vcpu_regs.rbx = 0x1234000;
-        vcpu_regs.rcx = 1;
-        vcpu_regs.rdx = 0;
-        vcpu.set_regs(&vcpu_regs).unwrap();
-
+        // A well-behaved guest does not trigger notify VM exits.
         match vcpu.run().unwrap() {
-            VcpuExit::Hypercall(exit) => {
-                assert_eq!(exit.nr, KVM_HC_MAP_GPA_RANGE);
-                assert_eq!(exit.args[0], 0x1234000);
-                assert_eq!(exit.args[1], 1);
-                assert_eq!(exit.args[2], 0);
-            }
+            VcpuExit::IoOut(port, _) => assert_eq!(port, 0x10),
             e => panic!("Unexpected exit: {:?}", e),
         }
     }
@@ -3309,12 +6713,7 @@ mod tests {
         if !vm.check_extension(Cap::X86UserSpaceMsr) {
             return;
         }
-        let cap = kvm_enable_cap {
-            cap: Cap::X86UserSpaceMsr as u32,
-            args: [MsrExitReason::Unknown.bits() as u64, 0, 0, 0],
-            ..Default::default()
-        };
-        vm.enable_cap(&cap).unwrap();
+        vm.enable_userspace_msr(MsrExitReason::Unknown).unwrap();
 
         let mem_size = 0x4000;
         let load_addr = mmap_anonymous(mem_size).as_ptr();
@@ -3460,6 +6859,87 @@ mod tests {
         assert_eq!(data, (DATA as u8).to_le_bytes());
     }
 
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_ioevent_datamatch_width() {
+        use crate::{Datamatch, IoEventAddress};
+        use std::io::Write;
+        use vmm_sys_util::eventfd::EventFd;
+
+        #[rustfmt::skip]
+        let code = [
+            0xba, 0xf0, 0x00,                               // mov dx, 0xf0
+            0xb0, 0x11,                                     // mov al, 0x11
+            0xee,                                           // out dx, al
+            0xba, 0xf2, 0x00,                               // mov dx, 0xf2
+            0xb8, 0x22, 0x22,                               // mov ax, 0x2222
+            0xef,                                           // out dx, ax
+            0xba, 0xf4, 0x00,                               // mov dx, 0xf4
+            0x66, 0xb8, 0x44, 0x44, 0x44, 0x44,             // mov eax, 0x44444444
+            0x66, 0xef,                                     // out dx, eax
+            0xc6, 0x06, 0x00, 0x80, 0x11,                   // mov byte [0x8000], 0x11
+            0xc7, 0x06, 0x10, 0x80, 0x22, 0x22,             // mov word [0x8010], 0x2222
+            0x66, 0xc7, 0x06, 0x20, 0x80, 0x44, 0x44, 0x44, 0x44, // mov dword [0x8020], 0x44444444
+            0xf4,                                           // hlt
+        ];
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        let mem_size = 0x4000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let guest_addr: u64 = 0x1000;
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: guest_addr,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+
+            // Get a mutable slice of `mem_size` from `load_addr`.
+            // This is safe because we mapped it before.
+            let mut slice = std::slice::from_raw_parts_mut(load_addr, mem_size);
+            slice.write_all(&code).unwrap();
+        }
+
+        let events = [
+            (IoEventAddress::Pio(0xf0), Datamatch::U8(0x11)),
+            (IoEventAddress::Pio(0xf2), Datamatch::U16(0x2222)),
+            (IoEventAddress::Pio(0xf4), Datamatch::U32(0x4444_4444)),
+            (IoEventAddress::Mmio(0x8000), Datamatch::U8(0x11)),
+            (IoEventAddress::Mmio(0x8010), Datamatch::U16(0x2222)),
+            (IoEventAddress::Mmio(0x8020), Datamatch::U32(0x4444_4444)),
+        ];
+        let evtfds: Vec<EventFd> = events
+            .iter()
+            .map(|(addr, datamatch)| {
+                let evtfd = EventFd::new(libc::EFD_NONBLOCK).unwrap();
+                vm.register_ioevent2(&evtfd, addr, *datamatch).unwrap();
+                evtfd
+            })
+            .collect();
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = guest_addr;
+        regs.rflags = 2;
+        vcpu.set_regs(&regs).unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+
+        // All the writes are handled by the ioeventfds, so the first exit is the hlt.
+        let exit = vcpu.run().unwrap();
+        assert!(matches!(exit, VcpuExit::Hlt), "{:?}", exit);
+        for evtfd in evtfds.iter() {
+            assert_eq!(evtfd.read().unwrap(), 1);
+        }
+    }
+
     #[test]
     #[cfg(target_arch = "x86_64")]
     fn test_coalesced_mmio() {
@@ -9,12 +9,19 @@ use kvm_bindings::*;
 use std::fs::File;
 use std::os::raw::c_void;
 use std::os::raw::{c_int, c_ulong};
-use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use crate::cap::Cap;
+#[cfg(target_arch = "aarch64")]
+use crate::ioctls::arm::{create_device, GicVersion};
 use crate::ioctls::device::new_device;
-use crate::ioctls::device::DeviceFd;
+use crate::ioctls::device::{DeviceFd, TypedDevice};
+#[cfg(target_arch = "aarch64")]
+use crate::ioctls::system::KVM_ARM_DEFAULT_IPA_BITS;
 use crate::ioctls::vcpu::new_vcpu;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+use crate::ioctls::vcpu::MsrExitReason;
 use crate::ioctls::vcpu::VcpuFd;
 use crate::ioctls::{KvmRunWrapper, Result};
 use crate::kvm_ioctls::*;
@@ -50,834 +57,2275 @@ impl From<NoDatamatch> for u64 {
     }
 }
 
-/// Wrapper over KVM VM ioctls.
+/// Value and width of the write matched by an ioeventfd.
+///
+/// Used by [`register_ioevent2`](struct.VmFd.html#method.register_ioevent2). The width of the
+/// guest write must match the variant for the event to be signaled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Datamatch {
+    /// Any write to the address signals the event.
+    None,
+    /// Matches 1-byte writes of the given value.
+    U8(u8),
+    /// Matches 2-byte writes of the given value.
+    U16(u16),
+    /// Matches 4-byte writes of the given value.
+    U32(u32),
+    /// Matches 8-byte writes of the given value.
+    U64(u64),
+}
+
+impl Datamatch {
+    fn value(&self) -> Option<u64> {
+        match *self {
+            Datamatch::None => None,
+            Datamatch::U8(v) => Some(u64::from(v)),
+            Datamatch::U16(v) => Some(u64::from(v)),
+            Datamatch::U32(v) => Some(u64::from(v)),
+            Datamatch::U64(v) => Some(v),
+        }
+    }
+
+    fn len(&self) -> u32 {
+        match *self {
+            Datamatch::None => 0,
+            Datamatch::U8(_) => 1,
+            Datamatch::U16(_) => 2,
+            Datamatch::U32(_) => 4,
+            Datamatch::U64(_) => 8,
+        }
+    }
+}
+
+impl From<NoDatamatch> for Datamatch {
+    fn from(_: NoDatamatch) -> Datamatch {
+        Datamatch::None
+    }
+}
+
+/// Registration of an ioeventfd which is removed when dropped.
+///
+/// Returned by [register_ioevent_guarded](struct.VmFd.html#method.register_ioevent_guarded).
 #[derive(Debug)]
-pub struct VmFd {
-    vm: File,
-    run_size: usize,
+pub struct IoEventGuard<'a> {
+    vm: &'a VmFd,
+    fd: Option<EventFd>,
+    addr: IoEventAddress,
+    datamatch: Option<u64>,
+    len: u32,
 }
 
-impl VmFd {
-    /// Creates/modifies a guest physical memory slot.
+impl IoEventGuard<'_> {
+    /// Unregisters the event, reporting the error that dropping the guard would ignore.
+    pub fn unregister(mut self) -> Result<()> {
+        self.deassign()
+    }
+
+    /// Consumes the guard without unregistering the event.
     ///
-    /// See the documentation for `KVM_SET_USER_MEMORY_REGION`.
+    /// The event stays registered until it is removed with
+    /// [unregister_ioevent](struct.VmFd.html#method.unregister_ioevent) or the VM is
+    /// destroyed.
+    pub fn forget(mut self) {
+        self.fd.take();
+    }
+
+    fn deassign(&mut self) -> Result<()> {
+        match self.fd.take() {
+            Some(fd) => self
+                .vm
+                .ioeventfd(&fd, &self.addr, self.datamatch, self.len, true),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for IoEventGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.deassign();
+    }
+}
+
+/// Registration of an encrypted memory region which is unregistered when dropped.
+///
+/// Returned by
+/// [register_enc_memory_region_guarded](struct.VmFd.html#method.register_enc_memory_region_guarded).
+#[derive(Debug)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct EncRegionGuard<'a> {
+    vm: &'a VmFd,
+    region: Option<kvm_enc_region>,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl EncRegionGuard<'_> {
+    /// Unregisters the region, reporting the error that dropping the guard would ignore.
+    pub fn unregister(mut self) -> Result<()> {
+        self.release()
+    }
+
+    fn release(&mut self) -> Result<()> {
+        match self.region.take() {
+            Some(region) => self.vm.unregister_enc_memory_region(&region),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Drop for EncRegionGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.release();
+    }
+}
+
+/// Priority of Xen event channels using the 2-level ABI, the only one supported by KVM.
+///
+/// Used by [`IrqRoutingEntry::xen_evtchn`] and [`VmFd::xen_evtchn_send`]. This is
+/// `KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL`, which is missing from the bindings.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL: u32 = u32::MAX;
+
+// The following definitions come from `include/uapi/linux/kvm_para.h` and
+// `arch/x86/include/uapi/asm/kvm_para.h`, and are missing from the `kvm-bindings` used by this
+// crate.
+
+/// Hypercall waking up a halted vCPU, `KVM_HC_KICK_CPU`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_HC_KICK_CPU: u64 = 5;
+/// Hypercall pairing the host clock with the guest TSC, `KVM_HC_CLOCK_PAIRING`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_HC_CLOCK_PAIRING: u64 = 9;
+/// Hypercall sending IPIs to a set of vCPUs, `KVM_HC_SEND_IPI`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_HC_SEND_IPI: u64 = 10;
+/// Hypercall yielding to a preempted vCPU, `KVM_HC_SCHED_YIELD`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_HC_SCHED_YIELD: u64 = 11;
+/// Hypercall converting a guest physical range between private and shared,
+/// `KVM_HC_MAP_GPA_RANGE`.
+///
+/// Handled by userspace once enabled with [`VmFd::enable_exit_hypercall`]. The arguments are
+/// the start address, the number of pages and the `KVM_MAP_GPA_RANGE_*` attributes.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_HC_MAP_GPA_RANGE: u64 = 12;
+/// `KVM_HC_MAP_GPA_RANGE` attribute: the range is made of 4 KiB pages.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_MAP_GPA_RANGE_PAGE_SZ_4K: u64 = 0;
+/// `KVM_HC_MAP_GPA_RANGE` attribute: the range is made of 2 MiB pages.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_MAP_GPA_RANGE_PAGE_SZ_2M: u64 = 1;
+/// `KVM_HC_MAP_GPA_RANGE` attribute: the range is made of 1 GiB pages.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_MAP_GPA_RANGE_PAGE_SZ_1G: u64 = 2;
+/// `KVM_HC_MAP_GPA_RANGE` attribute: the range becomes private (encrypted).
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_MAP_GPA_RANGE_ENCRYPTED: u64 = 1 << 4;
+/// `KVM_HC_MAP_GPA_RANGE` attribute: the range becomes shared (decrypted).
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_MAP_GPA_RANGE_DECRYPTED: u64 = 0;
+
+/// `KVM_SET_PMU_EVENT_FILTER` flag: the events are masked events, as described in the
+/// documentation for `KVM_CAP_PMU_EVENT_MASKED_EVENTS`. This is
+/// `KVM_PMU_EVENT_FLAG_MASKED_EVENTS`, which is missing from the bindings.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub const KVM_PMU_EVENT_FLAG_MASKED_EVENTS: u32 = 1 << 0;
+
+// Conventional address of the TSS region, just below the BIOS and the identity map.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const DEFAULT_TSS_ADDRESS: usize = 0xfffb_d000;
+// First address rejected for the TSS region, which must lie below 4 GiB.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const TSS_ADDRESS_LIMIT: usize = 0xffff_c000;
+// Conventional address of the identity map page, right below the TSS region.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const DEFAULT_IDENTITY_MAP_ADDRESS: u64 = 0xfffb_c000;
+// Device whose mappings back the EPC sections of SGX guests.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const SGX_VEPC_PATH: &str = "/dev/sgx_vepc";
+// Size of the EPC pages, to which the EPC sections are aligned.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const SGX_EPC_PAGE_SIZE: u64 = 0x1000;
+// Maximum number of events accepted by `KVM_SET_PMU_EVENT_FILTER`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const PMU_EVENT_FILTER_MAX_EVENTS: usize = 300;
+
+// The following definitions come from `arch/arm64/include/uapi/asm/kvm.h` and are missing from
+// the `kvm-bindings` used by this crate.
+#[cfg(target_arch = "aarch64")]
+const KVM_ARM_VCPU_HAS_EL2: u32 = 7;
+#[allow(non_camel_case_types)]
+#[cfg(target_arch = "aarch64")]
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct kvm_arm_counter_offset {
+    pub(crate) counter_offset: u64,
+    pub(crate) reserved: u64,
+}
+
+/// A GSI routing entry for [`GsiRouting`].
+///
+/// Wraps a `kvm_irq_routing_entry` with its `type_`, `flags` and union member set
+/// consistently.
+#[derive(Debug, Clone, Copy)]
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+pub struct IrqRoutingEntry(kvm_irq_routing_entry);
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl IrqRoutingEntry {
+    /// Routes `gsi` to the `pin` of the in-kernel interrupt controller `irqchip`.
+    pub fn irqchip(gsi: u32, irqchip: u32, pin: u32) -> Self {
+        let mut entry = kvm_irq_routing_entry {
+            gsi,
+            type_: KVM_IRQ_ROUTING_IRQCHIP,
+            ..Default::default()
+        };
+        entry.u.irqchip = kvm_irq_routing_irqchip { irqchip, pin };
+        IrqRoutingEntry(entry)
+    }
+
+    /// Routes `gsi` to the MSI described by `address_hi`, `address_lo` and `data`.
+    pub fn msi(gsi: u32, address_hi: u32, address_lo: u32, data: u32) -> Self {
+        let mut entry = kvm_irq_routing_entry {
+            gsi,
+            type_: KVM_IRQ_ROUTING_MSI,
+            ..Default::default()
+        };
+        entry.u.msi = kvm_irq_routing_msi {
+            address_lo,
+            address_hi,
+            data,
+            ..Default::default()
+        };
+        IrqRoutingEntry(entry)
+    }
+
+    /// Routes `gsi` to the MSI described by `address_hi`, `address_lo` and `data`, issued by
+    /// the device `devid`.
     ///
-    /// # Arguments
+    /// Sets `KVM_MSI_VALID_DEVID`, which is required when the VM has a GICv3 ITS (see
+    /// [supports_msi_devid](struct.VmFd.html#method.supports_msi_devid)), as created by
+    /// [GicDevice::add_its](crate::GicDevice::add_its).
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn msi_with_devid(
+        gsi: u32,
+        address_hi: u32,
+        address_lo: u32,
+        data: u32,
+        devid: u32,
+    ) -> Self {
+        let mut entry = Self::msi(gsi, address_hi, address_lo, data);
+        entry.0.flags = KVM_MSI_VALID_DEVID;
+        entry.0.u.msi.__bindgen_anon_1.devid = devid;
+        entry
+    }
+
+    /// Routes `gsi` to the Hyper-V synthetic interrupt source `sint` of the vCPU `vcpu`.
     ///
-    /// * `user_memory_region` - Guest physical memory slot. For details check the
-    ///             `kvm_userspace_memory_region` structure in the
-    ///             [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// The vCPU must have SynIC enabled (see
+    /// [hyperv_synic_supported](struct.VmFd.html#method.hyperv_synic_supported)).
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn hv_sint(gsi: u32, vcpu: u32, sint: u32) -> Self {
+        let mut entry = kvm_irq_routing_entry {
+            gsi,
+            type_: KVM_IRQ_ROUTING_HV_SINT,
+            ..Default::default()
+        };
+        entry.u.hv_sint = kvm_irq_routing_hv_sint { vcpu, sint };
+        IrqRoutingEntry(entry)
+    }
+
+    /// Routes `gsi` to the Xen event channel `port` of the vCPU `vcpu`.
     ///
-    /// # Safety
+    /// `priority` must be [`KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL`]. Xen event channel routes
+    /// are only accepted once the VM has been configured through `KVM_XEN_HVM_CONFIG` with the
+    /// `KVM_XEN_HVM_CONFIG_EVTCHN_2LEVEL` feature (see
+    /// [supported_xen_hvm_features](struct.VmFd.html#method.supported_xen_hvm_features)).
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn xen_evtchn(gsi: u32, port: u32, vcpu: u32, priority: u32) -> Self {
+        let mut entry = kvm_irq_routing_entry {
+            gsi,
+            type_: KVM_IRQ_ROUTING_XEN_EVTCHN,
+            ..Default::default()
+        };
+        entry.u.xen_evtchn = kvm_irq_routing_xen_evtchn {
+            port,
+            vcpu,
+            priority,
+        };
+        IrqRoutingEntry(entry)
+    }
+
+    /// Returns the GSI being routed.
+    pub fn gsi(&self) -> u32 {
+        self.0.gsi
+    }
+
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn is_msi_without_devid(&self) -> bool {
+        self.0.type_ == KVM_IRQ_ROUTING_MSI && self.0.flags & KVM_MSI_VALID_DEVID == 0
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl From<IrqRoutingEntry> for kvm_irq_routing_entry {
+    fn from(entry: IrqRoutingEntry) -> kvm_irq_routing_entry {
+        entry.0
+    }
+}
+
+/// Builder for the GSI routing table set through `KVM_SET_GSI_ROUTING`.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::{GsiRouting, IrqRoutingEntry, Kvm};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+/// {
+///     vm.create_irq_chip().unwrap();
+///     let mut routing = GsiRouting::new();
+///     routing
+///         .add_entry(IrqRoutingEntry::irqchip(4, 0, 4))
+///         .add_entry(IrqRoutingEntry::msi(24, 0, 0xfee0_0000, 0x30));
+///     routing.commit(&vm).unwrap();
+/// }
+/// ```
+#[derive(Default)]
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+pub struct GsiRouting {
+    entries: Vec<IrqRoutingEntry>,
+    // Serialized table kept by `commit_reusing` between commits.
+    buffer: Vec<kvm_irq_routing>,
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl GsiRouting {
+    /// Creates an empty routing table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the maximum number of entries accepted by `KVM_SET_GSI_ROUTING`.
     ///
-    /// This function is unsafe because there is no guarantee `userspace_addr` points to a valid
-    /// memory region, nor the memory region lives as long as the kernel needs it to.
+    /// The value is reported by `KVM_CAP_IRQ_ROUTING` and is 0 if routing is not supported.
     ///
-    /// The caller of this method must make sure that:
-    /// - the raw pointer (`userspace_addr`) points to valid memory
-    /// - the regions provided to KVM are not overlapping other memory regions.
+    /// # Arguments
     ///
-    /// # Example
+    /// * `vm` - The VM whose routing table is configured.
+    pub fn capacity(vm: &VmFd) -> usize {
+        vm.check_extension_int(Cap::IrqRouting).max(0) as usize
+    }
+
+    /// Appends `entry` to the routing table.
+    pub fn add_entry(&mut self, entry: IrqRoutingEntry) -> &mut Self {
+        self.entries.push(entry);
+        self
+    }
+
+    /// Allocates a GSI from `allocator` and routes it to the MSI described by `address_hi`,
+    /// `address_lo` and `data`.
     ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// extern crate kvm_bindings;
+    /// Returns the allocated GSI, which is not freed when the entry is removed.
     ///
-    /// use kvm_bindings::kvm_userspace_memory_region;
-    /// use kvm_ioctls::Kvm;
+    /// # Arguments
     ///
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    /// let mem_region = kvm_userspace_memory_region {
-    ///     slot: 0,
-    ///     guest_phys_addr: 0x10000 as u64,
-    ///     memory_size: 0x10000 as u64,
-    ///     userspace_addr: 0x0 as u64,
-    ///     flags: 0,
-    /// };
-    /// unsafe {
-    ///     vm.set_user_memory_region(mem_region).unwrap();
-    /// };
-    /// ```
-    pub unsafe fn set_user_memory_region(
-        &self,
-        user_memory_region: kvm_userspace_memory_region,
-    ) -> Result<()> {
-        let ret = ioctl_with_ref(self, KVM_SET_USER_MEMORY_REGION(), &user_memory_region);
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
-        }
+    /// * `allocator` - Allocator of the VM's GSIs.
+    /// * `address_hi` - High 32 bits of the MSI address.
+    /// * `address_lo` - Low 32 bits of the MSI address.
+    /// * `data` - MSI data.
+    pub fn add_msi_auto(
+        &mut self,
+        allocator: &GsiAllocator,
+        address_hi: u32,
+        address_lo: u32,
+        data: u32,
+    ) -> std::result::Result<u32, GsiAllocatorError> {
+        let gsi = allocator.allocate()?;
+        self.add_entry(IrqRoutingEntry::msi(gsi, address_hi, address_lo, data));
+        Ok(gsi)
     }
 
-    /// Sets the address of the three-page region in the VM's address space.
+    /// Routes `gsi` to the fixed, edge-triggered MSI with `vector` targeting the APIC ID
+    /// `dest_id` in physical destination mode.
     ///
-    /// See the documentation for `KVM_SET_TSS_ADDR`.
+    /// The destination is encoded in the MSI address according to the x2APIC API of `vm` (see
+    /// [enable_x2apic_api](struct.VmFd.html#method.enable_x2apic_api)). Fails with `EINVAL`
+    /// if `dest_id` does not fit in 8 bits and 32-bit APIC IDs are not enabled.
     ///
     /// # Arguments
     ///
-    /// * `offset` - Physical address of a three-page region in the guest's physical address space.
+    /// * `vm` - The VM whose routing table is configured.
+    /// * `gsi` - GSI being routed.
+    /// * `dest_id` - APIC ID of the destination vCPU.
+    /// * `vector` - Interrupt vector.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_ioctls::{GsiRouting, Kvm};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// vm.set_tss_address(0xfffb_d000).unwrap();
+    /// vm.create_irq_chip().unwrap();
+    /// let mut routing = GsiRouting::new();
+    /// routing.add_x86_msi(&vm, 24, 1, 0x30).unwrap();
+    /// routing.commit(&vm).unwrap();
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn set_tss_address(&self, offset: usize) -> Result<()> {
-        // SAFETY: Safe because we know that our file is a VM fd and we verify the return result.
-        let ret = unsafe { ioctl_with_val(self, KVM_SET_TSS_ADDR(), offset as c_ulong) };
-        if ret == 0 {
-            Ok(())
+    pub fn add_x86_msi(
+        &mut self,
+        vm: &VmFd,
+        gsi: u32,
+        dest_id: u32,
+        vector: u8,
+    ) -> Result<&mut Self> {
+        let address_hi = if vm.x2apic_32bit_ids.load(Ordering::Relaxed) {
+            dest_id & !0xff
+        } else if dest_id > 0xff {
+            return Err(errno::Error::new(libc::EINVAL));
         } else {
-            Err(errno::Error::last())
-        }
+            0
+        };
+        let address_lo = 0xfee0_0000 | ((dest_id & 0xff) << 12);
+        Ok(self.add_entry(IrqRoutingEntry::msi(
+            gsi,
+            address_hi,
+            address_lo,
+            u32::from(vector),
+        )))
     }
 
-    /// Sets the address of the one-page region in the VM's address space.
+    /// Returns the entries of the routing table.
+    pub fn entries(&self) -> &[IrqRoutingEntry] {
+        &self.entries
+    }
+
+    /// Returns the entries of the routing table for in-place updates.
+    pub fn entries_mut(&mut self) -> &mut [IrqRoutingEntry] {
+        &mut self.entries
+    }
+
+    /// Replaces the GSI routing table of `vm` with this one.
     ///
-    /// See the documentation for `KVM_SET_IDENTITY_MAP_ADDR`.
+    /// Fails with `EINVAL` if the table holds more entries than [`capacity`](Self::capacity).
+    /// On arm/aarch64, also fails with `EINVAL` if the VM requires MSIs to carry a device ID
+    /// (see [supports_msi_devid](struct.VmFd.html#method.supports_msi_devid)) and one of the
+    /// MSI entries was not built with
+    /// [msi_with_devid](struct.IrqRoutingEntry.html#method.msi_with_devid).
     ///
     /// # Arguments
     ///
-    /// * `address` - Physical address of a one-page region in the guest's physical address space.
-    ///
-    /// # Example
-    ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// # use kvm_ioctls::Kvm;
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    /// vm.set_identity_map_address(0xfffb_c000).unwrap();
-    /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn set_identity_map_address(&self, address: u64) -> Result<()> {
-        // SAFETY: Safe because we know that our file is a VM fd and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_SET_IDENTITY_MAP_ADDR(), &address) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
-        }
+    /// * `vm` - The VM whose routing table is replaced.
+    pub fn commit(&self, vm: &VmFd) -> Result<()> {
+        self.validate(vm)?;
+        let mut irq_routing = Vec::new();
+        Self::serialize(&self.entries, &mut irq_routing);
+        vm.set_gsi_routing(&irq_routing[0])
     }
 
-    /// Creates an in-kernel interrupt controller.
+    /// Replaces the GSI routing table of `vm` with this one, reusing the serialized table
+    /// from the previous call.
     ///
-    /// See the documentation for `KVM_CREATE_IRQCHIP`.
+    /// Only the entries which changed since the previous call are rewritten, and no memory is
+    /// allocated unless the table grew. Fails in the same cases as [`commit`](Self::commit).
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The VM whose routing table is replaced.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate kvm_bindings;
-    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_ioctls::{GsiRouting, IrqRoutingEntry, Kvm};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    ///
     /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    /// vm.create_irq_chip().unwrap();
-    /// #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     /// {
-    ///     use kvm_bindings::{
-    ///         kvm_create_device, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, KVM_CREATE_DEVICE_TEST,
-    ///     };
-    ///     let mut gic_device = kvm_bindings::kvm_create_device {
-    ///         type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
-    ///         fd: 0,
-    ///         flags: KVM_CREATE_DEVICE_TEST,
-    ///     };
-    ///     if vm.create_device(&mut gic_device).is_ok() {
-    ///         vm.create_irq_chip().unwrap();
+    ///     vm.create_irq_chip().unwrap();
+    ///     let mut routing = GsiRouting::new();
+    ///     for gsi in 24..32 {
+    ///         routing.add_entry(IrqRoutingEntry::msi(gsi, 0, 0xfee0_0000, 0x30));
     ///     }
+    ///     routing.commit_reusing(&vm).unwrap();
+    ///
+    ///     // Retarget one vector.
+    ///     routing.entries_mut()[0] = IrqRoutingEntry::msi(24, 0, 0xfee0_1000, 0x31);
+    ///     routing.commit_reusing(&vm).unwrap();
     /// }
     /// ```
-    #[cfg(any(
-        target_arch = "x86",
-        target_arch = "x86_64",
-        target_arch = "arm",
-        target_arch = "aarch64"
-    ))]
-    pub fn create_irq_chip(&self) -> Result<()> {
-        // SAFETY: Safe because we know that our file is a VM fd and we verify the return result.
-        let ret = unsafe { ioctl(self, KVM_CREATE_IRQCHIP()) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
+    pub fn commit_reusing(&mut self, vm: &VmFd) -> Result<()> {
+        self.validate(vm)?;
+        Self::serialize(&self.entries, &mut self.buffer);
+        vm.set_gsi_routing(&self.buffer[0])
+    }
+
+    fn validate(&self, vm: &VmFd) -> Result<()> {
+        if self.entries.len() > Self::capacity(vm) {
+            return Err(errno::Error::new(libc::EINVAL));
         }
+        #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+        if self.entries.iter().any(|e| e.is_msi_without_devid()) && vm.supports_msi_devid() {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        Ok(())
     }
 
-    /// X86 specific call to retrieve the state of a kernel interrupt controller.
-    ///
-    /// See the documentation for `KVM_GET_IRQCHIP` in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
-    ///
-    /// # Arguments
-    ///
-    /// * `irqchip` - `kvm_irqchip` (input/output) to be read.
-    ///
-    /// # Example
-    ///
-    /// ```rust
-    /// # extern crate kvm_bindings;
-    /// # extern crate kvm_ioctls;
-    /// # use kvm_bindings::{kvm_irqchip, KVM_IRQCHIP_PIC_MASTER};
-    /// # use kvm_ioctls::Kvm;
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    ///
-    /// vm.create_irq_chip().unwrap();
-    /// let mut irqchip = kvm_irqchip::default();
-    /// irqchip.chip_id = KVM_IRQCHIP_PIC_MASTER;
-    /// vm.get_irqchip(&mut irqchip).unwrap();
-    /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_irqchip(&self, irqchip: &mut kvm_irqchip) -> Result<()> {
-        // SAFETY: Here we trust the kernel not to read past the end of the kvm_irqchip struct.
-        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_IRQCHIP(), irqchip) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
+    /// Serializes `entries` into `buffer` as a `kvm_irq_routing` header followed by the
+    /// entries, growing `buffer` if needed and only writing the entries which differ.
+    fn serialize(entries: &[IrqRoutingEntry], buffer: &mut Vec<kvm_irq_routing>) {
+        let header_size = std::mem::size_of::<kvm_irq_routing>();
+        let size = header_size + entries.len() * std::mem::size_of::<kvm_irq_routing_entry>();
+        let len = size.div_ceil(header_size);
+        if buffer.len() < len {
+            buffer.resize_with(len, kvm_irq_routing::default);
+        }
+        buffer[0].nr = entries.len() as u32;
+        // SAFETY: The vector holds enough memory past the header for `nr` entries.
+        let dst_entries = unsafe { buffer[0].entries.as_mut_slice(entries.len()) };
+        for (dst, src) in dst_entries.iter_mut().zip(entries.iter()) {
+            if entry_bytes(dst) != entry_bytes(&src.0) {
+                *dst = src.0;
+            }
         }
     }
+}
 
-    /// X86 specific call to set the state of a kernel interrupt controller.
-    ///
-    /// See the documentation for `KVM_SET_IRQCHIP` in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl Clone for GsiRouting {
+    fn clone(&self) -> Self {
+        GsiRouting {
+            entries: self.entries.clone(),
+            buffer: Vec::new(),
+        }
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl std::fmt::Debug for GsiRouting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GsiRouting")
+            .field("entries", &self.entries)
+            .finish()
+    }
+}
+
+/// Returns the raw bytes of a routing entry.
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+fn entry_bytes(entry: &kvm_irq_routing_entry) -> &[u8] {
+    // SAFETY: `kvm_irq_routing_entry` is a plain C structure without padding.
+    unsafe {
+        std::slice::from_raw_parts(
+            entry as *const kvm_irq_routing_entry as *const u8,
+            std::mem::size_of::<kvm_irq_routing_entry>(),
+        )
+    }
+}
+
+/// Errors returned by [`GsiAllocator`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+pub enum GsiAllocatorError {
+    /// All the GSIs below `max` are allocated.
+    Exhausted {
+        /// Upper bound (exclusive) of the GSIs handed out by the allocator.
+        max: u32,
+    },
+    /// The GSI is not currently allocated.
+    NotAllocated(u32),
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl GsiAllocatorError {
+    /// Returns the errno code corresponding to the error.
+    pub fn errno(&self) -> i32 {
+        errno::Error::from(*self).errno()
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl From<GsiAllocatorError> for errno::Error {
+    fn from(err: GsiAllocatorError) -> errno::Error {
+        match err {
+            GsiAllocatorError::Exhausted { .. } => errno::Error::new(libc::ENOSPC),
+            GsiAllocatorError::NotAllocated(_) => errno::Error::new(libc::EINVAL),
+        }
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl std::fmt::Display for GsiAllocatorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GsiAllocatorError::Exhausted { max } => {
+                write!(f, "all the GSIs below {} are allocated", max)
+            }
+            GsiAllocatorError::NotAllocated(gsi) => write!(f, "GSI {} is not allocated", gsi),
+        }
+    }
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl std::error::Error for GsiAllocatorError {}
+
+#[derive(Debug)]
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+struct GsiAllocatorState {
+    // Lowest GSI which has never been allocated.
+    next: u32,
+    // First GSI handed out by the allocator.
+    start: u32,
+    max: u32,
+    // GSIs below `next` which were freed.
+    freed: std::collections::BTreeSet<u32>,
+}
+
+/// Allocator of the GSIs used for routing entries and irqfds.
+///
+/// GSIs are allocated in increasing order, the lowest freed GSI being reused first. The
+/// allocator is a handle to a shared state: clones allocate from the same pool, which lets
+/// [`IrqFdGuard`]s free their GSI when dropped.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::{GsiAllocator, GsiRouting, Kvm};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let allocator = GsiAllocator::new(&vm);
+/// let mut routing = GsiRouting::new();
+/// let gsi = routing
+///     .add_msi_auto(&allocator, 0, 0xfee0_0000, 0x30)
+///     .unwrap();
+/// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+/// // The IOAPIC pins are not handed out.
+/// assert_eq!(gsi, 24);
+/// ```
+#[derive(Debug, Clone)]
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+pub struct GsiAllocator {
+    state: std::sync::Arc<std::sync::Mutex<GsiAllocatorState>>,
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl GsiAllocator {
+    /// Creates an allocator for the GSIs of `vm`.
+    ///
+    /// The GSIs of the legacy interrupt controller pins are reserved: on x86 these are the
+    /// 24 IOAPIC pins, which also cover the PIC pins, while nothing is reserved on
+    /// arm/aarch64. The upper bound is the maximum number of routes reported by
+    /// `KVM_CAP_IRQ_ROUTING` (see [GsiRouting::capacity](struct.GsiRouting.html#method.capacity)).
     ///
     /// # Arguments
     ///
-    /// * `irqchip` - `kvm_irqchip` (input/output) to be written.
-    ///
-    /// # Example
-    ///
-    /// ```rust
-    /// # extern crate kvm_bindings;
-    /// # extern crate kvm_ioctls;
-    /// # use kvm_bindings::{kvm_irqchip, KVM_IRQCHIP_PIC_MASTER};
-    /// # use kvm_ioctls::Kvm;
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    ///
-    /// vm.create_irq_chip().unwrap();
-    /// let mut irqchip = kvm_irqchip::default();
-    /// irqchip.chip_id = KVM_IRQCHIP_PIC_MASTER;
-    /// // Your `irqchip` manipulation here.
-    /// vm.set_irqchip(&mut irqchip).unwrap();
-    /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn set_irqchip(&self, irqchip: &kvm_irqchip) -> Result<()> {
-        // SAFETY: Here we trust the kernel not to read past the end of the kvm_irqchip struct.
-        let ret = unsafe { ioctl_with_ref(self, KVM_SET_IRQCHIP(), irqchip) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
-        }
+    /// * `vm` - The VM the GSIs are allocated for.
+    pub fn new(vm: &VmFd) -> Self {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        let start = KVM_IOAPIC_NUM_PINS;
+        #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+        let start = 0;
+        let max = u32::try_from(GsiRouting::capacity(vm)).unwrap_or(u32::MAX);
+        Self::with_range(start, max)
     }
 
-    /// Creates a PIT as per the `KVM_CREATE_PIT2` ioctl.
+    /// Creates an allocator handing out the GSIs from `start` to `max` (exclusive).
     ///
     /// # Arguments
     ///
-    /// * pit_config - PIT configuration. For details check the `kvm_pit_config` structure in the
-    ///                [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
-    /// # Example
-    ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// extern crate kvm_bindings;
-    /// # use kvm_ioctls::Kvm;
-    /// use kvm_bindings::kvm_pit_config;
+    /// * `start` - First GSI to allocate.
+    /// * `max` - Upper bound (exclusive) of the allocated GSIs.
+    pub fn with_range(start: u32, max: u32) -> Self {
+        GsiAllocator {
+            state: std::sync::Arc::new(std::sync::Mutex::new(GsiAllocatorState {
+                next: start,
+                start,
+                max,
+                freed: std::collections::BTreeSet::new(),
+            })),
+        }
+    }
+
+    /// Returns the upper bound (exclusive) of the allocated GSIs.
+    pub fn max(&self) -> u32 {
+        self.state().max
+    }
+
+    /// Allocates the lowest available GSI.
     ///
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    /// let pit_config = kvm_pit_config::default();
-    /// vm.create_pit2(pit_config).unwrap();
-    /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn create_pit2(&self, pit_config: kvm_pit_config) -> Result<()> {
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_CREATE_PIT2(), &pit_config) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
+    /// Fails with [`GsiAllocatorError::Exhausted`] if all the GSIs are allocated.
+    pub fn allocate(&self) -> std::result::Result<u32, GsiAllocatorError> {
+        let mut state = self.state();
+        if let Some(gsi) = state.freed.pop_first() {
+            return Ok(gsi);
         }
+        if state.next >= state.max {
+            return Err(GsiAllocatorError::Exhausted { max: state.max });
+        }
+        state.next += 1;
+        Ok(state.next - 1)
     }
 
-    /// X86 specific call to retrieve the state of the in-kernel PIT model.
+    /// Returns `gsi` to the allocator.
     ///
-    /// See the documentation for `KVM_GET_PIT2` in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// Fails with [`GsiAllocatorError::NotAllocated`] if `gsi` is not allocated.
     ///
     /// # Arguments
     ///
-    /// * `pitstate` - `kvm_pit_state2` to be read.
-    ///
-    /// # Example
-    ///
-    /// ```rust
-    /// # extern crate kvm_bindings;
-    /// # extern crate kvm_ioctls;
-    /// # use kvm_bindings::kvm_pit_config;
-    /// # use kvm_ioctls::Kvm;
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
+    /// * `gsi` - GSI returned by [`allocate`](Self::allocate).
+    pub fn free(&self, gsi: u32) -> std::result::Result<(), GsiAllocatorError> {
+        let mut guard = self.state();
+        let state = &mut *guard;
+        if gsi < state.start || gsi >= state.next || !state.freed.insert(gsi) {
+            return Err(GsiAllocatorError::NotAllocated(gsi));
+        }
+        // Shrink the allocated range rather than keeping trailing GSIs in the free set.
+        while state.next > state.start && state.freed.remove(&(state.next - 1)) {
+            state.next -= 1;
+        }
+        Ok(())
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, GsiAllocatorState> {
+        // The state is consistent after each operation, so a poisoned lock can be used.
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Registration of an irqfd which is removed when dropped, its GSI being returned to the
+/// [`GsiAllocator`] it was allocated from, if any.
+///
+/// Returned by [register_irqfd_guarded](struct.VmFd.html#method.register_irqfd_guarded) and
+/// [register_irqfd_with_resample_guarded](struct.VmFd.html#method.register_irqfd_with_resample_guarded).
+#[derive(Debug)]
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+pub struct IrqFdGuard<'a> {
+    vm: &'a VmFd,
+    fd: Option<EventFd>,
+    gsi: u32,
+    allocator: Option<GsiAllocator>,
+}
+
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl IrqFdGuard<'_> {
+    /// Returns the GSI the irqfd is registered for.
+    pub fn gsi(&self) -> u32 {
+        self.gsi
+    }
+
+    /// Unregisters the irqfd and frees its GSI, reporting the error that dropping the guard
+    /// would ignore.
+    pub fn unregister(mut self) -> Result<()> {
+        self.deassign()
+    }
+
+    /// Consumes the guard without unregistering the irqfd, whose GSI stays allocated.
     ///
-    /// let pit_config = kvm_pit_config::default();
-    /// vm.create_pit2(pit_config).unwrap();
-    /// let pitstate = vm.get_pit2().unwrap();
-    /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_pit2(&self) -> Result<kvm_pit_state2> {
-        let mut pitstate = Default::default();
-        // SAFETY: Here we trust the kernel not to read past the end of the kvm_pit_state2 struct.
-        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_PIT2(), &mut pitstate) };
-        if ret == 0 {
-            Ok(pitstate)
-        } else {
-            Err(errno::Error::last())
+    /// The irqfd stays registered until it is removed with
+    /// [unregister_irqfd](struct.VmFd.html#method.unregister_irqfd) or the VM is destroyed.
+    pub fn forget(mut self) {
+        self.fd.take();
+    }
+
+    fn deassign(&mut self) -> Result<()> {
+        match self.fd.take() {
+            Some(fd) => {
+                self.vm.unregister_irqfd(&fd, self.gsi)?;
+                if let Some(allocator) = &self.allocator {
+                    allocator.free(self.gsi)?;
+                }
+                Ok(())
+            }
+            None => Ok(()),
         }
     }
+}
 
-    /// X86 specific call to set the state of the in-kernel PIT model.
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+impl Drop for IrqFdGuard<'_> {
+    fn drop(&mut self) {
+        let _ = self.deassign();
+    }
+}
+
+/// Identifier of an in-kernel interrupt controller.
+///
+/// The `IrqChipId` is used for selecting the chip whose state is retrieved with
+/// [get_irqchip](struct.VmFd.html#method.get_irqchip).
+#[repr(u32)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub enum IrqChipId {
+    /// Master 8259 PIC.
+    PicMaster = KVM_IRQCHIP_PIC_MASTER,
+    /// Slave 8259 PIC.
+    PicSlave = KVM_IRQCHIP_PIC_SLAVE,
+    /// IOAPIC.
+    Ioapic = KVM_IRQCHIP_IOAPIC,
+}
+
+/// PIC view of the `kvm_irqchip` state of an 8259 PIC.
+///
+/// Dereferences to the underlying `kvm_irqchip`, so it can be passed back to
+/// [set_irqchip](struct.VmFd.html#method.set_irqchip).
+#[derive(Debug, Clone, Copy)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct PicState(kvm_irqchip);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl PicState {
+    /// Creates the PIC view of `irqchip`.
     ///
-    /// See the documentation for `KVM_SET_PIT2` in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// Fails with `EINVAL` if the `chip_id` of `irqchip` is not one of the PICs.
     ///
     /// # Arguments
     ///
-    /// * `pitstate` - `kvm_pit_state2` to be written.
-    ///
-    /// # Example
-    ///
-    /// ```rust
-    /// # extern crate kvm_bindings;
-    /// # extern crate kvm_ioctls;
-    /// # use kvm_bindings::{kvm_pit_config, kvm_pit_state2};
-    /// # use kvm_ioctls::Kvm;
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    ///
-    /// let pit_config = kvm_pit_config::default();
-    /// vm.create_pit2(pit_config).unwrap();
-    /// let mut pitstate = kvm_pit_state2::default();
-    /// // Your `pitstate` manipulation here.
-    /// vm.set_pit2(&mut pitstate).unwrap();
-    /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn set_pit2(&self, pitstate: &kvm_pit_state2) -> Result<()> {
-        // SAFETY: Here we trust the kernel not to read past the end of the kvm_pit_state2 struct.
-        let ret = unsafe { ioctl_with_ref(self, KVM_SET_PIT2(), pitstate) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
+    /// * `irqchip` - State returned by [get_irqchip](struct.VmFd.html#method.get_irqchip).
+    pub fn new(irqchip: kvm_irqchip) -> Result<Self> {
+        match irqchip.chip_id {
+            KVM_IRQCHIP_PIC_MASTER | KVM_IRQCHIP_PIC_SLAVE => Ok(PicState(irqchip)),
+            _ => Err(errno::Error::new(libc::EINVAL)),
         }
     }
 
-    /// X86 specific call to retrieve the current timestamp of kvmclock.
+    fn pic(&self) -> &kvm_pic_state {
+        // SAFETY: `new` checked that `pic` is the active member of the union.
+        unsafe { &self.0.chip.pic }
+    }
+
+    fn pic_mut(&mut self) -> &mut kvm_pic_state {
+        // SAFETY: `new` checked that `pic` is the active member of the union.
+        unsafe { &mut self.0.chip.pic }
+    }
+
+    /// Returns the interrupt request register.
+    pub fn irr(&self) -> u8 {
+        self.pic().irr
+    }
+
+    /// Sets the interrupt request register.
+    pub fn set_irr(&mut self, irr: u8) {
+        self.pic_mut().irr = irr;
+    }
+
+    /// Returns the in-service register.
+    pub fn isr(&self) -> u8 {
+        self.pic().isr
+    }
+
+    /// Sets the in-service register.
+    pub fn set_isr(&mut self, isr: u8) {
+        self.pic_mut().isr = isr;
+    }
+
+    /// Returns the interrupt mask register.
+    pub fn imr(&self) -> u8 {
+        self.pic().imr
+    }
+
+    /// Sets the interrupt mask register.
+    pub fn set_imr(&mut self, imr: u8) {
+        self.pic_mut().imr = imr;
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<PicState> for kvm_irqchip {
+    fn from(state: PicState) -> Self {
+        state.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::Deref for PicState {
+    type Target = kvm_irqchip;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// IOAPIC view of the `kvm_irqchip` state of the IOAPIC.
+///
+/// Dereferences to the underlying `kvm_irqchip`, so it can be passed back to
+/// [set_irqchip](struct.VmFd.html#method.set_irqchip).
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::{IoapicState, IrqChipId, Kvm};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// vm.create_irq_chip().unwrap();
+/// let mut ioapic = IoapicState::new(vm.get_irqchip(IrqChipId::Ioapic).unwrap()).unwrap();
+/// // Unmask pin 4 and deliver it on vector 0x34.
+/// ioapic.set_redirection_entry(4, 0x34).unwrap();
+/// vm.set_irqchip(&ioapic).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct IoapicState(kvm_irqchip);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl IoapicState {
+    /// Creates the IOAPIC view of `irqchip`.
     ///
-    /// See the documentation for `KVM_GET_CLOCK` in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// Fails with `EINVAL` if the `chip_id` of `irqchip` is not the IOAPIC.
     ///
     /// # Arguments
     ///
-    /// * `clock` - `kvm_clock_data` to be read.
-    ///
-    /// # Example
-    ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// # use kvm_ioctls::Kvm;
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    /// let clock = vm.get_clock().unwrap();
-    /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn get_clock(&self) -> Result<kvm_clock_data> {
-        let mut clock = Default::default();
-        // SAFETY: Here we trust the kernel not to read past the end of the kvm_clock_data struct.
-        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_CLOCK(), &mut clock) };
-        if ret == 0 {
-            Ok(clock)
-        } else {
-            Err(errno::Error::last())
+    /// * `irqchip` - State returned by [get_irqchip](struct.VmFd.html#method.get_irqchip).
+    pub fn new(irqchip: kvm_irqchip) -> Result<Self> {
+        match irqchip.chip_id {
+            KVM_IRQCHIP_IOAPIC => Ok(IoapicState(irqchip)),
+            _ => Err(errno::Error::new(libc::EINVAL)),
         }
     }
 
-    /// X86 specific call to set the current timestamp of kvmclock.
+    fn ioapic(&self) -> &kvm_ioapic_state {
+        // SAFETY: `new` checked that `ioapic` is the active member of the union.
+        unsafe { &self.0.chip.ioapic }
+    }
+
+    fn ioapic_mut(&mut self) -> &mut kvm_ioapic_state {
+        // SAFETY: `new` checked that `ioapic` is the active member of the union.
+        unsafe { &mut self.0.chip.ioapic }
+    }
+
+    /// Returns the IOAPIC id.
+    pub fn id(&self) -> u32 {
+        self.ioapic().id
+    }
+
+    /// Returns the interrupt request register, with one bit per pin.
+    pub fn irr(&self) -> u32 {
+        self.ioapic().irr
+    }
+
+    /// Returns the redirection table entry of `pin`, or `None` if `pin` is not below
+    /// `KVM_IOAPIC_NUM_PINS`.
+    pub fn redirection_entry(&self, pin: usize) -> Option<u64> {
+        self.ioapic()
+            .redirtbl
+            .get(pin)
+            // SAFETY: Both members of the union are plain integers.
+            .map(|entry| unsafe { entry.bits })
+    }
+
+    /// Sets the redirection table entry of `pin`.
     ///
-    /// See the documentation for `KVM_SET_CLOCK` in the
-    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// Fails with `EINVAL` if `pin` is not below `KVM_IOAPIC_NUM_PINS`.
     ///
     /// # Arguments
     ///
-    /// * `clock` - `kvm_clock_data` to be written.
-    ///
-    /// # Example
-    ///
-    /// ```rust
-    /// # extern crate kvm_bindings;
-    /// # extern crate kvm_ioctls;
-    /// # use kvm_bindings::kvm_clock_data;
-    /// # use kvm_ioctls::Kvm;
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    /// let mut clock = kvm_clock_data::default();
-    /// vm.set_clock(&mut clock).unwrap();
-    /// ```
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn set_clock(&self, clock: &kvm_clock_data) -> Result<()> {
-        // SAFETY: Here we trust the kernel not to read past the end of the kvm_clock_data struct.
-        let ret = unsafe { ioctl_with_ref(self, KVM_SET_CLOCK(), clock) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
+    /// * `pin` - IOAPIC pin.
+    /// * `entry` - Raw 64-bit redirection table entry.
+    pub fn set_redirection_entry(&mut self, pin: usize, entry: u64) -> Result<()> {
+        let slot = self
+            .ioapic_mut()
+            .redirtbl
+            .get_mut(pin)
+            .ok_or_else(|| errno::Error::new(libc::EINVAL))?;
+        slot.bits = entry;
+        Ok(())
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<IoapicState> for kvm_irqchip {
+    fn from(state: IoapicState) -> Self {
+        state.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::Deref for IoapicState {
+    type Target = kvm_irqchip;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// State of the kvmclock of a VM, with the `KVM_CLOCK_*` flags decoded.
+///
+/// Returned by [get_clock_full](struct.VmFd.html#method.get_clock_full) and restored with
+/// [set_clock_full](struct.VmFd.html#method.set_clock_full).
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct ClockState {
+    /// Raw clock data.
+    pub data: kvm_clock_data,
+    /// Whether the kvmclock is stable across vCPUs (`KVM_CLOCK_TSC_STABLE`).
+    pub tsc_stable: bool,
+    /// Whether `data.realtime` holds the host realtime sampled with the clock
+    /// (`KVM_CLOCK_REALTIME`).
+    pub realtime: bool,
+    /// Whether `data.host_tsc` holds the host TSC sampled with the clock
+    /// (`KVM_CLOCK_HOST_TSC`).
+    pub host_tsc: bool,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<kvm_clock_data> for ClockState {
+    fn from(data: kvm_clock_data) -> Self {
+        ClockState {
+            data,
+            tsc_stable: data.flags & KVM_CLOCK_TSC_STABLE != 0,
+            realtime: data.flags & KVM_CLOCK_REALTIME != 0,
+            host_tsc: data.flags & KVM_CLOCK_HOST_TSC != 0,
         }
     }
+}
 
-    /// Directly injects a MSI message as per the `KVM_SIGNAL_MSI` ioctl.
+/// Interrupt line of an arm/aarch64 VM, as encoded in the `irq` field of `KVM_IRQ_LINE`.
+///
+/// The encoding packs the interrupt type, the target vCPU index and the interrupt number:
+///
+/// | bits  | 31 ... 28   | 27 ... 24 | 23 ... 16  | 15 ... 0 |
+/// |-------|-------------|-----------|------------|----------|
+/// | field | vcpu2_index | irq_type  | vcpu_index | irq_id   |
+///
+/// vCPU indexes above 255 are split between `vcpu2_index` and `vcpu_index`, which requires
+/// `KVM_CAP_ARM_IRQ_LINE_LAYOUT_2`.
+///
+/// Used by [set_arm_irq_line](struct.VmFd.html#method.set_arm_irq_line).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+pub struct ArmIrqLine(u32);
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+impl ArmIrqLine {
+    const MAX_VCPU_INDEX: u32 = (KVM_ARM_IRQ_VCPU2_MASK + 1) * (KVM_ARM_IRQ_VCPU_MASK + 1) - 1;
+
+    /// Shared peripheral interrupt of the in-kernel GIC.
     ///
-    /// See the documentation for `KVM_SIGNAL_MSI`.
+    /// Fails with `EINVAL` if `intid` is not between 32 and 1019.
+    pub fn spi(intid: u32) -> Result<Self> {
+        if !(32..=1019).contains(&intid) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        Self::encode(KVM_ARM_IRQ_TYPE_SPI, 0, intid)
+    }
+
+    /// Private peripheral interrupt of the in-kernel GIC, targeting the vCPU `vcpu_index`.
     ///
-    /// This ioctl returns > 0 when the MSI is successfully delivered and 0
-    /// when the guest blocked the MSI.
+    /// Fails with `EINVAL` if `intid` is not between 16 and 31.
+    pub fn ppi(vcpu_index: u32, intid: u32) -> Result<Self> {
+        if !(16..=31).contains(&intid) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        Self::encode(KVM_ARM_IRQ_TYPE_PPI, vcpu_index, intid)
+    }
+
+    /// IRQ or FIQ line of the vCPU `vcpu_index`, when the GIC is emulated in userspace.
     ///
-    /// # Arguments
+    /// Fails with `EINVAL` if `level_type` is neither `KVM_ARM_IRQ_CPU_IRQ` nor
+    /// `KVM_ARM_IRQ_CPU_FIQ`.
+    pub fn cpu_irq(vcpu_index: u32, level_type: u32) -> Result<Self> {
+        if level_type != KVM_ARM_IRQ_CPU_IRQ && level_type != KVM_ARM_IRQ_CPU_FIQ {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        Self::encode(KVM_ARM_IRQ_TYPE_CPU, vcpu_index, level_type)
+    }
+
+    /// Returns the encoded value of the `irq` field.
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    fn encode(irq_type: u32, vcpu_index: u32, num: u32) -> Result<Self> {
+        if vcpu_index > Self::MAX_VCPU_INDEX {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let vcpu = vcpu_index & KVM_ARM_IRQ_VCPU_MASK;
+        let vcpu2 = vcpu_index / (KVM_ARM_IRQ_VCPU_MASK + 1);
+        Ok(ArmIrqLine(
+            vcpu2 << KVM_ARM_IRQ_VCPU2_SHIFT
+                | irq_type << KVM_ARM_IRQ_TYPE_SHIFT
+                | vcpu << KVM_ARM_IRQ_VCPU_SHIFT
+                | num << KVM_ARM_IRQ_NUM_SHIFT,
+        ))
+    }
+}
+
+/// Direction of a copy of MTE allocation tags, passed to
+/// [mte_copy_tags](struct.VmFd.html#method.mte_copy_tags).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(target_arch = "aarch64")]
+pub enum MteCopyFlags {
+    /// The tags are written to the guest memory (`KVM_ARM_TAGS_TO_GUEST`).
+    ToGuest = KVM_ARM_TAGS_TO_GUEST as isize,
+    /// The tags are read from the guest memory (`KVM_ARM_TAGS_FROM_GUEST`).
+    FromGuest = KVM_ARM_TAGS_FROM_GUEST as isize,
+}
+
+/// Number of bytes of memory covered by one MTE allocation tag.
+#[cfg(target_arch = "aarch64")]
+const MTE_GRANULE_SIZE: u64 = 16;
+
+// INTIDs of the PPIs, the only interrupts the PMU of a vCPU can be wired to.
+#[cfg(target_arch = "aarch64")]
+pub(crate) const PMU_PPI_RANGE: std::ops::Range<u32> = 16..32;
+
+/// Optional feature of an aarch64 vCPU, requested through an [`ArmVcpuInitBuilder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(target_arch = "aarch64")]
+pub enum ArmVcpuFeature {
+    /// PSCI v0.2 and later (`KVM_ARM_VCPU_PSCI_0_2`), requires `KVM_CAP_ARM_PSCI_0_2`.
+    Psci02,
+    /// PMUv3 emulation (`KVM_ARM_VCPU_PMU_V3`), requires `KVM_CAP_ARM_PMU_V3`.
+    Pmu,
+    /// Scalable Vector Extension (`KVM_ARM_VCPU_SVE`), requires `KVM_CAP_ARM_SVE`.
+    Sve,
+    /// Address and generic pointer authentication (`KVM_ARM_VCPU_PTRAUTH_ADDRESS` and
+    /// `KVM_ARM_VCPU_PTRAUTH_GENERIC`), requires `KVM_CAP_ARM_PTRAUTH_ADDRESS` and
+    /// `KVM_CAP_ARM_PTRAUTH_GENERIC`.
+    PtrAuth,
+    /// Nested virtualization, with the vCPU starting at EL2 (`KVM_ARM_VCPU_HAS_EL2`),
+    /// requires `KVM_CAP_ARM_EL2` and a GICv3.
+    El2,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl ArmVcpuFeature {
+    // Bits of the feature in the first word of `kvm_vcpu_init.features`.
+    fn bits(self) -> u32 {
+        match self {
+            ArmVcpuFeature::Psci02 => 1 << KVM_ARM_VCPU_PSCI_0_2,
+            ArmVcpuFeature::Pmu => 1 << KVM_ARM_VCPU_PMU_V3,
+            ArmVcpuFeature::Sve => 1 << KVM_ARM_VCPU_SVE,
+            ArmVcpuFeature::PtrAuth => {
+                1 << KVM_ARM_VCPU_PTRAUTH_ADDRESS | 1 << KVM_ARM_VCPU_PTRAUTH_GENERIC
+            }
+            ArmVcpuFeature::El2 => 1 << KVM_ARM_VCPU_HAS_EL2,
+        }
+    }
+
+    // Capabilities the VM must report for the feature to be supported.
+    fn caps(self) -> &'static [Cap] {
+        match self {
+            ArmVcpuFeature::Psci02 => &[Cap::ArmPsci02],
+            ArmVcpuFeature::Pmu => &[Cap::ArmPmuV3],
+            ArmVcpuFeature::Sve => &[Cap::ArmSve],
+            ArmVcpuFeature::PtrAuth => &[Cap::ArmPtrAuthAddress, Cap::ArmPtrAuthGeneric],
+            ArmVcpuFeature::El2 => &[Cap::ArmEl2],
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl std::fmt::Display for ArmVcpuFeature {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ArmVcpuFeature::Psci02 => write!(f, "PSCI v0.2"),
+            ArmVcpuFeature::Pmu => write!(f, "PMUv3"),
+            ArmVcpuFeature::Sve => write!(f, "SVE"),
+            ArmVcpuFeature::PtrAuth => write!(f, "pointer authentication"),
+            ArmVcpuFeature::El2 => write!(f, "EL2"),
+        }
+    }
+}
+
+/// Errors returned when requesting features from an [`ArmVcpuInitBuilder`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(target_arch = "aarch64")]
+pub enum ArmVcpuInitError {
+    /// The VM does not report the capabilities needed by the feature.
+    Unsupported(ArmVcpuFeature),
+    /// The PMU overflow interrupt is not a PPI, i.e. an INTID between 16 and 31.
+    InvalidPmuIrq(u32),
+}
+
+#[cfg(target_arch = "aarch64")]
+impl ArmVcpuInitError {
+    /// Returns the errno code corresponding to the error.
+    pub fn errno(&self) -> i32 {
+        errno::Error::from(*self).errno()
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl From<ArmVcpuInitError> for errno::Error {
+    fn from(err: ArmVcpuInitError) -> errno::Error {
+        match err {
+            // Same error as `KVM_ARM_VCPU_INIT` with an unsupported feature.
+            ArmVcpuInitError::Unsupported(_) => errno::Error::new(libc::EINVAL),
+            ArmVcpuInitError::InvalidPmuIrq(_) => errno::Error::new(libc::EINVAL),
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl std::fmt::Display for ArmVcpuInitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            ArmVcpuInitError::Unsupported(feature) => {
+                write!(f, "the vCPU feature {} is not supported", feature)
+            }
+            ArmVcpuInitError::InvalidPmuIrq(irq) => {
+                write!(f, "the PMU interrupt {} is not a PPI", irq)
+            }
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+impl std::error::Error for ArmVcpuInitError {}
+
+/// Builder for the `kvm_vcpu_init` of aarch64 vCPUs, created by
+/// [arm_vcpu_init_builder](struct.VmFd.html#method.arm_vcpu_init_builder).
+///
+/// Starts from the preferred target of the host, and only lets the features supported by the
+/// VM be requested. The same builder can initialize all the vCPUs of the VM.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::Kvm;
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// let vcpu = vm.create_vcpu(0).unwrap();
+/// let builder = vm.arm_vcpu_init_builder().unwrap().with_psci_0_2().unwrap();
+/// builder.init(&vcpu).unwrap();
+/// ```
+#[derive(Debug, Clone, Copy)]
+#[cfg(target_arch = "aarch64")]
+pub struct ArmVcpuInitBuilder {
+    kvi: kvm_vcpu_init,
+    // Bits of the features supported by the VM, probed when the builder is created.
+    supported: u32,
+    // PPI of the PMU overflow interrupt, set up by `init`.
+    pmu_irq: Option<u32>,
+}
+
+#[cfg(target_arch = "aarch64")]
+impl ArmVcpuInitBuilder {
+    /// Requests PSCI v0.2 and later versions, so that the guest can use PSCI calls such as
+    /// `SYSTEM_OFF` or `CPU_ON`.
+    pub fn with_psci_0_2(self) -> std::result::Result<Self, ArmVcpuInitError> {
+        self.with(ArmVcpuFeature::Psci02)
+    }
+
+    /// Requests PMUv3 emulation.
     ///
-    /// * kvm_msi - MSI message configuration. For details check the `kvm_msi` structure in the
-    ///                [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
-    /// # Example
+    /// The PMU must then be set up through the `KVM_ARM_VCPU_PMU_V3_CTRL` vCPU attributes
+    /// before the vCPU runs.
+    pub fn with_pmu(self) -> std::result::Result<Self, ArmVcpuInitError> {
+        self.with(ArmVcpuFeature::Pmu)
+    }
+
+    /// Requests PMUv3 emulation with its overflow interrupt on the PPI `irq`, set up by
+    /// [init](Self::init) with [pmu_set_irq](struct.VcpuFd.html#method.pmu_set_irq) and
+    /// [pmu_init](struct.VcpuFd.html#method.pmu_init).
     ///
-    /// In this example, the important function signal_msi() calling into
-    /// the actual ioctl is commented out. The reason is that MSI vectors are
-    /// not chosen from the HW side (VMM). The guest OS (or anything that runs
-    /// inside the VM) is supposed to allocate the MSI vectors, and usually
-    /// communicate back through PCI configuration space. Sending a random MSI
-    /// vector through this signal_msi() function will always result in a
-    /// failure, which is why it needs to be commented out.
+    /// The in-kernel vGIC must then be created and initialized before the vCPUs are
+    /// initialized.
     ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// extern crate kvm_bindings;
-    /// # use kvm_ioctls::Kvm;
-    /// use kvm_bindings::kvm_msi;
+    /// # Arguments
     ///
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    /// let msi = kvm_msi::default();
-    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    /// vm.create_irq_chip().unwrap();
-    /// //vm.signal_msi(msi).unwrap();
-    /// ```
-    #[cfg(any(
-        target_arch = "x86",
-        target_arch = "x86_64",
-        target_arch = "arm",
-        target_arch = "aarch64"
-    ))]
-    pub fn signal_msi(&self, msi: kvm_msi) -> Result<c_int> {
-        // SAFETY: Safe because we allocated the structure and we know the kernel
-        // will read exactly the size of the structure.
-        let ret = unsafe { ioctl_with_ref(self, KVM_SIGNAL_MSI(), &msi) };
-        if ret >= 0 {
-            Ok(ret)
-        } else {
-            Err(errno::Error::last())
+    /// * `irq` - INTID of the PPI, between 16 and 31.
+    pub fn with_pmu_irq(mut self, irq: u32) -> std::result::Result<Self, ArmVcpuInitError> {
+        if !PMU_PPI_RANGE.contains(&irq) {
+            return Err(ArmVcpuInitError::InvalidPmuIrq(irq));
         }
+        self = self.with(ArmVcpuFeature::Pmu)?;
+        self.pmu_irq = Some(irq);
+        Ok(self)
     }
 
-    /// Sets the GSI routing table entries, overwriting any previously set
-    /// entries, as per the `KVM_SET_GSI_ROUTING` ioctl.
-    ///
-    /// See the documentation for `KVM_SET_GSI_ROUTING`.
-    ///
-    /// Returns an io::Error when the table could not be updated.
+    /// Requests SVE with the default set of vector lengths, since [init](Self::init)
+    /// finalizes the SVE configuration right after initializing the vCPU.
+    pub fn with_sve(self) -> std::result::Result<Self, ArmVcpuInitError> {
+        self.with(ArmVcpuFeature::Sve)
+    }
+
+    /// Requests both address and generic pointer authentication, which KVM only supports
+    /// together.
+    pub fn with_ptrauth(self) -> std::result::Result<Self, ArmVcpuInitError> {
+        self.with(ArmVcpuFeature::PtrAuth)
+    }
+
+    /// Requests nested virtualization: the vCPU starts at EL2 instead of EL1.
+    ///
+    /// KVM only supports nested virtualization along with an in-kernel GICv3, whose
+    /// virtualization features are emulated for the guest hypervisor: the vGIC must be created
+    /// as a [`GicVersion::V3`](crate::GicVersion::V3). The vCPU also exposes the EL2 system
+    /// registers through `KVM_GET_REG_LIST`, so its register list is best retrieved with
+    /// [get_reg_ids](struct.VcpuFd.html#method.get_reg_ids).
+    pub fn with_el2(self) -> std::result::Result<Self, ArmVcpuInitError> {
+        self.with(ArmVcpuFeature::El2)
+    }
+
+    /// Returns the `kvm_vcpu_init` built so far.
+    pub fn kvi(&self) -> kvm_vcpu_init {
+        self.kvi
+    }
+
+    /// Initializes `vcpu` with `KVM_ARM_VCPU_INIT`, then finalizes the features requiring it
+    /// with `KVM_ARM_VCPU_FINALIZE` and sets up the PMU requested by
+    /// [with_pmu_irq](Self::with_pmu_irq).
     ///
     /// # Arguments
     ///
-    /// * kvm_irq_routing - IRQ routing configuration. Describe all routes
-    ///                     associated with GSI entries. For details check
-    ///                     the `kvm_irq_routing` and `kvm_irq_routing_entry`
-    ///                     structures in the
-    ///                     [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
-    /// # Example
+    /// * `vcpu` - vCPU of the VM the builder was created for.
+    pub fn init(&self, vcpu: &VcpuFd) -> Result<()> {
+        vcpu.vcpu_init(&self.kvi)?;
+        if self.kvi.features[0] & ArmVcpuFeature::Sve.bits() != 0 {
+            vcpu.vcpu_finalize(KVM_ARM_VCPU_SVE as i32)?;
+        }
+        if let Some(irq) = self.pmu_irq {
+            vcpu.pmu_set_irq(irq)?;
+            vcpu.pmu_init()?;
+        }
+        Ok(())
+    }
+
+    fn with(mut self, feature: ArmVcpuFeature) -> std::result::Result<Self, ArmVcpuInitError> {
+        let bits = feature.bits();
+        if self.supported & bits != bits {
+            return Err(ArmVcpuInitError::Unsupported(feature));
+        }
+        self.kvi.features[0] |= bits;
+        Ok(self)
+    }
+}
+
+/// Errors returned by [create_irq_chip](struct.VmFd.html#method.create_irq_chip).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqChipError {
+    /// An in-kernel irqchip has already been created for this VM (`EEXIST`).
+    AlreadyExists,
+    /// The split irqchip model has been enabled on this VM, which excludes the full in-kernel
+    /// irqchip (`EEXIST`).
+    SplitIrqChip,
+    /// The in-kernel irqchip cannot be created through `KVM_CREATE_IRQCHIP` on this host.
+    ///
+    /// On arm/aarch64 this means that the host cannot emulate a GICv2; the interrupt
+    /// controller must be created with [create_device](struct.VmFd.html#method.create_device)
+    /// instead, for instance as a GICv3.
+    Unsupported(errno::Error),
+    /// Any other error returned by `KVM_CREATE_IRQCHIP`.
+    Other(errno::Error),
+}
+
+impl IrqChipError {
+    /// Returns the errno code corresponding to the error.
+    pub fn errno(&self) -> i32 {
+        errno::Error::from(*self).errno()
+    }
+}
+
+impl From<IrqChipError> for errno::Error {
+    fn from(err: IrqChipError) -> errno::Error {
+        match err {
+            IrqChipError::AlreadyExists | IrqChipError::SplitIrqChip => {
+                errno::Error::new(libc::EEXIST)
+            }
+            IrqChipError::Unsupported(err) | IrqChipError::Other(err) => err,
+        }
+    }
+}
+
+impl std::fmt::Display for IrqChipError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            IrqChipError::AlreadyExists => write!(f, "the in-kernel irqchip already exists"),
+            IrqChipError::SplitIrqChip => write!(f, "the split irqchip model is enabled"),
+            IrqChipError::Unsupported(err) => write!(
+                f,
+                "the in-kernel irqchip is not supported, use create_device instead: {}",
+                err
+            ),
+            IrqChipError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for IrqChipError {}
+
+/// Configuration of the in-kernel PIT created by
+/// [create_pit](struct.VmFd.html#method.create_pit).
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::PitConfig;
+/// // Handle the PC speaker port (0x61) in the kernel too.
+/// let config = PitConfig::new().speaker_dummy(true);
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct PitConfig {
+    flags: u32,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl PitConfig {
+    /// Creates the default configuration, with all the flags cleared.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether the kernel emulates a dummy PC speaker (`KVM_PIT_SPEAKER_DUMMY`).
     ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// extern crate kvm_bindings;
-    /// # use kvm_ioctls::Kvm;
-    /// use kvm_bindings::kvm_irq_routing;
+    /// Without it, every access to port 0x61 exits to userspace.
     ///
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm = kvm.create_vm().unwrap();
-    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    /// vm.create_irq_chip().unwrap();
+    /// # Arguments
     ///
-    /// let irq_routing = kvm_irq_routing::default();
-    /// vm.set_gsi_routing(&irq_routing).unwrap();
-    /// ```
-    #[cfg(any(
-        target_arch = "x86",
-        target_arch = "x86_64",
-        target_arch = "arm",
-        target_arch = "aarch64"
-    ))]
-    pub fn set_gsi_routing(&self, irq_routing: &kvm_irq_routing) -> Result<()> {
-        // SAFETY: Safe because we allocated the structure and we know the kernel
-        // will read exactly the size of the structure.
-        let ret = unsafe { ioctl_with_ref(self, KVM_SET_GSI_ROUTING(), irq_routing) };
-        if ret == 0 {
-            Ok(())
+    /// * `enabled` - Whether to emulate the dummy speaker.
+    pub fn speaker_dummy(mut self, enabled: bool) -> Self {
+        if enabled {
+            self.flags |= KVM_PIT_SPEAKER_DUMMY;
         } else {
-            Err(errno::Error::last())
+            self.flags &= !KVM_PIT_SPEAKER_DUMMY;
         }
+        self
     }
+}
 
-    /// Registers an event to be signaled whenever a certain address is written to.
-    ///
-    /// See the documentation for `KVM_IOEVENTFD`.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<PitConfig> for kvm_pit_config {
+    fn from(config: PitConfig) -> Self {
+        kvm_pit_config {
+            flags: config.flags,
+            ..Default::default()
+        }
+    }
+}
+
+/// Configuration of a VM running Xen guests, passed to
+/// [xen_hvm_config](struct.VmFd.html#method.xen_hvm_config).
+///
+/// Only covers the flag-based configuration, where the hypercall page is generated by KVM
+/// rather than copied from userspace blobs.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::XenHvmConfig;
+/// // Forward the Xen hypercalls to userspace, and let the guest fill its hypercall page
+/// // through MSR 0x40000000.
+/// let config = XenHvmConfig::new()
+///     .intercept_hcall(true)
+///     .hypercall_msr(0x4000_0000);
+/// ```
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct XenHvmConfig {
+    flags: u32,
+    msr: u32,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl XenHvmConfig {
+    /// Creates an empty configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets whether Xen hypercalls exit to userspace with `KVM_EXIT_XEN`
+    /// (`KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL`).
     ///
     /// # Arguments
     ///
-    /// * `fd` - `EventFd` which will be signaled. When signaling, the usual `vmexit` to userspace
-    ///           is prevented.
-    /// * `addr` - Address being written to.
-    /// * `datamatch` - Limits signaling `fd` to only the cases where the value being written is
-    ///                 equal to this parameter. The size of `datamatch` is important and it must
-    ///                 match the expected size of the guest's write.
+    /// * `enabled` - Whether to intercept the hypercalls.
+    pub fn intercept_hcall(mut self, enabled: bool) -> Self {
+        self.set_flag(KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL, enabled);
+        self
+    }
+
+    /// Sets whether event channels can be delivered by
+    /// [xen_evtchn_send](struct.VmFd.html#method.xen_evtchn_send), and whether the guest
+    /// `EVTCHNOP_send` hypercalls are handled by KVM (`KVM_XEN_HVM_CONFIG_EVTCHN_SEND`).
     ///
-    /// # Example
+    /// # Arguments
     ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// extern crate libc;
-    /// extern crate vmm_sys_util;
-    /// # use kvm_ioctls::{IoEventAddress, Kvm, NoDatamatch};
-    /// use libc::{eventfd, EFD_NONBLOCK};
-    /// use vmm_sys_util::eventfd::EventFd;
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm_fd = kvm.create_vm().unwrap();
-    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
-    /// vm_fd
-    ///     .register_ioevent(&evtfd, &IoEventAddress::Pio(0xf4), NoDatamatch)
-    ///     .unwrap();
-    /// vm_fd
-    ///     .register_ioevent(&evtfd, &IoEventAddress::Mmio(0x1000), NoDatamatch)
-    ///     .unwrap();
-    /// ```
-    pub fn register_ioevent<T: Into<u64>>(
-        &self,
-        fd: &EventFd,
-        addr: &IoEventAddress,
-        datamatch: T,
-    ) -> Result<()> {
-        let mut flags = 0;
-        if std::mem::size_of::<T>() > 0 {
-            flags |= 1 << kvm_ioeventfd_flag_nr_datamatch
+    /// * `enabled` - Whether to enable in-kernel event channel delivery.
+    pub fn evtchn_send(mut self, enabled: bool) -> Self {
+        self.set_flag(KVM_XEN_HVM_CONFIG_EVTCHN_SEND, enabled);
+        self
+    }
+
+    /// Sets the MSR through which the guest requests its hypercall page, 0 to disable it.
+    ///
+    /// # Arguments
+    ///
+    /// * `msr` - Index of the MSR, usually 0x40000000.
+    pub fn hypercall_msr(mut self, msr: u32) -> Self {
+        self.msr = msr;
+        self
+    }
+
+    /// Returns the `KVM_XEN_HVM_CONFIG_*` flags of the configuration.
+    pub fn flags(&self) -> u32 {
+        self.flags
+    }
+
+    fn set_flag(&mut self, flag: u32, enabled: bool) {
+        if enabled {
+            self.flags |= flag;
+        } else {
+            self.flags &= !flag;
         }
-        if let IoEventAddress::Pio(_) = *addr {
-            flags |= 1 << kvm_ioeventfd_flag_nr_pio
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<XenHvmConfig> for kvm_xen_hvm_config {
+    fn from(config: XenHvmConfig) -> Self {
+        kvm_xen_hvm_config {
+            flags: config.flags,
+            msr: config.msr,
+            ..Default::default()
         }
+    }
+}
 
-        let ioeventfd = kvm_ioeventfd {
-            datamatch: datamatch.into(),
-            len: std::mem::size_of::<T>() as u32,
-            addr: match addr {
-                IoEventAddress::Pio(ref p) => *p,
-                IoEventAddress::Mmio(ref m) => *m,
-            },
-            fd: fd.as_raw_fd(),
-            flags,
+/// Builder of the MSR filter installed by
+/// [set_msr_filter](struct.VmFd.html#method.set_msr_filter).
+///
+/// Each range covers both the reads and the writes of `count` consecutive MSRs starting at
+/// `base`. The accesses to MSRs outside of all the ranges get the default action, and when
+/// ranges overlap the first one added takes precedence. Denied accesses inject a #GP in the
+/// guest, unless [enable_userspace_msr](struct.VmFd.html#method.enable_userspace_msr) was
+/// called with [`MsrExitReason::Filter`], in which case they exit to userspace.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::{Kvm, MsrFilterBuilder};
+/// let kvm = Kvm::new().unwrap();
+/// let vm = kvm.create_vm().unwrap();
+/// // Deny the accesses to the TSC (MSR 0x10) and allow everything else.
+/// let filter = MsrFilterBuilder::default_allow().deny_range(0x10, 1);
+/// filter.apply(&vm).unwrap();
+/// MsrFilterBuilder::clear(&vm).unwrap();
+/// ```
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct MsrFilterBuilder {
+    default_deny: bool,
+    // (base, count, allowed) of each range, in order.
+    ranges: Vec<(u32, u32, bool)>,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl MsrFilterBuilder {
+    /// Creates a filter without ranges that allows the accesses to all the MSRs.
+    pub fn default_allow() -> Self {
+        Self::default()
+    }
+
+    /// Creates a filter without ranges that denies the accesses to all the MSRs.
+    pub fn default_deny() -> Self {
+        MsrFilterBuilder {
+            default_deny: true,
             ..Default::default()
-        };
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IOEVENTFD(), &ioeventfd) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
         }
     }
 
-    /// Unregisters an event from a certain address it has been previously registered to.
-    ///
-    /// See the documentation for `KVM_IOEVENTFD`.
+    /// Adds a range of MSRs whose accesses are allowed.
     ///
     /// # Arguments
     ///
-    /// * `fd` - FD which will be unregistered.
-    /// * `addr` - Address being written to.
+    /// * `base` - Index of the first MSR of the range.
+    /// * `count` - Number of MSRs in the range.
+    pub fn allow_range(mut self, base: u32, count: u32) -> Self {
+        self.ranges.push((base, count, true));
+        self
+    }
+
+    /// Adds a range of MSRs whose accesses are denied.
     ///
-    /// # Safety
+    /// # Arguments
     ///
-    /// This function is unsafe because it relies on RawFd.
+    /// * `base` - Index of the first MSR of the range.
+    /// * `count` - Number of MSRs in the range.
+    pub fn deny_range(mut self, base: u32, count: u32) -> Self {
+        self.ranges.push((base, count, false));
+        self
+    }
+
+    /// Installs the filter on the VM, replacing the previous one.
     ///
-    /// # Example
+    /// Fails with `EINVAL` when there are more than `KVM_MSR_FILTER_MAX_RANGES` ranges, or
+    /// when a range is empty or longer than `KVM_MSR_FILTER_MAX_BITMAP_SIZE * 8` MSRs.
     ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// extern crate libc;
-    /// extern crate vmm_sys_util;
-    /// # use kvm_ioctls::{IoEventAddress, Kvm, NoDatamatch};
-    /// use libc::EFD_NONBLOCK;
-    /// use vmm_sys_util::eventfd::EventFd;
+    /// # Arguments
     ///
-    /// let kvm = Kvm::new().unwrap();
-    /// let vm_fd = kvm.create_vm().unwrap();
-    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
-    /// let pio_addr = IoEventAddress::Pio(0xf4);
-    /// let mmio_addr = IoEventAddress::Mmio(0x1000);
-    /// vm_fd
-    ///     .register_ioevent(&evtfd, &pio_addr, NoDatamatch)
-    ///     .unwrap();
-    /// vm_fd
-    ///     .register_ioevent(&evtfd, &mmio_addr, 0x1234u32)
-    ///     .unwrap();
-    /// vm_fd
-    ///     .unregister_ioevent(&evtfd, &pio_addr, NoDatamatch)
-    ///     .unwrap();
-    /// vm_fd
-    ///     .unregister_ioevent(&evtfd, &mmio_addr, 0x1234u32)
-    ///     .unwrap();
-    /// ```
-    pub fn unregister_ioevent<T: Into<u64>>(
-        &self,
-        fd: &EventFd,
-        addr: &IoEventAddress,
-        datamatch: T,
-    ) -> Result<()> {
-        let mut flags = 1 << kvm_ioeventfd_flag_nr_deassign;
-        if std::mem::size_of::<T>() > 0 {
-            flags |= 1 << kvm_ioeventfd_flag_nr_datamatch
+    /// * `vm` - The VM to filter the MSR accesses of.
+    pub fn apply(&self, vm: &VmFd) -> Result<()> {
+        if self.ranges.len() > KVM_MSR_FILTER_MAX_RANGES as usize {
+            return Err(errno::Error::new(libc::EINVAL));
         }
-        if let IoEventAddress::Pio(_) = *addr {
-            flags |= 1 << kvm_ioeventfd_flag_nr_pio
+        let max_msrs = KVM_MSR_FILTER_MAX_BITMAP_SIZE * 8;
+        if self
+            .ranges
+            .iter()
+            .any(|&(_, count, _)| count == 0 || count > max_msrs)
+        {
+            return Err(errno::Error::new(libc::EINVAL));
         }
 
-        let ioeventfd = kvm_ioeventfd {
-            datamatch: datamatch.into(),
-            len: std::mem::size_of::<T>() as u32,
-            addr: match addr {
-                IoEventAddress::Pio(ref p) => *p,
-                IoEventAddress::Mmio(ref m) => *m,
+        // The bitmaps must outlive the ioctl, the kernel copies them while installing the filter.
+        let mut bitmaps: Vec<Vec<u8>> = self
+            .ranges
+            .iter()
+            .map(|&(_, count, allowed)| {
+                let fill = if allowed { 0xff } else { 0 };
+                vec![fill; count.div_ceil(8) as usize]
+            })
+            .collect();
+
+        let mut filter = kvm_msr_filter {
+            flags: if self.default_deny {
+                KVM_MSR_FILTER_DEFAULT_DENY
+            } else {
+                KVM_MSR_FILTER_DEFAULT_ALLOW
             },
-            fd: fd.as_raw_fd(),
-            flags,
             ..Default::default()
         };
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IOEVENTFD(), &ioeventfd) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
+        for (i, (&(base, count, _), bitmap)) in
+            self.ranges.iter().zip(bitmaps.iter_mut()).enumerate()
+        {
+            filter.ranges[i] = kvm_msr_filter_range {
+                flags: KVM_MSR_FILTER_READ | KVM_MSR_FILTER_WRITE,
+                nmsrs: count,
+                base,
+                bitmap: bitmap.as_mut_ptr(),
+            };
         }
+        vm.set_msr_filter(&filter)
     }
 
-    /// Gets the bitmap of pages dirtied since the last call of this function.
-    ///
-    /// Leverages the dirty page logging feature in KVM. As a side-effect, this also resets the
-    /// bitmap inside the kernel. For the dirty log to be available, you have to set the flag
-    /// `KVM_MEM_LOG_DIRTY_PAGES` when creating guest memory regions.
-    ///
-    /// Check the documentation for `KVM_GET_DIRTY_LOG`.
+    /// Removes the MSR filter of the VM, by installing an empty filter that allows the
+    /// accesses to all the MSRs.
     ///
     /// # Arguments
     ///
-    /// * `slot` - Guest memory slot identifier.
-    /// * `memory_size` - Size of the memory region.
+    /// * `vm` - The VM to remove the filter of.
+    pub fn clear(vm: &VmFd) -> Result<()> {
+        Self::default_allow().apply(vm)
+    }
+}
+
+/// Channel of the in-kernel PIT.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub enum PitChannel {
+    /// Channel 0, connected to IRQ 0.
+    Timer = 0,
+    /// Channel 1, historically used for the DRAM refresh.
+    RamRefresh = 1,
+    /// Channel 2, connected to the PC speaker.
+    Speaker = 2,
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+bitflags::bitflags! {
+    /// Flags of the notify VM exits, passed to
+    /// [enable_notify_vmexit](struct.VmFd.html#method.enable_notify_vmexit).
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub struct NotifyVmexitFlags: u32 {
+        /// Corresponds to `KVM_X86_NOTIFY_VMEXIT_ENABLED`. Enables the notify VM exits.
+        const Enabled = KVM_X86_NOTIFY_VMEXIT_ENABLED;
+        /// Corresponds to `KVM_X86_NOTIFY_VMEXIT_USER`. Forwards the notify VM exits to
+        /// userspace as [`VcpuExit::Notify`](crate::VcpuExit::Notify).
+        const User = KVM_X86_NOTIFY_VMEXIT_USER;
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+bitflags::bitflags! {
+    /// Hyper-V enlightenments of a guest, prepared with
+    /// [enable_hyperv](struct.VmFd.html#method.enable_hyperv) and enabled on each vCPU with
+    /// [init_hyperv](struct.VcpuFd.html#method.init_hyperv).
+    ///
+    /// The values of the flags are stable, so that [`bits()`](Self::bits) and
+    /// [`from_bits()`](Self::from_bits) can be used to store them in a VM configuration.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct HypervFeatures: u64 {
+        /// Relaxed timing, which disables the watchdogs of the guest. Requires
+        /// `KVM_CAP_HYPERV`.
+        const Relaxed = 1 << 0;
+        /// Access to the local APIC through MSRs and the virtual APIC assist page. Requires
+        /// `KVM_CAP_HYPERV_VAPIC`.
+        const Vapic = 1 << 1;
+        /// The synthetic interrupt controller. Requires `KVM_CAP_HYPERV_SYNIC2` and an in-kernel
+        /// irqchip.
+        const Synic = 1 << 2;
+        /// The synthetic timers, which are delivered through SynIC. Requires [`Self::Synic`]
+        /// and `KVM_CAP_HYPERV_TIME`.
+        const Stimer = 1 << 3;
+        /// The reenlightenment notifications sent on TSC frequency changes. Requires
+        /// `KVM_CAP_HYPERV_TIME`.
+        const Reenlightenment = 1 << 4;
+        /// The enlightened VMCS of nested guests. Requires
+        /// `KVM_CAP_HYPERV_ENLIGHTENED_VMCS`, which is only supported on Intel hosts.
+        const EnlightenedVmcs = 1 << 5;
+    }
+}
+
+// Hyper-V CPUID leaves.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HYPERV_CPUID_FEATURES: u32 = 0x4000_0003;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HYPERV_CPUID_ENLIGHTMENT_INFO: u32 = 0x4000_0004;
+// `HYPERV_CPUID_FEATURES` EAX bits.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HV_SYNIC_AVAILABLE: u32 = 1 << 2;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HV_SYNTIMER_AVAILABLE: u32 = 1 << 3;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HV_APIC_ACCESS_AVAILABLE: u32 = 1 << 4;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HV_ACCESS_REENLIGHTENMENT: u32 = 1 << 13;
+// `HYPERV_CPUID_ENLIGHTMENT_INFO` EAX bits.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HV_APIC_ACCESS_RECOMMENDED: u32 = 1 << 3;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HV_RELAXED_TIMING_RECOMMENDED: u32 = 1 << 5;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+const HV_ENLIGHTENED_VMCS_RECOMMENDED: u32 = 1 << 14;
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl HypervFeatures {
+    /// Returns the enlightenments exposed by the Hyper-V CPUID leaves of `cpuid`, e.g. as
+    /// returned by [get_supported_hv_cpuid](struct.VcpuFd.html#method.get_supported_hv_cpuid).
+    pub fn from_hv_cpuid(cpuid: &CpuId) -> Self {
+        let leaf_eax = |function| {
+            cpuid
+                .as_slice()
+                .iter()
+                .find(|entry| entry.function == function)
+                .map_or(0, |entry| entry.eax)
+        };
+        let features = leaf_eax(HYPERV_CPUID_FEATURES);
+        let recommendations = leaf_eax(HYPERV_CPUID_ENLIGHTMENT_INFO);
+
+        let mut exposed = HypervFeatures::empty();
+        exposed.set(
+            HypervFeatures::Relaxed,
+            recommendations & HV_RELAXED_TIMING_RECOMMENDED != 0,
+        );
+        exposed.set(
+            HypervFeatures::Vapic,
+            features & HV_APIC_ACCESS_AVAILABLE != 0,
+        );
+        exposed.set(HypervFeatures::Synic, features & HV_SYNIC_AVAILABLE != 0);
+        exposed.set(
+            HypervFeatures::Stimer,
+            features & HV_SYNTIMER_AVAILABLE != 0,
+        );
+        exposed.set(
+            HypervFeatures::Reenlightenment,
+            features & HV_ACCESS_REENLIGHTENMENT != 0,
+        );
+        exposed.set(
+            HypervFeatures::EnlightenedVmcs,
+            recommendations & HV_ENLIGHTENED_VMCS_RECOMMENDED != 0,
+        );
+        exposed
+    }
+
+    /// Clears the bits of the enlightenments missing from `self` in the Hyper-V CPUID leaves
+    /// of `hv_cpuid`.
+    pub(crate) fn restrict_hv_cpuid(&self, hv_cpuid: &mut CpuId) {
+        let mut features = 0;
+        let mut recommendations = 0;
+        if !self.contains(HypervFeatures::Relaxed) {
+            recommendations |= HV_RELAXED_TIMING_RECOMMENDED;
+        }
+        if !self.contains(HypervFeatures::Vapic) {
+            features |= HV_APIC_ACCESS_AVAILABLE;
+            recommendations |= HV_APIC_ACCESS_RECOMMENDED;
+        }
+        if !self.contains(HypervFeatures::Synic) {
+            features |= HV_SYNIC_AVAILABLE;
+        }
+        if !self.contains(HypervFeatures::Stimer) {
+            features |= HV_SYNTIMER_AVAILABLE;
+        }
+        if !self.contains(HypervFeatures::Reenlightenment) {
+            features |= HV_ACCESS_REENLIGHTENMENT;
+        }
+        if !self.contains(HypervFeatures::EnlightenedVmcs) {
+            recommendations |= HV_ENLIGHTENED_VMCS_RECOMMENDED;
+        }
+        for entry in hv_cpuid.as_mut_slice() {
+            match entry.function {
+                HYPERV_CPUID_FEATURES => entry.eax &= !features,
+                HYPERV_CPUID_ENLIGHTMENT_INFO => entry.eax &= !recommendations,
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Action applied by a PMU event filter to the listed events, passed to
+/// [set_pmu_event_filter](struct.VmFd.html#method.set_pmu_event_filter) on x86 and to
+/// [pmu_set_filter](struct.VcpuFd.html#method.pmu_set_filter) on aarch64.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+pub enum PmuFilterAction {
+    /// Only the listed events can be programmed (`KVM_PMU_EVENT_ALLOW`).
+    Allow = KVM_PMU_EVENT_ALLOW as isize,
+    /// The listed events cannot be programmed (`KVM_PMU_EVENT_DENY`).
+    Deny = KVM_PMU_EVENT_DENY as isize,
+}
+
+/// Bus lock detection mode of a VM, passed to
+/// [enable_bus_lock_exit](struct.VmFd.html#method.enable_bus_lock_exit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub enum BusLockExitMode {
+    /// Bus locks are not detected (`KVM_BUS_LOCK_DETECTION_OFF`).
+    Off = KVM_BUS_LOCK_DETECTION_OFF as isize,
+    /// Bus locks exit to userspace (`KVM_BUS_LOCK_DETECTION_EXIT`), which can throttle the
+    /// guest.
+    Exit = KVM_BUS_LOCK_DETECTION_EXIT as isize,
+}
+
+/// State of the in-kernel PIT, as returned by [get_pit2](struct.VmFd.html#method.get_pit2).
+///
+/// Dereferences to the underlying `kvm_pit_state2`, and provides accessors for the state of
+/// each [`PitChannel`].
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct Pit2State(kvm_pit_state2);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl Pit2State {
+    /// Returns the current count of `channel`.
+    pub fn count(&self, channel: PitChannel) -> u32 {
+        self.0.channels[channel as usize].count
+    }
+
+    /// Sets the count of `channel`, which the kernel reloads when the state is restored.
     ///
-    /// # Example
+    /// # Arguments
     ///
-    /// ```rust
-    /// # extern crate kvm_ioctls;
-    /// # extern crate kvm_bindings;
-    /// # use std::io::Write;
-    /// # use std::ptr::null_mut;
-    /// # use std::slice;
-    /// # use kvm_ioctls::{Kvm, VcpuExit};
-    /// # use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
-    /// # let kvm = Kvm::new().unwrap();
-    /// # let vm = kvm.create_vm().unwrap();
-    /// // This example is based on https://lwn.net/Articles/658511/.
-    /// let mem_size = 0x4000;
-    /// let guest_addr: u64 = 0x1000;
-    /// let load_addr: *mut u8 = unsafe {
-    ///     libc::mmap(
-    ///         null_mut(),
-    ///         mem_size,
-    ///         libc::PROT_READ | libc::PROT_WRITE,
-    ///         libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
-    ///         -1,
-    ///         0,
-    ///     ) as *mut u8
-    /// };
+    /// * `channel` - PIT channel.
+    /// * `count` - Count, 0 standing for 0x10000.
+    pub fn set_count(&mut self, channel: PitChannel, count: u32) {
+        self.0.channels[channel as usize].count = count;
+    }
+
+    /// Returns the count latched on `channel`.
+    pub fn latched_count(&self, channel: PitChannel) -> u16 {
+        self.0.channels[channel as usize].latched_count
+    }
+
+    /// Returns the operating mode (0 to 5) of `channel`.
+    pub fn mode(&self, channel: PitChannel) -> u8 {
+        self.0.channels[channel as usize].mode
+    }
+
+    /// Sets the operating mode of `channel`.
     ///
-    /// // Initialize a guest memory region using the flag `KVM_MEM_LOG_DIRTY_PAGES`.
-    /// let mem_region = kvm_userspace_memory_region {
-    ///     slot: 0,
-    ///     guest_phys_addr: guest_addr,
-    ///     memory_size: mem_size as u64,
-    ///     userspace_addr: load_addr as u64,
-    ///     flags: KVM_MEM_LOG_DIRTY_PAGES,
-    /// };
-    /// unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+    /// # Arguments
     ///
-    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    /// // ASM code that just forces a MMIO Write.
-    /// let asm_code = [0xc6, 0x06, 0x00, 0x80, 0x00];
-    /// #[cfg(target_arch = "aarch64")]
-    /// let asm_code = [
-    ///     0x01, 0x00, 0x00, 0x10, /* adr x1, <this address> */
-    ///     0x22, 0x10, 0x00, 0xb9, /* str w2, [x1, #16]; write to this page */
-    ///     0x02, 0x00, 0x00, 0xb9, /* str w2, [x0]; force MMIO exit */
-    ///     0x00, 0x00, 0x00,
-    ///     0x14, /* b <this address>; shouldn't get here, but if so loop forever */
-    /// ];
+    /// * `channel` - PIT channel.
+    /// * `mode` - Operating mode, from 0 to 5.
+    pub fn set_mode(&mut self, channel: PitChannel, mode: u8) {
+        self.0.channels[channel as usize].mode = mode;
+    }
+
+    /// Returns whether the gate input of `channel` is high.
+    pub fn gate(&self, channel: PitChannel) -> bool {
+        self.0.channels[channel as usize].gate != 0
+    }
+
+    /// Sets the gate input of `channel`.
     ///
-    /// // Write the code in the guest memory. This will generate a dirty page.
-    /// unsafe {
-    ///     let mut slice = slice::from_raw_parts_mut(load_addr, mem_size);
-    ///     slice.write(&asm_code).unwrap();
-    /// }
+    /// # Arguments
     ///
-    /// let mut vcpu_fd = vm.create_vcpu(0).unwrap();
+    /// * `channel` - PIT channel.
+    /// * `gate` - Whether the gate input is high.
+    pub fn set_gate(&mut self, channel: PitChannel, gate: bool) {
+        self.0.channels[channel as usize].gate = u8::from(gate);
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<kvm_pit_state2> for Pit2State {
+    fn from(state: kvm_pit_state2) -> Self {
+        Pit2State(state)
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<Pit2State> for kvm_pit_state2 {
+    fn from(state: Pit2State) -> Self {
+        state.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::Deref for Pit2State {
+    type Target = kvm_pit_state2;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::DerefMut for Pit2State {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// A Xen HVM attribute for [xen_hvm_set_attr](struct.VmFd.html#method.xen_hvm_set_attr) and
+/// [xen_hvm_get_attr](struct.VmFd.html#method.xen_hvm_get_attr).
+///
+/// Wraps a `kvm_xen_hvm_attr` with its `type_` and union member set consistently, and
+/// dereferences to it.
+///
+/// # Example
+///
+/// ```rust
+/// # extern crate kvm_ioctls;
+/// # use kvm_ioctls::XenHvmAttr;
+/// let attr = XenHvmAttr::shared_info(0x10);
+/// assert_eq!(attr.shared_info_gfn(), Some(0x10));
+/// assert_eq!(attr.vector(), None);
+/// ```
+#[derive(Debug, Clone, Copy)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub struct XenHvmAttr(kvm_xen_hvm_attr);
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl XenHvmAttr {
+    /// Attribute placing the Xen shared info page at the guest frame `gfn`
+    /// (`KVM_XEN_ATTR_TYPE_SHARED_INFO`), `u64::MAX` removing it.
+    pub fn shared_info(gfn: u64) -> Self {
+        let mut attr = kvm_xen_hvm_attr {
+            type_: KVM_XEN_ATTR_TYPE_SHARED_INFO as u16,
+            ..Default::default()
+        };
+        attr.u.shared_info.gfn = gfn;
+        XenHvmAttr(attr)
+    }
+
+    /// Attribute setting the vector of the event channel upcall
+    /// (`KVM_XEN_ATTR_TYPE_UPCALL_VECTOR`), 0 disabling it.
+    pub fn upcall_vector(vector: u8) -> Self {
+        let mut attr = kvm_xen_hvm_attr {
+            type_: KVM_XEN_ATTR_TYPE_UPCALL_VECTOR as u16,
+            ..Default::default()
+        };
+        attr.u.vector = vector;
+        XenHvmAttr(attr)
+    }
+
+    /// Attribute setting whether the guest uses the 64-bit Xen ABI
+    /// (`KVM_XEN_ATTR_TYPE_LONG_MODE`).
+    pub fn long_mode(enabled: bool) -> Self {
+        let mut attr = kvm_xen_hvm_attr {
+            type_: KVM_XEN_ATTR_TYPE_LONG_MODE as u16,
+            ..Default::default()
+        };
+        attr.u.long_mode = u8::from(enabled);
+        XenHvmAttr(attr)
+    }
+
+    /// Returns the guest frame of the shared info page, if this is a shared info attribute.
+    pub fn shared_info_gfn(&self) -> Option<u64> {
+        if u32::from(self.0.type_) != KVM_XEN_ATTR_TYPE_SHARED_INFO {
+            return None;
+        }
+        // SAFETY: `type_` tells that `shared_info` is the active member of the union.
+        Some(unsafe { self.0.u.shared_info.gfn })
+    }
+
+    /// Returns the upcall vector, if this is an upcall vector attribute.
+    pub fn vector(&self) -> Option<u8> {
+        if u32::from(self.0.type_) != KVM_XEN_ATTR_TYPE_UPCALL_VECTOR {
+            return None;
+        }
+        // SAFETY: `type_` tells that `vector` is the active member of the union.
+        Some(unsafe { self.0.u.vector })
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<XenHvmAttr> for kvm_xen_hvm_attr {
+    fn from(attr: XenHvmAttr) -> Self {
+        attr.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::Deref for XenHvmAttr {
+    type Target = kvm_xen_hvm_attr;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::ops::DerefMut for XenHvmAttr {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.0
+    }
+}
+
+/// Errors returned by [create_pit](struct.VmFd.html#method.create_pit).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub enum PitError {
+    /// The host does not report `KVM_CAP_PIT2`.
+    Unsupported,
+    /// An in-kernel PIT has already been created for this VM (`EEXIST`).
+    AlreadyExists,
+    /// Any other error returned by `KVM_CREATE_PIT2`.
+    Other(errno::Error),
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl PitError {
+    /// Returns the errno code corresponding to the error.
+    pub fn errno(&self) -> i32 {
+        errno::Error::from(*self).errno()
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl From<PitError> for errno::Error {
+    fn from(err: PitError) -> errno::Error {
+        match err {
+            // Same error as the unknown `KVM_CREATE_PIT2` ioctl.
+            PitError::Unsupported => errno::Error::new(libc::ENOTTY),
+            PitError::AlreadyExists => errno::Error::new(libc::EEXIST),
+            PitError::Other(err) => err,
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::fmt::Display for PitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            PitError::Unsupported => write!(f, "the in-kernel PIT is not supported"),
+            PitError::AlreadyExists => write!(f, "the in-kernel PIT already exists"),
+            PitError::Other(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+impl std::error::Error for PitError {}
+
+/// Wrapper over KVM VM ioctls.
+#[derive(Debug)]
+pub struct VmFd {
+    vm: File,
+    run_size: usize,
+    // Whether an in-kernel irqchip, full or split, was created through this VmFd.
+    irqchip: AtomicBool,
+    // Whether the split irqchip model was enabled through this VmFd.
+    split_irqchip: AtomicBool,
+    // Whether an SEV-ES launch was started through this VmFd.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub(crate) sev_es: AtomicBool,
+    // Whether 32-bit APIC IDs were enabled through this VmFd.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    x2apic_32bit_ids: AtomicBool,
+    // Type the VM was created with, unknown for VMs created from a raw file descriptor.
+    #[cfg_attr(
+        not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")),
+        allow(dead_code)
+    )]
+    pub(crate) vm_type: Option<u64>,
+}
+
+impl VmFd {
+    /// Creates/modifies a guest physical memory slot.
     ///
-    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    /// {
-    ///     // x86_64 specific registry setup.
-    ///     let mut vcpu_sregs = vcpu_fd.get_sregs().unwrap();
-    ///     vcpu_sregs.cs.base = 0;
-    ///     vcpu_sregs.cs.selector = 0;
-    ///     vcpu_fd.set_sregs(&vcpu_sregs).unwrap();
+    /// See the documentation for `KVM_SET_USER_MEMORY_REGION`.
     ///
-    ///     let mut vcpu_regs = vcpu_fd.get_regs().unwrap();
-    ///     // Set the Instruction Pointer to the guest address where we loaded the code.
-    ///     vcpu_regs.rip = guest_addr;
-    ///     vcpu_regs.rax = 2;
-    ///     vcpu_regs.rbx = 3;
-    ///     vcpu_regs.rflags = 2;
-    ///     vcpu_fd.set_regs(&vcpu_regs).unwrap();
-    /// }
+    /// # Arguments
     ///
-    /// #[cfg(target_arch = "aarch64")]
-    /// {
-    ///     // aarch64 specific registry setup.
-    ///     let mut kvi = kvm_bindings::kvm_vcpu_init::default();
-    ///     vm.get_preferred_target(&mut kvi).unwrap();
-    ///     vcpu_fd.vcpu_init(&kvi).unwrap();
+    /// * `user_memory_region` - Guest physical memory slot. For details check the
+    ///             `kvm_userspace_memory_region` structure in the
+    ///             [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     ///
-    ///     let core_reg_base: u64 = 0x6030_0000_0010_0000;
-    ///     let mmio_addr: u64 = guest_addr + mem_size as u64;
-    ///     vcpu_fd.set_one_reg(core_reg_base + 2 * 32, &guest_addr.to_le_bytes()); // set PC
-    ///     vcpu_fd.set_one_reg(core_reg_base + 2 * 0, &mmio_addr.to_le_bytes()); // set X0
-    /// }
+    /// # Safety
     ///
-    /// loop {
-    ///     match vcpu_fd.run().expect("run failed") {
-    ///         VcpuExit::MmioWrite(addr, data) => {
-    ///             // On x86_64, the code snippet dirties 1 page when loading the code in memory
-    ///             // while on aarch64 the dirty bit comes from writing to guest_addr (current PC).
-    ///             let dirty_pages_bitmap = vm.get_dirty_log(0, mem_size).unwrap();
-    ///             let dirty_pages = dirty_pages_bitmap
-    ///                 .into_iter()
-    ///                 .map(|page| page.count_ones())
-    ///                 .fold(0, |dirty_page_count, i| dirty_page_count + i);
-    ///             assert_eq!(dirty_pages, 1);
-    ///             break;
-    ///         }
-    ///         exit_reason => panic!("unexpected exit reason: {:?}", exit_reason),
-    ///     }
-    /// }
+    /// This function is unsafe because there is no guarantee `userspace_addr` points to a valid
+    /// memory region, nor the memory region lives as long as the kernel needs it to.
+    ///
+    /// The caller of this method must make sure that:
+    /// - the raw pointer (`userspace_addr`) points to valid memory
+    /// - the regions provided to KVM are not overlapping other memory regions.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate kvm_bindings;
+    ///
+    /// use kvm_bindings::kvm_userspace_memory_region;
+    /// use kvm_ioctls::Kvm;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mem_region = kvm_userspace_memory_region {
+    ///     slot: 0,
+    ///     guest_phys_addr: 0x10000 as u64,
+    ///     memory_size: 0x10000 as u64,
+    ///     userspace_addr: 0x0 as u64,
+    ///     flags: 0,
+    /// };
+    /// unsafe {
+    ///     vm.set_user_memory_region(mem_region).unwrap();
+    /// };
     /// ```
-    pub fn get_dirty_log(&self, slot: u32, memory_size: usize) -> Result<Vec<u64>> {
-        // Compute the length of the bitmap needed for all dirty pages in one memory slot.
-        // One memory page is `page_size` bytes and `KVM_GET_DIRTY_LOG` returns one dirty bit for
-        // each page.
-        // SAFETY: We trust the sysconf libc function and we're calling it with a correct parameter.
-        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
-            -1 => return Err(errno::Error::last()),
-            ps => ps as usize,
-        };
-
-        // For ease of access we are saving the bitmap in a u64 vector. We are using ceil to
-        // make sure we count all dirty pages even when `memory_size` is not a multiple of
-        // `page_size * 64`.
-        let div_ceil = |dividend, divisor| (dividend + divisor - 1) / divisor;
-        let bitmap_size = div_ceil(memory_size, page_size * 64);
-        let mut bitmap = vec![0u64; bitmap_size];
-        let dirtylog = kvm_dirty_log {
-            slot,
-            padding1: 0,
-            __bindgen_anon_1: kvm_dirty_log__bindgen_ty_1 {
-                dirty_bitmap: bitmap.as_mut_ptr() as *mut c_void,
-            },
-        };
-        // SAFETY: Safe because we know that our file is a VM fd, and we know that the amount of
-        // memory we allocated for the bitmap is at least one bit per page.
-        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DIRTY_LOG(), &dirtylog) };
+    pub unsafe fn set_user_memory_region(
+        &self,
+        user_memory_region: kvm_userspace_memory_region,
+    ) -> Result<()> {
+        let ret = ioctl_with_ref(self, KVM_SET_USER_MEMORY_REGION(), &user_memory_region);
         if ret == 0 {
-            Ok(bitmap)
+            Ok(())
         } else {
             Err(errno::Error::last())
         }
     }
 
-    /// Registers an event that will, when signaled, trigger the `gsi` IRQ.
+    /// Sets the address of the three-page region in the VM's address space.
+    ///
+    /// See the documentation for `KVM_SET_TSS_ADDR`.
     ///
     /// # Arguments
     ///
-    /// * `fd` - `EventFd` to be signaled.
-    /// * `gsi` - IRQ to be triggered.
+    /// * `offset` - Physical address of a three-page region in the guest's physical address space.
+    ///              Fails with `EINVAL` if it is not below `0xffff_c000`, as the region must
+    ///              fit below 4 GiB.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate libc;
-    /// # extern crate vmm_sys_util;
     /// # use kvm_ioctls::Kvm;
-    /// # use libc::EFD_NONBLOCK;
-    /// # use vmm_sys_util::eventfd::EventFd;
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
-    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    /// {
-    ///     vm.create_irq_chip().unwrap();
-    ///     vm.register_irqfd(&evtfd, 0).unwrap();
-    /// }
+    /// vm.set_tss_address(0xfffb_d000).unwrap();
     /// ```
-    #[cfg(any(
-        target_arch = "x86",
-        target_arch = "x86_64",
-        target_arch = "arm",
-        target_arch = "aarch64"
-    ))]
-    pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
-        let irqfd = kvm_irqfd {
-            fd: fd.as_raw_fd() as u32,
-            gsi,
-            ..Default::default()
-        };
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_tss_address(&self, offset: usize) -> Result<()> {
+        if offset >= TSS_ADDRESS_LIMIT {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // SAFETY: Safe because we know that our file is a VM fd and we verify the return result.
+        let ret = unsafe { ioctl_with_val(self, KVM_SET_TSS_ADDR(), offset as c_ulong) };
         if ret == 0 {
             Ok(())
         } else {
@@ -885,58 +2333,50 @@ impl VmFd {
         }
     }
 
-    /// Registers an event that will, when signaled, assert the `gsi` IRQ.
-    /// If the irqchip is resampled by the guest, the IRQ is de-asserted,
-    /// and `resamplefd` is notified.
-    ///
-    /// # Arguments
+    /// Sets the address of the three-page TSS region to the conventional `0xfffb_d000`.
     ///
-    /// * `fd` - `EventFd` to be signaled.
-    /// * `resamplefd` - `EventFd`to be notified on resample.
-    /// * `gsi` - IRQ to be triggered.
+    /// This is the address used by most VMMs, right after the one-page identity map at
+    /// `0xfffb_c000`. See [set_tss_address](struct.VmFd.html#method.set_tss_address).
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate libc;
-    /// # extern crate vmm_sys_util;
     /// # use kvm_ioctls::Kvm;
-    /// # use libc::EFD_NONBLOCK;
-    /// # use vmm_sys_util::eventfd::EventFd;
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
-    /// let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
-    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    /// {
-    ///     vm.create_irq_chip().unwrap();
-    ///     vm.register_irqfd_with_resample(&evtfd, &resamplefd, 0)
-    ///         .unwrap();
-    /// }
+    /// vm.set_default_tss_address().unwrap();
     /// ```
-    #[cfg(any(
-        target_arch = "x86",
-        target_arch = "x86_64",
-        target_arch = "arm",
-        target_arch = "aarch64"
-    ))]
-    pub fn register_irqfd_with_resample(
-        &self,
-        fd: &EventFd,
-        resamplefd: &EventFd,
-        gsi: u32,
-    ) -> Result<()> {
-        let irqfd = kvm_irqfd {
-            fd: fd.as_raw_fd() as u32,
-            resamplefd: resamplefd.as_raw_fd() as u32,
-            gsi,
-            flags: KVM_IRQFD_FLAG_RESAMPLE,
-            ..Default::default()
-        };
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_default_tss_address(&self) -> Result<()> {
+        self.set_tss_address(DEFAULT_TSS_ADDRESS)
+    }
+
+    /// Sets the address of the one-page region in the VM's address space.
+    ///
+    /// See the documentation for `KVM_SET_IDENTITY_MAP_ADDR`.
+    ///
+    /// # Arguments
+    ///
+    /// * `address` - Physical address of a one-page region in the guest's physical address space.
+    ///               Fails with `EINVAL` if it is not page aligned or not below 4 GiB.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// vm.set_identity_map_address(0xfffb_c000).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_identity_map_address(&self, address: u64) -> Result<()> {
+        if address & 0xfff != 0 || address >= 1 << 32 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // SAFETY: Safe because we know that our file is a VM fd and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_IDENTITY_MAP_ADDR(), &address) };
         if ret == 0 {
             Ok(())
         } else {
@@ -944,34 +2384,62 @@ impl VmFd {
         }
     }
 
-    /// Unregisters an event that will, when signaled, trigger the `gsi` IRQ.
+    /// Sets the address of the one-page identity map to the conventional `0xfffb_c000`.
     ///
-    /// # Arguments
+    /// This is the page right below the [default TSS
+    /// region](struct.VmFd.html#method.set_default_tss_address). See
+    /// [set_identity_map_address](struct.VmFd.html#method.set_identity_map_address).
     ///
-    /// * `fd` - `EventFd` to be signaled.
-    /// * `gsi` - IRQ to be triggered.
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// vm.set_default_identity_map_address().unwrap();
+    /// vm.set_default_tss_address().unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_default_identity_map_address(&self) -> Result<()> {
+        self.set_identity_map_address(DEFAULT_IDENTITY_MAP_ADDRESS)
+    }
+
+    /// Creates an in-kernel interrupt controller.
+    ///
+    /// See the documentation for `KVM_CREATE_IRQCHIP`.
+    ///
+    /// # Errors
+    ///
+    /// Returns an [`IrqChipError`] telling apart an irqchip that already exists, a VM using
+    /// the split irqchip model, and a host that cannot create the irqchip this way. On
+    /// arm/aarch64, `KVM_CREATE_IRQCHIP` can only create a GICv2; other interrupt controllers,
+    /// such as the GICv3, must be created with [create_device](struct.VmFd.html#method.create_device).
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate libc;
-    /// # extern crate vmm_sys_util;
+    /// # extern crate kvm_bindings;
     /// # use kvm_ioctls::Kvm;
-    /// # use libc::EFD_NONBLOCK;
-    /// # use vmm_sys_util::eventfd::EventFd;
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
-    /// let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+    ///
     /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// vm.create_irq_chip().unwrap();
+    /// #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
     /// {
-    ///     vm.create_irq_chip().unwrap();
-    ///     vm.register_irqfd(&evtfd, 0).unwrap();
-    ///     vm.unregister_irqfd(&evtfd, 0).unwrap();
-    ///     vm.register_irqfd_with_resample(&evtfd, &resamplefd, 0)
-    ///         .unwrap();
-    ///     vm.unregister_irqfd(&evtfd, 0).unwrap();
+    ///     use kvm_bindings::{
+    ///         kvm_create_device, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, KVM_CREATE_DEVICE_TEST,
+    ///     };
+    ///     let mut gic_device = kvm_bindings::kvm_create_device {
+    ///         type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+    ///         fd: 0,
+    ///         flags: KVM_CREATE_DEVICE_TEST,
+    ///     };
+    ///     if vm.create_device(&mut gic_device).is_ok() {
+    ///         vm.create_irq_chip().unwrap();
+    ///     }
     /// }
     /// ```
     #[cfg(any(
@@ -980,69 +2448,44 @@ impl VmFd {
         target_arch = "arm",
         target_arch = "aarch64"
     ))]
-    pub fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
-        let irqfd = kvm_irqfd {
-            fd: fd.as_raw_fd() as u32,
-            gsi,
-            flags: KVM_IRQFD_FLAG_DEASSIGN,
-            ..Default::default()
-        };
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+    pub fn create_irq_chip(&self) -> std::result::Result<(), IrqChipError> {
+        // SAFETY: Safe because we know that our file is a VM fd and we verify the return result.
+        let ret = unsafe { ioctl(self, KVM_CREATE_IRQCHIP()) };
         if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
+            self.irqchip.store(true, Ordering::Relaxed);
+            return Ok(());
         }
+        let err = errno::Error::last();
+        Err(match err.errno() {
+            libc::EEXIST if self.split_irqchip.load(Ordering::Relaxed) => {
+                IrqChipError::SplitIrqChip
+            }
+            libc::EEXIST => IrqChipError::AlreadyExists,
+            libc::ENODEV | libc::ENXIO | libc::ENOTTY => IrqChipError::Unsupported(err),
+            _ => IrqChipError::Other(err),
+        })
     }
 
-    /// Sets the level on the given irq to 1 if `active` is true, and 0 otherwise.
-    ///
-    /// # Arguments
-    ///
-    /// * `irq` - IRQ to be set.
-    /// * `active` - Level of the IRQ input.
-    ///
-    /// # Errors
+    /// Checks whether an in-kernel interrupt controller was created for this VM.
     ///
-    /// Returns an io::Error when the irq field is invalid
+    /// Returns true if the VM supports `KVM_CAP_IRQCHIP` and an irqchip was created through
+    /// [create_irq_chip](struct.VmFd.html#method.create_irq_chip) or, on x86,
+    /// [enable_split_irqchip](struct.VmFd.html#method.enable_split_irqchip). Interrupt
+    /// controllers created with [create_device](struct.VmFd.html#method.create_device) are
+    /// not tracked.
     ///
-    /// # Examples
+    /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate libc;
-    /// # extern crate vmm_sys_util;
-    /// # use kvm_ioctls::{Kvm, VmFd};
-    /// # use libc::EFD_NONBLOCK;
-    /// # use vmm_sys_util::eventfd::EventFd;
-    /// fn arch_setup(vm_fd: &VmFd) {
-    ///     // Arch-specific setup:
-    ///     // For x86 architectures, it simply means calling vm.create_irq_chip().unwrap().
-    /// #   #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    /// #   vm_fd.create_irq_chip().unwrap();
-    ///     // For Arm architectures, the IRQ controllers need to be setup first.
-    ///     // Details please refer to the kernel documentation.
-    ///     // https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt
-    /// #   #[cfg(any(target_arch = "arm", target_arch = "aarch64"))] {
-    /// #       vm_fd.create_vcpu(0).unwrap();
-    /// #       // ... rest of setup for Arm goes here
-    /// #   }
-    /// }
-    ///
+    /// # use kvm_ioctls::Kvm;
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// arch_setup(&vm);
+    /// assert!(!vm.has_irqchip());
     /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     /// {
-    ///     vm.set_irq_line(4, true);
-    ///     // ...
-    /// }
-    /// #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-    /// {
-    ///     vm.set_irq_line(0x01_00_0020, true);
-    ///     // ....
+    ///     vm.create_irq_chip().unwrap();
+    ///     assert!(vm.has_irqchip());
     /// }
     /// ```
     #[cfg(any(
@@ -1051,320 +2494,413 @@ impl VmFd {
         target_arch = "arm",
         target_arch = "aarch64"
     ))]
-    pub fn set_irq_line(&self, irq: u32, active: bool) -> Result<()> {
-        let mut irq_level = kvm_irq_level::default();
-        irq_level.__bindgen_anon_1.irq = irq;
-        irq_level.level = u32::from(active);
-
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_IRQ_LINE(), &irq_level) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
-        }
+    pub fn has_irqchip(&self) -> bool {
+        self.irqchip.load(Ordering::Relaxed) && self.check_extension(Cap::Irqchip)
     }
 
-    /// Creates a new KVM vCPU file descriptor and maps the memory corresponding
-    /// its `kvm_run` structure.
-    ///
-    /// See the documentation for `KVM_CREATE_VCPU`.
+    /// Enables the split irqchip model, where the local APIC is emulated in the kernel while
+    /// the IOAPIC and the PIC are expected to be emulated in userspace.
     ///
-    /// # Arguments
+    /// This is a wrapper over `KVM_ENABLE_CAP` with `KVM_CAP_SPLIT_IRQCHIP`. It must be called
+    /// before any vCPU is created and it is mutually exclusive with
+    /// [`create_irq_chip`](Self::create_irq_chip): once the split irqchip is enabled, creating
+    /// the in-kernel irqchip fails with `EEXIST`.
     ///
-    /// * `id` - The vCPU ID.
+    /// EOIs for level-triggered interrupts routed through the userspace IOAPIC are reported as
+    /// [`VcpuExit::IoapicEoi`](crate::VcpuExit::IoapicEoi) so that the remote IRR bit can be
+    /// cleared.
     ///
-    /// # Errors
+    /// # Arguments
     ///
-    /// Returns an io::Error when the VM fd is invalid or the vCPU memory cannot
-    /// be mapped correctly.
+    /// * `ioapic_pins` - Number of routes reserved for the userspace IOAPIC. An IOAPIC supports
+    ///                   at most 24 pins, larger values are rejected with `EINVAL`.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_ioctls::{Cap, Kvm};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// // Create one vCPU with the ID=0.
-    /// let vcpu = vm.create_vcpu(0);
+    /// if kvm.check_extension(Cap::SplitIrqchip) {
+    ///     vm.enable_split_irqchip(24).unwrap();
+    ///     let vcpu = vm.create_vcpu(0).unwrap();
+    /// }
     /// ```
-    pub fn create_vcpu(&self, id: u64) -> Result<VcpuFd> {
-        #[allow(clippy::cast_lossless)]
-        // SAFETY: Safe because we know that vm is a VM fd and we verify the return result.
-        let vcpu_fd = unsafe { ioctl_with_val(&self.vm, KVM_CREATE_VCPU(), id as c_ulong) };
-        if vcpu_fd < 0 {
-            return Err(errno::Error::last());
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn enable_split_irqchip(&self, ioapic_pins: u32) -> Result<()> {
+        if ioapic_pins > KVM_IOAPIC_NUM_PINS {
+            return Err(errno::Error::new(libc::EINVAL));
         }
-
-        // Wrap the vCPU now in case the following ? returns early.
-        // SAFETY: This is safe because we verified the value of the fd and we own the fd.
-        let vcpu = unsafe { File::from_raw_fd(vcpu_fd) };
-
-        let kvm_run_ptr = KvmRunWrapper::mmap_from_fd(&vcpu, self.run_size)?;
-
-        Ok(new_vcpu(vcpu, kvm_run_ptr))
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_SPLIT_IRQCHIP,
+            ..Default::default()
+        };
+        cap.args[0] = u64::from(ioapic_pins);
+        self.enable_cap(&cap)?;
+        self.irqchip.store(true, Ordering::Relaxed);
+        self.split_irqchip.store(true, Ordering::Relaxed);
+        Ok(())
     }
 
-    /// Creates a VcpuFd object from a vcpu RawFd.
+    /// Configures the x2APIC API of the VM, by enabling `KVM_CAP_X2APIC_API`.
     ///
-    /// # Arguments
+    /// It must be called before any vCPU is created.
     ///
-    /// * `fd` - the RawFd used for creating the VcpuFd object.
+    /// With 32-bit APIC IDs, the destination ID of the MSIs is no longer limited to 8 bits:
+    /// bits 8-31 of the ID are taken from bits 8-31 of the `address_hi` of
+    /// `kvm_irq_routing_msi` and `kvm_msi`, whose bits 0-7 must then be zero. The
+    /// `kvm_lapic_state` of the vCPUs also holds the full x2APIC ID. [`GsiRouting::add_x86_msi`]
+    /// encodes the destination according to this setting.
     ///
-    /// # Safety
+    /// Without the broadcast quirk, the destination ID 0xff only means a broadcast in xAPIC
+    /// mode, so that it can address the vCPU with x2APIC ID 255.
     ///
-    /// This function is unsafe as the primitives currently returned have the contract that
-    /// they are the sole owner of the file descriptor they are wrapping. Usage of this function
-    /// could accidentally allow violating this contract which can cause memory unsafety in code
-    /// that relies on it being true.
+    /// # Arguments
     ///
-    /// The caller of this method must make sure the fd is valid and nothing else uses it.
+    /// * `use_32bit_ids` - Whether to set `KVM_X2APIC_API_USE_32BIT_IDS`.
+    /// * `disable_broadcast_quirk` - Whether to set `KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK`.
+    ///
+    /// Fails with `EINVAL` if one of the requested flags is not supported by the host.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # use std::os::unix::io::AsRawFd;
-    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_ioctls::{Cap, Kvm};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// // Create one vCPU with the ID=0.
-    /// let vcpu = vm.create_vcpu(0).unwrap();
-    /// let rawfd = unsafe { libc::dup(vcpu.as_raw_fd()) };
-    /// assert!(rawfd >= 0);
-    /// let vcpu = unsafe { vm.create_vcpu_from_rawfd(rawfd).unwrap() };
+    /// if vm.check_extension(Cap::X2ApicApi) {
+    ///     vm.enable_x2apic_api(true, true).unwrap();
+    /// }
     /// ```
-    pub unsafe fn create_vcpu_from_rawfd(&self, fd: RawFd) -> Result<VcpuFd> {
-        let vcpu = File::from_raw_fd(fd);
-        let kvm_run_ptr = KvmRunWrapper::mmap_from_fd(&vcpu, self.run_size)?;
-        Ok(new_vcpu(vcpu, kvm_run_ptr))
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn enable_x2apic_api(
+        &self,
+        use_32bit_ids: bool,
+        disable_broadcast_quirk: bool,
+    ) -> Result<()> {
+        let mut flags = 0;
+        if use_32bit_ids {
+            flags |= KVM_X2APIC_API_USE_32BIT_IDS;
+        }
+        if disable_broadcast_quirk {
+            flags |= KVM_X2APIC_API_DISABLE_BROADCAST_QUIRK;
+        }
+        let supported = self.check_extension_int(Cap::X2ApicApi).max(0) as u32;
+        if flags & !supported != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_X2APIC_API,
+            args: [u64::from(flags), 0, 0, 0],
+            ..Default::default()
+        };
+        self.enable_cap(&cap)?;
+        self.x2apic_32bit_ids
+            .store(use_32bit_ids, Ordering::Relaxed);
+        Ok(())
     }
 
-    /// Creates an emulated device in the kernel.
+    /// Allows the guest to use the SGX attribute controlled by `attribute_fd`, by enabling
+    /// `KVM_CAP_SGX_ATTRIBUTE`.
     ///
-    /// See the documentation for `KVM_CREATE_DEVICE`.
+    /// Restricted attributes such as the provision key are only available to the guests of
+    /// VMMs which can open their securityfs file, e.g. `/dev/sgx_provision`.
     ///
     /// # Arguments
     ///
-    /// * `device`: device configuration. For details check the `kvm_create_device` structure in the
-    ///                [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// * `attribute_fd` - The file of the SGX attribute.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate kvm_bindings;
-    /// # use kvm_ioctls::Kvm;
-    /// use kvm_bindings::{
-    ///     kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
-    ///     kvm_device_type_KVM_DEV_TYPE_VFIO, KVM_CREATE_DEVICE_TEST,
-    /// };
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// # use std::fs::File;
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
+    /// if vm.check_extension(Cap::SgxAttribute) {
+    ///     if let Ok(provision) = File::open("/dev/sgx_provision") {
+    ///         vm.enable_sgx_attribute(&provision).unwrap();
+    ///     }
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn enable_sgx_attribute(&self, attribute_fd: &File) -> Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_SGX_ATTRIBUTE,
+            args: [attribute_fd.as_raw_fd() as u64, 0, 0, 0],
+            ..Default::default()
+        };
+        self.enable_cap(&cap)
+    }
+
+    /// Registers an EPC section of an SGX guest in the memory slot `slot`.
     ///
-    /// // Creating a device with the KVM_CREATE_DEVICE_TEST flag to check
-    /// // whether the device type is supported. This will not create the device.
-    /// // To create the device the flag needs to be removed.
-    /// let mut device = kvm_bindings::kvm_create_device {
-    ///     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    ///     type_: kvm_device_type_KVM_DEV_TYPE_VFIO,
-    ///     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-    ///     type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
-    ///     fd: 0,
-    ///     flags: KVM_CREATE_DEVICE_TEST,
-    /// };
-    /// // On ARM, creating VGICv3 may fail due to hardware dependency.
-    /// // Retry to create VGICv2 in that case.
-    /// let device_fd = vm.create_device(&mut device).unwrap_or_else(|_| {
-    ///     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    ///     panic!("Cannot create VFIO device.");
-    ///     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-    ///     {
-    ///         device.type_ = kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2;
-    ///         vm.create_device(&mut device)
-    ///             .expect("Cannot create vGIC device")
-    ///     }
-    /// });
-    /// ```
-    pub fn create_device(&self, device: &mut kvm_create_device) -> Result<DeviceFd> {
-        // SAFETY: Safe because we are calling this with the VM fd and we trust the kernel.
-        let ret = unsafe { ioctl_with_ref(self, KVM_CREATE_DEVICE(), device) };
-        if ret == 0 {
-            // SAFETY: We validated the return of the function creating the fd and we trust the
-            // kernel.
-            Ok(new_device(unsafe { File::from_raw_fd(device.fd as i32) }))
-        } else {
-            Err(errno::Error::last())
-        }
-    }
-
-    /// Returns the preferred CPU target type which can be emulated by KVM on underlying host.
-    ///
-    /// The preferred CPU target is returned in the `kvi` parameter.
-    /// See documentation for `KVM_ARM_PREFERRED_TARGET`.
+    /// This is [set_user_memory_region](struct.VmFd.html#method.set_user_memory_region)
+    /// with the checks required by EPC sections: `vepc_mapping` must be a mapping of
+    /// `/dev/sgx_vepc` and, like `guest_phys_addr`, be aligned to the 4 KiB EPC pages.
+    /// The origin of the mapping is checked against `/proc/self/maps` when it is available.
     ///
     /// # Arguments
-    /// * `kvi` - CPU target configuration (out). For details check the `kvm_vcpu_init`
-    ///           structure in the
-    ///           [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// * `slot` - The memory slot of the EPC section.
+    /// * `guest_phys_addr` - The guest physical address of the EPC section.
+    /// * `vepc_mapping` - The mapping of `/dev/sgx_vepc` backing the EPC section.
+    ///
+    /// Fails with `EINVAL` if one of the requirements above is not met.
+    ///
+    /// # Safety
+    ///
+    /// The caller of this method must make sure that `vepc_mapping` stays mapped while the
+    /// memory slot exists, and that the EPC section does not overlap other memory regions.
     ///
     /// # Example
     ///
     /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate kvm_bindings;
+    /// # extern crate libc;
     /// # use kvm_ioctls::Kvm;
-    /// use kvm_bindings::kvm_vcpu_init;
+    /// # use std::fs::OpenOptions;
+    /// # use std::os::unix::io::AsRawFd;
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// let mut kvi = kvm_vcpu_init::default();
-    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// if let Ok(vepc) = OpenOptions::new().read(true).write(true).open("/dev/sgx_vepc") {
+    ///     let size = 0x10_0000;
+    ///     let addr = unsafe {
+    ///         libc::mmap(
+    ///             std::ptr::null_mut(),
+    ///             size,
+    ///             libc::PROT_READ | libc::PROT_WRITE,
+    ///             libc::MAP_SHARED,
+    ///             vepc.as_raw_fd(),
+    ///             0,
+    ///         )
+    ///     };
+    ///     assert_ne!(addr, libc::MAP_FAILED);
+    ///     let epc = unsafe { std::slice::from_raw_parts(addr as *const u8, size) };
+    ///     unsafe { vm.register_epc_section(0, 0x1_0000_0000, epc).unwrap() };
+    /// }
     /// ```
-    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-    pub fn get_preferred_target(&self, kvi: &mut kvm_vcpu_init) -> Result<()> {
-        // SAFETY: The ioctl is safe because we allocated the struct and we know the
-        // kernel will write exactly the size of the struct.
-        let ret = unsafe { ioctl_with_mut_ref(self, KVM_ARM_PREFERRED_TARGET(), kvi) };
-        if ret != 0 {
-            return Err(errno::Error::last());
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub unsafe fn register_epc_section(
+        &self,
+        slot: u32,
+        guest_phys_addr: u64,
+        vepc_mapping: &[u8],
+    ) -> Result<()> {
+        let userspace_addr = vepc_mapping.as_ptr() as u64;
+        let memory_size = vepc_mapping.len() as u64;
+        if memory_size == 0
+            || (guest_phys_addr | userspace_addr | memory_size) & (SGX_EPC_PAGE_SIZE - 1) != 0
+        {
+            return Err(errno::Error::new(libc::EINVAL));
         }
-        Ok(())
+        if let Some(path) = mapping_path(userspace_addr) {
+            if path != SGX_VEPC_PATH {
+                return Err(errno::Error::new(libc::EINVAL));
+            }
+        }
+
+        let region = kvm_userspace_memory_region {
+            slot,
+            guest_phys_addr,
+            memory_size,
+            userspace_addr,
+            flags: 0,
+        };
+        self.set_user_memory_region(region)
     }
 
-    /// Enable the specified capability as per the `KVM_ENABLE_CAP` ioctl.
-    ///
-    /// See the documentation for `KVM_ENABLE_CAP`.
-    ///
-    /// Returns an io::Error when the capability could not be enabled.
+    /// Returns a copy of `base` adjusted for the vCPU `vcpu_id` of a VM with `vcpu_count`
+    /// vCPUs and for the configuration of this VM.
+    ///
+    /// `base` is usually the CPUID returned by
+    /// [get_supported_cpuid](struct.Kvm.html#method.get_supported_cpuid). The following
+    /// leaves are patched:
+    /// * leaf 0x1: the initial APIC ID, the logical processor count, the HTT feature and the
+    ///   hypervisor bit. The x2APIC and TSC deadline features are removed unless the VM has an
+    ///   in-kernel local APIC, which emulates them.
+    /// * leaves 0xB and 0x1F: the topology, with one thread per core and all the vCPUs in a
+    ///   single package, and the x2APIC ID.
+    /// * leaf 0x40000000: the `KVMKVMKVM` hypervisor signature, if the leaf is present.
+    ///
+    /// The result can be passed to [set_cpuid2](struct.VcpuFd.html#method.set_cpuid2) of the
+    /// vCPU `vcpu_id`, once the irqchip configuration of the VM is final.
     ///
     /// # Arguments
     ///
-    /// * kvm_enable_cap - KVM capability structure. For details check the `kvm_enable_cap`
-    ///                    structure in the
-    ///                    [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// * `base` - CPUID to adjust.
+    /// * `vcpu_count` - Number of vCPUs of the VM.
+    /// * `vcpu_id` - ID of the vCPU, used as its APIC ID.
     ///
     /// # Example
     ///
     /// ```rust
+    /// # extern crate kvm_bindings;
     /// # extern crate kvm_ioctls;
-    /// extern crate kvm_bindings;
-    ///
+    /// # use kvm_bindings::KVM_MAX_CPUID_ENTRIES;
     /// # use kvm_ioctls::Kvm;
-    /// use kvm_bindings::{kvm_enable_cap, KVM_CAP_SPLIT_IRQCHIP};
-    ///
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// let mut cap: kvm_enable_cap = Default::default();
-    /// // This example cannot enable an arm/aarch64 capability since there
-    /// // is no capability available for these architectures.
-    /// if cfg!(target_arch = "x86") || cfg!(target_arch = "x86_64") {
-    ///     cap.cap = KVM_CAP_SPLIT_IRQCHIP;
-    ///     // As per the KVM documentation, KVM_CAP_SPLIT_IRQCHIP only emulates
-    ///     // the local APIC in kernel, expecting that a userspace IOAPIC will
-    ///     // be implemented by the VMM.
-    ///     // Along with this capability, the user needs to specify the number
-    ///     // of pins reserved for the userspace IOAPIC. This number needs to be
-    ///     // provided through the first argument of the capability structure, as
-    ///     // specified in KVM documentation:
-    ///     //     args[0] - number of routes reserved for userspace IOAPICs
-    ///     //
-    ///     // Because an IOAPIC supports 24 pins, that's the reason why this test
-    ///     // picked this number as reference.
-    ///     cap.args[0] = 24;
-    ///     vm.enable_cap(&cap).unwrap();
+    /// vm.create_irq_chip().unwrap();
+    /// let base = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+    /// for id in 0..2 {
+    ///     let vcpu = vm.create_vcpu(u64::from(id)).unwrap();
+    ///     vcpu.set_cpuid2(&vm.normalized_cpuid(&base, 2, id)).unwrap();
     /// }
     /// ```
-    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
-    pub fn enable_cap(&self, cap: &kvm_enable_cap) -> Result<()> {
-        // SAFETY: The ioctl is safe because we allocated the struct and we know the
-        // kernel will write exactly the size of the struct.
-        let ret = unsafe { ioctl_with_ref(self, KVM_ENABLE_CAP(), cap) };
-        if ret == 0 {
-            Ok(())
-        } else {
-            Err(errno::Error::last())
-        }
-    }
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn normalized_cpuid(&self, base: &CpuId, vcpu_count: u8, vcpu_id: u8) -> CpuId {
+        // Leaf 0x1 bits.
+        const EBX_LOGICAL_COUNT_SHIFT: u32 = 16;
+        const EBX_APIC_ID_SHIFT: u32 = 24;
+        const ECX_X2APIC: u32 = 1 << 21;
+        const ECX_TSC_DEADLINE: u32 = 1 << 24;
+        const ECX_HYPERVISOR: u32 = 1 << 31;
+        const EDX_HTT: u32 = 1 << 28;
+        // Level types of leaves 0xB and 0x1F.
+        const LEVEL_TYPE_SMT: u32 = 1;
+        const LEVEL_TYPE_CORE: u32 = 2;
 
-    /// Get the `kvm_run` size.
-    pub fn run_size(&self) -> usize {
-        self.run_size
-    }
+        let has_lapic = self.irqchip.load(Ordering::Relaxed);
+        // Number of x2APIC ID bits used by the cores of the package.
+        let core_bits = u32::from(vcpu_count).next_power_of_two().trailing_zeros();
 
-    /// Wrapper over `KVM_CHECK_EXTENSION`.
-    ///
-    /// Returns 0 if the capability is not available and a positive integer otherwise.
-    fn check_extension_int(&self, c: Cap) -> i32 {
-        // SAFETY: Safe because we know that our file is a VM fd and that the extension is one of
-        // the ones defined by kernel.
-        unsafe { ioctl_with_val(self, KVM_CHECK_EXTENSION(), c as c_ulong) }
+        let mut cpuid = base.clone();
+        for entry in cpuid.as_mut_slice() {
+            match entry.function {
+                0x1 => {
+                    entry.ebx &= 0xffff;
+                    entry.ebx |= u32::from(vcpu_id) << EBX_APIC_ID_SHIFT;
+                    entry.ebx |= u32::from(vcpu_count) << EBX_LOGICAL_COUNT_SHIFT;
+                    if vcpu_count > 1 {
+                        entry.edx |= EDX_HTT;
+                    } else {
+                        entry.edx &= !EDX_HTT;
+                    }
+                    entry.ecx |= ECX_HYPERVISOR;
+                    if !has_lapic {
+                        entry.ecx &= !(ECX_X2APIC | ECX_TSC_DEADLINE);
+                    }
+                }
+                0xb | 0x1f => {
+                    let (shift, count, level_type) = match entry.index {
+                        0 => (0, 1, LEVEL_TYPE_SMT),
+                        1 => (core_bits, u32::from(vcpu_count), LEVEL_TYPE_CORE),
+                        _ => (0, 0, 0),
+                    };
+                    entry.eax = shift;
+                    entry.ebx = count;
+                    entry.ecx = (level_type << 8) | (entry.index & 0xff);
+                    entry.edx = u32::from(vcpu_id);
+                }
+                0x4000_0000 => {
+                    // "KVMKVMKVM\0\0\0"
+                    entry.ebx = 0x4b4d_564b;
+                    entry.ecx = 0x564b_4d56;
+                    entry.edx = 0x4d;
+                }
+                _ => {}
+            }
+        }
+        cpuid
     }
 
-    /// Checks if a particular `Cap` is available.
+    /// X86 specific call to retrieve the state of a kernel interrupt controller.
     ///
-    /// Returns true if the capability is supported and false otherwise.
-    /// See the documentation for `KVM_CHECK_EXTENSION`.
+    /// See the documentation for `KVM_GET_IRQCHIP` in the
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     ///
     /// # Arguments
     ///
-    /// * `c` - VM capability to check.
+    /// * `chip_id` - Identifier of the interrupt controller to be read.
     ///
     /// # Example
     ///
-    /// ```
-    /// # use kvm_ioctls::Kvm;
-    /// use kvm_ioctls::Cap;
-    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{IrqChipId, Kvm};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// // Check if `KVM_CAP_MP_STATE` is supported.
-    /// assert!(vm.check_extension(Cap::MpState));
+    ///
+    /// vm.create_irq_chip().unwrap();
+    /// let irqchip = vm.get_irqchip(IrqChipId::PicMaster).unwrap();
     /// ```
-    pub fn check_extension(&self, c: Cap) -> bool {
-        self.check_extension_int(c) > 0
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_irqchip(&self, chip_id: IrqChipId) -> Result<kvm_irqchip> {
+        let mut irqchip = kvm_irqchip {
+            chip_id: chip_id as u32,
+            ..Default::default()
+        };
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_irqchip struct.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_IRQCHIP(), &mut irqchip) };
+        if ret == 0 {
+            Ok(irqchip)
+        } else {
+            Err(errno::Error::last())
+        }
     }
 
-    /// Issues platform-specific memory encryption commands to manage encrypted VMs if
-    /// the platform supports creating those encrypted VMs.
-    ///
-    /// Currently, this ioctl is used for issuing Secure Encrypted Virtualization
-    /// (SEV) commands on AMD Processors.
+    /// X86 specific call to set the state of a kernel interrupt controller.
     ///
-    /// See the documentation for `KVM_MEMORY_ENCRYPT_OP` in the
+    /// See the documentation for `KVM_SET_IRQCHIP` in the
     /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     ///
-    /// For SEV-specific functionality, prefer safe wrapper:
-    /// - [`encrypt_op_sev`](Self::encrypt_op_sev)
+    /// # Arguments
     ///
-    /// # Safety
+    /// * `irqchip` - `kvm_irqchip` to be written. Its `chip_id` field selects the interrupt
+    ///               controller.
     ///
-    /// This function is unsafe because there is no guarantee `T` is valid in this context, how
-    /// much data kernel will read from memory and where it will write data on error.
+    /// # Example
     ///
-    /// # Arguments
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{IrqChipId, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
     ///
-    /// * `op` - an opaque platform specific structure.
+    /// vm.create_irq_chip().unwrap();
+    /// let irqchip = vm.get_irqchip(IrqChipId::PicMaster).unwrap();
+    /// // Your `irqchip` manipulation here.
+    /// vm.set_irqchip(&irqchip).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_irqchip(&self, irqchip: &kvm_irqchip) -> Result<()> {
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_irqchip struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_IRQCHIP(), irqchip) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Creates a PIT as per the `KVM_CREATE_PIT2` ioctl.
     ///
+    /// # Arguments
+    ///
+    /// * pit_config - PIT configuration. For details check the `kvm_pit_config` structure in the
+    ///                [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     /// # Example
-    #[cfg_attr(has_sev, doc = "```rust")]
-    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    ///
+    /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate kvm_bindings;
-    /// use kvm_bindings::bindings::kvm_sev_cmd;
+    /// extern crate kvm_bindings;
     /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::kvm_pit_config;
     ///
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    ///
-    /// // Initialize the SEV platform context.
-    /// let mut init: kvm_sev_cmd = Default::default();
-    /// unsafe { vm.encrypt_op(&mut init).unwrap() };
+    /// let pit_config = kvm_pit_config::default();
+    /// vm.create_pit2(pit_config).unwrap();
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub unsafe fn encrypt_op<T>(&self, op: *mut T) -> Result<()> {
-        let ret = ioctl_with_mut_ptr(self, KVM_MEMORY_ENCRYPT_OP(), op);
+    pub fn create_pit2(&self, pit_config: kvm_pit_config) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_CREATE_PIT2(), &pit_config) };
         if ret == 0 {
             Ok(())
         } else {
@@ -1372,113 +2908,105 @@ impl VmFd {
         }
     }
 
-    /// Issue common lifecycle events of SEV guests, such as launching, running, snapshotting,
-    /// migrating and decommissioning via `KVM_MEMORY_ENCRYPT_OP` ioctl.
-    ///
-    /// Kernel documentation states that this ioctl can be used for testing whether SEV is enabled
-    /// by sending `NULL`. To do that, pass [`std::ptr::null_mut`](std::ptr::null_mut) to [`encrypt_op`](Self::encrypt_op).
+    /// Creates the in-kernel PIT from a [`PitConfig`].
     ///
-    /// See the documentation for Secure Encrypted Virtualization (SEV).
+    /// Same as [create_pit2](struct.VmFd.html#method.create_pit2), checking for `KVM_CAP_PIT2`
+    /// first. The kernel requires an in-kernel irqchip to be created beforehand, and fails
+    /// with `ENOENT` otherwise.
     ///
     /// # Arguments
     ///
-    /// * `op` - SEV-specific structure. For details check the
-    ///         [Secure Encrypted Virtualization (SEV) doc](https://www.kernel.org/doc/Documentation/virtual/kvm/amd-memory-encryption.rst).
+    /// * `config` - PIT configuration.
     ///
     /// # Example
-    #[cfg_attr(has_sev, doc = "```rust")]
-    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
-    /// # extern crate kvm_ioctls;
-    /// # extern crate kvm_bindings;
-    /// # use std::{os::raw::c_void, ptr::null_mut};
-    /// use kvm_bindings::bindings::kvm_sev_cmd;
-    /// # use kvm_ioctls::Kvm;
     ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Kvm, PitConfig};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    ///
-    /// // Check whether SEV is enabled, optional.
-    /// assert!(unsafe { vm.encrypt_op(null_mut() as *mut c_void) }.is_ok());
-    ///
-    /// // Initialize the SEV platform context.
-    /// let mut init: kvm_sev_cmd = Default::default();
-    /// vm.encrypt_op_sev(&mut init).unwrap();
+    /// vm.create_irq_chip().unwrap();
+    /// vm.create_pit(PitConfig::new().speaker_dummy(true)).unwrap();
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn encrypt_op_sev(&self, op: &mut kvm_sev_cmd) -> Result<()> {
-        // SAFETY: Safe because we know that kernel will only read the correct amount of memory
-        // from our pointer and we know where it will write it (op.error).
-        unsafe { self.encrypt_op(op) }
+    pub fn create_pit(&self, config: PitConfig) -> std::result::Result<(), PitError> {
+        if !self.check_extension(Cap::Pit2) {
+            return Err(PitError::Unsupported);
+        }
+        self.create_pit2(config.into())
+            .map_err(|err| match err.errno() {
+                libc::EEXIST => PitError::AlreadyExists,
+                _ => PitError::Other(err),
+            })
     }
 
-    /// Register a guest memory region which may contain encrypted data.
-    ///
-    /// It is used in the SEV-enabled guest.
+    /// X86 specific call to retrieve the state of the in-kernel PIT model.
     ///
-    /// See the documentation for `KVM_MEMORY_ENCRYPT_REG_REGION` in the
+    /// See the documentation for `KVM_GET_PIT2` in the
     /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// Fails with `ENXIO` if no PIT was created with
+    /// [create_pit2](struct.VmFd.html#method.create_pit2).
     ///
-    /// # Arguments
-    ///
-    /// * `memory_region` - Guest physical memory region.
+    /// The state is returned in a [`Pit2State`], wrapping the `kvm_pit_state2` structure.
     ///
     /// # Example
-    #[cfg_attr(has_sev, doc = "```rust")]
-    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    ///
+    /// ```rust
     /// # extern crate kvm_bindings;
     /// # extern crate kvm_ioctls;
-    /// # extern crate libc;
-    /// # use std::{fs::OpenOptions, ptr::null_mut};
-    /// # use std::os::unix::io::AsRawFd;
-    /// use kvm_bindings::bindings::{kvm_enc_region, kvm_sev_cmd, kvm_sev_launch_start, sev_cmd_id_KVM_SEV_LAUNCH_START};
-    /// # use kvm_ioctls::Kvm;
-    /// use libc;
-    ///
+    /// # use kvm_bindings::kvm_pit_config;
+    /// # use kvm_ioctls::{Kvm, PitChannel};
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// let sev = OpenOptions::new()
-    ///     .read(true)
-    ///     .write(true)
-    ///     .open("/dev/sev")
-    ///     .unwrap();
     ///
-    /// // Initialize the SEV platform context.
-    /// let mut init: kvm_sev_cmd = Default::default();
-    /// assert!(vm.encrypt_op_sev(&mut init).is_ok());
+    /// let pit_config = kvm_pit_config::default();
+    /// vm.create_pit2(pit_config).unwrap();
+    /// let pitstate = vm.get_pit2().unwrap();
+    /// let mode = pitstate.mode(PitChannel::Timer);
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_pit2(&self) -> Result<Pit2State> {
+        let mut pitstate = Pit2State::default();
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_pit_state2 struct.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_PIT2(), &mut pitstate.0) };
+        if ret == 0 {
+            Ok(pitstate)
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// X86 specific call to set the state of the in-kernel PIT model.
     ///
-    /// // Create the memory encryption context.
-    /// let start_data: kvm_sev_launch_start = Default::default();
-    /// let mut start = kvm_sev_cmd {
-    ///     id: sev_cmd_id_KVM_SEV_LAUNCH_START,
-    ///     data: &start_data as *const kvm_sev_launch_start as _,
-    ///     sev_fd: sev.as_raw_fd() as _,
-    ///     ..Default::default()
-    /// };
-    /// assert!(vm.encrypt_op_sev(&mut start).is_ok());
+    /// See the documentation for `KVM_SET_PIT2` in the
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// Fails with `ENXIO` if no PIT was created with
+    /// [create_pit2](struct.VmFd.html#method.create_pit2).
     ///
-    /// let addr = unsafe {
-    ///     libc::mmap(
-    ///         null_mut(),
-    ///         4096,
-    ///         libc::PROT_READ | libc::PROT_WRITE,
-    ///         libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-    ///         -1,
-    ///         0,
-    ///     )
-    /// };
-    /// assert_ne!(addr, libc::MAP_FAILED);
+    /// # Arguments
     ///
-    /// let memory_region = kvm_enc_region {
-    ///     addr: addr as _,
-    ///     size: 4096,
-    /// };
-    /// vm.register_enc_memory_region(&memory_region).unwrap();
+    /// * `pitstate` - `kvm_pit_state2` to be written. A [`Pit2State`] can be passed as well.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::{kvm_pit_config, kvm_pit_state2};
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// let pit_config = kvm_pit_config::default();
+    /// vm.create_pit2(pit_config).unwrap();
+    /// let mut pitstate = kvm_pit_state2::default();
+    /// // Your `pitstate` manipulation here.
+    /// vm.set_pit2(&mut pitstate).unwrap();
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn register_enc_memory_region(&self, memory_region: &kvm_enc_region) -> Result<()> {
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_MEMORY_ENCRYPT_REG_REGION(), memory_region) };
+    pub fn set_pit2(&self, pitstate: &kvm_pit_state2) -> Result<()> {
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_pit_state2 struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_PIT2(), pitstate) };
         if ret == 0 {
             Ok(())
         } else {
@@ -1486,76 +3014,76 @@ impl VmFd {
         }
     }
 
-    /// Unregister a guest memory region registered with
-    /// [`register_enc_memory_region`](Self::register_enc_memory_region).
-    ///
-    /// It is used in the SEV-enabled guest.
+    /// X86 specific call to retrieve the current timestamp of kvmclock.
     ///
-    /// See the documentation for `KVM_MEMORY_ENCRYPT_UNREG_REGION` in the
+    /// See the documentation for `KVM_GET_CLOCK` in the
     /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     ///
+    /// When the `flags` field of the returned structure contains `KVM_CLOCK_REALTIME`
+    /// (respectively `KVM_CLOCK_HOST_TSC`), the `realtime` (respectively `host_tsc`) field holds
+    /// the host's `CLOCK_REALTIME` (respectively the host TSC) sampled at the same instant as
+    /// the kvmclock value.
+    ///
     /// # Arguments
     ///
-    /// * `memory_region` - Guest physical memory region.
+    /// * `clock` - `kvm_clock_data` to be read.
     ///
     /// # Example
-    #[cfg_attr(has_sev, doc = "```rust")]
-    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
-    /// # extern crate kvm_bindings;
+    ///
+    /// ```rust
     /// # extern crate kvm_ioctls;
-    /// # extern crate libc;
-    /// # use std::{fs::OpenOptions, ptr::null_mut};
-    /// # use std::os::unix::io::AsRawFd;
-    /// use kvm_bindings::bindings::{kvm_enc_region, kvm_sev_cmd, kvm_sev_launch_start, sev_cmd_id_KVM_SEV_LAUNCH_START};
     /// # use kvm_ioctls::Kvm;
-    /// use libc;
-    ///
     /// let kvm = Kvm::new().unwrap();
     /// let vm = kvm.create_vm().unwrap();
-    /// let sev = OpenOptions::new()
-    ///     .read(true)
-    ///     .write(true)
-    ///     .open("/dev/sev")
-    ///     .unwrap();
+    /// let clock = vm.get_clock().unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_clock(&self) -> Result<kvm_clock_data> {
+        let mut clock = Default::default();
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_clock_data struct.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_GET_CLOCK(), &mut clock) };
+        if ret == 0 {
+            Ok(clock)
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// X86 specific call to set the current timestamp of kvmclock.
     ///
-    /// // Initialize the SEV platform context.
-    /// let mut init: kvm_sev_cmd = Default::default();
-    /// assert!(vm.encrypt_op_sev(&mut init).is_ok());
+    /// See the documentation for `KVM_SET_CLOCK` in the
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
     ///
-    /// // Create the memory encryption context.
-    /// let start_data: kvm_sev_launch_start = Default::default();
-    /// let mut start = kvm_sev_cmd {
-    ///     id: sev_cmd_id_KVM_SEV_LAUNCH_START,
-    ///     data: &start_data as *const kvm_sev_launch_start as _,
-    ///     sev_fd: sev.as_raw_fd() as _,
-    ///     ..Default::default()
-    /// };
-    /// assert!(vm.encrypt_op_sev(&mut start).is_ok());
+    /// If the `flags` field contains `KVM_CLOCK_REALTIME`, KVM advances the clock by the time
+    /// elapsed since the `realtime` field was sampled, which allows pinning the guest clock to
+    /// the host realtime across a migration. `KVM_CLOCK_REALTIME` and `KVM_CLOCK_HOST_TSC` are
+    /// rejected with `EINVAL` when not reported by
+    /// [`supported_clock_flags`](Self::supported_clock_flags).
     ///
-    /// let addr = unsafe {
-    ///     libc::mmap(
-    ///         null_mut(),
-    ///         4096,
-    ///         libc::PROT_READ | libc::PROT_WRITE,
-    ///         libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
-    ///         -1,
-    ///         0,
-    ///     )
-    /// };
-    /// assert_ne!(addr, libc::MAP_FAILED);
+    /// # Arguments
     ///
-    /// let memory_region = kvm_enc_region {
-    ///     addr: addr as _,
-    ///     size: 4096,
-    /// };
-    /// vm.register_enc_memory_region(&memory_region).unwrap();
-    /// vm.unregister_enc_memory_region(&memory_region).unwrap();
+    /// * `clock` - `kvm_clock_data` to be written.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::kvm_clock_data;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut clock = kvm_clock_data::default();
+    /// vm.set_clock(&mut clock).unwrap();
     /// ```
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    pub fn unregister_enc_memory_region(&self, memory_region: &kvm_enc_region) -> Result<()> {
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_MEMORY_ENCRYPT_UNREG_REGION(), memory_region) };
+    pub fn set_clock(&self, clock: &kvm_clock_data) -> Result<()> {
+        let host_flags = clock.flags & (KVM_CLOCK_REALTIME | KVM_CLOCK_HOST_TSC);
+        if host_flags & !self.supported_clock_flags() != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // SAFETY: Here we trust the kernel not to read past the end of the kvm_clock_data struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_CLOCK(), clock) };
         if ret == 0 {
             Ok(())
         } else {
@@ -1563,592 +3091,4991 @@ impl VmFd {
         }
     }
 
-    /// Registers an address for coalesced MMIO. Write accesses to the address
-    /// will not cause a corresponding [`VcpuExit`](crate::VcpuExit), but
-    /// instead will be appended to the MMIO ring buffer. The [`VcpuFd`] can
-    /// read entries in the ring buffer via [`VcpuFd::coalesced_mmio_read()`].
-    /// If entries are not read the buffer will eventually be full,
-    /// preventing further elements from being appended by the kernel.
+    /// X86 specific call to retrieve the kvmclock with its flags decoded.
     ///
-    /// Needs `KVM_CAP_COALESCED_MMIO` ([`Cap::CoalescedMmio`](crate::Cap::CoalescedMmio))
-    /// and/or `KVM_CAP_COALESCED_PIO` ([`Cap::CoalescedMmio`](crate::Cap::CoalescedPio)).
+    /// Same as [get_clock](struct.VmFd.html#method.get_clock), returning a [`ClockState`].
     ///
-    /// See the documentation for `KVM_REGISTER_COALESCED_MMIO`.
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let clock = vm.get_clock_full().unwrap();
+    /// if clock.realtime {
+    ///     // `clock.data.realtime` was sampled along with `clock.data.clock`.
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn get_clock_full(&self) -> Result<ClockState> {
+        self.get_clock().map(ClockState::from)
+    }
+
+    /// X86 specific call to restore the kvmclock from a [`ClockState`].
+    ///
+    /// The flags passed to [set_clock](struct.VmFd.html#method.set_clock) are rebuilt from the
+    /// decoded booleans: `KVM_CLOCK_TSC_STABLE` is only reported by `KVM_GET_CLOCK` and is
+    /// cleared, while `KVM_CLOCK_REALTIME` and `KVM_CLOCK_HOST_TSC` are only passed when
+    /// reported by [`supported_clock_flags`](Self::supported_clock_flags).
     ///
     /// # Arguments
     ///
-    /// * `addr` - Address being written to.
-    /// * `size` - The size of the write for the mechanism to trigger.
-    pub fn register_coalesced_mmio(&self, addr: IoEventAddress, size: u32) -> Result<()> {
-        let (addr, pio) = match addr {
-            IoEventAddress::Pio(addr) => (addr, 1),
-            IoEventAddress::Mmio(addr) => (addr, 0),
-        };
-        let mut zone = kvm_coalesced_mmio_zone {
-            addr,
-            size,
-            ..Default::default()
-        };
-        zone.__bindgen_anon_1.pio = pio;
-
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_REGISTER_COALESCED_MMIO(), &zone) };
-        if ret != 0 {
-            return Err(errno::Error::last());
+    /// * `state` - Clock state to be restored.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let clock = vm.get_clock_full().unwrap();
+    /// vm.set_clock_full(&clock).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_clock_full(&self, state: &ClockState) -> Result<()> {
+        let mut flags = 0;
+        if state.realtime {
+            flags |= KVM_CLOCK_REALTIME;
         }
-        Ok(())
+        if state.host_tsc {
+            flags |= KVM_CLOCK_HOST_TSC;
+        }
+        let clock = kvm_clock_data {
+            flags: flags & self.supported_clock_flags(),
+            ..state.data
+        };
+        self.set_clock(&clock)
     }
 
-    /// Unregister an address that was previously registered via
-    /// [`register_coalesced_mmio()`](VmFd::register_coalesced_mmio).
+    /// X86 specific call returning the `KVM_CLOCK_*` flags understood by
+    /// [`get_clock`](Self::get_clock) and [`set_clock`](Self::set_clock).
     ///
-    /// See the documentation for `KVM_UNREGISTER_COALESCED_MMIO`.
-    pub fn unregister_coalesced_mmio(&self, addr: IoEventAddress, size: u32) -> Result<()> {
-        let (addr, pio) = match addr {
-            IoEventAddress::Pio(addr) => (addr, 1),
-            IoEventAddress::Mmio(addr) => (addr, 0),
-        };
-        let mut zone = kvm_coalesced_mmio_zone {
-            addr,
-            size,
-            ..Default::default()
-        };
-        zone.__bindgen_anon_1.pio = pio;
-
-        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
-        // the correct amount of memory from our pointer, and we verify the return result.
-        let ret = unsafe { ioctl_with_ref(self, KVM_UNREGISTER_COALESCED_MMIO(), &zone) };
-        if ret != 0 {
-            return Err(errno::Error::last());
-        }
-        Ok(())
+    /// The flags are reported by `KVM_CAP_ADJUST_CLOCK`. Returns 0 if the capability is not
+    /// available.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::KVM_CLOCK_REALTIME;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let clock = vm.get_clock().unwrap();
+    /// if vm.supported_clock_flags() & KVM_CLOCK_REALTIME != 0 {
+    ///     // `clock.realtime` can be used to account for the time elapsed until
+    ///     // the clock is restored.
+    ///     vm.set_clock(&clock).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn supported_clock_flags(&self) -> u32 {
+        self.check_extension_int(Cap::AdjustClock).max(0) as u32
     }
-}
 
-/// Helper function to create a new `VmFd`.
-///
-/// This should not be exported as a public function because the preferred way is to use
+    /// Directly injects a MSI message as per the `KVM_SIGNAL_MSI` ioctl.
+    ///
+    /// See the documentation for `KVM_SIGNAL_MSI`.
+    ///
+    /// This ioctl returns > 0 when the MSI is successfully delivered and 0
+    /// when the guest blocked the MSI.
+    ///
+    /// # Arguments
+    ///
+    /// * kvm_msi - MSI message configuration. For details check the `kvm_msi` structure in the
+    ///                [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// # Example
+    ///
+    /// In this example, the important function signal_msi() calling into
+    /// the actual ioctl is commented out. The reason is that MSI vectors are
+    /// not chosen from the HW side (VMM). The guest OS (or anything that runs
+    /// inside the VM) is supposed to allocate the MSI vectors, and usually
+    /// communicate back through PCI configuration space. Sending a random MSI
+    /// vector through this signal_msi() function will always result in a
+    /// failure, which is why it needs to be commented out.
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::kvm_msi;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let msi = kvm_msi::default();
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// vm.create_irq_chip().unwrap();
+    /// //vm.signal_msi(msi).unwrap();
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn signal_msi(&self, msi: kvm_msi) -> Result<c_int> {
+        // SAFETY: Safe because we allocated the structure and we know the kernel
+        // will read exactly the size of the structure.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SIGNAL_MSI(), &msi) };
+        if ret >= 0 {
+            Ok(ret)
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Sets the GSI routing table entries, overwriting any previously set
+    /// entries, as per the `KVM_SET_GSI_ROUTING` ioctl.
+    ///
+    /// See the documentation for `KVM_SET_GSI_ROUTING`.
+    ///
+    /// Returns an io::Error when the table could not be updated.
+    ///
+    /// # Arguments
+    ///
+    /// * kvm_irq_routing - IRQ routing configuration. Describe all routes
+    ///                     associated with GSI entries. For details check
+    ///                     the `kvm_irq_routing` and `kvm_irq_routing_entry`
+    ///                     structures in the
+    ///                     [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::kvm_irq_routing;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// vm.create_irq_chip().unwrap();
+    ///
+    /// let irq_routing = kvm_irq_routing::default();
+    /// vm.set_gsi_routing(&irq_routing).unwrap();
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn set_gsi_routing(&self, irq_routing: &kvm_irq_routing) -> Result<()> {
+        // SAFETY: Safe because we allocated the structure and we know the kernel
+        // will read exactly the size of the structure.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_GSI_ROUTING(), irq_routing) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Checks whether MSIs signaled or routed on this VM must carry a device ID.
+    ///
+    /// See the documentation for `KVM_CAP_MSI_DEVID`. On arm/aarch64 this is the case once a
+    /// GICv3 ITS has been created, in which case MSI routes must be built with
+    /// [msi_with_devid](struct.IrqRoutingEntry.html#method.msi_with_devid) and `kvm_msi` must
+    /// have the `KVM_MSI_VALID_DEVID` flag set.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// // No ITS has been created yet.
+    /// assert!(!vm.supports_msi_devid());
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn supports_msi_devid(&self) -> bool {
+        self.check_extension(Cap::MsiDevid)
+    }
+
+    /// Checks whether the Hyper-V SynIC v2 can be enabled on the vCPUs of this VM.
+    ///
+    /// See the documentation for `KVM_CAP_HYPERV_SYNIC2`. SynIC is enabled per vCPU through
+    /// [enable_cap](struct.VcpuFd.html#method.enable_cap), after which GSIs can be routed to
+    /// synthetic interrupt sources with
+    /// [hv_sint](struct.IrqRoutingEntry.html#method.hv_sint).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.hyperv_synic_supported() {
+    ///     // Enable `KVM_CAP_HYPERV_SYNIC2` on the vcpus.
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn hyperv_synic_supported(&self) -> bool {
+        self.check_extension(Cap::HypervSynic2)
+    }
+
+    /// Returns the Hyper-V enlightenments which KVM can provide to the guest.
+    ///
+    /// Each enlightenment is reported according to the capabilities listed in
+    /// [`HypervFeatures`]. None is reported without `KVM_CAP_HYPERV_CPUID` and
+    /// `KVM_CAP_HYPERV_ENFORCE_CPUID`, which [init_hyperv](struct.VcpuFd.html#method.init_hyperv)
+    /// relies on.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{HypervFeatures, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let supported = vm.supported_hyperv_features();
+    /// if supported.contains(HypervFeatures::Synic) {
+    ///     // Create an in-kernel irqchip, then enable SynIC.
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn supported_hyperv_features(&self) -> HypervFeatures {
+        if !self.check_extension(Cap::HypervCpuid) || !self.check_extension(Cap::HypervEnforceCpuid)
+        {
+            return HypervFeatures::empty();
+        }
+        let time = self.check_extension(Cap::HypervTime);
+        let synic = self.check_extension(Cap::HypervSynic2);
+
+        let mut supported = HypervFeatures::empty();
+        supported.set(HypervFeatures::Relaxed, self.check_extension(Cap::Hyperv));
+        supported.set(
+            HypervFeatures::Vapic,
+            self.check_extension(Cap::HypervVapic),
+        );
+        supported.set(HypervFeatures::Synic, synic);
+        supported.set(HypervFeatures::Stimer, synic && time);
+        supported.set(HypervFeatures::Reenlightenment, time);
+        supported.set(
+            HypervFeatures::EnlightenedVmcs,
+            self.check_extension(Cap::HypervEnlightenedVmcs),
+        );
+        supported
+    }
+
+    /// Prepares the VM for the Hyper-V enlightenments `features`.
+    ///
+    /// KVM has no VM-wide switch for the enlightenments, which are enabled on each vCPU with
+    /// [init_hyperv](struct.VcpuFd.html#method.init_hyperv). This checks that KVM supports
+    /// `features` and that the VM satisfies their requirements, before any vCPU is set up.
+    ///
+    /// # Arguments
+    ///
+    /// * `features` - The enlightenments of the guest.
+    ///
+    /// Fails with `EINVAL` if one of `features` is not supported, if [`HypervFeatures::Stimer`]
+    /// is requested without [`HypervFeatures::Synic`], or if SynIC is requested without an
+    /// in-kernel irqchip.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{HypervFeatures, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let features = HypervFeatures::Relaxed | HypervFeatures::Vapic;
+    /// if vm.supported_hyperv_features().contains(features) {
+    ///     vm.enable_hyperv(features).unwrap();
+    ///     let vcpu = vm.create_vcpu(0).unwrap();
+    ///     let enabled = vcpu.init_hyperv(&features).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn enable_hyperv(&self, features: HypervFeatures) -> Result<()> {
+        if !self.supported_hyperv_features().contains(features) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        if features.contains(HypervFeatures::Stimer) && !features.contains(HypervFeatures::Synic) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        if features.contains(HypervFeatures::Synic) && !self.irqchip.load(Ordering::Relaxed) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        Ok(())
+    }
+
+    /// Installs an MSR filter on the VM as per the `KVM_X86_SET_MSR_FILTER` ioctl.
+    ///
+    /// See the documentation for `KVM_CAP_X86_MSR_FILTER`. [`MsrFilterBuilder`] builds the
+    /// filter and keeps its bitmaps alive during the call.
+    ///
+    /// # Arguments
+    ///
+    /// * `filter` - The MSR filter. For details check the `kvm_msr_filter` structure in the
+    ///              [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///              The bitmaps of its ranges must be valid for the duration of the call.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::{kvm_msr_filter, KVM_MSR_FILTER_DEFAULT_ALLOW};
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let filter = kvm_msr_filter {
+    ///     flags: KVM_MSR_FILTER_DEFAULT_ALLOW,
+    ///     ..Default::default()
+    /// };
+    /// vm.set_msr_filter(&filter).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_msr_filter(&self, filter: &kvm_msr_filter) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        // The bitmaps pointed to by the ranges are valid for the duration of the call.
+        let ret = unsafe { ioctl_with_ref(self, KVM_X86_SET_MSR_FILTER(), filter) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Restricts the PMU events the guest can program as per the `KVM_SET_PMU_EVENT_FILTER`
+    /// ioctl.
+    ///
+    /// See the documentation for `KVM_CAP_PMU_EVENT_FILTER`. An `Allow` filter without events
+    /// denies all of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - Whether the listed events are allowed or denied.
+    /// * `events` - The filtered events, at most 300. With `KVM_PMU_EVENT_FLAG_MASKED_EVENTS`
+    ///              these are masked events, otherwise event selects and unit masks.
+    /// * `fixed_counter_bitmap` - The fixed counters to which `action` applies.
+    /// * `flags` - 0 or [`KVM_PMU_EVENT_FLAG_MASKED_EVENTS`] if `KVM_CAP_PMU_EVENT_MASKED_EVENTS`
+    ///             is supported.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm, PmuFilterAction};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.check_extension(Cap::PmuEventFilter) {
+    ///     // Deny all the events.
+    ///     vm.set_pmu_event_filter(PmuFilterAction::Allow, &[], 0, 0)
+    ///         .unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_pmu_event_filter(
+        &self,
+        action: PmuFilterAction,
+        events: &[u64],
+        fixed_counter_bitmap: u32,
+        flags: u32,
+    ) -> Result<()> {
+        if events.len() > PMU_EVENT_FILTER_MAX_EVENTS {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // Allocate the header followed by enough room for the events.
+        let header_size = std::mem::size_of::<kvm_pmu_event_filter>();
+        let size = header_size + std::mem::size_of_val(events);
+        let mut buffer = Vec::with_capacity(size.div_ceil(header_size));
+        buffer.resize_with(size.div_ceil(header_size), kvm_pmu_event_filter::default);
+        let filter = &mut buffer[0];
+        filter.action = action as u32;
+        filter.nevents = events.len() as u32;
+        filter.fixed_counter_bitmap = fixed_counter_bitmap;
+        filter.flags = flags;
+        // SAFETY: The vector holds enough memory past the header for `nevents` events.
+        unsafe { filter.events.as_mut_slice(events.len()) }.copy_from_slice(events);
+
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_SET_PMU_EVENT_FILTER(), &buffer[0]) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Returns the reasons for which KVM can forward the MSR accesses of the VM to userspace.
+    ///
+    /// See the documentation for `KVM_CAP_X86_USER_SPACE_MSR`. The result holds all the reasons
+    /// if the capability is supported, and is empty otherwise.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Kvm, MsrExitReason};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.supported_msr_exit_reasons().contains(MsrExitReason::Unknown) {
+    ///     vm.enable_userspace_msr(MsrExitReason::Unknown).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn supported_msr_exit_reasons(&self) -> MsrExitReason {
+        // KVM reports the capability as a boolean, and supports all the reasons when it is set.
+        if self.check_extension(Cap::X86UserSpaceMsr) {
+            MsrExitReason::all()
+        } else {
+            MsrExitReason::empty()
+        }
+    }
+
+    /// Forwards the MSR accesses of the VM to userspace, by enabling
+    /// `KVM_CAP_X86_USER_SPACE_MSR`.
+    ///
+    /// The accesses failing for one of the `reasons` exit with
+    /// [`VcpuExit::X86Rdmsr`](crate::VcpuExit::X86Rdmsr) or
+    /// [`VcpuExit::X86Wrmsr`](crate::VcpuExit::X86Wrmsr) instead of injecting a #GP in the guest.
+    /// The accesses denied by a [`MsrFilterBuilder`] only exit when `reasons` contains
+    /// [`MsrExitReason::Filter`], which must be enabled before running the vCPUs. Empty `reasons`
+    /// disable the exits.
+    ///
+    /// # Arguments
+    ///
+    /// * `reasons` - Reasons for which the MSR accesses exit to userspace. Must be a subset of
+    ///               the [supported ones](struct.VmFd.html#method.supported_msr_exit_reasons).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Kvm, MsrExitReason, MsrFilterBuilder};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.supported_msr_exit_reasons().contains(MsrExitReason::Filter) {
+    ///     vm.enable_userspace_msr(MsrExitReason::Filter).unwrap();
+    ///     MsrFilterBuilder::default_allow()
+    ///         .deny_range(0x10, 1)
+    ///         .apply(&vm)
+    ///         .unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn enable_userspace_msr(&self, reasons: MsrExitReason) -> Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_USER_SPACE_MSR,
+            args: [u64::from(reasons.bits()), 0, 0, 0],
+            ..Default::default()
+        };
+        self.enable_cap(&cap)
+    }
+
+    /// Returns the hypercalls which can be forwarded to userspace, as a bitmask of
+    /// `1 << nr`.
+    ///
+    /// See the documentation for `KVM_CAP_EXIT_HYPERCALL`. The result is 0 if the capability
+    /// is not supported.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Kvm, KVM_HC_MAP_GPA_RANGE};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.supported_exit_hypercalls() & (1 << KVM_HC_MAP_GPA_RANGE) != 0 {
+    ///     // The guest can request private/shared conversions from userspace.
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn supported_exit_hypercalls(&self) -> u64 {
+        self.check_extension_int(Cap::ExitHypercall).max(0) as u64
+    }
+
+    /// Forwards hypercalls to userspace, by enabling `KVM_CAP_EXIT_HYPERCALL`.
+    ///
+    /// The enabled hypercalls exit with [`VcpuExit::Hypercall`](crate::VcpuExit::Hypercall),
+    /// whose `ret` is returned to the guest in RAX when the vCPU is run again.
+    ///
+    /// # Arguments
+    ///
+    /// * `hypercalls` - Bitmask of `1 << nr` for each hypercall number to forward, e.g.
+    ///                  `1 << KVM_HC_MAP_GPA_RANGE`. Fails with `EINVAL` if it is not a subset
+    ///                  of the [supported ones](struct.VmFd.html#method.supported_exit_hypercalls).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Kvm, KVM_HC_MAP_GPA_RANGE};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let hypercalls = 1 << KVM_HC_MAP_GPA_RANGE;
+    /// if vm.supported_exit_hypercalls() & hypercalls != 0 {
+    ///     vm.enable_exit_hypercall(hypercalls).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn enable_exit_hypercall(&self, hypercalls: u64) -> Result<()> {
+        if hypercalls & !self.supported_exit_hypercalls() != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_EXIT_HYPERCALL,
+            args: [hypercalls, 0, 0, 0],
+            ..Default::default()
+        };
+        self.enable_cap(&cap)
+    }
+
+    /// Sets the bus lock detection mode of the VM, by enabling `KVM_CAP_X86_BUS_LOCK_EXIT`.
+    ///
+    /// With [`BusLockExitMode::Exit`], the bus locks taken by the guest, e.g. with atomic
+    /// accesses split across cache lines, exit with
+    /// [`VcpuExit::X86BusLock`](crate::VcpuExit::X86BusLock). The kernel may also report a
+    /// bus lock alongside another exit reason, which is why
+    /// [bus_lock_detected](struct.VcpuFd.html#method.bus_lock_detected) should be checked
+    /// after every exit. The mode must be set before creating the vCPUs.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - Bus lock detection mode. Fails with `EINVAL` if the mode is not supported by
+    ///            the host.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{BusLockExitMode, Cap, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.check_extension(Cap::X86BusLockExit) {
+    ///     vm.enable_bus_lock_exit(BusLockExitMode::Exit).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn enable_bus_lock_exit(&self, mode: BusLockExitMode) -> Result<()> {
+        let supported = self.check_extension_int(Cap::X86BusLockExit).max(0) as u64;
+        let mode = mode as u64;
+        if mode & supported == 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_BUS_LOCK_EXIT,
+            args: [mode, 0, 0, 0],
+            ..Default::default()
+        };
+        self.enable_cap(&cap)
+    }
+
+    /// Enables the notify VM exits, by enabling `KVM_CAP_X86_NOTIFY_VMEXIT`.
+    ///
+    /// A vCPU stuck for more than `window` cycles in a state where no VM exit can happen,
+    /// e.g. because of a malicious instruction sequence, triggers a notify VM exit. It is
+    /// handled by KVM, or forwarded to userspace with [`NotifyVmexitFlags::User`]. This must
+    /// be done before creating the vCPUs.
+    ///
+    /// # Arguments
+    ///
+    /// * `window` - Number of cycles without VM exits after which the notify VM exit happens.
+    /// * `flags` - Flags of the notify VM exits. Fails with `EINVAL` if they are not
+    ///             supported by the host.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm, NotifyVmexitFlags};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.check_extension(Cap::X86NotifyVmexit) {
+    ///     let flags = NotifyVmexitFlags::Enabled | NotifyVmexitFlags::User;
+    ///     vm.enable_notify_vmexit(1 << 20, flags).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn enable_notify_vmexit(&self, window: u32, flags: NotifyVmexitFlags) -> Result<()> {
+        let supported = self.check_extension_int(Cap::X86NotifyVmexit).max(0) as u32;
+        if flags.bits() & !supported != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_X86_NOTIFY_VMEXIT,
+            args: [(u64::from(window) << 32) | u64::from(flags.bits()), 0, 0, 0],
+            ..Default::default()
+        };
+        self.enable_cap(&cap)
+    }
+
+    /// Returns the Xen HVM features supported by KVM.
+    ///
+    /// See the documentation for `KVM_CAP_XEN_HVM`. The result is a bitmask of the
+    /// `KVM_XEN_HVM_CONFIG_*` flags, and 0 if Xen guests are not supported.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::KVM_XEN_HVM_CONFIG_EVTCHN_SEND;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.supported_xen_hvm_features() & KVM_XEN_HVM_CONFIG_EVTCHN_SEND != 0 {
+    ///     // Event channels can be delivered with `xen_evtchn_send`.
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn supported_xen_hvm_features(&self) -> u32 {
+        self.check_extension_int(Cap::XenHvm).max(0) as u32
+    }
+
+    /// Configures the VM for running Xen guests as per the `KVM_XEN_HVM_CONFIG` ioctl.
+    ///
+    /// The flags must be a subset of the
+    /// [supported features](struct.VmFd.html#method.supported_xen_hvm_features), otherwise the
+    /// kernel fails with `EINVAL`. A configuration without hypercall blobs can be built with
+    /// [`XenHvmConfig`].
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - Xen HVM configuration. For details check the `kvm_xen_hvm_config`
+    ///              structure in the
+    ///              [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL;
+    /// # use kvm_ioctls::{Kvm, XenHvmConfig};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.supported_xen_hvm_features() & KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL != 0 {
+    ///     let config = XenHvmConfig::new().intercept_hcall(true);
+    ///     vm.xen_hvm_config(&config.into()).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn xen_hvm_config(&self, config: &kvm_xen_hvm_config) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_XEN_HVM_CONFIG(), config) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Sets a Xen HVM attribute of the VM as per the `KVM_XEN_HVM_SET_ATTR` ioctl.
+    ///
+    /// The VM must support `KVM_XEN_HVM_CONFIG_SHARED_INFO` (see
+    /// [supported_xen_hvm_features](struct.VmFd.html#method.supported_xen_hvm_features)).
+    /// The common attributes can be built with [`XenHvmAttr`].
+    ///
+    /// # Arguments
+    ///
+    /// * `attr` - Attribute to set. For details check the `kvm_xen_hvm_attr` structure in the
+    ///            [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::KVM_XEN_HVM_CONFIG_SHARED_INFO;
+    /// # use kvm_ioctls::{Kvm, XenHvmAttr};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.supported_xen_hvm_features() & KVM_XEN_HVM_CONFIG_SHARED_INFO != 0 {
+    ///     vm.xen_hvm_set_attr(&XenHvmAttr::upcall_vector(0xf3)).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn xen_hvm_set_attr(&self, attr: &kvm_xen_hvm_attr) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_XEN_HVM_SET_ATTR(), attr) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Gets a Xen HVM attribute of the VM as per the `KVM_XEN_HVM_GET_ATTR` ioctl.
+    ///
+    /// The `type_` of `attr` selects the attribute, whose value is written back to `attr`.
+    ///
+    /// # Arguments
+    ///
+    /// * `attr` - Attribute to get. For details check the `kvm_xen_hvm_attr` structure in the
+    ///            [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::KVM_XEN_HVM_CONFIG_SHARED_INFO;
+    /// # use kvm_ioctls::{Kvm, XenHvmAttr};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.supported_xen_hvm_features() & KVM_XEN_HVM_CONFIG_SHARED_INFO != 0 {
+    ///     let mut attr = XenHvmAttr::upcall_vector(0);
+    ///     vm.xen_hvm_get_attr(&mut attr).unwrap();
+    ///     let vector = attr.vector().unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn xen_hvm_get_attr(&self, attr: &mut kvm_xen_hvm_attr) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // write the correct amount of memory to our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_XEN_HVM_GET_ATTR(), attr) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Delivers a Xen event channel as per the `KVM_XEN_HVM_EVTCHN_SEND` ioctl.
+    ///
+    /// The VM must have been configured through `KVM_XEN_HVM_CONFIG` with the
+    /// `KVM_XEN_HVM_CONFIG_EVTCHN_SEND` flag, and its Xen shared info page must be set for the
+    /// event to be delivered. Fails with `EINVAL` if `KVM_XEN_HVM_CONFIG_EVTCHN_SEND` is not
+    /// supported (see
+    /// [supported_xen_hvm_features](struct.VmFd.html#method.supported_xen_hvm_features)).
+    ///
+    /// # Arguments
+    ///
+    /// * `evtchn` - Event channel port, target vCPU and priority, which must be
+    ///   [`KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_bindings::kvm_irq_routing_xen_evtchn;
+    /// # use kvm_ioctls::{Kvm, KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let evtchn = kvm_irq_routing_xen_evtchn {
+    ///     port: 3,
+    ///     vcpu: 0,
+    ///     priority: KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL,
+    /// };
+    /// // The VM has not been configured for Xen guests.
+    /// assert!(vm.xen_evtchn_send(&evtchn).is_err());
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn xen_evtchn_send(&self, evtchn: &kvm_irq_routing_xen_evtchn) -> Result<()> {
+        if self.supported_xen_hvm_features() & KVM_XEN_HVM_CONFIG_EVTCHN_SEND == 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_XEN_HVM_EVTCHN_SEND(), evtchn) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Registers an event to be signaled whenever a certain address is written to.
+    ///
+    /// See the documentation for `KVM_IOEVENTFD`.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` which will be signaled. When signaling, the usual `vmexit` to userspace
+    ///           is prevented.
+    /// * `addr` - Address being written to.
+    /// * `datamatch` - Limits signaling `fd` to only the cases where the value being written is
+    ///                 equal to this parameter. The size of `datamatch` is important and it must
+    ///                 match the expected size of the guest's write.
+    ///
+    /// Returns `EINVAL` if a PIO address does not fit in the 16-bit port space or if a PIO
+    /// `datamatch` is 8 bytes wide. MMIO addresses accept 8-byte datamatches.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate libc;
+    /// extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{IoEventAddress, Kvm, NoDatamatch};
+    /// use libc::{eventfd, EFD_NONBLOCK};
+    /// use vmm_sys_util::eventfd::EventFd;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm_fd = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// vm_fd
+    ///     .register_ioevent(&evtfd, &IoEventAddress::Pio(0xf4), NoDatamatch)
+    ///     .unwrap();
+    /// vm_fd
+    ///     .register_ioevent(&evtfd, &IoEventAddress::Mmio(0x1000), NoDatamatch)
+    ///     .unwrap();
+    /// ```
+    pub fn register_ioevent<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        let len = std::mem::size_of::<T>() as u32;
+        let datamatch = (len > 0).then(|| datamatch.into());
+        self.ioeventfd(fd, addr, datamatch, len, false)
+    }
+
+    /// Unregisters an event from a certain address it has been previously registered to.
+    ///
+    /// See the documentation for `KVM_IOEVENTFD`.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - FD which will be unregistered.
+    /// * `addr` - Address being written to.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because it relies on RawFd.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate libc;
+    /// extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{IoEventAddress, Kvm, NoDatamatch};
+    /// use libc::EFD_NONBLOCK;
+    /// use vmm_sys_util::eventfd::EventFd;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm_fd = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// let pio_addr = IoEventAddress::Pio(0xf4);
+    /// let mmio_addr = IoEventAddress::Mmio(0x1000);
+    /// vm_fd
+    ///     .register_ioevent(&evtfd, &pio_addr, NoDatamatch)
+    ///     .unwrap();
+    /// vm_fd
+    ///     .register_ioevent(&evtfd, &mmio_addr, 0x1234u32)
+    ///     .unwrap();
+    /// vm_fd
+    ///     .unregister_ioevent(&evtfd, &pio_addr, NoDatamatch)
+    ///     .unwrap();
+    /// vm_fd
+    ///     .unregister_ioevent(&evtfd, &mmio_addr, 0x1234u32)
+    ///     .unwrap();
+    /// ```
+    pub fn unregister_ioevent<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<()> {
+        let len = std::mem::size_of::<T>() as u32;
+        let datamatch = (len > 0).then(|| datamatch.into());
+        self.ioeventfd(fd, addr, datamatch, len, true)
+    }
+
+    /// Registers an event to be signaled whenever a certain address is written to, with the
+    /// width of the write given by `datamatch`.
+    ///
+    /// See the documentation for `KVM_IOEVENTFD`. Unlike
+    /// [`register_ioevent`](Self::register_ioevent), the length of the match is derived from
+    /// the [`Datamatch`] variant instead of the size of a type parameter.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` which will be signaled.
+    /// * `addr` - Address being written to. Fails with `EINVAL` for PIO addresses above
+    ///            `0xffff`.
+    /// * `datamatch` - Value and width of the write signaling `fd`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate libc;
+    /// extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{Datamatch, IoEventAddress, Kvm, NoDatamatch};
+    /// use libc::EFD_NONBLOCK;
+    /// use vmm_sys_util::eventfd::EventFd;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm_fd = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// vm_fd
+    ///     .register_ioevent2(&evtfd, &IoEventAddress::Pio(0xf4), NoDatamatch.into())
+    ///     .unwrap();
+    /// vm_fd
+    ///     .register_ioevent2(&evtfd, &IoEventAddress::Mmio(0x1000), Datamatch::U16(0x1337))
+    ///     .unwrap();
+    /// ```
+    pub fn register_ioevent2(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        self.ioeventfd(fd, addr, datamatch.value(), datamatch.len(), false)
+    }
+
+    /// Unregisters an event registered with [`register_ioevent2`](Self::register_ioevent2).
+    ///
+    /// See the documentation for `KVM_IOEVENTFD`.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` which will be unregistered.
+    /// * `addr` - Address being written to.
+    /// * `datamatch` - Value and width used at registration.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate libc;
+    /// extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{Datamatch, IoEventAddress, Kvm};
+    /// use libc::EFD_NONBLOCK;
+    /// use vmm_sys_util::eventfd::EventFd;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm_fd = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// let addr = IoEventAddress::Mmio(0x1000);
+    /// vm_fd
+    ///     .register_ioevent2(&evtfd, &addr, Datamatch::U32(0x1234))
+    ///     .unwrap();
+    /// vm_fd
+    ///     .unregister_ioevent2(&evtfd, &addr, Datamatch::U32(0x1234))
+    ///     .unwrap();
+    /// ```
+    pub fn unregister_ioevent2(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Datamatch,
+    ) -> Result<()> {
+        self.ioeventfd(fd, addr, datamatch.value(), datamatch.len(), true)
+    }
+
+    /// Registers an event to be signaled whenever a certain address is written to with a write
+    /// of `len` bytes, whatever the value written.
+    ///
+    /// See the documentation for `KVM_IOEVENTFD`. The registration omits
+    /// `KVM_IOEVENTFD_FLAG_DATAMATCH`, as [`register_ioevent`](Self::register_ioevent) does with
+    /// [`NoDatamatch`], but keeps the width of the write: this is the usual doorbell register,
+    /// which the guest writes e.g. a queue index to. Writes of other widths to the address
+    /// still exit to userspace.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` which will be signaled.
+    /// * `addr` - Address being written to.
+    /// * `len` - Width of the write: 1, 2, 4 or 8 bytes, or 0 for any width. PIO addresses
+    ///           do not accept 8-byte writes.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate libc;
+    /// extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{IoEventAddress, Kvm};
+    /// use libc::EFD_NONBLOCK;
+    /// use vmm_sys_util::eventfd::EventFd;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm_fd = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// vm_fd
+    ///     .register_ioevent_any(&evtfd, &IoEventAddress::Mmio(0x1000), 4)
+    ///     .unwrap();
+    /// ```
+    pub fn register_ioevent_any(&self, fd: &EventFd, addr: &IoEventAddress, len: u8) -> Result<()> {
+        self.ioeventfd(fd, addr, None, u32::from(len), false)
+    }
+
+    /// Unregisters an event registered with
+    /// [`register_ioevent_any`](Self::register_ioevent_any).
+    ///
+    /// See the documentation for `KVM_IOEVENTFD`.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` which will be unregistered.
+    /// * `addr` - Address being written to.
+    /// * `len` - Width of the write used at registration.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate libc;
+    /// extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{IoEventAddress, Kvm};
+    /// use libc::EFD_NONBLOCK;
+    /// use vmm_sys_util::eventfd::EventFd;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm_fd = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// let addr = IoEventAddress::Pio(0xf0);
+    /// vm_fd.register_ioevent_any(&evtfd, &addr, 2).unwrap();
+    /// vm_fd.unregister_ioevent_any(&evtfd, &addr, 2).unwrap();
+    /// ```
+    pub fn unregister_ioevent_any(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        len: u8,
+    ) -> Result<()> {
+        self.ioeventfd(fd, addr, None, u32::from(len), true)
+    }
+
+    /// Registers an event like [`register_ioevent`](Self::register_ioevent) and returns a guard
+    /// unregistering it when dropped.
+    ///
+    /// The guard keeps a duplicate of `fd` and the original arguments, so the deassignment
+    /// matches the registration.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` which will be signaled.
+    /// * `addr` - Address being written to.
+    /// * `datamatch` - Limits signaling `fd` to only the cases where the value being written is
+    ///                 equal to this parameter.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate libc;
+    /// extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{IoEventAddress, Kvm};
+    /// use libc::EFD_NONBLOCK;
+    /// use vmm_sys_util::eventfd::EventFd;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm_fd = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// let mmio_addr = IoEventAddress::Mmio(0x1000);
+    /// {
+    ///     let _guard = vm_fd
+    ///         .register_ioevent_guarded(&evtfd, &mmio_addr, 0x1234u32)
+    ///         .unwrap();
+    /// }
+    /// // The event has been unregistered when the guard went out of scope.
+    /// vm_fd
+    ///     .register_ioevent(&evtfd, &mmio_addr, 0x1234u32)
+    ///     .unwrap();
+    /// ```
+    pub fn register_ioevent_guarded<T: Into<u64>>(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: T,
+    ) -> Result<IoEventGuard<'_>> {
+        let len = std::mem::size_of::<T>() as u32;
+        let datamatch = (len > 0).then(|| datamatch.into());
+        let fd = fd
+            .try_clone()
+            .map_err(|e| errno::Error::new(e.raw_os_error().unwrap_or(libc::EINVAL)))?;
+        self.ioeventfd(&fd, addr, datamatch, len, false)?;
+        Ok(IoEventGuard {
+            vm: self,
+            fd: Some(fd),
+            addr: *addr,
+            datamatch,
+            len,
+        })
+    }
+
+    /// Assigns or deassigns an ioeventfd as per the `KVM_IOEVENTFD` ioctl.
+    ///
+    /// A `datamatch` of `None` signals `fd` for any value, and a `len` of 0 for writes of any
+    /// width. PIO addresses must fit in 16 bits and PIO writes can only be 1, 2 or 4 bytes
+    /// wide, the sizes of the `out` instruction.
+    fn ioeventfd(
+        &self,
+        fd: &EventFd,
+        addr: &IoEventAddress,
+        datamatch: Option<u64>,
+        len: u32,
+        deassign: bool,
+    ) -> Result<()> {
+        if let IoEventAddress::Pio(port) = *addr {
+            if port > u64::from(u16::MAX) || !matches!(len, 0 | 1 | 2 | 4) {
+                return Err(errno::Error::new(libc::EINVAL));
+            }
+        }
+        let mut flags = 0;
+        if deassign {
+            flags |= 1 << kvm_ioeventfd_flag_nr_deassign
+        }
+        if datamatch.is_some() {
+            flags |= 1 << kvm_ioeventfd_flag_nr_datamatch
+        }
+        if let IoEventAddress::Pio(_) = *addr {
+            flags |= 1 << kvm_ioeventfd_flag_nr_pio
+        }
+
+        let ioeventfd = kvm_ioeventfd {
+            datamatch: datamatch.unwrap_or(0),
+            len,
+            addr: match addr {
+                IoEventAddress::Pio(ref p) => *p,
+                IoEventAddress::Mmio(ref m) => *m,
+            },
+            fd: fd.as_raw_fd(),
+            flags,
+            ..Default::default()
+        };
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IOEVENTFD(), &ioeventfd) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Gets the bitmap of pages dirtied since the last call of this function.
+    ///
+    /// Leverages the dirty page logging feature in KVM. As a side-effect, this also resets the
+    /// bitmap inside the kernel. For the dirty log to be available, you have to set the flag
+    /// `KVM_MEM_LOG_DIRTY_PAGES` when creating guest memory regions.
+    ///
+    /// Check the documentation for `KVM_GET_DIRTY_LOG`.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - Guest memory slot identifier.
+    /// * `memory_size` - Size of the memory region.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use std::io::Write;
+    /// # use std::ptr::null_mut;
+    /// # use std::slice;
+    /// # use kvm_ioctls::{Kvm, VcpuExit};
+    /// # use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
+    /// # let kvm = Kvm::new().unwrap();
+    /// # let vm = kvm.create_vm().unwrap();
+    /// // This example is based on https://lwn.net/Articles/658511/.
+    /// let mem_size = 0x4000;
+    /// let guest_addr: u64 = 0x1000;
+    /// let load_addr: *mut u8 = unsafe {
+    ///     libc::mmap(
+    ///         null_mut(),
+    ///         mem_size,
+    ///         libc::PROT_READ | libc::PROT_WRITE,
+    ///         libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+    ///         -1,
+    ///         0,
+    ///     ) as *mut u8
+    /// };
+    ///
+    /// // Initialize a guest memory region using the flag `KVM_MEM_LOG_DIRTY_PAGES`.
+    /// let mem_region = kvm_userspace_memory_region {
+    ///     slot: 0,
+    ///     guest_phys_addr: guest_addr,
+    ///     memory_size: mem_size as u64,
+    ///     userspace_addr: load_addr as u64,
+    ///     flags: KVM_MEM_LOG_DIRTY_PAGES,
+    /// };
+    /// unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+    ///
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// // ASM code that just forces a MMIO Write.
+    /// let asm_code = [0xc6, 0x06, 0x00, 0x80, 0x00];
+    /// #[cfg(target_arch = "aarch64")]
+    /// let asm_code = [
+    ///     0x01, 0x00, 0x00, 0x10, /* adr x1, <this address> */
+    ///     0x22, 0x10, 0x00, 0xb9, /* str w2, [x1, #16]; write to this page */
+    ///     0x02, 0x00, 0x00, 0xb9, /* str w2, [x0]; force MMIO exit */
+    ///     0x00, 0x00, 0x00,
+    ///     0x14, /* b <this address>; shouldn't get here, but if so loop forever */
+    /// ];
+    ///
+    /// // Write the code in the guest memory. This will generate a dirty page.
+    /// unsafe {
+    ///     let mut slice = slice::from_raw_parts_mut(load_addr, mem_size);
+    ///     slice.write(&asm_code).unwrap();
+    /// }
+    ///
+    /// let mut vcpu_fd = vm.create_vcpu(0).unwrap();
+    ///
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// {
+    ///     // x86_64 specific registry setup.
+    ///     let mut vcpu_sregs = vcpu_fd.get_sregs().unwrap();
+    ///     vcpu_sregs.cs.base = 0;
+    ///     vcpu_sregs.cs.selector = 0;
+    ///     vcpu_fd.set_sregs(&vcpu_sregs).unwrap();
+    ///
+    ///     let mut vcpu_regs = vcpu_fd.get_regs().unwrap();
+    ///     // Set the Instruction Pointer to the guest address where we loaded the code.
+    ///     vcpu_regs.rip = guest_addr;
+    ///     vcpu_regs.rax = 2;
+    ///     vcpu_regs.rbx = 3;
+    ///     vcpu_regs.rflags = 2;
+    ///     vcpu_fd.set_regs(&vcpu_regs).unwrap();
+    /// }
+    ///
+    /// #[cfg(target_arch = "aarch64")]
+    /// {
+    ///     // aarch64 specific registry setup.
+    ///     let mut kvi = kvm_bindings::kvm_vcpu_init::default();
+    ///     vm.get_preferred_target(&mut kvi).unwrap();
+    ///     vcpu_fd.vcpu_init(&kvi).unwrap();
+    ///
+    ///     let core_reg_base: u64 = 0x6030_0000_0010_0000;
+    ///     let mmio_addr: u64 = guest_addr + mem_size as u64;
+    ///     vcpu_fd.set_one_reg(core_reg_base + 2 * 32, &guest_addr.to_le_bytes()); // set PC
+    ///     vcpu_fd.set_one_reg(core_reg_base + 2 * 0, &mmio_addr.to_le_bytes()); // set X0
+    /// }
+    ///
+    /// loop {
+    ///     match vcpu_fd.run().expect("run failed") {
+    ///         VcpuExit::MmioWrite(addr, data) => {
+    ///             // On x86_64, the code snippet dirties 1 page when loading the code in memory
+    ///             // while on aarch64 the dirty bit comes from writing to guest_addr (current PC).
+    ///             let dirty_pages_bitmap = vm.get_dirty_log(0, mem_size).unwrap();
+    ///             let dirty_pages = dirty_pages_bitmap
+    ///                 .into_iter()
+    ///                 .map(|page| page.count_ones())
+    ///                 .fold(0, |dirty_page_count, i| dirty_page_count + i);
+    ///             assert_eq!(dirty_pages, 1);
+    ///             break;
+    ///         }
+    ///         exit_reason => panic!("unexpected exit reason: {:?}", exit_reason),
+    ///     }
+    /// }
+    /// ```
+    pub fn get_dirty_log(&self, slot: u32, memory_size: usize) -> Result<Vec<u64>> {
+        // Compute the length of the bitmap needed for all dirty pages in one memory slot.
+        // One memory page is `page_size` bytes and `KVM_GET_DIRTY_LOG` returns one dirty bit for
+        // each page.
+        // SAFETY: We trust the sysconf libc function and we're calling it with a correct parameter.
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            -1 => return Err(errno::Error::last()),
+            ps => ps as usize,
+        };
+
+        // For ease of access we are saving the bitmap in a u64 vector. We are using ceil to
+        // make sure we count all dirty pages even when `memory_size` is not a multiple of
+        // `page_size * 64`.
+        let bitmap_size = dirty_log_pages(memory_size, page_size)?.div_ceil(64);
+        let mut bitmap = vec![0u64; bitmap_size];
+        let dirtylog = kvm_dirty_log {
+            slot,
+            padding1: 0,
+            __bindgen_anon_1: kvm_dirty_log__bindgen_ty_1 {
+                dirty_bitmap: bitmap.as_mut_ptr() as *mut c_void,
+            },
+        };
+        // SAFETY: Safe because we know that our file is a VM fd, and we know that the amount of
+        // memory we allocated for the bitmap is at least one bit per page.
+        let ret = unsafe { ioctl_with_ref(self, KVM_GET_DIRTY_LOG(), &dirtylog) };
+        if ret == 0 {
+            Ok(bitmap)
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Returns the indices of the dirty pages of a memory slot, as per the `KVM_GET_DIRTY_LOG`
+    /// ioctl.
+    ///
+    /// The dirty bitmap is fetched with [get_dirty_log](struct.VmFd.html#method.get_dirty_log)
+    /// and scanned lazily. Indices are relative to the start of the slot and are yielded in
+    /// increasing order.
+    ///
+    /// # Arguments
+    ///
+    /// * `slot` - Guest memory slot identifier.
+    /// * `memory_size` - Size of the memory region.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_bindings::{kvm_userspace_memory_region, KVM_MEM_LOG_DIRTY_PAGES};
+    /// # use std::ptr::null_mut;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mem_size = 0x4000;
+    /// let load_addr = unsafe {
+    ///     libc::mmap(
+    ///         null_mut(),
+    ///         mem_size,
+    ///         libc::PROT_READ | libc::PROT_WRITE,
+    ///         libc::MAP_ANONYMOUS | libc::MAP_SHARED | libc::MAP_NORESERVE,
+    ///         -1,
+    ///         0,
+    ///     )
+    /// };
+    /// let mem_region = kvm_userspace_memory_region {
+    ///     slot: 0,
+    ///     guest_phys_addr: 0x1000,
+    ///     memory_size: mem_size as u64,
+    ///     userspace_addr: load_addr as u64,
+    ///     flags: KVM_MEM_LOG_DIRTY_PAGES,
+    /// };
+    /// unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+    /// // The guest has not run, so no page is dirty.
+    /// assert_eq!(vm.dirty_page_indices(0, mem_size).unwrap().count(), 0);
+    /// ```
+    pub fn dirty_page_indices(
+        &self,
+        slot: u32,
+        memory_size: usize,
+    ) -> Result<impl Iterator<Item = u64>> {
+        // SAFETY: We trust the sysconf libc function and we're calling it with a correct parameter.
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            -1 => return Err(errno::Error::last()),
+            ps => ps as usize,
+        };
+        // Bits of the last word past the end of the slot are ignored.
+        let nr_pages = dirty_log_pages(memory_size, page_size)? as u64;
+        let bitmap = self.get_dirty_log(slot, memory_size)?;
+
+        Ok(bitmap
+            .into_iter()
+            .enumerate()
+            .flat_map(|(word_idx, mut word)| {
+                std::iter::from_fn(move || {
+                    if word == 0 {
+                        return None;
+                    }
+                    let bit = word.trailing_zeros();
+                    // Clear the lowest set bit.
+                    word &= word - 1;
+                    Some(word_idx as u64 * 64 + u64::from(bit))
+                })
+            })
+            .take_while(move |&page| page < nr_pages))
+    }
+
+    /// Registers an event that will, when signaled, trigger the `gsi` IRQ.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` to be signaled.
+    /// * `gsi` - IRQ to be triggered.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # extern crate vmm_sys_util;
+    /// # use kvm_ioctls::Kvm;
+    /// # use libc::EFD_NONBLOCK;
+    /// # use vmm_sys_util::eventfd::EventFd;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// {
+    ///     vm.create_irq_chip().unwrap();
+    ///     vm.register_irqfd(&evtfd, 0).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn register_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        let irqfd = kvm_irqfd {
+            fd: fd.as_raw_fd() as u32,
+            gsi,
+            ..Default::default()
+        };
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Registers an event that will, when signaled, assert the `gsi` IRQ.
+    /// If the irqchip is resampled by the guest, the IRQ is de-asserted,
+    /// and `resamplefd` is notified.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` to be signaled.
+    /// * `resamplefd` - `EventFd`to be notified on resample.
+    /// * `gsi` - IRQ to be triggered.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # extern crate vmm_sys_util;
+    /// # use kvm_ioctls::Kvm;
+    /// # use libc::EFD_NONBLOCK;
+    /// # use vmm_sys_util::eventfd::EventFd;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// {
+    ///     vm.create_irq_chip().unwrap();
+    ///     vm.register_irqfd_with_resample(&evtfd, &resamplefd, 0)
+    ///         .unwrap();
+    /// }
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn register_irqfd_with_resample(
+        &self,
+        fd: &EventFd,
+        resamplefd: &EventFd,
+        gsi: u32,
+    ) -> Result<()> {
+        let irqfd = kvm_irqfd {
+            fd: fd.as_raw_fd() as u32,
+            resamplefd: resamplefd.as_raw_fd() as u32,
+            gsi,
+            flags: KVM_IRQFD_FLAG_RESAMPLE,
+            ..Default::default()
+        };
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Unregisters an event that will, when signaled, trigger the `gsi` IRQ.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` to be signaled.
+    /// * `gsi` - IRQ to be triggered.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # extern crate vmm_sys_util;
+    /// # use kvm_ioctls::Kvm;
+    /// # use libc::EFD_NONBLOCK;
+    /// # use vmm_sys_util::eventfd::EventFd;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// {
+    ///     vm.create_irq_chip().unwrap();
+    ///     vm.register_irqfd(&evtfd, 0).unwrap();
+    ///     vm.unregister_irqfd(&evtfd, 0).unwrap();
+    ///     vm.register_irqfd_with_resample(&evtfd, &resamplefd, 0)
+    ///         .unwrap();
+    ///     vm.unregister_irqfd(&evtfd, 0).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn unregister_irqfd(&self, fd: &EventFd, gsi: u32) -> Result<()> {
+        let irqfd = kvm_irqfd {
+            fd: fd.as_raw_fd() as u32,
+            gsi,
+            flags: KVM_IRQFD_FLAG_DEASSIGN,
+            ..Default::default()
+        };
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IRQFD(), &irqfd) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Connects the Hyper-V connection id `conn_id` to `fd` as per the `KVM_HYPERV_EVENTFD`
+    /// ioctl.
+    ///
+    /// The guest `HvSignalEvent` hypercalls on `conn_id` then signal `fd` without exiting to
+    /// userspace. Fails with `EINVAL` if `conn_id` does not fit in the 24 bits of
+    /// `KVM_HYPERV_CONN_ID_MASK` or if `KVM_CAP_HYPERV_EVENTFD` is not supported, and with
+    /// `EEXIST` if `conn_id` is already connected.
+    ///
+    /// # Arguments
+    ///
+    /// * `conn_id` - Hyper-V connection id.
+    /// * `fd` - `EventFd` to be signaled.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// # use libc::EFD_NONBLOCK;
+    /// # use vmm_sys_util::eventfd::EventFd;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// if vm.check_extension(Cap::HypervEventfd) {
+    ///     vm.register_hyperv_eventfd(1, &evtfd).unwrap();
+    ///     vm.unregister_hyperv_eventfd(1).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn register_hyperv_eventfd(&self, conn_id: u32, fd: &EventFd) -> Result<()> {
+        self.hyperv_eventfd(conn_id, fd.as_raw_fd(), 0)
+    }
+
+    /// Disconnects the Hyper-V connection id `conn_id` from its eventfd.
+    ///
+    /// Fails with `ENOENT` if `conn_id` is not connected, see
+    /// [register_hyperv_eventfd](struct.VmFd.html#method.register_hyperv_eventfd).
+    ///
+    /// # Arguments
+    ///
+    /// * `conn_id` - Hyper-V connection id.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn unregister_hyperv_eventfd(&self, conn_id: u32) -> Result<()> {
+        self.hyperv_eventfd(conn_id, -1, KVM_HYPERV_EVENTFD_DEASSIGN)
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn hyperv_eventfd(&self, conn_id: u32, fd: RawFd, flags: u32) -> Result<()> {
+        if conn_id & !KVM_HYPERV_CONN_ID_MASK != 0 || !self.check_extension(Cap::HypervEventfd) {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let eventfd = kvm_hyperv_eventfd {
+            conn_id,
+            fd,
+            flags,
+            ..Default::default()
+        };
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_HYPERV_EVENTFD(), &eventfd) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Registers an irqfd for a GSI allocated from `allocator`, returning a guard which
+    /// unregisters it and frees the GSI when dropped.
+    ///
+    /// See [register_irqfd](struct.VmFd.html#method.register_irqfd).
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` to be signaled.
+    /// * `gsi` - IRQ to be triggered, allocated from `allocator`.
+    /// * `allocator` - Allocator `gsi` is returned to.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{GsiAllocator, Kvm};
+    /// # use libc::EFD_NONBLOCK;
+    /// # use vmm_sys_util::eventfd::EventFd;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let allocator = GsiAllocator::new(&vm);
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// {
+    ///     vm.create_irq_chip().unwrap();
+    ///     let gsi = allocator.allocate().unwrap();
+    ///     let guard = vm.register_irqfd_guarded(&evtfd, gsi, &allocator).unwrap();
+    ///     drop(guard);
+    ///     // The GSI can be allocated again.
+    ///     assert_eq!(allocator.allocate().unwrap(), gsi);
+    /// }
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn register_irqfd_guarded(
+        &self,
+        fd: &EventFd,
+        gsi: u32,
+        allocator: &GsiAllocator,
+    ) -> Result<IrqFdGuard<'_>> {
+        let fd = fd
+            .try_clone()
+            .map_err(|e| errno::Error::new(e.raw_os_error().unwrap_or(libc::EINVAL)))?;
+        self.register_irqfd(&fd, gsi)?;
+        Ok(IrqFdGuard {
+            vm: self,
+            fd: Some(fd),
+            gsi,
+            allocator: Some(allocator.clone()),
+        })
+    }
+
+    /// Registers a resampling irqfd like
+    /// [register_irqfd_with_resample](struct.VmFd.html#method.register_irqfd_with_resample),
+    /// returning a guard which unregisters it when dropped.
+    ///
+    /// Resampling irqfds usually target the fixed GSI of a legacy interrupt controller pin,
+    /// so the GSI is not returned to any allocator.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - `EventFd` to be signaled.
+    /// * `resamplefd` - `EventFd` to be notified on resample.
+    /// * `gsi` - IRQ to be triggered.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # extern crate vmm_sys_util;
+    /// # use kvm_ioctls::Kvm;
+    /// # use libc::EFD_NONBLOCK;
+    /// # use vmm_sys_util::eventfd::EventFd;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// {
+    ///     vm.create_irq_chip().unwrap();
+    ///     let guard = vm
+    ///         .register_irqfd_with_resample_guarded(&evtfd, &resamplefd, 5)
+    ///         .unwrap();
+    ///     drop(guard);
+    ///     // The irqfd was unregistered.
+    ///     vm.register_irqfd(&evtfd, 5).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn register_irqfd_with_resample_guarded(
+        &self,
+        fd: &EventFd,
+        resamplefd: &EventFd,
+        gsi: u32,
+    ) -> Result<IrqFdGuard<'_>> {
+        let fd = fd
+            .try_clone()
+            .map_err(|e| errno::Error::new(e.raw_os_error().unwrap_or(libc::EINVAL)))?;
+        self.register_irqfd_with_resample(&fd, resamplefd, gsi)?;
+        Ok(IrqFdGuard {
+            vm: self,
+            fd: Some(fd),
+            gsi,
+            allocator: None,
+        })
+    }
+
+    /// Sets the level on the given irq to 1 if `active` is true, and 0 otherwise.
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - IRQ to be set.
+    /// * `active` - Level of the IRQ input.
+    ///
+    /// # Errors
+    ///
+    /// Returns an io::Error when the irq field is invalid
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # extern crate vmm_sys_util;
+    /// # use kvm_ioctls::{Kvm, VmFd};
+    /// # use libc::EFD_NONBLOCK;
+    /// # use vmm_sys_util::eventfd::EventFd;
+    /// fn arch_setup(vm_fd: &VmFd) {
+    ///     // Arch-specific setup:
+    ///     // For x86 architectures, it simply means calling vm.create_irq_chip().unwrap().
+    /// #   #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// #   vm_fd.create_irq_chip().unwrap();
+    ///     // For Arm architectures, the IRQ controllers need to be setup first.
+    ///     // Details please refer to the kernel documentation.
+    ///     // https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt
+    /// #   #[cfg(any(target_arch = "arm", target_arch = "aarch64"))] {
+    /// #       vm_fd.create_vcpu(0).unwrap();
+    /// #       // ... rest of setup for Arm goes here
+    /// #   }
+    /// }
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// arch_setup(&vm);
+    /// #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    /// {
+    ///     vm.set_irq_line(4, true);
+    ///     // ...
+    /// }
+    /// #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    /// {
+    ///     vm.set_irq_line(0x01_00_0020, true);
+    ///     // ....
+    /// }
+    /// ```
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    pub fn set_irq_line(&self, irq: u32, active: bool) -> Result<()> {
+        let mut irq_level = kvm_irq_level::default();
+        irq_level.__bindgen_anon_1.irq = irq;
+        irq_level.level = u32::from(active);
+
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_IRQ_LINE(), &irq_level) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Sets the level of an arm/aarch64 interrupt line as per the `KVM_IRQ_LINE` ioctl.
+    ///
+    /// Wrapper over [set_irq_line](struct.VmFd.html#method.set_irq_line) taking the typed
+    /// encoding of the line.
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - Interrupt line.
+    /// * `active` - Level of the line.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::ArmIrqLine;
+    /// let line = ArmIrqLine::spi(32).unwrap();
+    /// assert_eq!(line.raw(), 0x01_00_0020);
+    /// // The in-kernel GIC must be initialized before calling `vm.set_arm_irq_line(line, true)`.
+    /// ```
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn set_arm_irq_line(&self, line: ArmIrqLine, active: bool) -> Result<()> {
+        self.set_irq_line(line.raw(), active)
+    }
+
+    /// Sets the level of a level-triggered interrupt line as per the `KVM_IRQ_LINE` ioctl.
+    ///
+    /// Same as [set_irq_line](struct.VmFd.html#method.set_irq_line). The in-kernel irqchip
+    /// must have been created first with
+    /// [create_irq_chip](struct.VmFd.html#method.create_irq_chip).
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - GSI of the interrupt line.
+    /// * `level` - Whether the line is asserted.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// vm.create_irq_chip().unwrap();
+    /// vm.set_level_irq(4, true).unwrap();
+    /// vm.set_level_irq(4, false).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn set_level_irq(&self, irq: u32, level: bool) -> Result<()> {
+        self.set_irq_line(irq, level)
+    }
+
+    /// Sets the level of a level-triggered interrupt line as per the `KVM_IRQ_LINE` ioctl.
+    ///
+    /// Same as [set_arm_irq_line](struct.VmFd.html#method.set_arm_irq_line). SPIs and PPIs
+    /// require the in-kernel vGIC to have been initialized first, while IRQ and FIQ lines
+    /// require the GIC to be emulated in userspace.
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - Interrupt line.
+    /// * `level` - Whether the line is asserted.
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn set_level_irq(&self, irq: ArmIrqLine, level: bool) -> Result<()> {
+        self.set_arm_irq_line(irq, level)
+    }
+
+    /// Pulses an edge-triggered interrupt line, asserting then deasserting it through
+    /// `KVM_IRQ_LINE`.
+    ///
+    /// The in-kernel irqchip must have been created first with
+    /// [create_irq_chip](struct.VmFd.html#method.create_irq_chip). If asserting the line
+    /// fails, it is not deasserted.
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - GSI of the interrupt line.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// vm.create_irq_chip().unwrap();
+    /// // Signal the PIT interrupt.
+    /// vm.pulse_irq_line(0).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn pulse_irq_line(&self, irq: u32) -> Result<()> {
+        self.set_irq_line(irq, true)?;
+        self.set_irq_line(irq, false)
+    }
+
+    /// Pulses an edge-triggered interrupt line, asserting then deasserting it through
+    /// `KVM_IRQ_LINE`.
+    ///
+    /// The prerequisites are the ones of
+    /// [set_level_irq](struct.VmFd.html#method.set_level_irq). If asserting the line fails,
+    /// it is not deasserted.
+    ///
+    /// # Arguments
+    ///
+    /// * `irq` - Interrupt line.
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn pulse_irq_line(&self, irq: ArmIrqLine) -> Result<()> {
+        self.set_arm_irq_line(irq, true)?;
+        self.set_arm_irq_line(irq, false)
+    }
+
+    /// Creates a new KVM vCPU file descriptor and maps the memory corresponding
+    /// its `kvm_run` structure.
+    ///
+    /// See the documentation for `KVM_CREATE_VCPU`.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The vCPU ID.
+    ///
+    /// # Errors
+    ///
+    /// Returns an io::Error when the VM fd is invalid or the vCPU memory cannot
+    /// be mapped correctly.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// // Create one vCPU with the ID=0.
+    /// let vcpu = vm.create_vcpu(0);
+    /// ```
+    pub fn create_vcpu(&self, id: u64) -> Result<VcpuFd> {
+        #[allow(clippy::cast_lossless)]
+        // SAFETY: Safe because we know that vm is a VM fd and we verify the return result.
+        let vcpu_fd = unsafe { ioctl_with_val(&self.vm, KVM_CREATE_VCPU(), id as c_ulong) };
+        if vcpu_fd < 0 {
+            return Err(errno::Error::last());
+        }
+
+        // Wrap the vCPU now in case the following ? returns early.
+        // SAFETY: This is safe because we verified the value of the fd and we own the fd.
+        let vcpu = unsafe { File::from_raw_fd(vcpu_fd) };
+
+        let kvm_run_ptr = KvmRunWrapper::mmap_from_fd(&vcpu, self.run_size)?;
+
+        Ok(new_vcpu(vcpu, kvm_run_ptr))
+    }
+
+    /// Creates a VcpuFd object from a vcpu RawFd.
+    ///
+    /// # Arguments
+    ///
+    /// * `fd` - the RawFd used for creating the VcpuFd object.
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe as the primitives currently returned have the contract that
+    /// they are the sole owner of the file descriptor they are wrapping. Usage of this function
+    /// could accidentally allow violating this contract which can cause memory unsafety in code
+    /// that relies on it being true.
+    ///
+    /// The caller of this method must make sure the fd is valid and nothing else uses it.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use std::os::unix::io::AsRawFd;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// // Create one vCPU with the ID=0.
+    /// let vcpu = vm.create_vcpu(0).unwrap();
+    /// let rawfd = unsafe { libc::dup(vcpu.as_raw_fd()) };
+    /// assert!(rawfd >= 0);
+    /// let vcpu = unsafe { vm.create_vcpu_from_rawfd(rawfd).unwrap() };
+    /// ```
+    pub unsafe fn create_vcpu_from_rawfd(&self, fd: RawFd) -> Result<VcpuFd> {
+        let vcpu = File::from_raw_fd(fd);
+        let kvm_run_ptr = KvmRunWrapper::mmap_from_fd(&vcpu, self.run_size)?;
+        Ok(new_vcpu(vcpu, kvm_run_ptr))
+    }
+
+    /// Creates an emulated device in the kernel.
+    ///
+    /// See the documentation for `KVM_CREATE_DEVICE`.
+    ///
+    /// # Arguments
+    ///
+    /// * `device`: device configuration. For details check the `kvm_create_device` structure in the
+    ///                [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::{
+    ///     kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2, kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+    ///     kvm_device_type_KVM_DEV_TYPE_VFIO, KVM_CREATE_DEVICE_TEST,
+    /// };
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// // Creating a device with the KVM_CREATE_DEVICE_TEST flag to check
+    /// // whether the device type is supported. This will not create the device.
+    /// // To create the device the flag needs to be removed.
+    /// let mut device = kvm_bindings::kvm_create_device {
+    ///     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    ///     type_: kvm_device_type_KVM_DEV_TYPE_VFIO,
+    ///     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    ///     type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+    ///     fd: 0,
+    ///     flags: KVM_CREATE_DEVICE_TEST,
+    /// };
+    /// // On ARM, creating VGICv3 may fail due to hardware dependency.
+    /// // Retry to create VGICv2 in that case.
+    /// let device_fd = vm.create_device(&mut device).unwrap_or_else(|_| {
+    ///     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    ///     panic!("Cannot create VFIO device.");
+    ///     #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    ///     {
+    ///         device.type_ = kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2;
+    ///         vm.create_device(&mut device)
+    ///             .expect("Cannot create vGIC device")
+    ///     }
+    /// });
+    /// ```
+    pub fn create_device(&self, device: &mut kvm_create_device) -> Result<DeviceFd> {
+        // SAFETY: Safe because we are calling this with the VM fd and we trust the kernel.
+        let ret = unsafe { ioctl_with_ref(self, KVM_CREATE_DEVICE(), device) };
+        if ret == 0 {
+            // SAFETY: We validated the return of the function creating the fd and we trust the
+            // kernel.
+            Ok(new_device(unsafe { File::from_raw_fd(device.fd as i32) }))
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Creates an emulated device in the kernel, returning it tagged with its kind.
+    ///
+    /// Same as [create_device](struct.VmFd.html#method.create_device), except that the
+    /// returned [`TypedDevice`] remembers the device type. Passing `KVM_CREATE_DEVICE_TEST`
+    /// in `flags` only checks that the device type is supported: the returned variant then
+    /// holds no file descriptor.
+    ///
+    /// # Arguments
+    ///
+    /// * `type_` - Device type, one of the `KVM_DEV_TYPE_*` values.
+    /// * `flags` - Creation flags, i.e. `0` or `KVM_CREATE_DEVICE_TEST`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::{Kvm, TypedDevice};
+    /// use kvm_bindings::{kvm_device_type_KVM_DEV_TYPE_VFIO, KVM_CREATE_DEVICE_TEST};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// let device = vm
+    ///     .create_typed_device(kvm_device_type_KVM_DEV_TYPE_VFIO, KVM_CREATE_DEVICE_TEST)
+    ///     .unwrap();
+    /// assert!(matches!(device, TypedDevice::Vfio(None)));
+    /// ```
+    pub fn create_typed_device(&self, type_: u32, flags: u32) -> Result<TypedDevice> {
+        let mut device = kvm_create_device {
+            type_,
+            fd: 0,
+            flags,
+        };
+        // SAFETY: Safe because we are calling this with the VM fd, and the kernel only writes
+        // the fd field of the struct.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_CREATE_DEVICE(), &mut device) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        if flags & KVM_CREATE_DEVICE_TEST != 0 {
+            return Ok(TypedDevice::new(type_, None));
+        }
+        // SAFETY: We validated the return of the function creating the fd and we trust the
+        // kernel.
+        let fd = new_device(unsafe { File::from_raw_fd(device.fd as i32) });
+        Ok(TypedDevice::new(type_, Some(fd)))
+    }
+
+    /// Creates the in-kernel vGIC, trying the `prefer`red version first and falling back to
+    /// the other one.
+    ///
+    /// [GicVersion::Auto] prefers a GICv3. When neither version can be created, the error of
+    /// the preferred one is returned. The vGIC still has to be configured and initialized,
+    /// see [GicBuilder](crate::GicBuilder) for doing it all at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `prefer` - Version of the vGIC to try first.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{GicVersion, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let (vgic, version) = vm.create_vgic(GicVersion::V3).unwrap();
+    /// assert_ne!(version, GicVersion::Auto);
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn create_vgic(&self, prefer: GicVersion) -> Result<(DeviceFd, GicVersion)> {
+        let (first, second) = match prefer {
+            GicVersion::V2 => (GicVersion::V2, GicVersion::V3),
+            GicVersion::V3 | GicVersion::Auto => (GicVersion::V3, GicVersion::V2),
+        };
+        match create_device(self, first.device_type()) {
+            Ok(fd) => Ok((fd, first)),
+            Err(err) => create_device(self, second.device_type())
+                .map(|fd| (fd, second))
+                .map_err(|_| err),
+        }
+    }
+
+    /// Returns the preferred CPU target type which can be emulated by KVM on underlying host.
+    ///
+    /// The preferred CPU target is returned in the `kvi` parameter.
+    /// See documentation for `KVM_ARM_PREFERRED_TARGET`.
+    ///
+    /// # Arguments
+    /// * `kvi` - CPU target configuration (out). For details check the `kvm_vcpu_init`
+    ///           structure in the
+    ///           [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::kvm_vcpu_init;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut kvi = kvm_vcpu_init::default();
+    /// vm.get_preferred_target(&mut kvi).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn get_preferred_target(&self, kvi: &mut kvm_vcpu_init) -> Result<()> {
+        // SAFETY: The ioctl is safe because we allocated the struct and we know the
+        // kernel will write exactly the size of the struct.
+        let ret = unsafe { ioctl_with_mut_ref(self, KVM_ARM_PREFERRED_TARGET(), kvi) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Returns an [`ArmVcpuInitBuilder`] starting from the preferred CPU target of the host.
+    ///
+    /// The optional vCPU features supported by the VM are probed with
+    /// [check_extension](struct.VmFd.html#method.check_extension) here, so that requesting
+    /// an unsupported one fails with [`ArmVcpuInitError::Unsupported`].
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{ArmVcpuFeature, ArmVcpuInitError, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let builder = vm.arm_vcpu_init_builder().unwrap().with_psci_0_2().unwrap();
+    /// let builder = match builder.with_pmu() {
+    ///     Ok(builder) => builder,
+    ///     Err(ArmVcpuInitError::Unsupported(ArmVcpuFeature::Pmu)) => builder,
+    ///     Err(err) => panic!("{}", err),
+    /// };
+    /// for id in 0..2 {
+    ///     builder.init(&vm.create_vcpu(id).unwrap()).unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn arm_vcpu_init_builder(&self) -> Result<ArmVcpuInitBuilder> {
+        let mut kvi = kvm_vcpu_init::default();
+        self.get_preferred_target(&mut kvi)?;
+        let supported = [
+            ArmVcpuFeature::Psci02,
+            ArmVcpuFeature::Pmu,
+            ArmVcpuFeature::Sve,
+            ArmVcpuFeature::PtrAuth,
+            ArmVcpuFeature::El2,
+        ]
+        .into_iter()
+        .filter(|feature| feature.caps().iter().all(|&cap| self.check_extension(cap)))
+        .fold(0, |supported, feature| supported | feature.bits());
+        Ok(ArmVcpuInitBuilder {
+            kvi,
+            supported,
+            pmu_irq: None,
+        })
+    }
+
+    /// Sets the guest physical address of an in-kernel device as per the
+    /// `KVM_ARM_SET_DEVICE_ADDR` ioctl.
+    ///
+    /// This is the legacy interface for placing the GICv2 distributor and CPU interface of an
+    /// interrupt controller created with [create_irq_chip](struct.VmFd.html#method.create_irq_chip).
+    /// Devices created with [create_device](struct.VmFd.html#method.create_device) use the
+    /// `KVM_DEV_ARM_VGIC_GRP_ADDR` device attribute group instead.
+    ///
+    /// # Arguments
+    ///
+    /// * `dev_addr` - Device id and address. The `id` field holds the device id in bits
+    ///                `KVM_ARM_DEVICE_ID_SHIFT` and above and the address type in the low bits.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::{
+    ///     kvm_arm_device_addr, KVM_ARM_DEVICE_ID_SHIFT, KVM_ARM_DEVICE_VGIC_V2,
+    ///     KVM_VGIC_V2_ADDR_TYPE_DIST,
+    /// };
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// // Only hosts able to emulate a GICv2 support the legacy interface.
+    /// if vm.create_irq_chip().is_ok() {
+    ///     let dev_addr = kvm_arm_device_addr {
+    ///         id: u64::from(
+    ///             KVM_ARM_DEVICE_VGIC_V2 << KVM_ARM_DEVICE_ID_SHIFT | KVM_VGIC_V2_ADDR_TYPE_DIST,
+    ///         ),
+    ///         addr: 0x0800_0000,
+    ///     };
+    ///     vm.arm_set_device_addr(&dev_addr).unwrap();
+    /// }
+    /// ```
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    pub fn arm_set_device_addr(&self, dev_addr: &kvm_arm_device_addr) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_ARM_SET_DEVICE_ADDR(), dev_addr) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Returns the IPA size of the VM, in bits, or `None` for VMs created from a raw file
+    /// descriptor.
+    ///
+    /// This is the size selected with [VmBuilder](crate::VmBuilder) or
+    /// [create_vm_with_ipa_size](struct.Kvm.html#method.create_vm_with_ipa_size), or 40 bits
+    /// for VMs created without one. Guest memory and MMIO regions must lie below `1 << bits`.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm_with_type(0).unwrap();
+    /// assert_eq!(vm.ipa_bits(), Some(40));
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn ipa_bits(&self) -> Option<u32> {
+        self.vm_type.map(
+            |vm_type| match vm_type as u32 & KVM_VM_TYPE_ARM_IPA_SIZE_MASK {
+                0 => KVM_ARM_DEFAULT_IPA_BITS,
+                bits => bits,
+            },
+        )
+    }
+
+    /// Sets the offset subtracted from the host counter for the virtual and physical counters
+    /// of all the vCPUs, as per the `KVM_ARM_SET_COUNTER_OFFSET` ioctl.
+    ///
+    /// This is how the counters of a migrated guest are restored: the offset is the difference
+    /// between the counter of the destination host and the counter of the guest. Once it is
+    /// set, KVM ignores the writes to `CNTVCT_EL0` and `CNTPCT_EL0` made through
+    /// [set_one_reg](struct.VcpuFd.html#method.set_one_reg), so that restoring the saved
+    /// vCPU registers does not undo it.
+    ///
+    /// All the vCPUs must be quiesced, i.e. not running `KVM_RUN`; the call fails with `EBUSY`
+    /// otherwise. Requires `KVM_CAP_COUNTER_OFFSET`.
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Counter offset, in counter ticks.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.check_extension(Cap::CounterOffset) {
+    ///     vm.set_counter_offset(0x1000).unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_counter_offset(&self, offset: u64) -> Result<()> {
+        let counter_offset = kvm_arm_counter_offset {
+            counter_offset: offset,
+            reserved: 0,
+        };
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_ARM_SET_COUNTER_OFFSET(), &counter_offset) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Issues one of the device attribute ioctls on the VM fd.
+    ///
+    /// The kernel reads `device_attr` and accesses the attribute value through its `addr`
+    /// field, the structure itself is never written.
+    #[cfg(target_arch = "aarch64")]
+    fn device_attr_ioctl(&self, request: c_ulong, device_attr: &kvm_device_attr) -> Result<()> {
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only
+        // read the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, request, device_attr) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Tests whether the VM supports a particular attribute, as per the `KVM_HAS_DEVICE_ATTR`
+    /// ioctl issued on the VM fd.
+    ///
+    /// The attribute groups known to the VM fd are:
+    ///
+    /// * `KVM_ARM_VM_SMCCC_CTRL` - with the `KVM_ARM_VM_SMCCC_FILTER` attribute, a
+    ///   `kvm_smccc_filter` telling KVM to handle, deny or forward to userspace a range of
+    ///   SMCCC function IDs. Write-only, and only before any vCPU has run.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_attr` - The VM attribute to be tested. `addr` field is ignored.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_bindings::kvm_device_attr;
+    /// # // From `arch/arm64/include/uapi/asm/kvm.h`, missing from the `kvm-bindings` in use.
+    /// # const KVM_ARM_VM_SMCCC_CTRL: u32 = 0;
+    /// # const KVM_ARM_VM_SMCCC_FILTER: u32 = 0;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let filter_attr = kvm_device_attr {
+    ///     group: KVM_ARM_VM_SMCCC_CTRL,
+    ///     attr: u64::from(KVM_ARM_VM_SMCCC_FILTER),
+    ///     addr: 0,
+    ///     flags: 0,
+    /// };
+    /// let smccc_filter = vm.has_device_attr(&filter_attr).is_ok();
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn has_device_attr(&self, device_attr: &kvm_device_attr) -> Result<()> {
+        self.device_attr_ioctl(KVM_HAS_DEVICE_ATTR(), device_attr)
+    }
+
+    /// Sets an attribute of the VM, as per the `KVM_SET_DEVICE_ATTR` ioctl issued on the
+    /// VM fd.
+    ///
+    /// See [has_device_attr](struct.VmFd.html#method.has_device_attr) for the attribute
+    /// groups known to the VM fd.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_attr` - The VM attribute to be set, its `addr` field pointing to the value.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_bindings::kvm_device_attr;
+    /// # // From `arch/arm64/include/uapi/asm/kvm.h`, missing from the `kvm-bindings` in use.
+    /// # const KVM_ARM_VM_SMCCC_CTRL: u32 = 0;
+    /// # const KVM_ARM_VM_SMCCC_FILTER: u32 = 0;
+    /// # const KVM_SMCCC_FILTER_DENY: u8 = 1;
+    /// # #[allow(non_camel_case_types)]
+    /// # #[repr(C)]
+    /// # #[derive(Default)]
+    /// # struct kvm_smccc_filter {
+    /// #     base: u32,
+    /// #     nr_functions: u32,
+    /// #     action: u8,
+    /// #     pad: [u8; 15],
+    /// # }
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// // Deny the SMCCC vendor specific hypervisor service calls.
+    /// let filter = kvm_smccc_filter {
+    ///     base: 0x8600_0000,
+    ///     nr_functions: 0x100,
+    ///     action: KVM_SMCCC_FILTER_DENY,
+    ///     ..Default::default()
+    /// };
+    /// let filter_attr = kvm_device_attr {
+    ///     group: KVM_ARM_VM_SMCCC_CTRL,
+    ///     attr: u64::from(KVM_ARM_VM_SMCCC_FILTER),
+    ///     addr: &filter as *const kvm_smccc_filter as u64,
+    ///     flags: 0,
+    /// };
+    /// if vm.has_device_attr(&filter_attr).is_ok() {
+    ///     vm.set_device_attr(&filter_attr).unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn set_device_attr(&self, device_attr: &kvm_device_attr) -> Result<()> {
+        self.device_attr_ioctl(KVM_SET_DEVICE_ATTR(), device_attr)
+    }
+
+    /// Gets an attribute of the VM, as per the `KVM_GET_DEVICE_ATTR` ioctl issued on the
+    /// VM fd.
+    ///
+    /// See [has_device_attr](struct.VmFd.html#method.has_device_attr) for the attribute
+    /// groups known to the VM fd. None of them is readable at the moment, the call is
+    /// provided for the groups added by future kernels.
+    ///
+    /// # Arguments
+    ///
+    /// * `device_attr` - The VM attribute to be read, its `addr` field pointing to where
+    ///                   the value is written.
+    ///
+    /// # Safety
+    ///
+    /// The kernel writes the value of the attribute at `device_attr.addr`. The caller must
+    /// ensure that it points to memory which is valid for writes of the size of the value.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use kvm_ioctls::Kvm;
+    /// # use kvm_bindings::kvm_device_attr;
+    /// # // From `arch/arm64/include/uapi/asm/kvm.h`, missing from the `kvm-bindings` in use.
+    /// # const KVM_ARM_VM_SMCCC_CTRL: u32 = 0;
+    /// # const KVM_ARM_VM_SMCCC_FILTER: u32 = 0;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut value = 0u64;
+    /// let mut attr = kvm_device_attr {
+    ///     group: KVM_ARM_VM_SMCCC_CTRL,
+    ///     attr: u64::from(KVM_ARM_VM_SMCCC_FILTER),
+    ///     addr: &mut value as *mut u64 as u64,
+    ///     flags: 0,
+    /// };
+    /// // The SMCCC filter cannot be read back.
+    /// assert!(unsafe { vm.get_device_attr(&mut attr) }.is_err());
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub unsafe fn get_device_attr(&self, device_attr: &mut kvm_device_attr) -> Result<()> {
+        self.device_attr_ioctl(KVM_GET_DEVICE_ATTR(), device_attr)
+    }
+
+    /// Enables the Memory Tagging Extension for the guest by enabling `KVM_CAP_ARM_MTE`.
+    ///
+    /// Must be called before creating the vCPUs. The guest memory must then be mapped with
+    /// memory able to hold tags, e.g. anonymous private memory.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.check_extension(Cap::ArmMte) {
+    ///     vm.enable_mte().unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn enable_mte(&self) -> Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_ARM_MTE,
+            ..Default::default()
+        };
+        self.enable_cap(&cap)
+    }
+
+    /// Lets the guest request a system suspend with the PSCI `SYSTEM_SUSPEND` call, by enabling
+    /// `KVM_CAP_ARM_SYSTEM_SUSPEND`.
+    ///
+    /// The call then causes a [`VcpuExit::SystemEvent`](crate::VcpuExit::SystemEvent) of type
+    /// [`SystemEventType::Suspend`](crate::SystemEventType::Suspend), with the vCPU registers
+    /// left untouched: `x1` holds the entry point and `x2` the context ID the guest wants to
+    /// be resumed with. The VMM either denies the request by writing a PSCI error to `x0`, or
+    /// sets the vCPU to [`MpState::Suspended`](crate::MpState::Suspended) for KVM to wait for
+    /// a wake-up event, and resumes it with
+    /// [`resume_from_suspend`](crate::VcpuFd::resume_from_suspend).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.check_extension(Cap::ArmSystemSuspend) {
+    ///     vm.enable_system_suspend().unwrap();
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn enable_system_suspend(&self) -> Result<()> {
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_ARM_SYSTEM_SUSPEND,
+            ..Default::default()
+        };
+        self.enable_cap(&cap)
+    }
+
+    /// Copies the MTE allocation tags of a range of guest memory from or to `addr`, as per
+    /// the `KVM_ARM_MTE_COPY_TAGS` ioctl.
+    ///
+    /// Each byte of `addr` holds the tag of one 16-byte granule of guest memory in its low 4
+    /// bits, so `addr` covers `16 * addr.len()` bytes of guest memory. This is how a VMM
+    /// migrates the tags of a guest with MTE enabled by [enable_mte](Self::enable_mte).
+    ///
+    /// Returns the number of tags copied, which is smaller than `addr.len()` if an error
+    /// occurred after copying some of them.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_ipa` - Guest physical address of the memory, page aligned.
+    /// * `addr` - Tags, one per granule of guest memory. The range of guest memory must be a
+    ///            whole number of pages, so the length must be a multiple of the page size
+    ///            divided by 16. Fails with `EINVAL` otherwise.
+    /// * `flags` - Whether the tags are written to or read from the guest.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm, MteCopyFlags};
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// if vm.check_extension(Cap::ArmMte) {
+    ///     vm.enable_mte().unwrap();
+    ///     // Read the tags of the first 4K page of guest memory, once it is mapped.
+    ///     let mut tags = [0u8; 4096 / 16];
+    ///     let _ = vm.mte_copy_tags(0, &mut tags, MteCopyFlags::FromGuest);
+    /// }
+    /// ```
+    #[cfg(target_arch = "aarch64")]
+    pub fn mte_copy_tags(
+        &self,
+        guest_ipa: u64,
+        addr: &mut [u8],
+        flags: MteCopyFlags,
+    ) -> Result<usize> {
+        // SAFETY: We trust the sysconf libc function and we're calling it with a correct parameter.
+        let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            -1 => return Err(errno::Error::last()),
+            ps => ps as u64,
+        };
+        let length = addr.len() as u64 * MTE_GRANULE_SIZE;
+        if length == 0 || (guest_ipa | length) & (page_size - 1) != 0 {
+            return Err(errno::Error::new(libc::EINVAL));
+        }
+        let copy_tags = kvm_arm_copy_mte_tags {
+            guest_ipa,
+            length,
+            addr: addr.as_mut_ptr() as *mut c_void,
+            flags: flags as u64,
+            reserved: [0; 2],
+        };
+        // SAFETY: Safe because we know that our file is a VM fd, the kernel only accesses the
+        // `length / 16` bytes of `addr`, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_ARM_MTE_COPY_TAGS(), &copy_tags) };
+        if ret < 0 {
+            return Err(errno::Error::last());
+        }
+        // The kernel returns the number of bytes of guest memory whose tags were copied.
+        Ok(ret as usize / MTE_GRANULE_SIZE as usize)
+    }
+
+    /// Enable the specified capability as per the `KVM_ENABLE_CAP` ioctl.
+    ///
+    /// See the documentation for `KVM_ENABLE_CAP`.
+    ///
+    /// Returns an io::Error when the capability could not be enabled. The kernel does not
+    /// write anything back into `cap`.
+    ///
+    /// # Arguments
+    ///
+    /// * kvm_enable_cap - KVM capability structure. For details check the `kvm_enable_cap`
+    ///                    structure in the
+    ///                    [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// extern crate kvm_bindings;
+    ///
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_bindings::{kvm_enable_cap, KVM_CAP_SPLIT_IRQCHIP};
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let mut cap: kvm_enable_cap = Default::default();
+    /// // The split irqchip model only exists on x86; on aarch64, see for
+    /// // instance `enable_mte`.
+    /// if cfg!(target_arch = "x86") || cfg!(target_arch = "x86_64") {
+    ///     cap.cap = KVM_CAP_SPLIT_IRQCHIP;
+    ///     // As per the KVM documentation, KVM_CAP_SPLIT_IRQCHIP only emulates
+    ///     // the local APIC in kernel, expecting that a userspace IOAPIC will
+    ///     // be implemented by the VMM.
+    ///     // Along with this capability, the user needs to specify the number
+    ///     // of pins reserved for the userspace IOAPIC. This number needs to be
+    ///     // provided through the first argument of the capability structure, as
+    ///     // specified in KVM documentation:
+    ///     //     args[0] - number of routes reserved for userspace IOAPICs
+    ///     //
+    ///     // Because an IOAPIC supports 24 pins, that's the reason why this test
+    ///     // picked this number as reference.
+    ///     cap.args[0] = 24;
+    ///     vm.enable_cap(&cap).unwrap();
+    /// }
+    /// ```
+    pub fn enable_cap(&self, cap: &kvm_enable_cap) -> Result<()> {
+        // SAFETY: The ioctl is safe because we allocated the struct and we know the
+        // kernel will write exactly the size of the struct.
+        let ret = unsafe { ioctl_with_ref(self, KVM_ENABLE_CAP(), cap) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Get the `kvm_run` size.
+    pub fn run_size(&self) -> usize {
+        self.run_size
+    }
+
+    /// Wrapper over `KVM_CHECK_EXTENSION` issued on the VM file descriptor.
+    ///
+    /// Returns 0 if the capability is not available and a positive integer otherwise.
+    /// See the documentation for `KVM_CHECK_EXTENSION`.
+    ///
+    /// Some capabilities depend on the configuration of the VM and must be checked here rather
+    /// than through [Kvm::check_extension_int](struct.Kvm.html#method.check_extension_int),
+    /// which may report a different value or an error. These include:
+    ///
+    /// * `Cap::ArmVmIPASize` - the IPA size limit of VMs of the VM's type;
+    /// * `Cap::MsiDevid` - whether MSIs need a device ID, which depends on the vGIC of the VM;
+    /// * `Cap::NrMemslots`, `Cap::MaxVcpus`, `Cap::MaxVcpuId` - which may depend on the VM type;
+    /// * `Cap::AdjustClock` - the clock flags supported for the VM;
+    /// * `Cap::IrqRouting` - the number of routes supported by the VM's irqchip.
+    ///
+    /// # Arguments
+    ///
+    /// * `c` - VM capability to check.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_ioctls::Cap;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// assert!(vm.check_extension_int(Cap::MaxVcpus) > 0);
+    /// ```
+    pub fn check_extension_int(&self, c: Cap) -> i32 {
+        // SAFETY: Safe because we know that our file is a VM fd and that the extension is one of
+        // the ones defined by kernel.
+        unsafe { ioctl_with_val(self, KVM_CHECK_EXTENSION(), c as c_ulong) }
+    }
+
+    /// Checks if a particular `Cap` is available.
+    ///
+    /// Returns true if the capability is supported and false otherwise.
+    /// See the documentation for `KVM_CHECK_EXTENSION`, and
+    /// [check_extension_int](struct.VmFd.html#method.check_extension_int) for the
+    /// capabilities that are VM-scoped.
+    ///
+    /// # Arguments
+    ///
+    /// * `c` - VM capability to check.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use kvm_ioctls::Kvm;
+    /// use kvm_ioctls::Cap;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// // Check if `KVM_CAP_MP_STATE` is supported.
+    /// assert!(vm.check_extension(Cap::MpState));
+    /// ```
+    pub fn check_extension(&self, c: Cap) -> bool {
+        self.check_extension_int(c) > 0
+    }
+
+    /// Returns the maximum number of memory slots of this VM.
+    ///
+    /// `KVM_CAP_NR_MEMSLOTS` is checked on the VM fd. KVM currently reports the same value as
+    /// [Kvm::get_nr_memslots](struct.Kvm.html#method.get_nr_memslots) does on the system fd,
+    /// the number of slots of each address space, so e.g. the SMM address space of x86 VMs
+    /// has as many slots again. Defaults to 32 when `KVM_CAP_NR_MEMSLOTS` is not implemented.
+    ///
+    /// # Example
+    ///
+    /// ```
+    /// # use kvm_ioctls::Kvm;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// assert!(vm.nr_memslots() > 0);
+    /// ```
+    pub fn nr_memslots(&self) -> usize {
+        let x = self.check_extension_int(Cap::NrMemslots);
+        if x > 0 {
+            x as usize
+        } else {
+            32
+        }
+    }
+
+    /// Issues platform-specific memory encryption commands to manage encrypted VMs if
+    /// the platform supports creating those encrypted VMs.
+    ///
+    /// Currently, this ioctl is used for issuing Secure Encrypted Virtualization
+    /// (SEV) commands on AMD Processors.
+    ///
+    /// See the documentation for `KVM_MEMORY_ENCRYPT_OP` in the
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// For SEV-specific functionality, prefer safe wrapper:
+    /// - [`encrypt_op_sev`](Self::encrypt_op_sev)
+    ///
+    /// # Safety
+    ///
+    /// This function is unsafe because there is no guarantee `T` is valid in this context, how
+    /// much data kernel will read from memory and where it will write data on error.
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - an opaque platform specific structure.
+    ///
+    /// # Example
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// use kvm_bindings::bindings::kvm_sev_cmd;
+    /// # use kvm_ioctls::Kvm;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// // Initialize the SEV platform context.
+    /// let mut init: kvm_sev_cmd = Default::default();
+    /// unsafe { vm.encrypt_op(&mut init).unwrap() };
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub unsafe fn encrypt_op<T>(&self, op: *mut T) -> Result<()> {
+        let ret = ioctl_with_mut_ptr(self, KVM_MEMORY_ENCRYPT_OP(), op);
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Issue common lifecycle events of SEV guests, such as launching, running, snapshotting,
+    /// migrating and decommissioning via `KVM_MEMORY_ENCRYPT_OP` ioctl.
+    ///
+    /// Kernel documentation states that this ioctl can be used for testing whether SEV is enabled
+    /// by sending `NULL`. To do that, pass [`std::ptr::null_mut`](std::ptr::null_mut) to [`encrypt_op`](Self::encrypt_op).
+    ///
+    /// See the documentation for Secure Encrypted Virtualization (SEV).
+    ///
+    /// # Arguments
+    ///
+    /// * `op` - SEV-specific structure. For details check the
+    ///         [Secure Encrypted Virtualization (SEV) doc](https://www.kernel.org/doc/Documentation/virtual/kvm/amd-memory-encryption.rst).
+    ///
+    /// # Example
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # extern crate kvm_ioctls;
+    /// # extern crate kvm_bindings;
+    /// # use std::{os::raw::c_void, ptr::null_mut};
+    /// use kvm_bindings::bindings::kvm_sev_cmd;
+    /// # use kvm_ioctls::Kvm;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    ///
+    /// // Check whether SEV is enabled, optional.
+    /// assert!(unsafe { vm.encrypt_op(null_mut() as *mut c_void) }.is_ok());
+    ///
+    /// // Initialize the SEV platform context.
+    /// let mut init: kvm_sev_cmd = Default::default();
+    /// vm.encrypt_op_sev(&mut init).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn encrypt_op_sev(&self, op: &mut kvm_sev_cmd) -> Result<()> {
+        // SAFETY: Safe because we know that kernel will only read the correct amount of memory
+        // from our pointer and we know where it will write it (op.error).
+        unsafe { self.encrypt_op(op) }
+    }
+
+    /// Register a guest memory region which may contain encrypted data.
+    ///
+    /// It is used in the SEV-enabled guest.
+    ///
+    /// Returns `EINVAL` without issuing the ioctl if the address or the size of the region
+    /// are not page aligned.
+    ///
+    /// See the documentation for `KVM_MEMORY_ENCRYPT_REG_REGION` in the
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_region` - Guest physical memory region.
+    ///
+    /// # Example
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # use std::{fs::OpenOptions, ptr::null_mut};
+    /// # use std::os::unix::io::AsRawFd;
+    /// use kvm_bindings::bindings::{kvm_enc_region, kvm_sev_cmd, kvm_sev_launch_start, sev_cmd_id_KVM_SEV_LAUNCH_START};
+    /// # use kvm_ioctls::Kvm;
+    /// use libc;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let sev = OpenOptions::new()
+    ///     .read(true)
+    ///     .write(true)
+    ///     .open("/dev/sev")
+    ///     .unwrap();
+    ///
+    /// // Initialize the SEV platform context.
+    /// let mut init: kvm_sev_cmd = Default::default();
+    /// assert!(vm.encrypt_op_sev(&mut init).is_ok());
+    ///
+    /// // Create the memory encryption context.
+    /// let start_data: kvm_sev_launch_start = Default::default();
+    /// let mut start = kvm_sev_cmd {
+    ///     id: sev_cmd_id_KVM_SEV_LAUNCH_START,
+    ///     data: &start_data as *const kvm_sev_launch_start as _,
+    ///     sev_fd: sev.as_raw_fd() as _,
+    ///     ..Default::default()
+    /// };
+    /// assert!(vm.encrypt_op_sev(&mut start).is_ok());
+    ///
+    /// let addr = unsafe {
+    ///     libc::mmap(
+    ///         null_mut(),
+    ///         4096,
+    ///         libc::PROT_READ | libc::PROT_WRITE,
+    ///         libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+    ///         -1,
+    ///         0,
+    ///     )
+    /// };
+    /// assert_ne!(addr, libc::MAP_FAILED);
+    ///
+    /// let memory_region = kvm_enc_region {
+    ///     addr: addr as _,
+    ///     size: 4096,
+    /// };
+    /// vm.register_enc_memory_region(&memory_region).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn register_enc_memory_region(&self, memory_region: &kvm_enc_region) -> Result<()> {
+        check_enc_region(memory_region)?;
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_MEMORY_ENCRYPT_REG_REGION(), memory_region) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Unregister a guest memory region registered with
+    /// [`register_enc_memory_region`](Self::register_enc_memory_region).
+    ///
+    /// It is used in the SEV-enabled guest.
+    ///
+    /// Returns `EINVAL` without issuing the ioctl if the address or the size of the region
+    /// are not page aligned.
+    ///
+    /// See the documentation for `KVM_MEMORY_ENCRYPT_UNREG_REGION` in the
+    /// [KVM API doc](https://www.kernel.org/doc/Documentation/virtual/kvm/api.txt).
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_region` - Guest physical memory region.
+    ///
+    /// # Example
+    #[cfg_attr(has_sev, doc = "```rust")]
+    #[cfg_attr(not(has_sev), doc = "```rust,no_run")]
+    /// # extern crate kvm_bindings;
+    /// # extern crate kvm_ioctls;
+    /// # extern crate libc;
+    /// # use std::{fs::OpenOptions, ptr::null_mut};
+    /// # use std::os::unix::io::AsRawFd;
+    /// use kvm_bindings::bindings::{kvm_enc_region, kvm_sev_cmd, kvm_sev_launch_start, sev_cmd_id_KVM_SEV_LAUNCH_START};
+    /// # use kvm_ioctls::Kvm;
+    /// use libc;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let sev = OpenOptions::new()
+    ///     .read(true)
+    ///     .write(true)
+    ///     .open("/dev/sev")
+    ///     .unwrap();
+    ///
+    /// // Initialize the SEV platform context.
+    /// let mut init: kvm_sev_cmd = Default::default();
+    /// assert!(vm.encrypt_op_sev(&mut init).is_ok());
+    ///
+    /// // Create the memory encryption context.
+    /// let start_data: kvm_sev_launch_start = Default::default();
+    /// let mut start = kvm_sev_cmd {
+    ///     id: sev_cmd_id_KVM_SEV_LAUNCH_START,
+    ///     data: &start_data as *const kvm_sev_launch_start as _,
+    ///     sev_fd: sev.as_raw_fd() as _,
+    ///     ..Default::default()
+    /// };
+    /// assert!(vm.encrypt_op_sev(&mut start).is_ok());
+    ///
+    /// let addr = unsafe {
+    ///     libc::mmap(
+    ///         null_mut(),
+    ///         4096,
+    ///         libc::PROT_READ | libc::PROT_WRITE,
+    ///         libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+    ///         -1,
+    ///         0,
+    ///     )
+    /// };
+    /// assert_ne!(addr, libc::MAP_FAILED);
+    ///
+    /// let memory_region = kvm_enc_region {
+    ///     addr: addr as _,
+    ///     size: 4096,
+    /// };
+    /// vm.register_enc_memory_region(&memory_region).unwrap();
+    /// vm.unregister_enc_memory_region(&memory_region).unwrap();
+    /// ```
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn unregister_enc_memory_region(&self, memory_region: &kvm_enc_region) -> Result<()> {
+        check_enc_region(memory_region)?;
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_MEMORY_ENCRYPT_UNREG_REGION(), memory_region) };
+        if ret == 0 {
+            Ok(())
+        } else {
+            Err(errno::Error::last())
+        }
+    }
+
+    /// Registers an encrypted memory region like
+    /// [`register_enc_memory_region`](Self::register_enc_memory_region) and returns a guard
+    /// unregistering it when dropped.
+    ///
+    /// # Arguments
+    ///
+    /// * `memory_region` - Guest physical memory region.
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    pub fn register_enc_memory_region_guarded(
+        &self,
+        memory_region: &kvm_enc_region,
+    ) -> Result<EncRegionGuard<'_>> {
+        self.register_enc_memory_region(memory_region)?;
+        Ok(EncRegionGuard {
+            vm: self,
+            region: Some(*memory_region),
+        })
+    }
+
+    /// Registers an address for coalesced MMIO. Write accesses to the address
+    /// will not cause a corresponding [`VcpuExit`](crate::VcpuExit), but
+    /// instead will be appended to the MMIO ring buffer. The [`VcpuFd`] can
+    /// read entries in the ring buffer via [`VcpuFd::coalesced_mmio_read()`].
+    /// If entries are not read the buffer will eventually be full,
+    /// preventing further elements from being appended by the kernel.
+    ///
+    /// Needs `KVM_CAP_COALESCED_MMIO` ([`Cap::CoalescedMmio`](crate::Cap::CoalescedMmio))
+    /// and/or `KVM_CAP_COALESCED_PIO` ([`Cap::CoalescedMmio`](crate::Cap::CoalescedPio)).
+    ///
+    /// See the documentation for `KVM_REGISTER_COALESCED_MMIO`.
+    ///
+    /// # Arguments
+    ///
+    /// * `addr` - Address being written to.
+    /// * `size` - The size of the write for the mechanism to trigger.
+    pub fn register_coalesced_mmio(&self, addr: IoEventAddress, size: u32) -> Result<()> {
+        let (addr, pio) = match addr {
+            IoEventAddress::Pio(addr) => (addr, 1),
+            IoEventAddress::Mmio(addr) => (addr, 0),
+        };
+        let mut zone = kvm_coalesced_mmio_zone {
+            addr,
+            size,
+            ..Default::default()
+        };
+        zone.__bindgen_anon_1.pio = pio;
+
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_REGISTER_COALESCED_MMIO(), &zone) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Unregister an address that was previously registered via
+    /// [`register_coalesced_mmio()`](VmFd::register_coalesced_mmio).
+    ///
+    /// See the documentation for `KVM_UNREGISTER_COALESCED_MMIO`.
+    pub fn unregister_coalesced_mmio(&self, addr: IoEventAddress, size: u32) -> Result<()> {
+        let (addr, pio) = match addr {
+            IoEventAddress::Pio(addr) => (addr, 1),
+            IoEventAddress::Mmio(addr) => (addr, 0),
+        };
+        let mut zone = kvm_coalesced_mmio_zone {
+            addr,
+            size,
+            ..Default::default()
+        };
+        zone.__bindgen_anon_1.pio = pio;
+
+        // SAFETY: Safe because we know that our file is a VM fd, we know the kernel will only read
+        // the correct amount of memory from our pointer, and we verify the return result.
+        let ret = unsafe { ioctl_with_ref(self, KVM_UNREGISTER_COALESCED_MMIO(), &zone) };
+        if ret != 0 {
+            return Err(errno::Error::last());
+        }
+        Ok(())
+    }
+
+    /// Issues an arbitrary ioctl on the VM fd.
+    ///
+    /// This is an escape hatch for the VM ioctls which this crate does not wrap yet. Prefer
+    /// the typed methods whenever they exist.
+    ///
+    /// # Arguments
+    ///
+    /// * `req` - The ioctl request number.
+    /// * `arg` - The argument of the ioctl, either a pointer or a value cast to a pointer.
+    ///
+    /// Returns the non-negative result of the ioctl, or the `errno` it failed with.
+    ///
+    /// # Safety
+    ///
+    /// The caller must make sure that `req` is a VM ioctl and that `arg` is what the kernel
+    /// expects for it, i.e. that any memory it points to is valid and large enough. The ioctl
+    /// must not break the invariants of this crate, e.g. by closing the fd.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::{Cap, Kvm};
+    /// # use std::os::raw::c_void;
+    /// // `KVM_CHECK_EXTENSION`, which is `_IO(KVMIO, 0x03)`.
+    /// const KVM_CHECK_EXTENSION: u64 = 0xae03;
+    ///
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let max_vcpus =
+    ///     unsafe { vm.raw_ioctl(KVM_CHECK_EXTENSION, Cap::MaxVcpus as usize as *mut c_void) };
+    /// assert_eq!(max_vcpus.unwrap(), vm.check_extension_int(Cap::MaxVcpus));
+    /// ```
+    pub unsafe fn raw_ioctl(&self, req: u64, arg: *mut c_void) -> Result<i32> {
+        crate::ioctls::raw_ioctl(self, req, arg)
+    }
+
+    /// Consumes the `VmFd` and returns its file descriptor.
+    ///
+    /// The VM lives as long as the file descriptor, which can be passed to another process and
+    /// wrapped again with [create_vmfd_from_rawfd](struct.Kvm.html#method.create_vmfd_from_rawfd),
+    /// e.g. to hand the VM over to a new VMM.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// # extern crate kvm_ioctls;
+    /// # use kvm_ioctls::Kvm;
+    /// # use std::os::unix::io::IntoRawFd;
+    /// let kvm = Kvm::new().unwrap();
+    /// let vm = kvm.create_vm().unwrap();
+    /// let fd = vm.into_owned_fd();
+    /// let vm = unsafe { kvm.create_vmfd_from_rawfd(fd.into_raw_fd()).unwrap() };
+    /// ```
+    pub fn into_owned_fd(self) -> OwnedFd {
+        OwnedFd::from(self.vm)
+    }
+}
+
+/// Returns the number of pages of a memory slot tracked by its dirty log.
+///
+/// Fails with `EINVAL` if the slot has more than `isize::MAX` pages.
+fn dirty_log_pages(memory_size: usize, page_size: usize) -> Result<usize> {
+    // Unlike `(memory_size + page_size - 1) / page_size`, this cannot overflow.
+    let pages = memory_size.div_ceil(page_size);
+    if pages > isize::MAX as usize {
+        return Err(errno::Error::new(libc::EINVAL));
+    }
+    Ok(pages)
+}
+
+/// Checks that an encrypted memory region starts and ends on a page boundary.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn check_enc_region(memory_region: &kvm_enc_region) -> Result<()> {
+    // SAFETY: We trust the sysconf libc function and we're calling it with a correct parameter.
+    let page_size = match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        -1 => return Err(errno::Error::last()),
+        ps => ps as u64,
+    };
+    if (memory_region.addr | memory_region.size) & (page_size - 1) != 0 {
+        return Err(errno::Error::new(libc::EINVAL));
+    }
+    Ok(())
+}
+
+// Returns the path of the file mapped at `addr` in `/proc/self/maps`, which is empty for
+// anonymous mappings, or `None` if `addr` is not mapped or the mappings cannot be read.
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+fn mapping_path(addr: u64) -> Option<String> {
+    let maps = std::fs::read_to_string("/proc/self/maps").ok()?;
+    maps.lines().find_map(|line| {
+        let mut fields = line.splitn(6, ' ');
+        let (start, end) = fields.next()?.split_once('-')?;
+        let range = u64::from_str_radix(start, 16).ok()?..u64::from_str_radix(end, 16).ok()?;
+        if !range.contains(&addr) {
+            return None;
+        }
+        Some(fields.nth(4).unwrap_or("").trim().to_string())
+    })
+}
+
+/// Helper function to create a new `VmFd`.
+///
+/// This should not be exported as a public function because the preferred way is to use
 /// `create_vm` from `Kvm`. The function cannot be part of the `VmFd` implementation because
 /// then it would be exported with the public `VmFd` interface.
-pub fn new_vmfd(vm: File, run_size: usize) -> VmFd {
-    VmFd { vm, run_size }
+pub fn new_vmfd(vm: File, run_size: usize, vm_type: Option<u64>) -> VmFd {
+    VmFd {
+        vm,
+        run_size,
+        irqchip: AtomicBool::new(false),
+        split_irqchip: AtomicBool::new(false),
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        sev_es: AtomicBool::new(false),
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        x2apic_32bit_ids: AtomicBool::new(false),
+        vm_type,
+    }
+}
+
+impl AsRawFd for VmFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.vm.as_raw_fd()
+    }
+}
+
+/// Create a dummy GIC device.
+///
+/// # Arguments
+///
+/// * `vm` - The vm file descriptor.
+/// * `flags` - Flags to be passed to `KVM_CREATE_DEVICE`.
+#[cfg(test)]
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+pub(crate) fn create_gic_device(vm: &VmFd, flags: u32) -> DeviceFd {
+    let mut gic_device = kvm_bindings::kvm_create_device {
+        type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
+        fd: 0,
+        flags,
+    };
+    match vm.create_device(&mut gic_device) {
+        Ok(fd) => fd,
+        Err(_) => {
+            gic_device.type_ = kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2;
+            vm.create_device(&mut gic_device)
+                .expect("Cannot create KVM vGIC device")
+        }
+    }
+}
+
+/// Set supported number of IRQs for vGIC.
+///
+/// # Arguments
+///
+/// * `vgic` - The vGIC file descriptor.
+/// * `nr_irqs` - Number of IRQs.
+#[cfg(test)]
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn set_supported_nr_irqs(vgic: &DeviceFd, nr_irqs: u32) {
+    crate::ioctls::arm::set_nr_irqs(vgic, nr_irqs).unwrap();
+}
+
+/// Request the initialization of the vGIC.
+///
+/// # Arguments
+///
+/// * `vgic` - The vGIC file descriptor.
+#[cfg(test)]
+#[cfg(target_arch = "aarch64")]
+pub(crate) fn request_gic_init(vgic: &DeviceFd) {
+    crate::ioctls::arm::ctrl(vgic, kvm_bindings::KVM_DEV_ARM_VGIC_CTRL_INIT).unwrap();
 }
 
-impl AsRawFd for VmFd {
-    fn as_raw_fd(&self) -> RawFd {
-        self.vm.as_raw_fd()
+#[cfg(test)]
+mod tests {
+    #![allow(clippy::undocumented_unsafe_blocks)]
+    use super::*;
+    use crate::ioctls::mmap_anonymous;
+    use crate::Kvm;
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    use crate::CpuIdExt;
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    use std::{fs::OpenOptions, ptr::null_mut};
+
+    use libc::EFD_NONBLOCK;
+
+    #[test]
+    fn test_set_invalid_memory() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let invalid_mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: 0,
+            userspace_addr: 0,
+            flags: 0,
+        };
+        assert!(unsafe { vm.set_user_memory_region(invalid_mem_region) }.is_err());
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_set_tss_address() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        assert!(vm.set_tss_address(0xfffb_d000).is_ok());
+        assert!(vm.set_default_tss_address().is_ok());
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_tss_address_out_of_range() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        for offset in [0xffff_c000, 0xffff_f000, 0x1_0000_0000] {
+            assert_eq!(
+                vm.set_tss_address(offset).unwrap_err().errno(),
+                libc::EINVAL
+            );
+        }
+        vm.set_tss_address(0xffff_b000).unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_set_identity_map_address() {
+        let kvm = Kvm::new().unwrap();
+        if kvm.check_extension(Cap::SetIdentityMapAddr) {
+            let vm = kvm.create_vm().unwrap();
+            assert!(vm.set_identity_map_address(0xfffb_c000).is_ok());
+            vm.create_vcpu(0).unwrap();
+            // Setting the identity map after creating a vCPU must fail.
+            assert!(vm.set_identity_map_address(0xfffb_c000).is_err());
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_set_identity_map_address_validation() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        for address in [0xfffb_c800, 0xfffb_c001, 0x1_0000_0000, 0x1_fffb_c000] {
+            assert_eq!(
+                vm.set_identity_map_address(address).unwrap_err().errno(),
+                libc::EINVAL
+            );
+        }
+        if kvm.check_extension(Cap::SetIdentityMapAddr) {
+            vm.set_default_identity_map_address().unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_irq_chip() {
+        use Cap;
+
+        let kvm = Kvm::new().unwrap();
+        assert!(kvm.check_extension(Cap::Irqchip));
+        let vm = kvm.create_vm().unwrap();
+        assert!(!vm.has_irqchip());
+        assert!(vm.create_irq_chip().is_ok());
+        assert!(vm.has_irqchip());
+        assert_eq!(vm.create_irq_chip(), Err(IrqChipError::AlreadyExists));
+        assert_eq!(vm.create_irq_chip().unwrap_err().errno(), libc::EEXIST);
+
+        let mut irqchip = kvm_irqchip {
+            chip_id: KVM_IRQCHIP_PIC_MASTER,
+            ..Default::default()
+        };
+        // Set the irq_base to a non-default value to check that set & get work.
+        irqchip.chip.pic.irq_base = 10;
+        assert!(vm.set_irqchip(&irqchip).is_ok());
+
+        let other_irqchip = vm.get_irqchip(IrqChipId::PicMaster).unwrap();
+        assert_eq!(other_irqchip.chip_id, KVM_IRQCHIP_PIC_MASTER);
+
+        // Safe because we know that the irqchip type is PIC.
+        unsafe { assert_eq!(irqchip.chip.pic, other_irqchip.chip.pic) };
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_irq_chip_save_restore() {
+        fn as_bytes(irqchip: &kvm_irqchip) -> &[u8] {
+            // SAFETY: `kvm_irqchip` is a plain C structure without padding.
+            unsafe {
+                std::slice::from_raw_parts(
+                    irqchip as *const kvm_irqchip as *const u8,
+                    std::mem::size_of::<kvm_irqchip>(),
+                )
+            }
+        }
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+
+        let ids = [IrqChipId::PicMaster, IrqChipId::PicSlave, IrqChipId::Ioapic];
+        let saved: Vec<kvm_irqchip> = ids.iter().map(|id| vm.get_irqchip(*id).unwrap()).collect();
+        for (id, irqchip) in ids.iter().zip(saved.iter()) {
+            assert_eq!(irqchip.chip_id, *id as u32);
+            vm.set_irqchip(irqchip).unwrap();
+        }
+        for (id, irqchip) in ids.iter().zip(saved.iter()) {
+            assert_eq!(as_bytes(&vm.get_irqchip(*id).unwrap()), as_bytes(irqchip));
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_irq_chip_views() {
+        const MASKED: u64 = 1 << 16;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+
+        let pic = vm.get_irqchip(IrqChipId::PicMaster).unwrap();
+        let ioapic = vm.get_irqchip(IrqChipId::Ioapic).unwrap();
+        // The views must match the chip.
+        assert_eq!(IoapicState::new(pic).unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(PicState::new(ioapic).unwrap_err().errno(), libc::EINVAL);
+
+        let mut pic = PicState::new(pic).unwrap();
+        assert_eq!(pic.irr(), 0);
+        assert_eq!(pic.isr(), 0);
+        pic.set_imr(0xfe);
+        vm.set_irqchip(&pic).unwrap();
+        let pic = PicState::new(vm.get_irqchip(IrqChipId::PicMaster).unwrap()).unwrap();
+        assert_eq!(pic.imr(), 0xfe);
+
+        let mut ioapic = IoapicState::new(ioapic).unwrap();
+        assert_eq!(ioapic.id(), 0);
+        // All the pins are masked after reset.
+        for pin in 0..KVM_IOAPIC_NUM_PINS as usize {
+            assert_ne!(ioapic.redirection_entry(pin).unwrap() & MASKED, 0);
+        }
+        assert!(ioapic
+            .redirection_entry(KVM_IOAPIC_NUM_PINS as usize)
+            .is_none());
+        assert_eq!(
+            ioapic
+                .set_redirection_entry(KVM_IOAPIC_NUM_PINS as usize, 0)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+
+        ioapic.set_redirection_entry(4, 0x34).unwrap();
+        vm.set_irqchip(&ioapic).unwrap();
+        let ioapic = IoapicState::new(vm.get_irqchip(IrqChipId::Ioapic).unwrap()).unwrap();
+        assert_eq!(ioapic.redirection_entry(4), Some(0x34));
+        assert_ne!(ioapic.redirection_entry(5).unwrap() & MASKED, 0);
+        assert_eq!(kvm_irqchip::from(ioapic).chip_id, KVM_IRQCHIP_IOAPIC);
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn test_irq_chip() {
+        use Cap;
+
+        let kvm = Kvm::new().unwrap();
+        assert!(kvm.check_extension(Cap::Irqchip));
+
+        let vm = kvm.create_vm().unwrap();
+
+        // On ARM/arm64, a GICv2 is created. It's better to check ahead whether GICv2
+        // can be emulated or not.
+        let mut gic_device = kvm_bindings::kvm_create_device {
+            type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
+            fd: 0,
+            flags: KVM_CREATE_DEVICE_TEST,
+        };
+
+        let vgic_v2_supported = vm.create_device(&mut gic_device).is_ok();
+        assert_eq!(vm.create_irq_chip().is_ok(), vgic_v2_supported);
+        assert_eq!(vm.has_irqchip(), vgic_v2_supported);
+        if vgic_v2_supported {
+            assert_eq!(vm.create_irq_chip(), Err(IrqChipError::AlreadyExists));
+        } else {
+            // A GICv3 must be created through `create_device` instead.
+            assert!(matches!(
+                vm.create_irq_chip(),
+                Err(IrqChipError::Unsupported(_))
+            ));
+        }
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_pit2() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        // There is no PIT state to get or set before the PIT is created.
+        assert_eq!(vm.get_pit2().unwrap_err().errno(), libc::ENXIO);
+        assert_eq!(
+            vm.set_pit2(&kvm_pit_state2::default()).unwrap_err().errno(),
+            libc::ENXIO
+        );
+        assert!(vm.create_pit2(kvm_pit_config::default()).is_ok());
+
+        let pit2 = vm.get_pit2().unwrap();
+        vm.set_pit2(&pit2).unwrap();
+        let mut other_pit2 = vm.get_pit2().unwrap();
+        // Load time will differ, let's overwrite it so we can test equality.
+        other_pit2.channels[0].count_load_time = pit2.channels[0].count_load_time;
+        other_pit2.channels[1].count_load_time = pit2.channels[1].count_load_time;
+        other_pit2.channels[2].count_load_time = pit2.channels[2].count_load_time;
+        assert_eq!(pit2, other_pit2);
+
+        // The channel counts are restored.
+        let mut pit2 = other_pit2;
+        pit2.channels[0].count = 0x1234;
+        vm.set_pit2(&pit2).unwrap();
+        assert_eq!(vm.get_pit2().unwrap().channels[0].count, 0x1234);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_pit2_channels() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        vm.create_pit(PitConfig::new().speaker_dummy(true)).unwrap();
+
+        let mut state = vm.get_pit2().unwrap();
+        // Only the speaker channel has its gate low after reset.
+        assert!(state.gate(PitChannel::Timer));
+        assert!(state.gate(PitChannel::RamRefresh));
+        assert!(!state.gate(PitChannel::Speaker));
+        assert_eq!(state.mode(PitChannel::Timer), state.channels[0].mode);
+        assert_eq!(
+            state.latched_count(PitChannel::Timer),
+            state.channels[0].latched_count
+        );
+
+        // Rate generator at ~1kHz on the timer channel, gate high on the speaker channel.
+        state.set_mode(PitChannel::Timer, 2);
+        state.set_count(PitChannel::Timer, 1193);
+        state.set_gate(PitChannel::Speaker, true);
+        assert_eq!(state.channels[0].mode, 2);
+        assert_eq!(state.channels[2].gate, 1);
+        vm.set_pit2(&state).unwrap();
+
+        let state = vm.get_pit2().unwrap();
+        assert_eq!(state.mode(PitChannel::Timer), 2);
+        assert_eq!(state.count(PitChannel::Timer), 1193);
+        assert!(state.gate(PitChannel::Speaker));
+        let raw_state = kvm_pit_state2::from(state);
+        assert_eq!(Pit2State::from(raw_state), state);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_clock() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        // Get current time.
+        let orig = vm.get_clock().unwrap();
+
+        // Reset time.
+        let fudged = kvm_clock_data {
+            clock: 10,
+            ..Default::default()
+        };
+        vm.set_clock(&fudged).unwrap();
+
+        // Get new time.
+        let new = vm.get_clock().unwrap();
+
+        // Verify new time has progressed but is smaller than orig time.
+        assert!(fudged.clock < new.clock);
+        assert!(new.clock < orig.clock);
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_clock_realtime() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        let saved = vm.get_clock().unwrap();
+        if vm.supported_clock_flags() & KVM_CLOCK_REALTIME == 0 {
+            let clock = kvm_clock_data {
+                flags: KVM_CLOCK_REALTIME,
+                ..saved
+            };
+            assert_eq!(vm.set_clock(&clock).unwrap_err().errno(), libc::EINVAL);
+            return;
+        }
+
+        // KVM only samples the host realtime when the host clocksource is TSC based.
+        if saved.flags & KVM_CLOCK_REALTIME != 0 {
+            let restored = kvm_clock_data {
+                flags: KVM_CLOCK_REALTIME,
+                ..saved
+            };
+            vm.set_clock(&restored).unwrap();
+            // The time elapsed since `saved.realtime` was sampled is accounted for.
+            let new = vm.get_clock().unwrap();
+            assert!(new.clock >= saved.clock);
+        }
+    }
+
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    #[test]
+    fn test_clock_full() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let supported = vm.supported_clock_flags();
+
+        let state = vm.get_clock_full().unwrap();
+        assert_eq!(
+            state.tsc_stable,
+            state.data.flags & KVM_CLOCK_TSC_STABLE != 0
+        );
+        assert_eq!(state.realtime, state.data.flags & KVM_CLOCK_REALTIME != 0);
+        assert_eq!(state.host_tsc, state.data.flags & KVM_CLOCK_HOST_TSC != 0);
+        // The realtime and host TSC are sampled along with the clock when supported, and
+        // the host clocksource is TSC based.
+        if state.realtime {
+            assert_ne!(supported & KVM_CLOCK_REALTIME, 0);
+            assert_ne!(state.data.realtime, 0);
+        }
+        if state.host_tsc {
+            assert_ne!(supported & KVM_CLOCK_HOST_TSC, 0);
+            assert_ne!(state.data.host_tsc, 0);
+        }
+
+        vm.set_clock_full(&state).unwrap();
+        let new = vm.get_clock_full().unwrap();
+        assert!(new.data.clock >= state.data.clock);
+
+        // Flags which cannot be set are dropped rather than rejected.
+        let state = ClockState {
+            tsc_stable: true,
+            realtime: true,
+            host_tsc: true,
+            ..state
+        };
+        vm.set_clock_full(&state).unwrap();
+    }
+
+    #[test]
+    fn test_nr_memslots() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let nr_memslots = vm.nr_memslots();
+        assert!(nr_memslots >= 32);
+        // KVM reports the same limit on the VM fd and on the system fd.
+        assert_eq!(nr_memslots, kvm.get_nr_memslots());
+
+        // The last slot can be used, the next one cannot.
+        let mem_size = 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let mem_region = kvm_userspace_memory_region {
+            slot: nr_memslots as u32 - 1,
+            guest_phys_addr: 0,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe { vm.set_user_memory_region(mem_region) }.unwrap();
+        let mem_region = kvm_userspace_memory_region {
+            slot: nr_memslots as u32,
+            guest_phys_addr: mem_size as u64,
+            ..mem_region
+        };
+        assert_eq!(
+            unsafe { vm.set_user_memory_region(mem_region) }
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    fn test_register_ioevent() {
+        assert_eq!(std::mem::size_of::<NoDatamatch>(), 0);
+
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        assert!(vm_fd
+            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xf4), NoDatamatch)
+            .is_ok());
+        assert!(vm_fd
+            .register_ioevent(&evtfd, &IoEventAddress::Mmio(0x1000), NoDatamatch)
+            .is_ok());
+        assert!(vm_fd
+            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xc1), 0x7fu8)
+            .is_ok());
+        assert!(vm_fd
+            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xc2), 0x1337u16)
+            .is_ok());
+        assert!(vm_fd
+            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xc4), 0xdead_beefu32)
+            .is_ok());
+        assert!(vm_fd
+            .register_ioevent(
+                &evtfd,
+                &IoEventAddress::Mmio(0x1008),
+                0xdead_beef_dead_beefu64
+            )
+            .is_ok());
+    }
+
+    #[test]
+    fn test_register_ioevent_invalid_pio() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        // The port is out of the 16-bit port space.
+        assert_eq!(
+            vm_fd
+                .register_ioevent(&evtfd, &IoEventAddress::Pio(0x1_0000), NoDatamatch)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            vm_fd
+                .register_ioevent(&evtfd, &IoEventAddress::Pio(u64::MAX), 0x7fu8)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        // PIO accesses are at most 4 bytes wide.
+        assert_eq!(
+            vm_fd
+                .register_ioevent(&evtfd, &IoEventAddress::Pio(0xc8), 0xdead_beef_dead_beefu64)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        assert_eq!(
+            vm_fd
+                .register_ioevent_guarded(&evtfd, &IoEventAddress::Pio(0xc8), 0x1337u64)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+        vm_fd
+            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xffff), 0x1337u16)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_unregister_ioevent() {
+        assert_eq!(std::mem::size_of::<NoDatamatch>(), 0);
+
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let pio_addr = IoEventAddress::Pio(0xf4);
+        let mmio_addr = IoEventAddress::Mmio(0x1000);
+
+        // First try to unregister addresses which have not been registered.
+        assert!(vm_fd
+            .unregister_ioevent(&evtfd, &pio_addr, NoDatamatch)
+            .is_err());
+        assert!(vm_fd
+            .unregister_ioevent(&evtfd, &mmio_addr, NoDatamatch)
+            .is_err());
+
+        // Now register the addresses
+        assert!(vm_fd
+            .register_ioevent(&evtfd, &pio_addr, NoDatamatch)
+            .is_ok());
+        assert!(vm_fd
+            .register_ioevent(&evtfd, &mmio_addr, 0x1337u16)
+            .is_ok());
+
+        // Try again unregistering the addresses. This time it should work
+        // since they have been previously registered.
+        assert!(vm_fd
+            .unregister_ioevent(&evtfd, &pio_addr, NoDatamatch)
+            .is_ok());
+        assert!(vm_fd
+            .unregister_ioevent(&evtfd, &mmio_addr, 0x1337u16)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_ioevent_reregister() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let mmio_addr = IoEventAddress::Mmio(0x1000);
+
+        vm_fd
+            .register_ioevent(&evtfd, &mmio_addr, 0x1337u16)
+            .unwrap();
+        assert_eq!(
+            vm_fd
+                .register_ioevent(&evtfd, &mmio_addr, 0x1337u16)
+                .unwrap_err()
+                .errno(),
+            libc::EEXIST
+        );
+        // The deassignment must match the assignment.
+        assert_eq!(
+            vm_fd
+                .unregister_ioevent(&evtfd, &mmio_addr, 0x1337u32)
+                .unwrap_err()
+                .errno(),
+            libc::ENOENT
+        );
+        vm_fd
+            .unregister_ioevent(&evtfd, &mmio_addr, 0x1337u16)
+            .unwrap();
+        vm_fd
+            .register_ioevent(&evtfd, &mmio_addr, 0x1337u16)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_register_ioevent2() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        let datamatches = [
+            Datamatch::from(NoDatamatch),
+            Datamatch::U8(0x7f),
+            Datamatch::U16(0x1337),
+            Datamatch::U32(0xdead_beef),
+            Datamatch::U64(0xdead_beef_dead_beef),
+        ];
+        for (i, datamatch) in datamatches.iter().enumerate() {
+            let offset = 8 * i as u64;
+            for addr in [
+                IoEventAddress::Pio(0xc0 + offset),
+                IoEventAddress::Mmio(0x1000 + offset),
+            ] {
+                if let (IoEventAddress::Pio(_), Datamatch::U64(_)) = (addr, datamatch) {
+                    assert_eq!(
+                        vm_fd
+                            .register_ioevent2(&evtfd, &addr, *datamatch)
+                            .unwrap_err()
+                            .errno(),
+                        libc::EINVAL
+                    );
+                    continue;
+                }
+                vm_fd.register_ioevent2(&evtfd, &addr, *datamatch).unwrap();
+                vm_fd
+                    .unregister_ioevent2(&evtfd, &addr, *datamatch)
+                    .unwrap();
+            }
+        }
+
+        // The width is part of the registration.
+        let addr = IoEventAddress::Mmio(0x2000);
+        vm_fd
+            .register_ioevent2(&evtfd, &addr, Datamatch::U16(0x1337))
+            .unwrap();
+        assert_eq!(
+            vm_fd
+                .unregister_ioevent2(&evtfd, &addr, Datamatch::U32(0x1337))
+                .unwrap_err()
+                .errno(),
+            libc::ENOENT
+        );
+        vm_fd.unregister_ioevent(&evtfd, &addr, 0x1337u16).unwrap();
+
+        // PIO addresses are 16 bits wide.
+        assert_eq!(
+            vm_fd
+                .register_ioevent2(&evtfd, &IoEventAddress::Pio(0x1_0000), Datamatch::None)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_register_ioevent_any() {
+        use crate::VcpuExit;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let doorbell = IoEventAddress::Pio(0xf0);
+        vm.register_ioevent_any(&evtfd, &doorbell, 2).unwrap();
+        assert_eq!(
+            vm.register_ioevent_any(&evtfd, &IoEventAddress::Pio(0xf8), 8)
+                .unwrap_err()
+                .errno(),
+            libc::EINVAL
+        );
+
+        #[rustfmt::skip]
+        let code = [
+            0xb8, 0x34, 0x12, /* mov ax, 0x1234 */
+            0xe7, 0xf0, /* out 0xf0, ax */
+            0xb8, 0x78, 0x56, /* mov ax, 0x5678 */
+            0xe7, 0xf0, /* out 0xf0, ax */
+            0xb0, 0x01, /* mov al, 0x1 */
+            0xe6, 0xf0, /* out 0xf0, al; a 1-byte write is not matched */
+            0xf4, /* hlt */
+        ];
+        let mem_size = 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            std::ptr::copy_nonoverlapping(code.as_ptr(), load_addr, code.len());
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = 0;
+        regs.rflags = 2;
+        vcpu.set_regs(&regs).unwrap();
+
+        // Both 2-byte writes signal the event without exiting.
+        match vcpu.run().expect("run failed") {
+            VcpuExit::IoOut(0xf0, data) => assert_eq!(data, &[0x01]),
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
+        assert_eq!(evtfd.read().unwrap(), 2);
+
+        vm.unregister_ioevent_any(&evtfd, &doorbell, 2).unwrap();
+        assert_eq!(
+            vm.unregister_ioevent_any(&evtfd, &doorbell, 2)
+                .unwrap_err()
+                .errno(),
+            libc::ENOENT
+        );
+    }
+
+    #[test]
+    fn test_ioevent_guard() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let pio_addr = IoEventAddress::Pio(0xf4);
+        let mmio_addr = IoEventAddress::Mmio(0x1000);
+
+        let guard = vm_fd
+            .register_ioevent_guarded(&evtfd, &pio_addr, NoDatamatch)
+            .unwrap();
+        assert_eq!(
+            vm_fd
+                .register_ioevent(&evtfd, &pio_addr, NoDatamatch)
+                .unwrap_err()
+                .errno(),
+            libc::EEXIST
+        );
+        drop(guard);
+        vm_fd
+            .register_ioevent(&evtfd, &pio_addr, NoDatamatch)
+            .unwrap();
+
+        let guard = vm_fd
+            .register_ioevent_guarded(&evtfd, &mmio_addr, 0xdead_beefu32)
+            .unwrap();
+        guard.unregister().unwrap();
+        vm_fd
+            .register_ioevent(&evtfd, &mmio_addr, 0xdead_beefu32)
+            .unwrap();
+
+        // A forgotten guard leaves the event registered.
+        let mmio_addr = IoEventAddress::Mmio(0x2000);
+        vm_fd
+            .register_ioevent_guarded(&evtfd, &mmio_addr, 0x1337u16)
+            .unwrap()
+            .forget();
+        assert_eq!(
+            vm_fd
+                .register_ioevent(&evtfd, &mmio_addr, 0x1337u16)
+                .unwrap_err()
+                .errno(),
+            libc::EEXIST
+        );
+        vm_fd
+            .unregister_ioevent(&evtfd, &mmio_addr, 0x1337u16)
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_register_unregister_irqfd() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        let evtfd1 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let evtfd2 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let evtfd3 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let evtfd4 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        assert!(vm_fd.create_irq_chip().is_ok());
+
+        assert!(vm_fd.register_irqfd(&evtfd1, 4).is_ok());
+        assert!(vm_fd.register_irqfd(&evtfd2, 8).is_ok());
+        assert!(vm_fd.register_irqfd(&evtfd3, 4).is_ok());
+        assert!(vm_fd.unregister_irqfd(&evtfd2, 8).is_ok());
+        // KVM irqfd doesn't report failure on this case:(
+        assert!(vm_fd.unregister_irqfd(&evtfd2, 8).is_ok());
+
+        // Duplicated eventfd registration.
+        // On x86_64 this fails as the event fd was already matched with a GSI.
+        assert!(vm_fd.register_irqfd(&evtfd3, 4).is_err());
+        assert!(vm_fd.register_irqfd(&evtfd3, 5).is_err());
+        // KVM irqfd doesn't report failure on this case:(
+        assert!(vm_fd.unregister_irqfd(&evtfd3, 5).is_ok());
+
+        if vm_fd.check_extension(Cap::IrqfdResample) {
+            assert!(vm_fd
+                .register_irqfd_with_resample(&evtfd4, &resamplefd, 6)
+                .is_ok());
+            assert!(vm_fd.unregister_irqfd(&evtfd4, 6).is_ok());
+        } else {
+            assert!(vm_fd
+                .register_irqfd_with_resample(&evtfd4, &resamplefd, 6)
+                .is_err());
+        }
     }
-}
 
-/// Create a dummy GIC device.
-///
-/// # Arguments
-///
-/// * `vm` - The vm file descriptor.
-/// * `flags` - Flags to be passed to `KVM_CREATE_DEVICE`.
-#[cfg(test)]
-#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-pub(crate) fn create_gic_device(vm: &VmFd, flags: u32) -> DeviceFd {
-    let mut gic_device = kvm_bindings::kvm_create_device {
-        type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V3,
-        fd: 0,
-        flags,
-    };
-    match vm.create_device(&mut gic_device) {
-        Ok(fd) => fd,
-        Err(_) => {
-            gic_device.type_ = kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2;
-            vm.create_device(&mut gic_device)
-                .expect("Cannot create KVM vGIC device")
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_register_unregister_irqfd() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        let evtfd1 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let evtfd2 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let evtfd3 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let evtfd4 = EventFd::new(EFD_NONBLOCK).unwrap();
+        let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        // Create the vGIC device.
+        let vgic_fd = create_gic_device(&vm_fd, 0);
+
+        // GICv3 on arm/aarch64 requires an online vCPU prior to setting device attributes,
+        // see: https://www.kernel.org/doc/html/latest/virt/kvm/devices/arm-vgic-v3.html
+        vm_fd.create_vcpu(0).unwrap();
+
+        // Set supported number of IRQs.
+        set_supported_nr_irqs(&vgic_fd, 128);
+        // Request the initialization of the vGIC.
+        request_gic_init(&vgic_fd);
+
+        assert!(vm_fd.register_irqfd(&evtfd1, 4).is_ok());
+        assert!(vm_fd.register_irqfd(&evtfd2, 8).is_ok());
+        assert!(vm_fd.register_irqfd(&evtfd3, 4).is_ok());
+        assert!(vm_fd.unregister_irqfd(&evtfd2, 8).is_ok());
+        // KVM irqfd doesn't report failure on this case:(
+        assert!(vm_fd.unregister_irqfd(&evtfd2, 8).is_ok());
+
+        // Duplicated eventfd registration.
+        // On aarch64, this fails because setting up the interrupt controller is mandatory before
+        // registering any IRQ.
+        assert!(vm_fd.register_irqfd(&evtfd3, 4).is_err());
+        assert!(vm_fd.register_irqfd(&evtfd3, 5).is_err());
+        // KVM irqfd doesn't report failure on this case:(
+        assert!(vm_fd.unregister_irqfd(&evtfd3, 5).is_ok());
+
+        if vm_fd.check_extension(Cap::IrqfdResample) {
+            assert!(vm_fd
+                .register_irqfd_with_resample(&evtfd4, &resamplefd, 6)
+                .is_ok());
+            assert!(vm_fd.unregister_irqfd(&evtfd4, 6).is_ok());
+        } else {
+            assert!(vm_fd
+                .register_irqfd_with_resample(&evtfd4, &resamplefd, 6)
+                .is_err());
         }
     }
-}
 
-/// Set supported number of IRQs for vGIC.
-///
-/// # Arguments
-///
-/// * `vgic` - The vGIC file descriptor.
-/// * `nr_irqs` - Number of IRQs.
-#[cfg(test)]
-#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-pub(crate) fn set_supported_nr_irqs(vgic: &DeviceFd, nr_irqs: u32) {
-    let vgic_attr = kvm_bindings::kvm_device_attr {
-        group: kvm_bindings::KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
-        attr: 0,
-        addr: &nr_irqs as *const u32 as u64,
-        flags: 0,
-    };
-    assert!(vgic.set_device_attr(&vgic_attr).is_ok());
-}
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_set_irq_line() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
 
-/// Request the initialization of the vGIC.
-///
-/// # Arguments
-///
-/// * `vgic` - The vGIC file descriptor.
-#[cfg(test)]
-#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-pub(crate) fn request_gic_init(vgic: &DeviceFd) {
-    let vgic_attr = kvm_bindings::kvm_device_attr {
-        group: kvm_bindings::KVM_DEV_ARM_VGIC_GRP_CTRL,
-        attr: u64::from(kvm_bindings::KVM_DEV_ARM_VGIC_CTRL_INIT),
-        addr: 0,
-        flags: 0,
-    };
-    assert!(vgic.set_device_attr(&vgic_attr).is_ok());
-}
+        assert!(vm_fd.create_irq_chip().is_ok());
 
-#[cfg(test)]
-mod tests {
-    #![allow(clippy::undocumented_unsafe_blocks)]
-    use super::*;
-    use crate::Kvm;
+        assert!(vm_fd.set_irq_line(4, true).is_ok());
+        assert!(vm_fd.set_irq_line(4, false).is_ok());
+        assert!(vm_fd.set_irq_line(4, true).is_ok());
+
+        // Pulse the PIT interrupt.
+        vm_fd.pulse_irq_line(0).unwrap();
+        vm_fd.set_level_irq(4, false).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    #[allow(clippy::unusual_byte_groupings)]
+    fn test_set_irq_line() {
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        // Create a vcpu for test case 2 of the KVM_IRQ_LINE API on aarch64.
+        vm_fd.create_vcpu(0).unwrap();
+
+        // Create the vGIC device.
+        let vgic_fd = create_gic_device(&vm_fd, 0);
+        // Set supported number of IRQs.
+        set_supported_nr_irqs(&vgic_fd, 128);
+        // Request the initialization of the vGIC.
+        request_gic_init(&vgic_fd);
+
+        // On arm/aarch64, irq field is interpreted like this:
+        // bits:  | 31 ... 24 | 23  ... 16 | 15    ...    0 |
+        // field: | irq_type  | vcpu_index |     irq_id     |
+        // The irq_type field has the following values:
+        // - irq_type[0]: out-of-kernel GIC: irq_id 0 is IRQ, irq_id 1 is FIQ
+        // - irq_type[1]: in-kernel GIC: SPI, irq_id between 32 and 1019 (incl.) (the vcpu_index field is ignored)
+        // - irq_type[2]: in-kernel GIC: PPI, irq_id between 16 and 31 (incl.)
+        // Hence, using irq_type = 1, irq_id = 32 (decimal), the irq field in hex is: 0x01_00_0020
+        assert!(vm_fd.set_irq_line(0x01_00_0020, true).is_ok());
+        assert!(vm_fd.set_irq_line(0x01_00_0020, false).is_ok());
+        assert!(vm_fd.set_irq_line(0x01_00_0020, true).is_ok());
+
+        // Case 2: using irq_type = 2, vcpu_index = 0, irq_id = 16 (decimal), the irq field in hex is: 0x02_00_0010
+        assert!(vm_fd.set_irq_line(0x02_00_0010, true).is_ok());
+        assert!(vm_fd.set_irq_line(0x02_00_0010, false).is_ok());
+        assert!(vm_fd.set_irq_line(0x02_00_0010, true).is_ok());
+
+        vm_fd.pulse_irq_line(ArmIrqLine::spi(33).unwrap()).unwrap();
+        vm_fd
+            .set_level_irq(ArmIrqLine::ppi(0, 16).unwrap(), false)
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn test_arm_irq_line() {
+        assert_eq!(ArmIrqLine::spi(32).unwrap().raw(), 0x01_00_0020);
+        assert_eq!(ArmIrqLine::ppi(0, 16).unwrap().raw(), 0x02_00_0010);
+        assert_eq!(
+            ArmIrqLine::cpu_irq(3, KVM_ARM_IRQ_CPU_FIQ).unwrap().raw(),
+            0x00_03_0001
+        );
+        // vCPU indexes above 255 use the vcpu2_index field.
+        assert_eq!(ArmIrqLine::ppi(300, 27).unwrap().raw(), 0x12_2c_001b);
+        assert_eq!(ArmIrqLine::ppi(4095, 27).unwrap().raw(), 0xf2_ff_001b);
+        assert!(ArmIrqLine::ppi(4096, 27).is_err());
+
+        // Interrupt numbers out of range for their type.
+        assert!(ArmIrqLine::spi(31).is_err());
+        assert!(ArmIrqLine::spi(1020).is_err());
+        assert!(ArmIrqLine::ppi(0, 15).is_err());
+        assert!(ArmIrqLine::ppi(0, 32).is_err());
+        assert!(ArmIrqLine::cpu_irq(0, 2).is_err());
+
+        let kvm = Kvm::new().unwrap();
+        let vm_fd = kvm.create_vm().unwrap();
+        vm_fd.create_vcpu(0).unwrap();
+        let vgic_fd = create_gic_device(&vm_fd, 0);
+        set_supported_nr_irqs(&vgic_fd, 128);
+        request_gic_init(&vgic_fd);
+
+        let spi = ArmIrqLine::spi(32).unwrap();
+        vm_fd.set_arm_irq_line(spi, true).unwrap();
+        vm_fd.set_arm_irq_line(spi, false).unwrap();
+        let ppi = ArmIrqLine::ppi(0, 16).unwrap();
+        vm_fd.set_arm_irq_line(ppi, true).unwrap();
+        vm_fd.set_arm_irq_line(ppi, false).unwrap();
+        // SPIs beyond the number of IRQs of the vGIC are rejected by KVM.
+        let spi = ArmIrqLine::spi(200).unwrap();
+        assert!(vm_fd.set_arm_irq_line(spi, true).is_err());
+    }
+
+    #[test]
+    #[cfg(target_pointer_width = "64")]
+    fn test_dirty_log_size() {
+        let page_size = 0x1000;
+        // Sizes around the u32 boundary.
+        assert_eq!(
+            dirty_log_pages(u32::MAX as usize, page_size).unwrap(),
+            1 << 20
+        );
+        assert_eq!(
+            dirty_log_pages((1 << 32) + 1, page_size).unwrap(),
+            (1 << 20) + 1
+        );
+
+        // The page count of slots with more than u32::MAX pages does not wrap.
+        let pages = dirty_log_pages(1 << 45, page_size).unwrap();
+        assert_eq!(pages, 1 << 33);
+        assert_eq!(pages.div_ceil(64), 1 << 27);
+
+        // Rounding up does not overflow for the largest sizes.
+        assert_eq!(
+            dirty_log_pages(usize::MAX, page_size).unwrap(),
+            (usize::MAX >> 12) + 1
+        );
+        assert_eq!(
+            dirty_log_pages(isize::MAX as usize, 1).unwrap(),
+            isize::MAX as usize
+        );
+        assert_eq!(
+            dirty_log_pages(usize::MAX, 1).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
 
+    #[test]
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    use std::{fs::OpenOptions, ptr::null_mut};
+    fn test_faulty_vm_fd() {
+        let badf_errno = libc::EBADF;
+
+        let faulty_vm_fd = VmFd {
+            vm: unsafe { File::from_raw_fd(-2) },
+            run_size: 0,
+            irqchip: AtomicBool::new(false),
+            split_irqchip: AtomicBool::new(false),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            sev_es: AtomicBool::new(false),
+            #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+            x2apic_32bit_ids: AtomicBool::new(false),
+            vm_type: Some(0),
+        };
+
+        let invalid_mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: 0,
+            userspace_addr: 0,
+            flags: 0,
+        };
+
+        assert_eq!(
+            unsafe {
+                faulty_vm_fd
+                    .set_user_memory_region(invalid_mem_region)
+                    .unwrap_err()
+                    .errno()
+            },
+            badf_errno
+        );
+        assert_eq!(
+            faulty_vm_fd.set_tss_address(0).unwrap_err().errno(),
+            badf_errno
+        );
+        assert_eq!(
+            faulty_vm_fd.create_irq_chip().unwrap_err().errno(),
+            badf_errno
+        );
+        assert_eq!(
+            faulty_vm_fd
+                .create_pit2(kvm_pit_config::default())
+                .unwrap_err()
+                .errno(),
+            badf_errno
+        );
+        let event_fd = EventFd::new(EFD_NONBLOCK).unwrap();
+        assert_eq!(
+            faulty_vm_fd
+                .register_ioevent(&event_fd, &IoEventAddress::Pio(0), 0u32)
+                .unwrap_err()
+                .errno(),
+            badf_errno
+        );
+        assert_eq!(
+            faulty_vm_fd
+                .get_irqchip(IrqChipId::PicMaster)
+                .unwrap_err()
+                .errno(),
+            badf_errno
+        );
+        assert_eq!(
+            faulty_vm_fd
+                .set_irqchip(&kvm_irqchip::default())
+                .unwrap_err()
+                .errno(),
+            badf_errno
+        );
+        assert_eq!(faulty_vm_fd.get_clock().unwrap_err().errno(), badf_errno);
+        assert_eq!(
+            faulty_vm_fd
+                .set_clock(&kvm_clock_data::default())
+                .unwrap_err()
+                .errno(),
+            badf_errno
+        );
+        assert_eq!(faulty_vm_fd.get_pit2().unwrap_err().errno(), badf_errno);
+        assert_eq!(
+            faulty_vm_fd
+                .set_pit2(&kvm_pit_state2::default())
+                .unwrap_err()
+                .errno(),
+            badf_errno
+        );
+        assert_eq!(
+            faulty_vm_fd
+                .register_irqfd(&event_fd, 0)
+                .unwrap_err()
+                .errno(),
+            badf_errno
+        );
 
-    use libc::EFD_NONBLOCK;
+        assert_eq!(
+            faulty_vm_fd.create_vcpu(0).err().unwrap().errno(),
+            badf_errno
+        );
 
-    #[test]
-    fn test_set_invalid_memory() {
-        let kvm = Kvm::new().unwrap();
-        let vm = kvm.create_vm().unwrap();
-        let invalid_mem_region = kvm_userspace_memory_region {
-            slot: 0,
-            guest_phys_addr: 0,
-            memory_size: 0,
-            userspace_addr: 0,
-            flags: 0,
-        };
-        assert!(unsafe { vm.set_user_memory_region(invalid_mem_region) }.is_err());
+        assert_eq!(
+            faulty_vm_fd.get_dirty_log(0, 0).unwrap_err().errno(),
+            badf_errno
+        );
     }
 
     #[test]
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn test_set_tss_address() {
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn test_get_preferred_target() {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        assert!(vm.set_tss_address(0xfffb_d000).is_ok());
-    }
-
-    #[test]
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn test_set_identity_map_address() {
-        let kvm = Kvm::new().unwrap();
-        if kvm.check_extension(Cap::SetIdentityMapAddr) {
-            let vm = kvm.create_vm().unwrap();
-            assert!(vm.set_identity_map_address(0xfffb_c000).is_ok());
-            vm.create_vcpu(0).unwrap();
-            // Setting the identity map after creating a vCPU must fail.
-            assert!(vm.set_identity_map_address(0xfffb_c000).is_err());
-        }
+        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
+        assert!(vm.get_preferred_target(&mut kvi).is_ok());
     }
 
     #[test]
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn test_irq_chip() {
-        use Cap;
-
+    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+    fn test_arm_set_device_addr() {
         let kvm = Kvm::new().unwrap();
-        assert!(kvm.check_extension(Cap::Irqchip));
         let vm = kvm.create_vm().unwrap();
-        assert!(vm.create_irq_chip().is_ok());
-
-        let mut irqchip = kvm_irqchip {
-            chip_id: KVM_IRQCHIP_PIC_MASTER,
-            ..Default::default()
+        let dist_addr = kvm_arm_device_addr {
+            id: u64::from(
+                KVM_ARM_DEVICE_VGIC_V2 << KVM_ARM_DEVICE_ID_SHIFT | KVM_VGIC_V2_ADDR_TYPE_DIST,
+            ),
+            addr: 0x0800_0000,
         };
-        // Set the irq_base to a non-default value to check that set & get work.
-        irqchip.chip.pic.irq_base = 10;
-        assert!(vm.set_irqchip(&irqchip).is_ok());
 
-        // We initialize a dummy irq chip (`other_irqchip`) in which the
-        // function `get_irqchip` returns its result.
-        let mut other_irqchip = kvm_irqchip {
-            chip_id: KVM_IRQCHIP_PIC_MASTER,
-            ..Default::default()
+        if vm.create_irq_chip().is_err() {
+            // GICv3-only hosts cannot emulate the GICv2 set up by `KVM_CREATE_IRQCHIP`.
+            assert!(vm.arm_set_device_addr(&dist_addr).is_err());
+            return;
+        }
+        vm.arm_set_device_addr(&dist_addr).unwrap();
+        // The address can only be set once.
+        assert_eq!(
+            vm.arm_set_device_addr(&dist_addr).unwrap_err().errno(),
+            libc::EEXIST
+        );
+        // The address must be 4K aligned.
+        let cpu_addr = kvm_arm_device_addr {
+            id: u64::from(
+                KVM_ARM_DEVICE_VGIC_V2 << KVM_ARM_DEVICE_ID_SHIFT | KVM_VGIC_V2_ADDR_TYPE_CPU,
+            ),
+            addr: 0x0801_0001,
         };
-        assert!(vm.get_irqchip(&mut other_irqchip).is_ok());
-
-        // Safe because we know that the irqchip type is PIC.
-        unsafe { assert_eq!(irqchip.chip.pic, other_irqchip.chip.pic) };
+        assert_eq!(
+            vm.arm_set_device_addr(&cpu_addr).unwrap_err().errno(),
+            libc::EINVAL
+        );
     }
 
+    /// As explained in the example code related to signal_msi(), sending
+    /// a random MSI vector will always fail because no vector has been
+    /// previously allocated from the guest itself.
     #[test]
-    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-    fn test_irq_chip() {
-        use Cap;
-
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    fn test_signal_msi_failure() {
         let kvm = Kvm::new().unwrap();
-        assert!(kvm.check_extension(Cap::Irqchip));
-
         let vm = kvm.create_vm().unwrap();
-
-        // On ARM/arm64, a GICv2 is created. It's better to check ahead whether GICv2
-        // can be emulated or not.
-        let mut gic_device = kvm_bindings::kvm_create_device {
-            type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_V2,
-            fd: 0,
-            flags: KVM_CREATE_DEVICE_TEST,
-        };
-
-        let vgic_v2_supported = vm.create_device(&mut gic_device).is_ok();
-        assert_eq!(vm.create_irq_chip().is_ok(), vgic_v2_supported);
+        let msi = kvm_msi::default();
+        assert!(vm.signal_msi(msi).is_err());
     }
 
     #[test]
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn test_pit2() {
+    fn test_enable_cap_failure() {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        assert!(vm.create_pit2(kvm_pit_config::default()).is_ok());
-
-        let pit2 = vm.get_pit2().unwrap();
-        vm.set_pit2(&pit2).unwrap();
-        let mut other_pit2 = vm.get_pit2().unwrap();
-        // Load time will differ, let's overwrite it so we can test equality.
-        other_pit2.channels[0].count_load_time = pit2.channels[0].count_load_time;
-        other_pit2.channels[1].count_load_time = pit2.channels[1].count_load_time;
-        other_pit2.channels[2].count_load_time = pit2.channels[2].count_load_time;
-        assert_eq!(pit2, other_pit2);
+        let cap: kvm_enable_cap = Default::default();
+        // Providing the `kvm_enable_cap` structure filled with default() should
+        // always result in a failure as it is not a valid capability.
+        assert!(vm.enable_cap(&cap).is_err());
     }
 
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
     #[test]
-    fn test_clock() {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_enable_split_irqchip_cap() {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-
-        // Get current time.
-        let orig = vm.get_clock().unwrap();
-
-        // Reset time.
-        let fudged = kvm_clock_data {
-            clock: 10,
+        let mut cap = kvm_enable_cap {
+            cap: KVM_CAP_SPLIT_IRQCHIP,
             ..Default::default()
         };
-        vm.set_clock(&fudged).unwrap();
-
-        // Get new time.
-        let new = vm.get_clock().unwrap();
-
-        // Verify new time has progressed but is smaller than orig time.
-        assert!(fudged.clock < new.clock);
-        assert!(new.clock < orig.clock);
-    }
-
-    #[test]
-    fn test_register_ioevent() {
-        assert_eq!(std::mem::size_of::<NoDatamatch>(), 0);
-
-        let kvm = Kvm::new().unwrap();
-        let vm_fd = kvm.create_vm().unwrap();
-        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
-        assert!(vm_fd
-            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xf4), NoDatamatch)
-            .is_ok());
-        assert!(vm_fd
-            .register_ioevent(&evtfd, &IoEventAddress::Mmio(0x1000), NoDatamatch)
-            .is_ok());
-        assert!(vm_fd
-            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xc1), 0x7fu8)
-            .is_ok());
-        assert!(vm_fd
-            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xc2), 0x1337u16)
-            .is_ok());
-        assert!(vm_fd
-            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xc4), 0xdead_beefu32)
-            .is_ok());
-        assert!(vm_fd
-            .register_ioevent(&evtfd, &IoEventAddress::Pio(0xc8), 0xdead_beef_dead_beefu64)
-            .is_ok());
+        // As per the KVM documentation, KVM_CAP_SPLIT_IRQCHIP only emulates
+        // the local APIC in kernel, expecting that a userspace IOAPIC will
+        // be implemented by the VMM.
+        // Along with this capability, the user needs to specify the number
+        // of pins reserved for the userspace IOAPIC. This number needs to be
+        // provided through the first argument of the capability structure, as
+        // specified in KVM documentation:
+        //     args[0] - number of routes reserved for userspace IOAPICs
+        //
+        // Because an IOAPIC supports 24 pins, that's the reason why this test
+        // picked this number as reference.
+        cap.args[0] = 24;
+        assert!(vm.enable_cap(&cap).is_ok());
     }
 
     #[test]
-    fn test_unregister_ioevent() {
-        assert_eq!(std::mem::size_of::<NoDatamatch>(), 0);
-
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_enable_split_irqchip() {
         let kvm = Kvm::new().unwrap();
-        let vm_fd = kvm.create_vm().unwrap();
-        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
-        let pio_addr = IoEventAddress::Pio(0xf4);
-        let mmio_addr = IoEventAddress::Mmio(0x1000);
-
-        // First try to unregister addresses which have not been registered.
-        assert!(vm_fd
-            .unregister_ioevent(&evtfd, &pio_addr, NoDatamatch)
-            .is_err());
-        assert!(vm_fd
-            .unregister_ioevent(&evtfd, &mmio_addr, NoDatamatch)
-            .is_err());
+        let vm = kvm.create_vm().unwrap();
 
-        // Now register the addresses
-        assert!(vm_fd
-            .register_ioevent(&evtfd, &pio_addr, NoDatamatch)
-            .is_ok());
-        assert!(vm_fd
-            .register_ioevent(&evtfd, &mmio_addr, 0x1337u16)
-            .is_ok());
+        // An IOAPIC has at most 24 pins.
+        assert_eq!(
+            vm.enable_split_irqchip(25).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        vm.enable_split_irqchip(24).unwrap();
+        // The split irqchip and the in-kernel irqchip are mutually exclusive.
+        assert_eq!(vm.create_irq_chip(), Err(IrqChipError::SplitIrqChip));
+        assert_eq!(vm.create_irq_chip().unwrap_err().errno(), libc::EEXIST);
+        assert!(vm.has_irqchip());
+        vm.create_vcpu(0).unwrap();
 
-        // Try again unregistering the addresses. This time it should work
-        // since they have been previously registered.
-        assert!(vm_fd
-            .unregister_ioevent(&evtfd, &pio_addr, NoDatamatch)
-            .is_ok());
-        assert!(vm_fd
-            .unregister_ioevent(&evtfd, &mmio_addr, 0x1337u16)
-            .is_ok());
+        // Enabling the split irqchip after creating a vCPU must fail.
+        let vm = kvm.create_vm().unwrap();
+        vm.create_vcpu(0).unwrap();
+        assert!(vm.enable_split_irqchip(24).is_err());
     }
 
     #[test]
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn test_register_unregister_irqfd() {
+    #[cfg(any(
+        target_arch = "x86",
+        target_arch = "x86_64",
+        target_arch = "arm",
+        target_arch = "aarch64"
+    ))]
+    fn test_set_gsi_routing() {
         let kvm = Kvm::new().unwrap();
-        let vm_fd = kvm.create_vm().unwrap();
-        let evtfd1 = EventFd::new(EFD_NONBLOCK).unwrap();
-        let evtfd2 = EventFd::new(EFD_NONBLOCK).unwrap();
-        let evtfd3 = EventFd::new(EFD_NONBLOCK).unwrap();
-        let evtfd4 = EventFd::new(EFD_NONBLOCK).unwrap();
-        let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
-
-        assert!(vm_fd.create_irq_chip().is_ok());
-
-        assert!(vm_fd.register_irqfd(&evtfd1, 4).is_ok());
-        assert!(vm_fd.register_irqfd(&evtfd2, 8).is_ok());
-        assert!(vm_fd.register_irqfd(&evtfd3, 4).is_ok());
-        assert!(vm_fd.unregister_irqfd(&evtfd2, 8).is_ok());
-        // KVM irqfd doesn't report failure on this case:(
-        assert!(vm_fd.unregister_irqfd(&evtfd2, 8).is_ok());
-
-        // Duplicated eventfd registration.
-        // On x86_64 this fails as the event fd was already matched with a GSI.
-        assert!(vm_fd.register_irqfd(&evtfd3, 4).is_err());
-        assert!(vm_fd.register_irqfd(&evtfd3, 5).is_err());
-        // KVM irqfd doesn't report failure on this case:(
-        assert!(vm_fd.unregister_irqfd(&evtfd3, 5).is_ok());
-
-        if vm_fd.check_extension(Cap::IrqfdResample) {
-            assert!(vm_fd
-                .register_irqfd_with_resample(&evtfd4, &resamplefd, 6)
-                .is_ok());
-            assert!(vm_fd.unregister_irqfd(&evtfd4, 6).is_ok());
-        } else {
-            assert!(vm_fd
-                .register_irqfd_with_resample(&evtfd4, &resamplefd, 6)
-                .is_err());
+        let vm = kvm.create_vm().unwrap();
+        if cfg!(target_arch = "x86") || cfg!(target_arch = "x86_64") {
+            let irq_routing = kvm_irq_routing::default();
+            // Expect failure for x86 since the irqchip is not created yet.
+            assert!(vm.set_gsi_routing(&irq_routing).is_err());
+            vm.create_irq_chip().unwrap();
         }
+        let irq_routing = kvm_irq_routing::default();
+        assert!(vm.set_gsi_routing(&irq_routing).is_ok());
     }
 
     #[test]
-    #[cfg(target_arch = "aarch64")]
-    fn test_register_unregister_irqfd() {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_gsi_routing() {
         let kvm = Kvm::new().unwrap();
-        let vm_fd = kvm.create_vm().unwrap();
-        let evtfd1 = EventFd::new(EFD_NONBLOCK).unwrap();
-        let evtfd2 = EventFd::new(EFD_NONBLOCK).unwrap();
-        let evtfd3 = EventFd::new(EFD_NONBLOCK).unwrap();
-        let evtfd4 = EventFd::new(EFD_NONBLOCK).unwrap();
-        let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let vm = kvm.create_vm().unwrap();
 
-        // Create the vGIC device.
-        let vgic_fd = create_gic_device(&vm_fd, 0);
+        let mut routing = GsiRouting::new();
+        routing
+            .add_entry(IrqRoutingEntry::irqchip(4, KVM_IRQCHIP_IOAPIC, 4))
+            .add_entry(IrqRoutingEntry::msi(24, 0, 0xfee0_0000, 0x30));
+        assert_eq!(routing.entries().len(), 2);
+        assert_eq!(routing.entries()[1].gsi(), 24);
 
-        // GICv3 on arm/aarch64 requires an online vCPU prior to setting device attributes,
-        // see: https://www.kernel.org/doc/html/latest/virt/kvm/devices/arm-vgic-v3.html
-        vm_fd.create_vcpu(0).unwrap();
+        let mut irq_routing = Vec::new();
+        GsiRouting::serialize(routing.entries(), &mut irq_routing);
+        assert_eq!(irq_routing[0].nr, 2);
+        let entries = unsafe { irq_routing[0].entries.as_slice(2) };
+        assert_eq!(entries[0].type_, KVM_IRQ_ROUTING_IRQCHIP);
+        assert_eq!(unsafe { entries[0].u.irqchip.pin }, 4);
+        assert_eq!(entries[1].type_, KVM_IRQ_ROUTING_MSI);
+        assert_eq!(unsafe { entries[1].u.msi.data }, 0x30);
 
-        // Set supported number of IRQs.
-        set_supported_nr_irqs(&vgic_fd, 128);
-        // Request the initialization of the vGIC.
-        request_gic_init(&vgic_fd);
+        // Routing requires the in-kernel irqchip.
+        assert!(routing.commit(&vm).is_err());
+        vm.create_irq_chip().unwrap();
+        routing.commit(&vm).unwrap();
+        assert!(!vm.supports_msi_devid());
+    }
 
-        assert!(vm_fd.register_irqfd(&evtfd1, 4).is_ok());
-        assert!(vm_fd.register_irqfd(&evtfd2, 8).is_ok());
-        assert!(vm_fd.register_irqfd(&evtfd3, 4).is_ok());
-        assert!(vm_fd.unregister_irqfd(&evtfd2, 8).is_ok());
-        // KVM irqfd doesn't report failure on this case:(
-        assert!(vm_fd.unregister_irqfd(&evtfd2, 8).is_ok());
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_x2apic_api_msi_routing() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
 
-        // Duplicated eventfd registration.
-        // On aarch64, this fails because setting up the interrupt controller is mandatory before
-        // registering any IRQ.
-        assert!(vm_fd.register_irqfd(&evtfd3, 4).is_err());
-        assert!(vm_fd.register_irqfd(&evtfd3, 5).is_err());
-        // KVM irqfd doesn't report failure on this case:(
-        assert!(vm_fd.unregister_irqfd(&evtfd3, 5).is_ok());
+        // APIC IDs above 255 need 32-bit APIC IDs.
+        let mut routing = GsiRouting::new();
+        assert_eq!(
+            routing.add_x86_msi(&vm, 24, 300, 0x30).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        routing.add_x86_msi(&vm, 24, 0x12, 0x30).unwrap();
+        let entry = kvm_irq_routing_entry::from(routing.entries()[0]);
+        assert_eq!(unsafe { entry.u.msi.address_lo }, 0xfee1_2000);
+        assert_eq!(unsafe { entry.u.msi.address_hi }, 0);
 
-        if vm_fd.check_extension(Cap::IrqfdResample) {
-            assert!(vm_fd
-                .register_irqfd_with_resample(&evtfd4, &resamplefd, 6)
-                .is_ok());
-            assert!(vm_fd.unregister_irqfd(&evtfd4, 6).is_ok());
-        } else {
-            assert!(vm_fd
-                .register_irqfd_with_resample(&evtfd4, &resamplefd, 6)
-                .is_err());
+        if !vm.check_extension(Cap::X2ApicApi) {
+            return;
         }
+        vm.enable_x2apic_api(true, true).unwrap();
+        let mut routing = GsiRouting::new();
+        routing.add_x86_msi(&vm, 24, 300, 0x30).unwrap();
+        let entry = kvm_irq_routing_entry::from(routing.entries()[0]);
+        assert_eq!(unsafe { entry.u.msi.address_lo }, 0xfee2_c000);
+        assert_eq!(unsafe { entry.u.msi.address_hi }, 0x100);
+        routing.commit(&vm).unwrap();
     }
 
     #[test]
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn test_set_irq_line() {
+    #[cfg(target_arch = "x86_64")]
+    fn test_hyperv() {
         let kvm = Kvm::new().unwrap();
-        let vm_fd = kvm.create_vm().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let base = HypervFeatures::Relaxed | HypervFeatures::Vapic;
+        assert_eq!(HypervFeatures::from_bits(base.bits()), Some(base));
+        let supported = vm.supported_hyperv_features();
+        if !supported.contains(base) {
+            assert_eq!(vm.enable_hyperv(base).unwrap_err().errno(), libc::EINVAL);
+            return;
+        }
 
-        assert!(vm_fd.create_irq_chip().is_ok());
+        if supported.contains(HypervFeatures::Synic | HypervFeatures::Stimer) {
+            // SynIC needs an in-kernel irqchip, and the timers need SynIC.
+            assert_eq!(
+                vm.enable_hyperv(HypervFeatures::Synic).unwrap_err().errno(),
+                libc::EINVAL
+            );
+            vm.create_irq_chip().unwrap();
+            assert_eq!(
+                vm.enable_hyperv(HypervFeatures::Stimer)
+                    .unwrap_err()
+                    .errno(),
+                libc::EINVAL
+            );
+        }
+        vm.enable_hyperv(base).unwrap();
 
-        assert!(vm_fd.set_irq_line(4, true).is_ok());
-        assert!(vm_fd.set_irq_line(4, false).is_ok());
-        assert!(vm_fd.set_irq_line(4, true).is_ok());
+        let cpuid = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
+        let kvm_leaf = *cpuid.leaf(0x4000_0000, 0).unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        vcpu.set_cpuid2(&cpuid).unwrap();
+        // Only the requested enlightenments are exposed.
+        assert_eq!(
+            vcpu.init_hyperv(&HypervFeatures::Relaxed).unwrap(),
+            HypervFeatures::Relaxed
+        );
+        let vcpu_cpuid = vcpu.get_cpuid2(KVM_MAX_CPUID_ENTRIES).unwrap();
+        assert_eq!(
+            HypervFeatures::from_hv_cpuid(&vcpu_cpuid),
+            HypervFeatures::Relaxed
+        );
+        // Enabling more enlightenments replaces the Hyper-V leaves.
+        assert_eq!(vcpu.init_hyperv(&base).unwrap(), base);
+        let vcpu_cpuid = vcpu.get_cpuid2(KVM_MAX_CPUID_ENTRIES).unwrap();
+        assert_eq!(HypervFeatures::from_hv_cpuid(&vcpu_cpuid), base);
+
+        // "Linux KVM Hv"
+        let signature = vcpu_cpuid.leaf(0x4000_0000, 0).unwrap();
+        assert_eq!(
+            (signature.ebx, signature.ecx, signature.edx),
+            (0x756e_694c, 0x564b_2078, 0x7648_204d)
+        );
+        // "Hv#1"
+        assert_eq!(vcpu_cpuid.leaf(0x4000_0001, 0).unwrap().eax, 0x3123_7648);
+        // The KVM leaves were moved out of the way, once.
+        let moved = vcpu_cpuid.leaf(0x4000_0100, 0).unwrap();
+        assert_eq!(
+            (moved.eax, moved.ebx, moved.ecx, moved.edx),
+            (
+                kvm_leaf.eax + 0x100,
+                kvm_leaf.ebx,
+                kvm_leaf.ecx,
+                kvm_leaf.edx
+            )
+        );
+        assert!(vcpu_cpuid.leaf(0x4000_0200, 0).is_none());
+        // The base leaves are left alone.
+        assert_eq!(
+            vcpu_cpuid.leaf(0, 0).unwrap().ebx,
+            cpuid.leaf(0, 0).unwrap().ebx
+        );
     }
 
     #[test]
-    #[cfg(target_arch = "aarch64")]
-    #[allow(clippy::unusual_byte_groupings)]
-    fn test_set_irq_line() {
+    #[cfg(target_arch = "x86_64")]
+    fn test_enable_sgx_attribute() {
         let kvm = Kvm::new().unwrap();
-        let vm_fd = kvm.create_vm().unwrap();
-        // Create a vcpu for test case 2 of the KVM_IRQ_LINE API on aarch64.
-        vm_fd.create_vcpu(0).unwrap();
+        let vm = kvm.create_vm().unwrap();
 
-        // Create the vGIC device.
-        let vgic_fd = create_gic_device(&vm_fd, 0);
-        // Set supported number of IRQs.
-        set_supported_nr_irqs(&vgic_fd, 128);
-        // Request the initialization of the vGIC.
-        request_gic_init(&vgic_fd);
+        // Only the securityfs files of the SGX attributes are accepted.
+        let not_attribute = File::open("/dev/null").unwrap();
+        assert_eq!(
+            vm.enable_sgx_attribute(&not_attribute).unwrap_err().errno(),
+            libc::EINVAL
+        );
+        if !vm.check_extension(Cap::SgxAttribute) {
+            return;
+        }
+        if let Ok(provision) = File::open("/dev/sgx_provision") {
+            vm.enable_sgx_attribute(&provision).unwrap();
+        }
+    }
 
-        // On arm/aarch64, irq field is interpreted like this:
-        // bits:  | 31 ... 24 | 23  ... 16 | 15    ...    0 |
-        // field: | irq_type  | vcpu_index |     irq_id     |
-        // The irq_type field has the following values:
-        // - irq_type[0]: out-of-kernel GIC: irq_id 0 is IRQ, irq_id 1 is FIQ
-        // - irq_type[1]: in-kernel GIC: SPI, irq_id between 32 and 1019 (incl.) (the vcpu_index field is ignored)
-        // - irq_type[2]: in-kernel GIC: PPI, irq_id between 16 and 31 (incl.)
-        // Hence, using irq_type = 1, irq_id = 32 (decimal), the irq field in hex is: 0x01_00_0020
-        assert!(vm_fd.set_irq_line(0x01_00_0020, true).is_ok());
-        assert!(vm_fd.set_irq_line(0x01_00_0020, false).is_ok());
-        assert!(vm_fd.set_irq_line(0x01_00_0020, true).is_ok());
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_register_epc_section() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
 
-        // Case 2: using irq_type = 2, vcpu_index = 0, irq_id = 16 (decimal), the irq field in hex is: 0x02_00_0010
-        assert!(vm_fd.set_irq_line(0x02_00_0010, true).is_ok());
-        assert!(vm_fd.set_irq_line(0x02_00_0010, false).is_ok());
-        assert!(vm_fd.set_irq_line(0x02_00_0010, true).is_ok());
+        let size = 0x2000;
+        let anon = mmap_anonymous(size).as_ptr();
+        let anon = unsafe { std::slice::from_raw_parts(anon, size) };
+        let einval = |gpa, mapping| unsafe {
+            vm.register_epc_section(0, gpa, mapping)
+                .unwrap_err()
+                .errno()
+                == libc::EINVAL
+        };
+        assert!(einval(0x1_0000_0000, &anon[..0]));
+        assert!(einval(0x1_0000_0800, anon));
+        assert!(einval(0x1_0000_0000, &anon[0x800..]));
+        assert!(einval(0x1_0000_0000, &anon[..0x1800]));
+        // Anonymous memory is not an EPC section.
+        assert_ne!(
+            mapping_path(anon.as_ptr() as u64).as_deref(),
+            Some(SGX_VEPC_PATH)
+        );
+        assert!(einval(0x1_0000_0000, anon));
+
+        let vepc = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(SGX_VEPC_PATH)
+        {
+            Ok(vepc) => vepc,
+            Err(_) => return,
+        };
+        let epc = unsafe {
+            libc::mmap(
+                null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                vepc.as_raw_fd(),
+                0,
+            )
+        };
+        assert_ne!(epc, libc::MAP_FAILED);
+        let epc = unsafe { std::slice::from_raw_parts(epc as *const u8, size) };
+        unsafe { vm.register_epc_section(0, 0x1_0000_0000, epc).unwrap() };
     }
 
     #[test]
-    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn test_faulty_vm_fd() {
-        let badf_errno = libc::EBADF;
+    #[cfg(target_arch = "x86_64")]
+    fn test_normalized_cpuid() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        vm.create_irq_chip().unwrap();
+        let base = kvm.get_supported_cpuid(KVM_MAX_CPUID_ENTRIES).unwrap();
 
-        let faulty_vm_fd = VmFd {
-            vm: unsafe { File::from_raw_fd(-2) },
-            run_size: 0,
+        for id in 0..4u8 {
+            let cpuid = vm.normalized_cpuid(&base, 4, id);
+            assert_eq!(cpuid.as_slice().len(), base.as_slice().len());
+            for entry in cpuid.as_slice() {
+                match (entry.function, entry.index) {
+                    (0x1, _) => {
+                        assert_eq!(entry.ebx >> 24, u32::from(id));
+                        assert_eq!((entry.ebx >> 16) & 0xff, 4);
+                        assert_ne!(entry.edx & (1 << 28), 0);
+                        assert_ne!(entry.ecx & (1 << 31), 0);
+                    }
+                    (0xb, 0) => {
+                        assert_eq!(entry.eax, 0);
+                        assert_eq!(entry.ebx, 1);
+                        assert_eq!(entry.ecx, 0x100);
+                        assert_eq!(entry.edx, u32::from(id));
+                    }
+                    (0xb, 1) => {
+                        assert_eq!(entry.eax, 2);
+                        assert_eq!(entry.ebx, 4);
+                        assert_eq!(entry.ecx, 0x201);
+                        assert_eq!(entry.edx, u32::from(id));
+                    }
+                    (0x4000_0000, _) => {
+                        assert_eq!(entry.ebx, 0x4b4d_564b);
+                    }
+                    _ => {}
+                }
+            }
+            let vcpu = vm.create_vcpu(u64::from(id)).unwrap();
+            vcpu.set_cpuid2(&cpuid).unwrap();
+        }
+
+        // Without an in-kernel local APIC, x2APIC and the TSC deadline timer are removed.
+        let vm = kvm.create_vm().unwrap();
+        let cpuid = vm.normalized_cpuid(&base, 1, 0);
+        let leaf1 = cpuid.as_slice().iter().find(|e| e.function == 1).unwrap();
+        assert_eq!(leaf1.ecx & ((1 << 21) | (1 << 24)), 0);
+        assert_eq!(leaf1.edx & (1 << 28), 0);
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_gsi_routing_hv_sint() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        if !vm.hyperv_synic_supported() {
+            return;
+        }
+        vm.create_irq_chip().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let cap = kvm_enable_cap {
+            cap: KVM_CAP_HYPERV_SYNIC2,
+            ..Default::default()
         };
+        vcpu.enable_cap(&cap).unwrap();
 
-        let invalid_mem_region = kvm_userspace_memory_region {
+        let entry = IrqRoutingEntry::hv_sint(30, 0, 2);
+        assert_eq!(entry.gsi(), 30);
+        let raw = kvm_irq_routing_entry::from(entry);
+        assert_eq!(raw.type_, KVM_IRQ_ROUTING_HV_SINT);
+        assert_eq!(unsafe { raw.u.hv_sint.vcpu }, 0);
+        assert_eq!(unsafe { raw.u.hv_sint.sint }, 2);
+
+        let mut routing = GsiRouting::new();
+        routing.add_entry(entry);
+        routing.commit(&vm).unwrap();
+
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        vm.register_irqfd(&evtfd, 30).unwrap();
+        evtfd.write(1).unwrap();
+        vm.unregister_irqfd(&evtfd, 30).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_arch = "x86_64")]
+    fn test_xen_evtchn() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let features = KVM_XEN_HVM_CONFIG_SHARED_INFO
+            | KVM_XEN_HVM_CONFIG_EVTCHN_2LEVEL
+            | KVM_XEN_HVM_CONFIG_EVTCHN_SEND;
+        if vm.supported_xen_hvm_features() & features != features {
+            return;
+        }
+        vm.create_irq_chip().unwrap();
+        let _vcpu = vm.create_vcpu(0).unwrap();
+
+        let entry = IrqRoutingEntry::xen_evtchn(40, 3, 0, KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL);
+        let mut routing = GsiRouting::new();
+        routing.add_entry(entry);
+        // Xen event channel routes require the VM to be configured for Xen guests.
+        assert_eq!(routing.commit(&vm).unwrap_err().errno(), libc::EINVAL);
+
+        let config = XenHvmConfig::new().evtchn_send(true);
+        assert_eq!(config.flags(), KVM_XEN_HVM_CONFIG_EVTCHN_SEND);
+        vm.xen_hvm_config(&config.into()).unwrap();
+        routing.commit(&vm).unwrap();
+
+        // Events are delivered through the shared info page of the guest.
+        let mem_size = 0x1000;
+        let load_addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                mem_size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(load_addr, libc::MAP_FAILED);
+        let mem_region = kvm_userspace_memory_region {
             slot: 0,
-            guest_phys_addr: 0,
-            memory_size: 0,
-            userspace_addr: 0,
+            guest_phys_addr: 0x10000,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
             flags: 0,
         };
+        unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+        vm.xen_hvm_set_attr(&XenHvmAttr::shared_info(0x10)).unwrap();
 
+        let evtchn = kvm_irq_routing_xen_evtchn {
+            port: 3,
+            vcpu: 0,
+            priority: KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL,
+        };
+        vm.xen_evtchn_send(&evtchn).unwrap();
+        // Only the 2-level ABI is supported.
+        let evtchn = kvm_irq_routing_xen_evtchn {
+            priority: 0,
+            ..evtchn
+        };
         assert_eq!(
-            unsafe {
-                faulty_vm_fd
-                    .set_user_memory_region(invalid_mem_region)
-                    .unwrap_err()
-                    .errno()
-            },
-            badf_errno
-        );
-        assert_eq!(
-            faulty_vm_fd.set_tss_address(0).unwrap_err().errno(),
-            badf_errno
-        );
-        assert_eq!(
-            faulty_vm_fd.create_irq_chip().unwrap_err().errno(),
-            badf_errno
-        );
-        assert_eq!(
-            faulty_vm_fd
-                .create_pit2(kvm_pit_config::default())
-                .unwrap_err()
-                .errno(),
-            badf_errno
-        );
-        let event_fd = EventFd::new(EFD_NONBLOCK).unwrap();
-        assert_eq!(
-            faulty_vm_fd
-                .register_ioevent(&event_fd, &IoEventAddress::Pio(0), 0u64)
-                .unwrap_err()
-                .errno(),
-            badf_errno
+            vm.xen_evtchn_send(&evtchn).unwrap_err().errno(),
+            libc::EINVAL
         );
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_hyperv_eventfd() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        // The connection id is a 24-bit field.
         assert_eq!(
-            faulty_vm_fd
-                .get_irqchip(&mut kvm_irqchip::default())
+            vm.register_hyperv_eventfd(1 << 24, &evtfd)
                 .unwrap_err()
                 .errno(),
-            badf_errno
+            libc::EINVAL
         );
+        if !vm.check_extension(Cap::HypervEventfd) {
+            assert_eq!(
+                vm.register_hyperv_eventfd(1, &evtfd).unwrap_err().errno(),
+                libc::EINVAL
+            );
+            return;
+        }
+
+        vm.register_hyperv_eventfd(KVM_HYPERV_CONN_ID_MASK, &evtfd)
+            .unwrap();
         assert_eq!(
-            faulty_vm_fd
-                .set_irqchip(&kvm_irqchip::default())
+            vm.register_hyperv_eventfd(KVM_HYPERV_CONN_ID_MASK, &evtfd)
                 .unwrap_err()
                 .errno(),
-            badf_errno
+            libc::EEXIST
         );
-        assert_eq!(faulty_vm_fd.get_clock().unwrap_err().errno(), badf_errno);
+        vm.unregister_hyperv_eventfd(KVM_HYPERV_CONN_ID_MASK)
+            .unwrap();
         assert_eq!(
-            faulty_vm_fd
-                .set_clock(&kvm_clock_data::default())
+            vm.unregister_hyperv_eventfd(KVM_HYPERV_CONN_ID_MASK)
                 .unwrap_err()
                 .errno(),
-            badf_errno
+            libc::ENOENT
         );
-        assert_eq!(faulty_vm_fd.get_pit2().unwrap_err().errno(), badf_errno);
-        assert_eq!(
-            faulty_vm_fd
-                .set_pit2(&kvm_pit_state2::default())
-                .unwrap_err()
-                .errno(),
-            badf_errno
+        // A freed connection id can be connected again.
+        vm.register_hyperv_eventfd(KVM_HYPERV_CONN_ID_MASK, &evtfd)
+            .unwrap();
+        vm.unregister_hyperv_eventfd(KVM_HYPERV_CONN_ID_MASK)
+            .unwrap();
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_xen_hvm_attr() {
+        let attr = XenHvmAttr::shared_info(0x10);
+        assert_eq!(u32::from(attr.type_), KVM_XEN_ATTR_TYPE_SHARED_INFO);
+        assert_eq!(attr.shared_info_gfn(), Some(0x10));
+        assert_eq!(attr.vector(), None);
+        let attr = XenHvmAttr::upcall_vector(0xf3);
+        assert_eq!(u32::from(attr.type_), KVM_XEN_ATTR_TYPE_UPCALL_VECTOR);
+        assert_eq!(attr.vector(), Some(0xf3));
+        assert_eq!(attr.shared_info_gfn(), None);
+        let attr = kvm_xen_hvm_attr::from(XenHvmAttr::long_mode(true));
+        assert_eq!(u32::from(attr.type_), KVM_XEN_ATTR_TYPE_LONG_MODE);
+        assert_eq!(unsafe { attr.u.long_mode }, 1);
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        if vm.supported_xen_hvm_features() & KVM_XEN_HVM_CONFIG_SHARED_INFO == 0 {
+            // The attributes can only be used on VMs supporting Xen guests.
+            assert!(vm.xen_hvm_set_attr(&XenHvmAttr::shared_info(0x10)).is_err());
+            return;
+        }
+
+        let mem_size = 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0x10000,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+
+        vm.xen_hvm_set_attr(&XenHvmAttr::shared_info(0x10)).unwrap();
+        let mut attr = XenHvmAttr::shared_info(0);
+        vm.xen_hvm_get_attr(&mut attr).unwrap();
+        assert_eq!(attr.shared_info_gfn(), Some(0x10));
+
+        vm.xen_hvm_set_attr(&XenHvmAttr::upcall_vector(0xf3))
+            .unwrap();
+        let mut attr = XenHvmAttr::upcall_vector(0);
+        vm.xen_hvm_get_attr(&mut attr).unwrap();
+        assert_eq!(attr.vector(), Some(0xf3));
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_set_pmu_event_filter() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        if !vm.check_extension(Cap::PmuEventFilter) {
+            return;
+        }
+
+        // An allow list without events denies all of them.
+        vm.set_pmu_event_filter(PmuFilterAction::Allow, &[], 0, 0)
+            .unwrap();
+        vm.set_pmu_event_filter(PmuFilterAction::Deny, &[0x00c0, 0x003c], 0, 0)
+            .unwrap();
+        let masked = vm.set_pmu_event_filter(
+            PmuFilterAction::Allow,
+            &[],
+            0,
+            KVM_PMU_EVENT_FLAG_MASKED_EVENTS,
         );
+        if vm.check_extension(Cap::PmuEventMaskedEvents) {
+            masked.unwrap();
+        } else {
+            assert_eq!(masked.unwrap_err().errno(), libc::EINVAL);
+        }
+
+        let events = [0; PMU_EVENT_FILTER_MAX_EVENTS + 1];
         assert_eq!(
-            faulty_vm_fd
-                .register_irqfd(&event_fd, 0)
+            vm.set_pmu_event_filter(PmuFilterAction::Deny, &events, 0, 0)
                 .unwrap_err()
                 .errno(),
-            badf_errno
+            libc::EINVAL
         );
+    }
 
-        assert_eq!(
-            faulty_vm_fd.create_vcpu(0).err().unwrap().errno(),
-            badf_errno
-        );
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_msr_filter_builder() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
 
-        assert_eq!(
-            faulty_vm_fd.get_dirty_log(0, 0).unwrap_err().errno(),
-            badf_errno
-        );
+        let max_msrs = KVM_MSR_FILTER_MAX_BITMAP_SIZE * 8;
+        let einval = errno::Error::new(libc::EINVAL);
+        let empty = MsrFilterBuilder::default_deny().deny_range(0x10, 0);
+        assert_eq!(empty.apply(&vm).unwrap_err(), einval);
+        let too_long = MsrFilterBuilder::default_deny().allow_range(0, max_msrs + 1);
+        assert_eq!(too_long.apply(&vm).unwrap_err(), einval);
+        let too_many = (0..=KVM_MSR_FILTER_MAX_RANGES)
+            .fold(MsrFilterBuilder::default_allow(), |filter, i| {
+                filter.deny_range(i * 0x100, 1)
+            });
+        assert_eq!(too_many.apply(&vm).unwrap_err(), einval);
+
+        if !vm.check_extension(Cap::X86MsrFilter) {
+            return;
+        }
+        MsrFilterBuilder::default_deny()
+            .allow_range(0, max_msrs)
+            .deny_range(0x10, 1)
+            .apply(&vm)
+            .unwrap();
+        MsrFilterBuilder::clear(&vm).unwrap();
     }
 
     #[test]
-    #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
-    fn test_get_preferred_target() {
+    #[cfg(target_arch = "x86_64")]
+    fn test_xen_hvm_config() {
+        use crate::VcpuExit;
+
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        let mut kvi: kvm_bindings::kvm_vcpu_init = kvm_bindings::kvm_vcpu_init::default();
-        assert!(vm.get_preferred_target(&mut kvi).is_ok());
+        let config = XenHvmConfig::new()
+            .intercept_hcall(true)
+            .hypercall_msr(0x4000_0000);
+        let raw_config = kvm_xen_hvm_config::from(config);
+        assert_eq!(raw_config.flags, KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL);
+        assert_eq!(raw_config.msr, 0x4000_0000);
+        assert_eq!(raw_config.blob_addr_32, 0);
+        assert_eq!(raw_config.blob_addr_64, 0);
+        assert_eq!(
+            XenHvmConfig::new().intercept_hcall(false),
+            XenHvmConfig::new()
+        );
+        if vm.supported_xen_hvm_features() & KVM_XEN_HVM_CONFIG_INTERCEPT_HCALL == 0 {
+            return;
+        }
+        vm.xen_hvm_config(&raw_config).unwrap();
+
+        #[rustfmt::skip]
+        let code = [
+            0x0f, 0x01, 0xc1, /* vmcall */
+            0xf4, /* hlt */
+        ];
+        let mem_size = 0x1000;
+        let load_addr = mmap_anonymous(mem_size).as_ptr();
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: mem_size as u64,
+            userspace_addr: load_addr as u64,
+            flags: 0,
+        };
+        unsafe {
+            vm.set_user_memory_region(mem_region).unwrap();
+            std::ptr::copy_nonoverlapping(code.as_ptr(), load_addr, code.len());
+        }
+
+        let mut vcpu = vm.create_vcpu(0).unwrap();
+        let mut sregs = vcpu.get_sregs().unwrap();
+        sregs.cs.base = 0;
+        sregs.cs.selector = 0;
+        vcpu.set_sregs(&sregs).unwrap();
+        let mut regs = vcpu.get_regs().unwrap();
+        regs.rip = 0;
+        regs.rflags = 2;
+        // __HYPERVISOR_xen_version.
+        regs.rax = 17;
+        vcpu.set_regs(&regs).unwrap();
+
+        match vcpu.run().expect("run failed") {
+            VcpuExit::Unsupported(KVM_EXIT_XEN) => {}
+            r => panic!("unexpected exit reason: {:?}", r),
+        }
     }
 
-    /// As explained in the example code related to signal_msi(), sending
-    /// a random MSI vector will always fail because no vector has been
-    /// previously allocated from the guest itself.
     #[test]
     #[cfg(any(
         target_arch = "x86",
@@ -2156,66 +8083,243 @@ mod tests {
         target_arch = "arm",
         target_arch = "aarch64"
     ))]
-    fn test_signal_msi_failure() {
+    fn test_gsi_allocator() {
+        let allocator = GsiAllocator::with_range(24, 28);
+        assert_eq!(allocator.max(), 28);
+        for gsi in 24..28 {
+            assert_eq!(allocator.allocate().unwrap(), gsi);
+        }
+        assert_eq!(
+            allocator.allocate(),
+            Err(GsiAllocatorError::Exhausted { max: 28 })
+        );
+        assert_eq!(allocator.allocate().unwrap_err().errno(), libc::ENOSPC);
+
+        // Freed GSIs are reused lowest first.
+        allocator.free(26).unwrap();
+        allocator.free(25).unwrap();
+        assert_eq!(allocator.free(25), Err(GsiAllocatorError::NotAllocated(25)));
+        assert_eq!(allocator.free(23), Err(GsiAllocatorError::NotAllocated(23)));
+        assert_eq!(allocator.free(28), Err(GsiAllocatorError::NotAllocated(28)));
+        assert_eq!(allocator.allocate().unwrap(), 25);
+        assert_eq!(allocator.allocate().unwrap(), 26);
+        assert!(allocator.allocate().is_err());
+
+        // Clones share the same pool.
+        let other = allocator.clone();
+        other.free(27).unwrap();
+        assert_eq!(allocator.allocate().unwrap(), 27);
+        for gsi in 24..28 {
+            allocator.free(gsi).unwrap();
+        }
+        assert_eq!(other.allocate().unwrap(), 24);
+
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        let msi = kvm_msi::default();
-        assert!(vm.signal_msi(msi).is_err());
+        let allocator = GsiAllocator::new(&vm);
+        assert_eq!(allocator.max() as usize, GsiRouting::capacity(&vm));
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        assert_eq!(allocator.allocate().unwrap(), KVM_IOAPIC_NUM_PINS);
+        #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+        assert_eq!(allocator.allocate().unwrap(), 0);
     }
 
     #[test]
-    #[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
-    fn test_enable_cap_failure() {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_irqfd_guarded() {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        let cap: kvm_enable_cap = Default::default();
-        // Providing the `kvm_enable_cap` structure filled with default() should
-        // always result in a failure as it is not a valid capability.
-        assert!(vm.enable_cap(&cap).is_err());
+        vm.create_irq_chip().unwrap();
+        let allocator = GsiAllocator::new(&vm);
+
+        let mut routing = GsiRouting::new();
+        let gsi = routing
+            .add_msi_auto(&allocator, 0, 0xfee0_0000, 0x30)
+            .unwrap();
+        assert_eq!(gsi, 24);
+        assert_eq!(routing.entries()[0].gsi(), gsi);
+        routing.commit(&vm).unwrap();
+
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let guard = vm.register_irqfd_guarded(&evtfd, gsi, &allocator).unwrap();
+        assert_eq!(guard.gsi(), gsi);
+        // The eventfd is already registered.
+        assert!(vm.register_irqfd(&evtfd, gsi).is_err());
+        evtfd.write(1).unwrap();
+        drop(guard);
+
+        // The irqfd was unregistered and its GSI freed.
+        assert_eq!(allocator.allocate().unwrap(), gsi);
+        let guard = vm.register_irqfd_guarded(&evtfd, gsi, &allocator).unwrap();
+        guard.unregister().unwrap();
+        vm.register_irqfd(&evtfd, gsi).unwrap();
+        vm.unregister_irqfd(&evtfd, gsi).unwrap();
+
+        // A forgotten guard leaves the irqfd registered and its GSI allocated.
+        let gsi = allocator.allocate().unwrap();
+        vm.register_irqfd_guarded(&evtfd, gsi, &allocator)
+            .unwrap()
+            .forget();
+        assert!(vm.register_irqfd(&evtfd, gsi).is_err());
+        assert_ne!(allocator.allocate().unwrap(), gsi);
+        vm.unregister_irqfd(&evtfd, gsi).unwrap();
     }
 
     #[test]
     #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-    fn test_enable_split_irqchip_cap() {
+    fn test_irqfd_with_resample_guarded() {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        let mut cap = kvm_enable_cap {
-            cap: KVM_CAP_SPLIT_IRQCHIP,
-            ..Default::default()
-        };
-        // As per the KVM documentation, KVM_CAP_SPLIT_IRQCHIP only emulates
-        // the local APIC in kernel, expecting that a userspace IOAPIC will
-        // be implemented by the VMM.
-        // Along with this capability, the user needs to specify the number
-        // of pins reserved for the userspace IOAPIC. This number needs to be
-        // provided through the first argument of the capability structure, as
-        // specified in KVM documentation:
-        //     args[0] - number of routes reserved for userspace IOAPICs
-        //
-        // Because an IOAPIC supports 24 pins, that's the reason why this test
-        // picked this number as reference.
-        cap.args[0] = 24;
-        assert!(vm.enable_cap(&cap).is_ok());
+        let evtfd = EventFd::new(EFD_NONBLOCK).unwrap();
+        let resamplefd = EventFd::new(EFD_NONBLOCK).unwrap();
+
+        // Resampling needs the in-kernel irqchip.
+        assert!(vm
+            .register_irqfd_with_resample_guarded(&evtfd, &resamplefd, 5)
+            .is_err());
+        vm.create_irq_chip().unwrap();
+
+        let guard = vm
+            .register_irqfd_with_resample_guarded(&evtfd, &resamplefd, 5)
+            .unwrap();
+        assert_eq!(guard.gsi(), 5);
+        assert!(vm.register_irqfd(&evtfd, 5).is_err());
+        drop(guard);
+
+        // The GSI can be registered again once the guard is dropped.
+        let guard = vm
+            .register_irqfd_with_resample_guarded(&evtfd, &resamplefd, 5)
+            .unwrap();
+        guard.unregister().unwrap();
+        vm.register_irqfd(&evtfd, 5).unwrap();
+        vm.unregister_irqfd(&evtfd, 5).unwrap();
     }
 
     #[test]
-    #[cfg(any(
-        target_arch = "x86",
-        target_arch = "x86_64",
-        target_arch = "arm",
-        target_arch = "aarch64"
-    ))]
-    fn test_set_gsi_routing() {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_gsi_routing_commit_reusing() {
+        const ENTRIES: u32 = 512;
+
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
-        if cfg!(target_arch = "x86") || cfg!(target_arch = "x86_64") {
-            let irq_routing = kvm_irq_routing::default();
-            // Expect failure for x86 since the irqchip is not created yet.
-            assert!(vm.set_gsi_routing(&irq_routing).is_err());
-            vm.create_irq_chip().unwrap();
+        vm.create_irq_chip().unwrap();
+        let capacity = GsiRouting::capacity(&vm);
+        assert!(capacity >= ENTRIES as usize);
+
+        let mut routing = GsiRouting::new();
+        for gsi in 0..ENTRIES {
+            routing.add_entry(IrqRoutingEntry::msi(gsi, 0, 0xfee0_0000, gsi));
         }
-        let irq_routing = kvm_irq_routing::default();
-        assert!(vm.set_gsi_routing(&irq_routing).is_ok());
+        routing.commit_reusing(&vm).unwrap();
+
+        // The serialized table is only allocated by the first commit.
+        let (buffer_ptr, buffer_len, buffer_capacity) = (
+            routing.buffer.as_ptr(),
+            routing.buffer.len(),
+            routing.buffer.capacity(),
+        );
+        for i in 0..1000 {
+            let gsi = i % ENTRIES;
+            routing.entries_mut()[gsi as usize] = IrqRoutingEntry::msi(gsi, 0, 0xfee0_0000, i);
+            routing.commit_reusing(&vm).unwrap();
+            assert_eq!(routing.buffer.as_ptr(), buffer_ptr);
+            assert_eq!(routing.buffer.len(), buffer_len);
+            assert_eq!(routing.buffer.capacity(), buffer_capacity);
+        }
+
+        // Tables larger than the capacity are rejected before reaching KVM.
+        let mut routing = GsiRouting::new();
+        for gsi in 0..=capacity as u32 {
+            routing.add_entry(IrqRoutingEntry::msi(gsi, 0, 0xfee0_0000, 0));
+        }
+        assert_eq!(routing.commit(&vm).unwrap_err().errno(), libc::EINVAL);
+        assert_eq!(
+            routing.commit_reusing(&vm).unwrap_err().errno(),
+            libc::EINVAL
+        );
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_gsi_routing_msi_devid() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vgic_fd = create_gic_device(&vm, 0);
+        vm.create_vcpu(0).unwrap();
+        set_supported_nr_irqs(&vgic_fd, 128);
+        request_gic_init(&vgic_fd);
+
+        // The ITS is only available with GICv3.
+        let mut its_device = kvm_create_device {
+            type_: kvm_device_type_KVM_DEV_TYPE_ARM_VGIC_ITS,
+            fd: 0,
+            flags: 0,
+        };
+        let its_fd = match vm.create_device(&mut its_device) {
+            Ok(fd) => fd,
+            Err(_) => return,
+        };
+        assert!(vm.supports_msi_devid());
+
+        let its_base: u64 = 0x0800_0000;
+        let its_attr = |group: u32, attr: u64, addr: *const u64| kvm_device_attr {
+            group,
+            attr,
+            addr: addr as u64,
+            flags: 0,
+        };
+        its_fd
+            .set_device_attr(&its_attr(
+                KVM_DEV_ARM_VGIC_GRP_ADDR,
+                u64::from(KVM_VGIC_ITS_ADDR_TYPE),
+                &its_base,
+            ))
+            .unwrap();
+        its_fd
+            .set_device_attr(&its_attr(
+                KVM_DEV_ARM_VGIC_GRP_CTRL,
+                u64::from(KVM_DEV_ARM_VGIC_CTRL_INIT),
+                std::ptr::null(),
+            ))
+            .unwrap();
+        // Enable the ITS: GITS_CBASER, GITS_BASER0 (devices) and GITS_BASER1 (collections)
+        // must be valid first.
+        let valid: u64 = 1 << 63;
+        for offset in [0x80, 0x100, 0x108] {
+            its_fd
+                .set_device_attr(&its_attr(KVM_DEV_ARM_VGIC_GRP_ITS_REGS, offset, &valid))
+                .unwrap();
+        }
+        let enable: u64 = 1;
+        its_fd
+            .set_device_attr(&its_attr(KVM_DEV_ARM_VGIC_GRP_ITS_REGS, 0, &enable))
+            .unwrap();
+
+        // MSIs target GITS_TRANSLATER.
+        let doorbell = its_base + 0x1_0040;
+        let (address_hi, address_lo) = ((doorbell >> 32) as u32, doorbell as u32);
+        let devid = 0x10;
+
+        let mut routing = GsiRouting::new();
+        routing.add_entry(IrqRoutingEntry::msi(32, address_hi, address_lo, 0));
+        assert_eq!(routing.commit(&vm).unwrap_err().errno(), libc::EINVAL);
+
+        let mut routing = GsiRouting::new();
+        routing.add_entry(IrqRoutingEntry::msi_with_devid(
+            32, address_hi, address_lo, 0, devid,
+        ));
+        routing.commit(&vm).unwrap();
+
+        let msi = kvm_msi {
+            address_lo,
+            address_hi,
+            data: 0,
+            flags: KVM_MSI_VALID_DEVID,
+            devid,
+            ..Default::default()
+        };
+        // The device has not been mapped by the guest, so the MSI is dropped.
+        vm.signal_msi(msi).unwrap();
     }
 
     #[test]
@@ -2240,6 +8344,233 @@ mod tests {
         let kvm = Kvm::new().unwrap();
         let vm = kvm.create_vm().unwrap();
         assert!(vm.check_extension(Cap::MpState));
+        assert!(vm.check_extension_int(Cap::MaxVcpus) > 0);
+    }
+
+    #[test]
+    fn test_vm_raw_ioctl() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        for cap in [Cap::MpState, Cap::MaxVcpus, Cap::NrMemslots] {
+            let ret =
+                unsafe { vm.raw_ioctl(KVM_CHECK_EXTENSION() as u64, cap as usize as *mut c_void) };
+            assert_eq!(ret.unwrap(), vm.check_extension_int(cap));
+        }
+        // `KVM_GET_API_VERSION` is not a VM ioctl. Unknown VM ioctls fail with `ENOTTY` on
+        // x86 and with `EINVAL` on arm64.
+        let err = unsafe { vm.raw_ioctl(KVM_GET_API_VERSION() as u64, std::ptr::null_mut()) };
+        let errno = err.unwrap_err().errno();
+        assert!(errno == libc::ENOTTY || errno == libc::EINVAL, "{}", errno);
+    }
+
+    #[test]
+    fn test_into_owned_fd() {
+        use std::os::unix::io::IntoRawFd;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let raw_fd = vm.as_raw_fd();
+
+        let fd = vm.into_owned_fd();
+        assert_eq!(fd.as_raw_fd(), raw_fd);
+        let vm = unsafe { kvm.create_vmfd_from_rawfd(fd.into_raw_fd()).unwrap() };
+        assert_eq!(vm.as_raw_fd(), raw_fd);
+        // The VM is still alive.
+        vm.create_vcpu(1).unwrap();
+        vcpu.get_mp_state().unwrap();
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_set_counter_offset() {
+        // ARM64_SYS_REG(3, 3, 14, 3, 2), i.e. CNTVCT_EL0.
+        const KVM_REG_ARM_TIMER_CNT: u64 =
+            KVM_REG_ARM64 | KVM_REG_SIZE_U64 | KVM_REG_ARM64_SYSREG as u64 | 0xdf1a;
+
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        if !vm.check_extension(Cap::CounterOffset) {
+            return;
+        }
+        let vcpu = vm.create_vcpu(0).unwrap();
+        let mut kvi = kvm_vcpu_init::default();
+        vm.get_preferred_target(&mut kvi).unwrap();
+        vcpu.vcpu_init(&kvi).unwrap();
+
+        // The counter of a new VM starts from 0.
+        let mut cnt = [0u8; 8];
+        vcpu.get_one_reg(KVM_REG_ARM_TIMER_CNT, &mut cnt).unwrap();
+        assert!(u64::from_le_bytes(cnt) < 1 << 63);
+
+        // With an offset larger than the host counter, the guest counter wraps around.
+        vm.set_counter_offset(1 << 63).unwrap();
+        vcpu.get_one_reg(KVM_REG_ARM_TIMER_CNT, &mut cnt).unwrap();
+        assert!(u64::from_le_bytes(cnt) >= 1 << 63);
+
+        // Writing the counter has no effect anymore.
+        vcpu.set_one_reg(KVM_REG_ARM_TIMER_CNT, &0u64.to_le_bytes())
+            .unwrap();
+        vcpu.get_one_reg(KVM_REG_ARM_TIMER_CNT, &mut cnt).unwrap();
+        assert!(u64::from_le_bytes(cnt) >= 1 << 63);
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_create_vgic() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let (vgic, version) = vm.create_vgic(GicVersion::V3).unwrap();
+        assert!(version == GicVersion::V3 || version == GicVersion::V2);
+        let nr_irqs_attr = kvm_device_attr {
+            group: KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+            attr: 0,
+            addr: 0,
+            flags: 0,
+        };
+        assert!(vgic.has_device_attr(&nr_irqs_attr).is_ok());
+        // A VM has a single vGIC.
+        assert_eq!(vm.create_vgic(version).unwrap_err().errno(), libc::EEXIST);
+
+        // Hosts with a GICv3 may not be able to emulate a GICv2.
+        let vm = kvm.create_vm().unwrap();
+        let (_, v2_version) = vm.create_vgic(GicVersion::V2).unwrap();
+        if v2_version == GicVersion::V3 {
+            assert_eq!(version, GicVersion::V3);
+        }
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_vm_device_attr() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        // Groups known to device fds only are rejected.
+        let nr_irqs_attr = kvm_device_attr {
+            group: KVM_DEV_ARM_VGIC_GRP_NR_IRQS,
+            attr: 0,
+            addr: 0,
+            flags: 0,
+        };
+        assert!(vm.has_device_attr(&nr_irqs_attr).is_err());
+
+        // The following definitions come from `arch/arm64/include/uapi/asm/kvm.h` and are
+        // missing from the `kvm-bindings` used by this crate.
+        const KVM_ARM_VM_SMCCC_CTRL: u32 = 0;
+        const KVM_ARM_VM_SMCCC_FILTER: u32 = 0;
+        const KVM_SMCCC_FILTER_DENY: u8 = 1;
+        #[allow(non_camel_case_types)]
+        #[repr(C)]
+        #[derive(Default)]
+        struct kvm_smccc_filter {
+            base: u32,
+            nr_functions: u32,
+            action: u8,
+            pad: [u8; 15],
+        }
+
+        let filter = kvm_smccc_filter {
+            base: 0x8600_0000,
+            nr_functions: 0x100,
+            action: KVM_SMCCC_FILTER_DENY,
+            ..Default::default()
+        };
+        let mut filter_attr = kvm_device_attr {
+            group: KVM_ARM_VM_SMCCC_CTRL,
+            attr: u64::from(KVM_ARM_VM_SMCCC_FILTER),
+            addr: &filter as *const kvm_smccc_filter as u64,
+            flags: 0,
+        };
+        if vm.has_device_attr(&filter_attr).is_err() {
+            return;
+        }
+        vm.set_device_attr(&filter_attr).unwrap();
+        // The range is already filtered.
+        assert_eq!(
+            vm.set_device_attr(&filter_attr).unwrap_err().errno(),
+            libc::EEXIST
+        );
+        // The filter is write-only.
+        let mut value = 0u64;
+        filter_attr.addr = &mut value as *mut u64 as u64;
+        assert!(unsafe { vm.get_device_attr(&mut filter_attr) }.is_err());
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_mte_copy_tags() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let mut tags = vec![0u8; page_size / 16];
+
+        // The range of guest memory must be made of whole pages.
+        let einval = |guest_ipa, tags: &mut [u8]| {
+            vm.mte_copy_tags(guest_ipa, tags, MteCopyFlags::FromGuest)
+                .unwrap_err()
+                .errno()
+                == libc::EINVAL
+        };
+        assert!(einval(0, &mut []));
+        assert!(einval(0, &mut tags[1..]));
+        assert!(einval(0x800, &mut tags));
+
+        if !vm.check_extension(Cap::ArmMte) {
+            return;
+        }
+        vm.enable_mte().unwrap();
+        let guest_mem = mmap_anonymous(page_size).as_ptr();
+        let mem_region = kvm_userspace_memory_region {
+            slot: 0,
+            guest_phys_addr: 0,
+            memory_size: page_size as u64,
+            userspace_addr: guest_mem as u64,
+            flags: 0,
+        };
+        unsafe { vm.set_user_memory_region(mem_region).unwrap() };
+
+        for (i, tag) in tags.iter_mut().enumerate() {
+            *tag = (i % 16) as u8;
+        }
+        assert_eq!(
+            vm.mte_copy_tags(0, &mut tags.clone(), MteCopyFlags::ToGuest)
+                .unwrap(),
+            tags.len()
+        );
+        let mut read_tags = vec![0u8; tags.len()];
+        assert_eq!(
+            vm.mte_copy_tags(0, &mut read_tags, MteCopyFlags::FromGuest)
+                .unwrap(),
+            tags.len()
+        );
+        assert_eq!(read_tags, tags);
+
+        // MTE cannot be enabled once there are vCPUs.
+        let vm = kvm.create_vm().unwrap();
+        vm.create_vcpu(0).unwrap();
+        assert!(vm.enable_mte().is_err());
+    }
+
+    #[test]
+    #[cfg(target_arch = "aarch64")]
+    fn test_check_extension_vm_scoped() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        // The IPA size limit is reported on both file descriptors, and a VM of the default type
+        // reports the host limit.
+        let host_ipa_limit = kvm.get_host_ipa_limit();
+        let vm_ipa_limit = vm.check_extension_int(Cap::ArmVmIPASize);
+        if host_ipa_limit > 0 {
+            assert!(vm_ipa_limit >= 32);
+            assert_eq!(vm_ipa_limit, host_ipa_limit);
+        }
+
+        // Whether MSIs need a device ID depends on the vGIC of a VM, so the system file
+        // descriptor cannot answer.
+        assert!(kvm.check_extension_int(Cap::MsiDevid) < 0);
+        assert_eq!(vm.check_extension_int(Cap::MsiDevid), 0);
     }
 
     #[test]
@@ -2318,5 +8649,68 @@ mod tests {
         );
         assert!(vm.register_enc_memory_region(&memory_region).is_ok());
         assert!(vm.unregister_enc_memory_region(&memory_region).is_ok());
+
+        let guard = vm
+            .register_enc_memory_region_guarded(&memory_region)
+            .unwrap();
+        guard.unregister().unwrap();
+        {
+            let _guard = vm
+                .register_enc_memory_region_guarded(&memory_region)
+                .unwrap();
+        }
+        // The region has been unregistered when the guard went out of scope.
+        assert!(vm.register_enc_memory_region(&memory_region).is_ok());
+    }
+
+    #[test]
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    fn test_enc_memory_region_validation() {
+        let kvm = Kvm::new().unwrap();
+        let vm = kvm.create_vm().unwrap();
+
+        let addr = unsafe {
+            libc::mmap(
+                null_mut(),
+                8192,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+
+        // Unaligned regions are rejected before reaching the kernel.
+        for (addr, size) in [(addr as u64 + 1, 4096), (addr as u64, 4095)] {
+            let region = kvm_enc_region { addr, size };
+            assert_eq!(
+                vm.register_enc_memory_region(&region).unwrap_err().errno(),
+                libc::EINVAL
+            );
+            assert_eq!(
+                vm.unregister_enc_memory_region(&region)
+                    .unwrap_err()
+                    .errno(),
+                libc::EINVAL
+            );
+            assert_eq!(
+                vm.register_enc_memory_region_guarded(&region)
+                    .unwrap_err()
+                    .errno(),
+                libc::EINVAL
+            );
+        }
+
+        // Without SEV, the kernel rejects the region and no guard is created.
+        let region = kvm_enc_region {
+            addr: addr as u64,
+            size: 8192,
+        };
+        if vm.register_enc_memory_region_guarded(&region).is_err() {
+            assert!(vm.unregister_enc_memory_region(&region).is_err());
+        }
+
+        unsafe { libc::munmap(addr, 8192) };
     }
 }
@@ -217,16 +217,66 @@ mod cap;
 mod ioctls;
 
 pub use cap::Cap;
-pub use ioctls::device::DeviceFd;
+#[cfg(target_arch = "aarch64")]
+pub use ioctls::arm::{
+    configure_pvtime, GicBuilder, GicDevice, GicError, GicVersion, ItsDevice, PvTimeError,
+    PVTIME_STRUCT_SIZE,
+};
+pub use ioctls::device::{DeviceAttrValue, DeviceFd, TypedDevice};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use ioctls::sev::{
+    SevError, SevFd, SevPolicy, SnpPageType, KVM_X86_SEV_ES_VM, KVM_X86_SEV_VM, KVM_X86_SNP_VM,
+};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use ioctls::system::CpuIdExt;
 pub use ioctls::system::Kvm;
+#[cfg(target_arch = "aarch64")]
+pub use ioctls::system::{IpaSizeError, VmBuilder};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use ioctls::tdx::{TdxCapabilities, TdxError, KVM_X86_TDX_VM};
 #[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
 pub use ioctls::vcpu::reg_size;
-pub use ioctls::vcpu::{HypercallExit, VcpuExit, VcpuFd};
+#[cfg(target_arch = "aarch64")]
+pub use ioctls::vcpu::CoreReg;
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "s390"
+))]
+pub use ioctls::vcpu::MpState;
+pub use ioctls::vcpu::{HypercallExit, SystemEventType, VcpuExit, VcpuFd};
 
 #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
-pub use ioctls::vcpu::{MsrExitReason, ReadMsrExit, SyncReg, WriteMsrExit};
+pub use ioctls::vcpu::{
+    BreakpointCondition, DebugRegs, Dr7Builder, FpuState, LapicState, MsrExitReason,
+    NotifyExitFlags, ReadMsrExit, SyncReg, VcpuEventsBuilder, WriteMsrExit, XcrsState,
+};
 
-pub use ioctls::vm::{IoEventAddress, NoDatamatch, VmFd};
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+pub use ioctls::vm::ArmIrqLine;
+#[cfg(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64"))]
+pub use ioctls::vm::PmuFilterAction;
+#[cfg(target_arch = "aarch64")]
+pub use ioctls::vm::{ArmVcpuFeature, ArmVcpuInitBuilder, ArmVcpuInitError, MteCopyFlags};
+#[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+pub use ioctls::vm::{
+    BusLockExitMode, ClockState, EncRegionGuard, HypervFeatures, IoapicState, IrqChipId,
+    MsrFilterBuilder, NotifyVmexitFlags, PicState, Pit2State, PitChannel, PitConfig, PitError,
+    XenHvmAttr, XenHvmConfig, KVM_HC_CLOCK_PAIRING, KVM_HC_KICK_CPU, KVM_HC_MAP_GPA_RANGE,
+    KVM_HC_SCHED_YIELD, KVM_HC_SEND_IPI, KVM_IRQ_ROUTING_XEN_EVTCHN_PRIO_2LEVEL,
+    KVM_MAP_GPA_RANGE_DECRYPTED, KVM_MAP_GPA_RANGE_ENCRYPTED, KVM_MAP_GPA_RANGE_PAGE_SZ_1G,
+    KVM_MAP_GPA_RANGE_PAGE_SZ_2M, KVM_MAP_GPA_RANGE_PAGE_SZ_4K, KVM_PMU_EVENT_FLAG_MASKED_EVENTS,
+};
+pub use ioctls::vm::{Datamatch, IoEventAddress, IoEventGuard, IrqChipError, NoDatamatch, VmFd};
+#[cfg(any(
+    target_arch = "x86",
+    target_arch = "x86_64",
+    target_arch = "arm",
+    target_arch = "aarch64"
+))]
+pub use ioctls::vm::{GsiAllocator, GsiAllocatorError, GsiRouting, IrqFdGuard, IrqRoutingEntry};
 // The following example is used to verify that our public
 // structures are exported properly.
 /// # Example